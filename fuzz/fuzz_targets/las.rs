@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tinyraytracer::materials::Material;
+use tinyraytracer::points::{PointCloud, parse_las};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(points) = parse_las(data) {
+        PointCloud::new(points, 0.1, Material::default());
+    }
+});
@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tinyraytracer::geometry::{Intersect, Ray, Vec3};
+use tinyraytracer::materials::Material;
+use tinyraytracer::mesh::{Mesh, parse_obj};
+
+fuzz_target!(|data: &[u8]| {
+    let source = match std::str::from_utf8(data) {
+        Ok(source) => source,
+        Err(_) => return,
+    };
+
+    if let Ok(obj) = parse_obj(source) {
+        let mesh = Mesh::new(obj.vertices, obj.faces, Material::default());
+        let ray = Ray {
+            origin: Vec3::new(0.0, 0.0, 10.0),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+        mesh.ray_intersect(&ray);
+    }
+});
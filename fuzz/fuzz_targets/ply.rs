@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tinyraytracer::geometry::{Intersect, Ray, Vec3};
+use tinyraytracer::materials::Material;
+use tinyraytracer::points::{PointCloud, parse_ply};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(points) = parse_ply(data) {
+        let cloud = PointCloud::new(points, 0.1, Material::default());
+        let ray = Ray {
+            origin: Vec3::new(0.0, 0.0, 10.0),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+        cloud.ray_intersect(&ray);
+    }
+});
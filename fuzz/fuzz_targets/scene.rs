@@ -0,0 +1,25 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tinyraytracer::camera::Camera;
+use tinyraytracer::render::Renderer;
+use tinyraytracer::scene::{self, Format};
+
+// Scenes that parse are shaded at a few pixels, to catch values that parse
+// but break rendering. Paths to meshes and images in the input are loaded
+// like any other, so run this somewhere without large or endless files.
+fuzz_target!(|data: &[u8]| {
+    let source = match std::str::from_utf8(data) {
+        Ok(source) => source,
+        Err(_) => return,
+    };
+
+    for format in [Format::Ron, Format::Json] {
+        if let Ok(scene) = scene::parse(source, format) {
+            let renderer = Renderer::default();
+            for (x, y) in [(0, 0), (4, 4), (7, 7)] {
+                renderer.render_pixel(&scene, &Camera::default(), 8, 8, x, y);
+            }
+        }
+    }
+});
@@ -0,0 +1,452 @@
+use crate::Result;
+use crate::camera::Camera;
+use crate::geometry::{Shape, Vec2, Vec3};
+use crate::materials::{Material, Pattern};
+use crate::scene::Scene;
+use crate::sky::Sky;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+// How a curve gets from one key to the next
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Interpolation {
+    // Holding each key's value until the next
+    Step,
+    #[default]
+    Linear,
+    // Along a Catmull-Rom spline through the keys, without corners
+    Smooth,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Key {
+    pub time: f32,
+    pub value: f32,
+}
+
+// A value changing over time in seconds, held at its first and last keys'
+// values before and after them, or starting over after the last if `looped`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Curve {
+    pub keys: Vec<Key>,
+    #[serde(default)]
+    pub interpolation: Interpolation,
+    #[serde(default)]
+    pub looped: bool,
+}
+
+impl Curve {
+    pub fn new(keys: &[(f32, f32)], interpolation: Interpolation) -> Self {
+        Curve {
+            keys: keys.iter().map(|&(time, value)| Key { time, value }).collect(),
+            interpolation,
+            looped: false,
+        }
+    }
+
+    // The value at `time`, or None if the curve has no keys. Keys are
+    // expected in order of time.
+    pub fn evaluate(&self, time: f32) -> Option<f32> {
+        let (first, last) = (self.keys.first()?, self.keys.last()?);
+        let duration = last.time - first.time;
+        let time = if self.looped && duration > 0.0 {
+            first.time + (time - first.time).rem_euclid(duration)
+        } else {
+            time
+        };
+
+        // The key at or before the time
+        let k = self.keys.partition_point(|key| key.time <= time);
+        if k == 0 {
+            return Some(first.value);
+        }
+        if k == self.keys.len() {
+            return Some(last.value);
+        }
+
+        let (a, b) = (self.keys[k - 1], self.keys[k]);
+        let t = if b.time > a.time { (time - a.time) / (b.time - a.time) } else { 1.0 };
+
+        let value = match self.interpolation {
+            Interpolation::Step => a.value,
+            Interpolation::Linear => a.value + (b.value - a.value) * t,
+            Interpolation::Smooth => {
+                // Slopes from the keys either side, scaled to this span
+                let slope = |before: Key, after: Key| {
+                    let span = after.time - before.time;
+                    if span > 0.0 { (after.value - before.value) / span * (b.time - a.time) } else { 0.0 }
+                };
+                let m0 = slope(self.keys[k.saturating_sub(2)], b);
+                let m1 = slope(a, self.keys[(k + 1).min(self.keys.len() - 1)]);
+
+                let (t2, t3) = (t * t, t * t * t);
+                (2.0 * t3 - 3.0 * t2 + 1.0) * a.value
+                    + (t3 - 2.0 * t2 + t) * m0
+                    + (-2.0 * t3 + 3.0 * t2) * b.value
+                    + (t3 - t2) * m1
+            }
+        };
+        Some(value)
+    }
+}
+
+// A named curve driving a number in the scene or camera, addressed by its
+// path through the scene file's fields. Tracks started `on` a trigger wait
+// for it, then play their curve from the moment it fires.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Track {
+    pub path: String,
+    pub curve: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub on: Option<String>,
+}
+
+// Curves, by name, and the numbers they drive. The numbers of lights,
+// shapes, materials and the sky are given by their path through the scene
+// file's fields, with shapes picked by index among all shapes or among those
+// of their kind, and the camera's by its position, yaw, pitch and field of
+// view (angles in radians):
+//
+//     animation: (
+//         curves: {"flicker": (keys: [(time: 0.0, value: 1.0), (time: 0.5, value: 0.2)], looped: true)},
+//         tracks: [(path: "lights[1].intensity", curve: "flicker"), (path: "spheres[2].radius", curve: "flicker")],
+//     )
+//
+// Meshes, point clouds, curves and scatters are loaded from or built by
+// their descriptions, so just their position and material can be animated.
+// Integers and switches can't be animated at all.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Animation {
+    #[serde(default)]
+    pub curves: BTreeMap<String, Curve>,
+    #[serde(default)]
+    pub tracks: Vec<Track>,
+    // When each trigger that has fired last did, in seconds
+    #[serde(skip)]
+    pub started: BTreeMap<String, f32>,
+}
+
+impl Animation {
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    // Starts the tracks waiting on `trigger` over from `time`, returning
+    // whether there were any
+    pub fn start(&mut self, trigger: &str, time: f32) -> bool {
+        self.started.insert(trigger.to_string(), time);
+        self.tracks.iter().any(|track| track.on.as_deref() == Some(trigger))
+    }
+
+    // How far into its curve a track is at `time`, if it's playing
+    fn track_time(&self, track: &Track, time: f32) -> Option<f32> {
+        match &track.on {
+            None => Some(time),
+            Some(trigger) => self.started.get(trigger).map(|&start| time - start),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Segment<'a> {
+    Field(&'a str),
+    Index(&'a str, usize),
+}
+
+impl fmt::Display for Segment<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Segment::Field(name) => write!(f, "{}", name),
+            Segment::Index(name, index) => write!(f, "{}[{}]", name, index),
+        }
+    }
+}
+
+// Splits `lights[1].position.x` into its fields and indices
+fn parse_path(path: &str) -> Result<Vec<Segment<'_>>> {
+    path.split('.')
+        .map(|part| match part.split_once('[') {
+            Some((name, index)) => index
+                .strip_suffix(']')
+                .and_then(|index| index.parse().ok())
+                .map(|index| Segment::Index(name, index))
+                .ok_or_else(|| format!("invalid index in `{}`", part).into()),
+            None if !part.is_empty() => Ok(Segment::Field(part)),
+            None => Err(format!("empty field in `{}`", path).into()),
+        })
+        .collect()
+}
+
+fn no_field(path: &[Segment]) -> Box<dyn std::error::Error> {
+    match path.first() {
+        Some(segment) => format!("no field `{}`", segment).into(),
+        None => "not a number".into(),
+    }
+}
+
+fn axis<'a>(vector: &'a mut Vec3<f32>, path: &[Segment]) -> Result<&'a mut f32> {
+    match path {
+        [Segment::Field("x")] => Ok(&mut vector.x),
+        [Segment::Field("y")] => Ok(&mut vector.y),
+        [Segment::Field("z")] => Ok(&mut vector.z),
+        _ => Err("expected an axis (x, y or z)".into()),
+    }
+}
+
+fn axis2<'a>(vector: &'a mut Vec2<f32>, path: &[Segment]) -> Result<&'a mut f32> {
+    match path {
+        [Segment::Field("x")] => Ok(&mut vector.x),
+        [Segment::Field("y")] => Ok(&mut vector.y),
+        _ => Err("expected an axis (x or y)".into()),
+    }
+}
+
+fn material_field<'a>(material: &'a mut Material, path: &[Segment]) -> Result<&'a mut f32> {
+    match path {
+        [Segment::Field("albedo"), rest @ ..] => axis2(&mut material.albedo, rest),
+        [Segment::Field("diffuse_colour"), rest @ ..] => axis(&mut material.diffuse_colour, rest),
+        [Segment::Field("specular_exponent")] => Ok(&mut material.specular_exponent),
+        [Segment::Field("reflectivity")] => Ok(&mut material.reflectivity),
+        [Segment::Field("pattern"), Segment::Field("Checkerboard"), rest @ ..] => match &mut material.pattern {
+            Pattern::Checkerboard { other_colour, size } => match rest {
+                [Segment::Field("other_colour"), rest @ ..] => axis(other_colour, rest),
+                [Segment::Field("size")] => Ok(size),
+                _ => Err(no_field(rest)),
+            },
+            Pattern::Solid => Err("the material has no checkerboard".into()),
+        },
+        _ => Err(no_field(path)),
+    }
+}
+
+fn shape_field<'a>(shape: &'a mut Shape, path: &[Segment]) -> Result<&'a mut f32> {
+    if let [Segment::Field("material"), rest @ ..] = path {
+        return material_field(shape.material_mut(), rest);
+    }
+
+    match (shape, path) {
+        (Shape::Sphere(sphere), [Segment::Field("centre"), rest @ ..]) => axis(&mut sphere.centre, rest),
+        (Shape::Sphere(sphere), [Segment::Field("radius")]) => Ok(&mut sphere.radius),
+        (Shape::Sphere(sphere), [Segment::Field("velocity"), rest @ ..]) => axis(&mut sphere.velocity, rest),
+        (Shape::Plane(plane), [Segment::Field("point"), rest @ ..]) => axis(&mut plane.point, rest),
+        (Shape::Plane(plane), [Segment::Field("normal"), rest @ ..]) => axis(&mut plane.normal, rest),
+        (Shape::Plane(plane), [Segment::Field("extent"), rest @ ..]) => match &mut plane.extent {
+            Some(extent) => axis2(extent, rest),
+            None => Err("the plane is unbounded".into()),
+        },
+        (Shape::Triangle(triangle), [Segment::Index("vertices", index), rest @ ..]) => {
+            match triangle.vertices.get_mut(*index) {
+                Some(vertex) => axis(vertex, rest),
+                None => Err(format!("there's no vertex {}", index).into()),
+            }
+        }
+        (Shape::Mesh(mesh), [Segment::Field("position"), rest @ ..]) => axis(&mut mesh.position, rest),
+        (Shape::Points(cloud), [Segment::Field("position"), rest @ ..]) => axis(&mut cloud.position, rest),
+        (Shape::Points(cloud), [Segment::Field("radius")]) => Ok(&mut cloud.radius),
+        (Shape::Curves(curves), [Segment::Field("position"), rest @ ..]) => axis(&mut curves.position, rest),
+        (Shape::Scatter(scatter), [Segment::Field("position"), rest @ ..]) => axis(&mut scatter.position, rest),
+        (_, path) => Err(no_field(path)),
+    }
+}
+
+fn sky_field<'a>(sky: &'a mut Sky, path: &[Segment]) -> Result<&'a mut f32> {
+    match path {
+        [Segment::Field("sun_elevation")] => Ok(&mut sky.sun_elevation),
+        [Segment::Field("sun_azimuth")] => Ok(&mut sky.sun_azimuth),
+        [Segment::Field("sun_intensity")] => Ok(&mut sky.sun_intensity),
+        [Segment::Field("sun_angular_diameter")] => Ok(&mut sky.sun_angular_diameter),
+        [Segment::Field("sky_intensity")] => Ok(&mut sky.sky_intensity),
+        _ => Err(no_field(path)),
+    }
+}
+
+// The shapes of each kind, by the plural used in paths
+fn kind(plural: &str) -> Option<&'static str> {
+    match plural {
+        "spheres" => Some("sphere"),
+        "planes" => Some("plane"),
+        "triangles" => Some("triangle"),
+        "meshes" => Some("mesh"),
+        "scatters" => Some("scatter"),
+        _ => None,
+    }
+}
+
+fn field<'a>(scene: &'a mut Scene, camera: &'a mut Camera, path: &[Segment]) -> Result<&'a mut f32> {
+    match path {
+        [Segment::Field("camera"), Segment::Field("position"), rest @ ..] => axis(&mut camera.position, rest),
+        [Segment::Field("camera"), Segment::Field("yaw")] => Ok(&mut camera.yaw),
+        [Segment::Field("camera"), Segment::Field("pitch")] => Ok(&mut camera.pitch),
+        [Segment::Field("camera"), Segment::Field("fov")] => Ok(&mut camera.fov),
+        [Segment::Index("lights", index), rest @ ..] => {
+            let light = scene.lights.get_mut(*index).ok_or_else(|| format!("there's no light {}", index))?;
+            match rest {
+                [Segment::Field("position"), rest @ ..] => axis(&mut light.position, rest),
+                [Segment::Field("intensity")] => Ok(&mut light.intensity),
+                _ => Err(no_field(rest)),
+            }
+        }
+        [Segment::Index("shapes", index), rest @ ..] => match scene.shapes.get_mut(*index) {
+            Some(shape) => shape_field(shape, rest),
+            None => Err(format!("there's no shape {}", index).into()),
+        },
+        [Segment::Index(plural, index), rest @ ..] if kind(plural).is_some() => {
+            let name = kind(plural).unwrap_or_default();
+            match scene.shapes.iter_mut().filter(|shape| shape.name() == name).nth(*index) {
+                Some(shape) => shape_field(shape, rest),
+                None => Err(format!("there's no {} {}", name, index).into()),
+            }
+        }
+        [Segment::Field("override_material"), rest @ ..] => match &mut scene.override_material {
+            Some(material) => material_field(material, rest),
+            None => Err("the scene has no override material".into()),
+        },
+        [Segment::Field("sky"), rest @ ..] => match &mut scene.sky {
+            Some(sky) => sky_field(sky, rest),
+            None => Err("the scene has no sky".into()),
+        },
+        _ => Err("unknown object".into()),
+    }
+}
+
+fn resolve<'a>(scene: &'a mut Scene, camera: &'a mut Camera, path: &str) -> Result<&'a mut f32> {
+    let error = |e| format!("can't animate `{}`: {}", path, e);
+    let segments = parse_path(path).map_err(error)?;
+    Ok(field(scene, camera, &segments).map_err(error)?)
+}
+
+// The number at the path
+pub fn get(scene: &mut Scene, camera: &mut Camera, path: &str) -> Result<f32> {
+    resolve(scene, camera, path).map(|field| *field)
+}
+
+// Sets the number at the path, returning whether it changed
+pub fn set(scene: &mut Scene, camera: &mut Camera, path: &str, value: f32) -> Result<bool> {
+    if !value.is_finite() {
+        return Err(format!("`{}` can't be set to {}", path, value).into());
+    }
+
+    let field = resolve(scene, camera, path)?;
+
+    let changed = *field != value;
+    *field = value;
+    Ok(changed)
+}
+
+// Sets every animated number to its curve's value at `time`, returning
+// whether anything changed. Tracks that can't be applied are skipped, having
+// been reported by `problems` on loading.
+pub fn animate(scene: &mut Scene, camera: &mut Camera, time: f32) -> bool {
+    if scene.animation.is_empty() {
+        return false;
+    }
+
+    let animation = std::mem::take(&mut scene.animation);
+    let mut changed = false;
+    for track in &animation.tracks {
+        let value = animation
+            .track_time(track, time)
+            .and_then(|time| animation.curves.get(&track.curve).and_then(|curve| curve.evaluate(time)));
+        if let Some(value) = value {
+            changed |= set(scene, camera, &track.path, value).unwrap_or(false);
+        }
+    }
+    scene.animation = animation;
+
+    changed
+}
+
+// Why each track that can't be animated can't be, found by trying it on a
+// copy of the scene
+pub fn problems(scene: &Scene) -> Vec<String> {
+    let mut copy = scene.clone();
+    let mut camera = Camera::default();
+    let mut problems = Vec::new();
+
+    for track in &scene.animation.tracks {
+        match scene.animation.curves.get(&track.curve).map(|curve| curve.evaluate(0.0)) {
+            None => problems.push(format!("track `{}` uses the curve `{}`, which doesn't exist", track.path, track.curve)),
+            Some(None) => problems.push(format!("the curve `{}` has no keys", track.curve)),
+            Some(Some(value)) => {
+                if let Err(e) = set(&mut copy, &mut camera, &track.path, value) {
+                    problems.push(e.to_string());
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curves_interpolate_between_keys() {
+        let keys = [(0.0, 0.0), (1.0, 2.0), (3.0, 2.0)];
+        let linear = Curve::new(&keys, Interpolation::Linear);
+        assert_eq!(linear.evaluate(-1.0), Some(0.0));
+        assert_eq!(linear.evaluate(0.5), Some(1.0));
+        assert_eq!(linear.evaluate(5.0), Some(2.0));
+        assert_eq!(Curve::new(&keys, Interpolation::Step).evaluate(0.9), Some(0.0));
+
+        // Passes through the keys, and is level where they are
+        let smooth = Curve::new(&keys, Interpolation::Smooth);
+        assert_eq!(smooth.evaluate(1.0), Some(2.0));
+        assert!((smooth.evaluate(2.0).unwrap() - 2.0).abs() < 0.2);
+
+        let looped = Curve { looped: true, ..linear };
+        assert_eq!(looped.evaluate(3.5), Some(1.0));
+        assert_eq!(Curve::default().evaluate(0.0), None);
+    }
+
+    #[test]
+    fn paths_reach_any_number() {
+        let mut scene = Scene::default_scene();
+        let mut camera = Camera::default();
+
+        assert!(set(&mut scene, &mut camera, "lights[1].intensity", 0.5).unwrap());
+        assert!(!set(&mut scene, &mut camera, "lights[1].intensity", 0.5).unwrap());
+        assert_eq!(scene.lights[1].intensity, 0.5);
+
+        set(&mut scene, &mut camera, "spheres[2].radius", 1.25).unwrap();
+        set(&mut scene, &mut camera, "shapes[0].material.diffuse_colour.y", 0.75).unwrap();
+        set(&mut scene, &mut camera, "planes[0].material.pattern.Checkerboard.size", 3.0).unwrap();
+        set(&mut scene, &mut camera, "camera.position.y", 2.0).unwrap();
+        set(&mut scene, &mut camera, "camera.fov", 1.0).unwrap();
+
+        match (&scene.shapes[2], &scene.shapes[0]) {
+            (Shape::Sphere(third), Shape::Sphere(first)) => {
+                assert_eq!(third.radius, 1.25);
+                assert_eq!(first.material.diffuse_colour.y, 0.75);
+            }
+            _ => panic!("the default scene starts with spheres"),
+        }
+        assert_eq!((camera.position.y, camera.fov), (2.0, 1.0));
+
+        assert!(set(&mut scene, &mut camera, "lights[9].intensity", 1.0).is_err());
+        assert!(set(&mut scene, &mut camera, "spheres[0].colour", 1.0).is_err());
+        assert!(set(&mut scene, &mut camera, "spheres[0].material", 1.0).is_err());
+        assert!(set(&mut scene, &mut camera, "sky.sun_elevation", 1.0).is_err());
+    }
+
+    #[test]
+    fn tracks_drive_the_scene() {
+        let mut scene = Scene::default_scene();
+        scene.animation.curves.insert("dim".to_string(), Curve::new(&[(0.0, 1.0), (2.0, 0.0)], Interpolation::Linear));
+        scene.animation.tracks.push(Track { path: "lights[0].intensity".to_string(), curve: "dim".to_string(), on: None });
+        assert!(problems(&scene).is_empty());
+
+        let mut camera = Camera::default();
+        assert!(animate(&mut scene, &mut camera, 1.0));
+        assert_eq!(scene.lights[0].intensity, 0.5);
+        assert!(!animate(&mut scene, &mut camera, 1.0));
+
+        scene.animation.tracks.push(Track { path: "meshes[0].position.x".to_string(), curve: "fade".to_string(), on: None });
+        assert_eq!(problems(&scene).len(), 1);
+    }
+}
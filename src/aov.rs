@@ -0,0 +1,144 @@
+use crate::geometry::Vec3;
+use crate::render::Components;
+
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+use std::str::FromStr;
+
+// A choice of which parts of the light to keep, written as terms joined by
+// `+`. Each term is `diffuse`, `specular` or `emission`, and the first two
+// can be narrowed to light arriving `.direct` from the lights or `.indirect`
+// by way of other surfaces, such as `diffuse.direct + specular.indirect`.
+//
+// Mirror reflections are the only indirect light this renderer traces, and
+// they're specular, so `diffuse.indirect` is always black.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Expression {
+    source: String,
+    diffuse: bool,
+    specular: bool,
+    reflection: bool,
+    emission: bool,
+}
+
+impl FromStr for Expression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let mut expression = Expression {
+            source: s.to_string(),
+            diffuse: false,
+            specular: false,
+            reflection: false,
+            emission: false,
+        };
+
+        for term in s.split('+').map(str::trim) {
+            let (event, depth) = match term.split_once('.') {
+                Some((event, depth)) => (event, Some(depth)),
+                None => (term, None),
+            };
+            let (direct, indirect) = match depth {
+                None => (true, true),
+                Some("direct") => (true, false),
+                Some("indirect") => (false, true),
+                Some(other) => return Err(format!("unknown light path `{}` in `{}` (expected direct or indirect)", other, s)),
+            };
+
+            match event {
+                "diffuse" => expression.diffuse |= direct,
+                "specular" => {
+                    expression.specular |= direct;
+                    expression.reflection |= indirect;
+                }
+                "emission" if depth.is_none() => expression.emission = true,
+                _ => return Err(format!("unknown light path `{}` in `{}` (expected diffuse, specular or emission)", term, s)),
+            }
+        }
+
+        Ok(expression)
+    }
+}
+
+impl TryFrom<String> for Expression {
+    type Error = String;
+
+    fn try_from(s: String) -> std::result::Result<Self, String> {
+        s.parse()
+    }
+}
+
+impl From<Expression> for String {
+    fn from(expression: Expression) -> Self {
+        expression.source
+    }
+}
+
+impl std::fmt::Display for Expression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Expression {
+    // The light along a ray that the expression keeps. Each component is
+    // counted once however many terms select it.
+    pub fn select(&self, components: &Components) -> Vec3<f32> {
+        let keep = |kept: bool, light: Vec3<f32>| if kept { light } else { Vec3::zero() };
+
+        keep(self.diffuse, components.diffuse)
+            + keep(self.specular, components.specular)
+            + keep(self.reflection, components.reflection)
+            + keep(self.emission, components.emission)
+    }
+}
+
+// An extra image rendered alongside the main one, keeping only part of the
+// light. Outputs are listed in scene files as
+//
+//     outputs: [(name: "diffuse", expression: "diffuse.direct")]
+//
+// and saved next to the main image with their name added to its file name.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Output {
+    pub name: String,
+    pub expression: Expression,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_expressions() {
+        let expression: Expression = "diffuse.direct + specular.indirect".parse().unwrap();
+        assert!(expression.diffuse && expression.reflection);
+        assert!(!expression.specular && !expression.emission);
+
+        let everything: Expression = "diffuse+specular+emission".parse().unwrap();
+        assert!(everything.diffuse && everything.specular && everything.reflection && everything.emission);
+
+        assert!("glossy".parse::<Expression>().is_err());
+        assert!("diffuse.bounced".parse::<Expression>().is_err());
+        assert!("emission.direct".parse::<Expression>().is_err());
+        assert!("diffuse +".parse::<Expression>().is_err());
+    }
+
+    #[test]
+    fn select_counts_each_component_once() {
+        let components = Components {
+            diffuse: Vec3::new(1.0, 0.0, 0.0),
+            specular: Vec3::new(0.0, 1.0, 0.0),
+            reflection: Vec3::new(0.0, 0.0, 1.0),
+            emission: Vec3::new(1.0, 1.0, 1.0),
+        };
+
+        let select = |s: &str| s.parse::<Expression>().unwrap().select(&components);
+        assert_eq!(select("diffuse + diffuse.direct"), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(select("diffuse.indirect"), Vec3::zero());
+        assert_eq!(select("specular"), Vec3::new(0.0, 1.0, 1.0));
+        assert_eq!(select("diffuse + specular + emission"), components.total());
+    }
+}
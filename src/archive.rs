@@ -0,0 +1,293 @@
+use crate::Result;
+use crate::geometry::Shape;
+use crate::image;
+use crate::scene::{self, Format, Scene};
+
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Component, Path};
+
+// Scene archives are zip files with this extension, holding the scene as
+// `scene.ron` and every file it refers to under `assets/`
+pub const EXTENSION: &str = "trscene";
+const SCENE_ENTRY: &str = "scene.ron";
+const ASSETS: &str = "assets/";
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_DIRECTORY: u32 = 0x0605_4b50;
+const VERSION: u16 = 20;
+// Names are UTF-8
+const FLAGS: u16 = 0x0800;
+const STORED: u16 = 0;
+// 1980-01-01, the earliest date zip can hold
+const DATE: u16 = 0x21;
+
+// Calls `f` with every path to a file the scene loads: meshes, point
+// clouds, scatter density maps and the environment. An environment that's
+// itself a scene is archived as that scene's file alone, without what it
+// refers to.
+pub fn for_each_path<F: FnMut(&mut String)>(scene: &mut Scene, f: &mut F) {
+    fn shape_paths<F: FnMut(&mut String)>(shape: &mut Shape, f: &mut F) {
+        match shape {
+            Shape::Mesh(mesh) => f(&mut mesh.path),
+            Shape::Points(cloud) => f(&mut cloud.path),
+            Shape::Scatter(scatter) => {
+                if let Some(density) = &mut scatter.density {
+                    f(density);
+                }
+                shape_paths(&mut scatter.prototype, f);
+            }
+            _ => {}
+        }
+    }
+
+    for shape in &mut scene.shapes {
+        shape_paths(shape, f);
+    }
+    if let Some(environment) = &mut scene.environment {
+        f(&mut environment.path);
+    }
+}
+
+// The entries of an archive of the scene: the scene with its paths pointing
+// into the archive, then each file it refers to once, named after its
+// position in the archive so files with the same name don't clash
+pub fn pack(scene: &Scene) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut packed = scene.clone();
+    let mut assets: Vec<(String, String)> = Vec::new();
+
+    for_each_path(&mut packed, &mut |path| {
+        if path.is_empty() {
+            return;
+        }
+        let entry = match assets.iter().find(|(original, _)| original == path) {
+            Some((_, entry)) => entry.clone(),
+            None => {
+                let name = Path::new(path.as_str()).file_name().and_then(|name| name.to_str()).unwrap_or("asset");
+                let entry = format!("{}{}-{}", ASSETS, assets.len(), name);
+                assets.push((path.clone(), entry.clone()));
+                entry
+            }
+        };
+        *path = entry;
+    });
+
+    let source = ron::ser::to_string_pretty(&packed, ron::ser::PrettyConfig::default())?;
+    let mut entries = vec![(SCENE_ENTRY.to_string(), source.into_bytes())];
+    for (original, entry) in assets {
+        let contents = fs::read(&original).map_err(|e| format!("failed to archive `{}`: {}", original, e))?;
+        entries.push((entry, contents));
+    }
+
+    Ok(entries)
+}
+
+pub fn save<P: AsRef<Path>>(scene: &Scene, path: P) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write(&mut writer, &pack(scene)?)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Loads an archived scene, unpacking its assets into a directory under the
+// system's temporary directory named after the archive's checksum, so
+// loading the same archive again reuses it
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Scene> {
+    let data = fs::read(path)?;
+    let checksum = image::crc32(&image::crc32_table(), &[&data]);
+    let directory = std::env::temp_dir().join(format!("tinyraytracer-{:08x}", checksum));
+
+    let mut source = None;
+    let mut unpacked = Vec::new();
+    for (name, contents) in read(&data)? {
+        if name == SCENE_ENTRY {
+            source = Some(String::from_utf8(contents)?);
+            continue;
+        }
+
+        // Only plain relative paths under assets/, so an archive can't write
+        // anywhere else
+        let relative = Path::new(&name);
+        if !name.starts_with(ASSETS) || !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            return Err(format!("unexpected entry `{}` in scene archive", name).into());
+        }
+        let target = directory.join(relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&target, contents)?;
+        unpacked.push((name, target));
+    }
+
+    // The assets' names only ever appear in the scene as the strings of the
+    // paths that were replaced with them
+    let mut source = source.ok_or("the scene archive has no scene.ron")?;
+    for (name, target) in unpacked {
+        source = source.replace(&format!("{:?}", name), &format!("{:?}", target.display().to_string()));
+    }
+
+    scene::parse(&source, Format::Ron)
+}
+
+// Writes the entries as a zip file, stored without compression: the assets
+// are mostly already compressed or small, and it keeps reading simple
+pub fn write<W: Write>(writer: &mut W, entries: &[(String, Vec<u8>)]) -> Result<()> {
+    let table = image::crc32_table();
+    let mut central = Vec::new();
+    let mut offset = 0usize;
+
+    for (name, contents) in entries {
+        let too_big = |what: &str| format!("{} is too big for a zip without zip64", what);
+        let size: u32 = contents.len().try_into().map_err(|_| too_big(name))?;
+        let start: u32 = offset.try_into().map_err(|_| too_big("the archive"))?;
+        let crc = image::crc32(&table, &[contents]);
+
+        // The fields both headers share, from the version needed onwards
+        let mut common = Vec::with_capacity(26);
+        for value in [VERSION, FLAGS, STORED, 0, DATE] {
+            common.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [crc, size, size] {
+            common.extend_from_slice(&value.to_le_bytes());
+        }
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+
+        writer.write_all(&LOCAL_HEADER.to_le_bytes())?;
+        writer.write_all(&common)?;
+        writer.write_all(name.as_bytes())?;
+        writer.write_all(contents)?;
+
+        central.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+        central.extend_from_slice(&VERSION.to_le_bytes());
+        central.extend_from_slice(&common);
+        // No comment, on the first disk, with no attributes
+        central.extend_from_slice(&[0; 10]);
+        central.extend_from_slice(&start.to_le_bytes());
+        central.extend_from_slice(name.as_bytes());
+
+        offset += 30 + name.len() + contents.len();
+    }
+
+    let count: u16 = entries.len().try_into().map_err(|_| "too many files for a zip without zip64")?;
+    writer.write_all(&central)?;
+    writer.write_all(&END_OF_DIRECTORY.to_le_bytes())?;
+    writer.write_all(&[0; 4])?;
+    writer.write_all(&count.to_le_bytes())?;
+    writer.write_all(&count.to_le_bytes())?;
+    writer.write_all(&(central.len() as u32).to_le_bytes())?;
+    writer.write_all(&(offset as u32).to_le_bytes())?;
+    writer.write_all(&[0; 2])?;
+
+    Ok(())
+}
+
+fn u16_at(data: &[u8], at: usize) -> Result<u16> {
+    let bytes = data.get(at..at + 2).ok_or("truncated zip")?;
+    Ok(u16::from_le_bytes(bytes.try_into()?))
+}
+
+fn u32_at(data: &[u8], at: usize) -> Result<u32> {
+    let bytes = data.get(at..at + 4).ok_or("truncated zip")?;
+    Ok(u32::from_le_bytes(bytes.try_into()?))
+}
+
+// Reads the entries of a zip file from its central directory. Only stored
+// entries can be read, as archives are written, not compressed ones.
+pub fn read(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    // The end of the directory record is 22 bytes, followed by a comment of
+    // up to 64 KiB
+    let end = (0..data.len().saturating_sub(21))
+        .rev()
+        .take(22 + u16::MAX as usize)
+        .find(|&at| u32_at(data, at).ok() == Some(END_OF_DIRECTORY))
+        .ok_or("not a zip file")?;
+    let count = u16_at(data, end + 10)? as usize;
+    let mut at = u32_at(data, end + 16)? as usize;
+
+    let table = image::crc32_table();
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if u32_at(data, at)? != CENTRAL_HEADER {
+            return Err("corrupt zip directory".into());
+        }
+        let method = u16_at(data, at + 10)?;
+        let crc = u32_at(data, at + 16)?;
+        let size = u32_at(data, at + 20)? as usize;
+        let name_length = u16_at(data, at + 28)? as usize;
+        let skipped = u16_at(data, at + 30)? as usize + u16_at(data, at + 32)? as usize;
+        let local = u32_at(data, at + 42)? as usize;
+        let name = String::from_utf8(data.get(at + 46..at + 46 + name_length).ok_or("truncated zip")?.to_vec())?;
+        at += 46 + name_length + skipped;
+
+        if method != STORED {
+            return Err(format!("`{}` is compressed, which scene archives aren't", name).into());
+        }
+        if u32_at(data, local)? != LOCAL_HEADER {
+            return Err(format!("corrupt zip entry `{}`", name).into());
+        }
+        let start = local + 30 + u16_at(data, local + 26)? as usize + u16_at(data, local + 28)? as usize;
+        let contents = data.get(start..start + size).ok_or("truncated zip")?.to_vec();
+        if image::crc32(&table, &[&contents]) != crc {
+            return Err(format!("`{}` is corrupt", name).into());
+        }
+        entries.push((name, contents));
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Vec3;
+    use crate::materials::Material;
+    use crate::mesh::Mesh;
+    use crate::scatter::Scatter;
+
+    #[test]
+    fn entries_round_trip_through_zip() {
+        let entries = vec![
+            ("scene.ron".to_string(), b"(shapes: [])".to_vec()),
+            ("assets/0-empty.obj".to_string(), Vec::new()),
+            ("assets/1-noise.bin".to_string(), (0..=255).collect()),
+        ];
+        let mut data = Vec::new();
+        write(&mut data, &entries).unwrap();
+        assert_eq!(u32_at(&data, 0).unwrap(), LOCAL_HEADER);
+        assert_eq!(read(&data).unwrap(), entries);
+
+        // A changed byte in an asset fails its checksum
+        let at = data.len() - 22 - 100;
+        let corrupt = data.iter().enumerate().map(|(i, &b)| if i == at { b ^ 1 } else { b }).collect::<Vec<u8>>();
+        assert!(read(&corrupt).is_err());
+        assert!(read(b"not a zip").is_err());
+    }
+
+    #[test]
+    fn each_asset_is_packed_once() {
+        let path = std::env::temp_dir().join("tinyraytracer-archive-triangle.obj");
+        fs::write(&path, "v 0 0 0\nv 1 0 0\nv 0 1 0\nf 1 2 3\n").unwrap();
+
+        let mut mesh = Mesh::new(vec![Vec3::zero(); 3], vec![[0, 1, 2]], Material::default());
+        mesh.path = path.display().to_string();
+        let mut scatter = Scatter::new(mesh.clone().into(), 0, 10);
+        scatter.density = Some(path.display().to_string());
+        let mut scene = Scene::default_scene();
+        scene.environment = None;
+        scene.shapes = vec![mesh.into(), scatter.into()];
+
+        let entries = pack(&scene).unwrap();
+        let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["scene.ron", "assets/0-tinyraytracer-archive-triangle.obj"]);
+        assert_eq!(entries[1].1, fs::read(&path).unwrap());
+
+        // The scene being archived keeps its own paths
+        let mut paths = Vec::new();
+        for_each_path(&mut scene, &mut |path| paths.push(path.clone()));
+        assert_eq!(paths, vec![path.display().to_string(); 3]);
+        let _ = fs::remove_file(&path);
+    }
+}
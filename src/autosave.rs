@@ -0,0 +1,113 @@
+use crate::Result;
+use crate::camera::Camera;
+use crate::scene::Scene;
+
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Where the interactive viewer keeps its snapshot, in the directory it's run
+// from alongside its screenshots
+pub const PATH: &str = "autosave.ron";
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+// The scene as it was being edited and the camera looking at it
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    // The scene file the session started from, if any
+    pub source: Option<String>,
+    // Seconds since the Unix epoch
+    pub saved_at: u64,
+    pub camera: Camera,
+    pub scene: Scene,
+}
+
+// Writes the snapshot beside the file and then renames it into place, so a
+// crash while saving leaves the last snapshot rather than half of this one
+pub fn save<P: AsRef<Path>>(snapshot: &Snapshot, path: P) -> Result<()> {
+    let path = path.as_ref();
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+
+    fs::write(&partial, ron::ser::to_string_pretty(snapshot, ron::ser::PrettyConfig::default())?)?;
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Snapshot> {
+    let mut snapshot: Snapshot = ron::from_str(&fs::read_to_string(path)?)?;
+    snapshot.scene.build_scatters()?;
+    Ok(snapshot)
+}
+
+// Snapshots a session every `interval` while it's changing. The snapshot is
+// removed when the session ends cleanly, so one that's found on starting is
+// from a session that crashed.
+pub struct Autosave {
+    pub path: PathBuf,
+    pub interval: Duration,
+    source: Option<String>,
+    last_saved: Instant,
+    changed: bool,
+}
+
+impl Autosave {
+    pub fn new<P: AsRef<Path>>(path: P, interval: Duration, source: Option<String>) -> Self {
+        Autosave { path: path.as_ref().to_path_buf(), interval, source, last_saved: Instant::now(), changed: false }
+    }
+
+    pub fn mark_changed(&mut self) {
+        self.changed = true;
+    }
+
+    // Saves if anything has changed and it's been long enough since the last
+    // save, returning whether it did
+    pub fn update(&mut self, scene: &Scene, camera: &Camera, now: Instant) -> Result<bool> {
+        if !self.changed || now.duration_since(self.last_saved) < self.interval {
+            return Ok(false);
+        }
+
+        let saved_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let snapshot = Snapshot { source: self.source.clone(), saved_at, camera: *camera, scene: scene.clone() };
+        self.last_saved = now;
+        self.changed = false;
+        save(&snapshot, &self.path)?;
+        Ok(true)
+    }
+
+    // Removes the snapshot on a clean exit
+    pub fn finish(self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_only_changes_once_the_interval_has_passed() {
+        let path = std::env::temp_dir().join(format!("tinyraytracer-autosave-{}.ron", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut autosave = Autosave::new(&path, Duration::from_secs(10), None);
+        let (scene, camera) = (Scene::default_scene(), Camera::default());
+        let start = Instant::now();
+
+        assert!(!autosave.update(&scene, &camera, start + Duration::from_secs(20)).unwrap());
+        autosave.mark_changed();
+        assert!(!autosave.update(&scene, &camera, start + Duration::from_secs(5)).unwrap());
+        assert!(autosave.update(&scene, &camera, start + Duration::from_secs(20)).unwrap());
+        assert!(path.exists());
+        assert!(!autosave.update(&scene, &camera, start + Duration::from_secs(40)).unwrap());
+
+        autosave.finish().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,326 @@
+use crate::Result;
+use crate::framebuffer::Framebuffer;
+use crate::geometry::{Ray, Shape, Vec3, dot, triangle_normal};
+use crate::mesh::Mesh;
+use crate::render::scene_intersect;
+use crate::sampling;
+use crate::scene::Scene;
+
+use std::str::FromStr;
+
+// Occlusion only counts hits within this fraction of the diagonal of the
+// mesh's bounds
+const OCCLUSION_DISTANCE: f32 = 0.25;
+
+// Rounds of spreading baked texels into the empty ones bordering them, so
+// that filtering across UV seams doesn't pull in the background
+const DILATION: usize = 4;
+
+const SURFACE_OFFSET: f32 = 1.0e-3;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BakeMode {
+    // The fraction of the hemisphere above the surface that's open
+    Occlusion,
+    // Direct light arriving at the surface, before its own colour is applied
+    Lightmap,
+}
+
+impl FromStr for BakeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "ao" => Ok(BakeMode::Occlusion),
+            "lightmap" => Ok(BakeMode::Lightmap),
+            _ => Err(format!("unknown bake mode `{}` (expected ao or lightmap)", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct BakeSettings {
+    pub mode: BakeMode,
+    // Width and height of the texture in texels
+    pub size: usize,
+    // Samples per texel, each at its own position in the texel
+    pub samples: usize,
+}
+
+impl Default for BakeSettings {
+    fn default() -> Self {
+        BakeSettings {
+            mode: BakeMode::Occlusion,
+            size: 1024,
+            samples: 64,
+        }
+    }
+}
+
+// Bakes the occlusion or lighting over a mesh in the scene into a square
+// texture laid out by the mesh's texture coordinates, with v pointing up the
+// image as other tools expect. The rest of the scene occludes and lights the
+// mesh as it would in a render.
+pub fn bake(scene: &Scene, shape: usize, settings: BakeSettings) -> Result<Framebuffer> {
+    let mesh = match scene.shapes.get(shape) {
+        Some(Shape::Mesh(mesh)) => mesh,
+        Some(other) => return Err(format!("shape {} is a {}, not a mesh", shape, other.name()).into()),
+        None => return Err(format!("there's no shape {}", shape).into()),
+    };
+
+    let size = settings.size.max(1);
+    let coverage = rasterise(mesh, size);
+    if coverage.iter().all(Option::is_none) {
+        return Err(format!("shape {} has no texture coordinates to bake into", shape).into());
+    }
+
+    let baker = Baker {
+        scene,
+        shape,
+        mesh,
+        size,
+        settings,
+        distance: mesh.bounds().size().length() * OCCLUSION_DISTANCE,
+    };
+
+    let mut framebuffer = Framebuffer::new(size, size);
+    framebuffer.render(|i, j| match coverage[j * size + i] {
+        Some(face) => baker.texel(face, i, j),
+        None => Vec3::zero(),
+    });
+
+    dilate(&mut framebuffer, &coverage);
+    Ok(framebuffer)
+}
+
+// The corners of a face in texel coordinates, if it has texture coordinates
+fn texel_triangle(mesh: &Mesh, face: usize, size: usize) -> Option<[(f32, f32); 3]> {
+    let texcoords = mesh.triangle_texcoords(face)?;
+    let size = size as f32;
+    Some(texcoords.map(|uv| (uv.x * size, (1.0 - uv.y) * size)))
+}
+
+// The barycentric coordinates of a point in a 2D triangle, or none if the
+// triangle has no area
+fn barycentric(p: (f32, f32), [a, b, c]: [(f32, f32); 3]) -> Option<[f32; 3]> {
+    let area = (b.0 - a.0) * (c.1 - a.1) - (c.0 - a.0) * (b.1 - a.1);
+    if area.abs() < 1.0e-12 {
+        return None;
+    }
+
+    let u = ((b.0 - p.0) * (c.1 - p.1) - (c.0 - p.0) * (b.1 - p.1)) / area;
+    let v = ((c.0 - p.0) * (a.1 - p.1) - (a.0 - p.0) * (c.1 - p.1)) / area;
+    Some([u, v, 1.0 - u - v])
+}
+
+// The face whose texture coordinates cover the centre of each texel, if any.
+// Where faces overlap in UV space the last one wins.
+fn rasterise(mesh: &Mesh, size: usize) -> Vec<Option<usize>> {
+    let mut coverage = vec![None; size * size];
+
+    for face in 0..mesh.face_count() {
+        let triangle = match texel_triangle(mesh, face, size) {
+            Some(triangle) => triangle,
+            None => continue,
+        };
+
+        let min = |f: fn(&(f32, f32)) -> f32| triangle.iter().map(f).fold(f32::MAX, f32::min);
+        let max = |f: fn(&(f32, f32)) -> f32| triangle.iter().map(f).fold(f32::MIN, f32::max);
+        let range = |low: f32, high: f32| (low.floor().max(0.0) as usize)..(high.ceil().max(0.0) as usize).min(size);
+
+        for j in range(min(|p| p.1), max(|p| p.1)) {
+            for i in range(min(|p| p.0), max(|p| p.0)) {
+                let centre = (i as f32 + 0.5, j as f32 + 0.5);
+                if let Some(weights) = barycentric(centre, triangle) {
+                    if weights.iter().all(|&w| w >= -1.0e-6) {
+                        coverage[j * size + i] = Some(face);
+                    }
+                }
+            }
+        }
+    }
+
+    coverage
+}
+
+struct Baker<'a> {
+    scene: &'a Scene,
+    shape: usize,
+    mesh: &'a Mesh,
+    size: usize,
+    settings: BakeSettings,
+    distance: f32,
+}
+
+impl<'a> Baker<'a> {
+    // The average over samples spread across the texel, each moved onto the
+    // face if it falls off the edge
+    fn texel(&self, face: usize, i: usize, j: usize) -> Vec3<f32> {
+        let triangle = texel_triangle(self.mesh, face, self.size).expect("covered faces have texture coordinates");
+        let corners = self.mesh.triangle(face);
+        let normal = triangle_normal(corners);
+
+        let samples = self.settings.samples.max(1);
+        let mut total = 0.0;
+
+        for k in 0..samples {
+            let (dx, dy) = sampling::r2(k);
+            let weights = match barycentric((i as f32 + dx, j as f32 + dy), triangle) {
+                Some(weights) => weights.map(|w| w.max(0.0)),
+                None => continue,
+            };
+
+            let sum: f32 = weights.iter().sum();
+            let point = self.mesh.position
+                + (corners[0] * weights[0] + corners[1] * weights[1] + corners[2] * weights[2]) * (1.0 / sum);
+
+            let seed = sampling::seed(j * self.size + i, k);
+            total += match self.settings.mode {
+                BakeMode::Occlusion => self.openness(point, normal, seed),
+                BakeMode::Lightmap => self.irradiance(point, normal),
+            };
+        }
+
+        let value = total / samples as f32;
+        Vec3::new(value, value, value)
+    }
+
+    // One if a random direction from the point escapes, or zero if it hits
+    // something nearby
+    fn openness(&self, point: Vec3<f32>, normal: Vec3<f32>, seed: u64) -> f32 {
+        let ray = Ray {
+            origin: point + normal * SURFACE_OFFSET,
+            direction: sampling::cosine_direction(normal, sampling::random(seed), sampling::random(seed.wrapping_add(1))),
+        };
+
+        match scene_intersect(&ray, self.scene) {
+            Some(hit) if hit.distance < self.distance => 0.0,
+            _ => 1.0,
+        }
+    }
+
+    // The direct light from every light linked to the mesh, as in the
+    // diffuse term of a render
+    fn irradiance(&self, point: Vec3<f32>, normal: Vec3<f32>) -> f32 {
+        let scene = self.scene;
+        let origin = point + normal * SURFACE_OFFSET;
+        let mut total = 0.0;
+
+        for (i, light) in scene.lights.iter().enumerate() {
+            if !scene.illuminates(i, self.shape) {
+                continue;
+            }
+
+            let offset = light.position - origin;
+            let direction = offset.normalise();
+            let cosine = dot(direction, normal);
+            if cosine <= 0.0 {
+                continue;
+            }
+
+            let shadow_ray = Ray { origin, direction };
+            let occluded = scene_intersect(&shadow_ray, scene).is_some_and(|hit| hit.distance < offset.length());
+
+            if !occluded {
+                total += light.intensity * scene.transmission(i, point) * cosine;
+            }
+        }
+
+        total.max(0.0)
+    }
+}
+
+// Fills empty texels next to baked ones with the average of those neighbours,
+// a ring at a time
+fn dilate(framebuffer: &mut Framebuffer, coverage: &[Option<usize>]) {
+    let (width, height) = (framebuffer.width, framebuffer.height);
+    let mut filled: Vec<bool> = coverage.iter().map(Option::is_some).collect();
+
+    for _ in 0..DILATION {
+        let mut ring = Vec::new();
+
+        for j in 0..height {
+            for i in 0..width {
+                if filled[j * width + i] {
+                    continue;
+                }
+
+                let mut sum = Vec3::zero();
+                let mut count = 0;
+
+                for y in j.saturating_sub(1)..(j + 2).min(height) {
+                    for x in i.saturating_sub(1)..(i + 2).min(width) {
+                        if filled[y * width + x] {
+                            sum = sum + framebuffer.colours[y * width + x];
+                            count += 1;
+                        }
+                    }
+                }
+
+                if count > 0 {
+                    ring.push((i, j, sum * (1.0 / count as f32)));
+                }
+            }
+        }
+
+        for (i, j, colour) in ring {
+            framebuffer.set(i, j, colour);
+            filled[j * width + i] = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Sphere;
+    use crate::materials::Material;
+
+    // A 2 x 2 square facing up, its texture coordinates covering the whole
+    // texture
+    const FLOOR: &str = "
+        v -1 0 1
+        v 1 0 1
+        v 1 0 -1
+        v -1 0 -1
+        vt 0 0
+        vt 1 0
+        vt 1 1
+        vt 0 1
+        f 1/1 2/2 3/3 4/4
+    ";
+
+    fn floor_scene() -> Scene {
+        let path = std::env::temp_dir().join("tinyraytracer-bake-floor.obj");
+        std::fs::write(&path, FLOOR).unwrap();
+        let mesh = Mesh::from_obj(path.to_str().unwrap(), Material::default()).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let mut scene = Scene::default_scene();
+        scene.shapes = vec![Shape::Mesh(mesh)];
+        scene
+    }
+
+    #[test]
+    fn occlusion_under_a_sphere() {
+        let mut scene = floor_scene();
+        let settings = BakeSettings { size: 8, samples: 32, ..BakeSettings::default() };
+
+        let open = bake(&scene, 0, settings).unwrap();
+        assert!(open.colours.iter().all(|c| c.x == 1.0));
+
+        scene.shapes.push(Shape::Sphere(Sphere::new(Vec3::new(0.0, 0.3, 0.0), 0.2, Material::default())));
+        let occluded = bake(&scene, 0, settings).unwrap();
+        let centre = occluded.colours[4 * 8 + 4].x;
+        let corner = occluded.colours[0].x;
+        assert!(centre < 0.9 && centre < corner);
+    }
+
+    #[test]
+    fn only_meshes_with_texture_coordinates_bake() {
+        let mut scene = floor_scene();
+        scene.shapes.push(Shape::Sphere(Sphere::new(Vec3::zero(), 1.0, Material::default())));
+        assert!(bake(&scene, 1, BakeSettings::default()).is_err());
+        assert!(bake(&scene, 2, BakeSettings::default()).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use crate::geometry::Shape;
+use crate::scene::Scene;
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+// How much memory the scene's meshes take after keeping to a budget, and
+// how many of their levels were dropped to do it
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Usage {
+    pub resident: usize,
+    pub limit: usize,
+    pub evicted: usize,
+}
+
+impl Usage {
+    pub fn exceeded(&self) -> bool {
+        self.resident > self.limit
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let megabytes = |bytes: usize| bytes as f32 / MEGABYTE as f32;
+        write!(f, "{:.1} MB of {:.1} MB", megabytes(self.resident), megabytes(self.limit))?;
+        if self.evicted > 0 {
+            write!(f, ", dropped {} mesh levels", self.evicted)?;
+        }
+        Ok(())
+    }
+}
+
+// A mesh level and the shapes holding it, as (shape, level) pairs
+struct Level {
+    bytes: usize,
+    used: u64,
+    evictable: bool,
+    holders: Vec<(usize, usize)>,
+}
+
+pub const MEGABYTE: usize = 1 << 20;
+
+// A limit on the memory taken by the scene's meshes. Levels of detail that
+// aren't in use are dropped to keep under it, least recently used first, and
+// loaded again when the camera next needs them. Levels in use and meshes that
+// weren't loaded from files are never dropped, so a scene that needs more
+// than the budget to draw what's in view goes over it, which is reported
+// rather than failing. Environment maps can't be dropped, so aren't counted.
+#[derive(Clone, Debug)]
+pub struct MemoryBudget {
+    pub limit: usize,
+    tick: u64,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget { limit, tick: 0 }
+    }
+
+    // Marks the levels now in use as used, then drops levels until the scene
+    // fits in the budget or there are none left that can go. Call after
+    // choosing levels of detail for the camera.
+    pub fn enforce(&mut self, scene: &mut Scene) -> Usage {
+        self.tick += 1;
+        for shape in &mut scene.shapes {
+            if let Shape::Mesh(mesh) = shape {
+                mesh.mark_used(self.tick);
+            }
+        }
+        share_levels(scene);
+
+        // Clones of a mesh share its levels, which only free their memory
+        // once every clone has dropped them
+        let mut levels: BTreeMap<usize, Level> = BTreeMap::new();
+        for (index, shape) in scene.shapes.iter().enumerate() {
+            if let Shape::Mesh(mesh) = shape {
+                for level in 0..mesh.level_count() {
+                    if let Some((key, bytes)) = mesh.loaded(level) {
+                        let new = Level { bytes, used: 0, evictable: true, holders: Vec::new() };
+                        let entry = levels.entry(key).or_insert(new);
+                        entry.used = entry.used.max(mesh.last_used(level));
+                        entry.evictable &= mesh.can_evict(level);
+                        entry.holders.push((index, level));
+                    }
+                }
+            }
+        }
+
+        let mut usage = Usage { resident: resident_bytes(scene), limit: self.limit, evicted: 0 };
+        let mut candidates: Vec<Level> = levels.into_values().filter(|level| level.evictable).collect();
+        candidates.sort_by_key(|level| level.used);
+
+        for candidate in candidates {
+            if !usage.exceeded() {
+                break;
+            }
+            for (index, level) in candidate.holders {
+                if let Shape::Mesh(mesh) = &mut scene.shapes[index] {
+                    mesh.evict(level);
+                }
+            }
+            usage.resident -= candidate.bytes;
+            usage.evicted += 1;
+        }
+
+        usage
+    }
+}
+
+// Clones of a mesh that each reloaded a level after it was dropped have
+// their own copy of it, so share the first clone's copy between them again
+fn share_levels(scene: &mut Scene) {
+    for index in 1..scene.shapes.len() {
+        let (earlier, rest) = scene.shapes.split_at_mut(index);
+        if let Shape::Mesh(mesh) = &mut rest[0] {
+            for shape in earlier {
+                if let Shape::Mesh(other) = shape {
+                    mesh.share_levels(other);
+                }
+            }
+        }
+    }
+}
+
+// The memory taken by the loaded levels of every mesh, counting shared
+// levels once
+pub fn resident_bytes(scene: &Scene) -> usize {
+    let mut levels = BTreeMap::new();
+    for shape in &scene.shapes {
+        if let Shape::Mesh(mesh) = shape {
+            levels.extend((0..mesh.level_count()).filter_map(|level| mesh.loaded(level)));
+        }
+    }
+
+    levels.values().sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Vec3;
+    use crate::materials::Material;
+    use crate::mesh::{Lod, Mesh};
+
+    #[test]
+    fn unused_levels_are_dropped_and_reloaded() {
+        // A fan of 16 triangles that simplifies to a few
+        let mut source = "v 0 0 0\n".to_string();
+        for k in 0..16 {
+            let angle = k as f32 * std::f32::consts::PI / 8.0;
+            source += &format!("v {} {} 0\n", angle.cos(), angle.sin());
+        }
+        for k in 0..16 {
+            source += &format!("f 1 {} {}\n", k + 2, (k + 1) % 16 + 2);
+        }
+        let path = std::env::temp_dir().join("tinyraytracer-budget-fan.obj");
+        std::fs::write(&path, source).unwrap();
+
+        let lod = Lod { distance: 10.0, triangles: Some(4), error: None };
+        let mesh = Mesh::from_obj(path.to_str().unwrap(), Material::default()).unwrap().with_lods(vec![lod]);
+        let mut scene = Scene::default_scene();
+        scene.environment = None;
+        scene.shapes = vec![mesh.clone().into(), mesh.into()];
+        let both = resident_bytes(&scene);
+
+        // Plenty of room keeps everything
+        let mut budget = MemoryBudget::new(both);
+        assert_eq!(budget.enforce(&mut scene).evicted, 0);
+
+        // Close up only the full mesh is needed, for both clones
+        budget.limit = 0;
+        let usage = budget.enforce(&mut scene);
+        assert_eq!(usage.evicted, 1);
+        assert!(usage.exceeded() && usage.resident < both);
+        assert_eq!(resident_bytes(&scene), usage.resident);
+
+        // Moving away brings the simplified level back, after which the full
+        // mesh can go, and it's loaded again from the file on coming back
+        scene.select_lods(Vec3::new(0.0, 0.0, 50.0));
+        assert_eq!(budget.enforce(&mut scene).evicted, 1);
+        let far = match &scene.shapes[0] {
+            Shape::Mesh(mesh) => (mesh.lod(), mesh.loaded(0), mesh.face_count()),
+            _ => unreachable!(),
+        };
+        assert!(far.0 == 1 && far.1.is_none() && far.2 <= 4);
+
+        assert!(scene.select_lods(Vec3::new(0.0, 0.0, 1.0)));
+        match &scene.shapes[1] {
+            Shape::Mesh(mesh) => assert_eq!(mesh.face_count(), 16),
+            _ => unreachable!(),
+        }
+
+        // Each clone loaded the full mesh again, which they share once more
+        budget.limit = both;
+        assert_eq!(budget.enforce(&mut scene).resident, resident_bytes(&scene));
+        let full: Vec<_> = scene
+            .shapes
+            .iter()
+            .map(|shape| match shape {
+                Shape::Mesh(mesh) => mesh.loaded(0).unwrap(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(full[0], full[1]);
+        let _ = std::fs::remove_file(&path);
+    }
+}
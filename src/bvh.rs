@@ -0,0 +1,173 @@
+use crate::geometry::{Aabb, Ray};
+
+// Primitives per leaf below which nodes are no longer split
+const MAX_LEAF_SIZE: usize = 4;
+
+#[derive(Clone, Debug)]
+enum Node {
+    Leaf { bounds: Aabb, start: usize, count: usize },
+    Interior { bounds: Aabb, left: usize, right: usize },
+}
+
+impl Node {
+    fn bounds(&self) -> &Aabb {
+        match self {
+            Node::Leaf { bounds, .. } | Node::Interior { bounds, .. } => bounds,
+        }
+    }
+}
+
+// Bounding volume hierarchy over an arbitrary list of primitives, which are
+// referred to by their index in the list the hierarchy was built from
+#[derive(Clone, Debug)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    indices: Vec<usize>,
+}
+
+impl Bvh {
+    // Builds the hierarchy top down, splitting each node at the median
+    // centroid along the longest axis of its centroids' bounds
+    pub fn build(bounds: &[Aabb]) -> Self {
+        let mut bvh = Bvh {
+            nodes: Vec::new(),
+            indices: (0..bounds.len()).collect(),
+        };
+
+        if !bounds.is_empty() {
+            bvh.build_node(bounds, 0, bounds.len());
+        }
+
+        bvh
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::empty(), |node| *node.bounds())
+    }
+
+    // The memory taken by the hierarchy's nodes and indices
+    pub fn bytes(&self) -> usize {
+        self.nodes.len() * std::mem::size_of::<Node>() + self.indices.len() * std::mem::size_of::<usize>()
+    }
+
+    fn build_node(&mut self, bounds: &[Aabb], start: usize, end: usize) -> usize {
+        let node_bounds = self.indices[start..end]
+            .iter()
+            .fold(Aabb::empty(), |aabb, &i| aabb.union(bounds[i]));
+
+        let index = self.nodes.len();
+        let count = end - start;
+
+        if count <= MAX_LEAF_SIZE {
+            self.nodes.push(Node::Leaf { bounds: node_bounds, start, count });
+            return index;
+        }
+
+        let centroids = self.indices[start..end]
+            .iter()
+            .fold(Aabb::empty(), |aabb, &i| aabb.grow(bounds[i].centre()));
+        let size = centroids.size();
+
+        let axis = |aabb: &Aabb| {
+            let c = aabb.centre();
+            if size.x >= size.y && size.x >= size.z {
+                c.x
+            } else if size.y >= size.z {
+                c.y
+            } else {
+                c.z
+            }
+        };
+
+        let middle = start + count / 2;
+        self.indices[start..end].select_nth_unstable_by(count / 2, |&a, &b| {
+            axis(&bounds[a]).total_cmp(&axis(&bounds[b]))
+        });
+
+        // Reserve this node's slot before building the children beneath it
+        self.nodes.push(Node::Leaf { bounds: node_bounds, start, count });
+        let left = self.build_node(bounds, start, middle);
+        let right = self.build_node(bounds, middle, end);
+        self.nodes[index] = Node::Interior { bounds: node_bounds, left, right };
+
+        index
+    }
+
+    // Finds the nearest primitive hit by the ray. `test(primitive, nearest)`
+    // should return the distance to the primitive if it's hit closer than
+    // `nearest`, recording whatever else the caller needs about the hit.
+    pub fn intersect<F>(&self, ray: &Ray, mut max_distance: f32, mut test: F) -> Option<f32>
+    where
+        F: FnMut(usize, f32) -> Option<f32>,
+    {
+        let mut nearest = None;
+
+        if self.nodes.is_empty() {
+            return nearest;
+        }
+
+        let mut stack = vec![0];
+
+        while let Some(node) = stack.pop() {
+            match self.nodes[node] {
+                Node::Leaf { bounds, start, count } => {
+                    if !matches!(bounds.ray_intersect(ray), Some((near, _)) if near < max_distance) {
+                        continue;
+                    }
+
+                    for &primitive in &self.indices[start..start + count] {
+                        if let Some(distance) = test(primitive, max_distance) {
+                            max_distance = distance;
+                            nearest = Some(distance);
+                        }
+                    }
+                }
+                Node::Interior { bounds, left, right } => {
+                    if matches!(bounds.ray_intersect(ray), Some((near, _)) if near < max_distance) {
+                        stack.push(right);
+                        stack.push(left);
+                    }
+                }
+            }
+        }
+
+        nearest
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Vec3;
+
+    #[test]
+    fn finds_nearest_box() {
+        // A row of unit boxes along -z, hit head on by the ray
+        let bounds: Vec<Aabb> = (0..20)
+            .map(|i| {
+                let z = -2.0 * i as f32 - 5.0;
+                Aabb::from_points(&[Vec3::new(-0.5, -0.5, z - 0.5), Vec3::new(0.5, 0.5, z + 0.5)])
+            })
+            .collect();
+
+        let bvh = Bvh::build(&bounds);
+        let ray = Ray {
+            origin: Vec3::zero(),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+
+        let mut nearest = None;
+        let distance = bvh.intersect(&ray, f32::MAX, |i, max| {
+            let (near, _) = bounds[i].ray_intersect(&ray)?;
+            if near < max {
+                nearest = Some(i);
+                Some(near)
+            } else {
+                None
+            }
+        });
+
+        assert_eq!(distance, Some(4.5));
+        assert_eq!(nearest, Some(0));
+    }
+}
@@ -0,0 +1,155 @@
+use crate::geometry::Vec3;
+
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::sync::RwLock;
+
+// Shadow rays traced in a cell before its average is reused
+const SAMPLES_PER_CELL: u32 = 4;
+
+// Locks are split over this many maps so render threads rarely wait on each
+// other
+const SHARDS: usize = 64;
+
+// The shape, the light and the cell of space the shaded point is in
+type Key = (usize, usize, [i32; 3]);
+
+// Keys are looked up for every shadow ray, so they're hashed with a quick
+// multiply and rotate rather than the standard library's collision-resistant
+// hash
+#[derive(Default)]
+struct CellHasher(u64);
+
+impl Hasher for CellHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u64(byte as u64);
+        }
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0.rotate_left(5) ^ n).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+
+    fn write_i32(&mut self, n: i32) {
+        self.write_u64(n as u32 as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+type Cells = HashMap<Key, (f32, u32), BuildHasherDefault<CellHasher>>;
+
+// How much of each light reaches the surface of each shape, remembered in
+// small cells over the surface. The first few shadow rays traced from a cell
+// are averaged and then reused for every later hit in it, from any ray and in
+// any frame, so shadows come out blurred by about a cell but cost nothing
+// once the cache is warm. That pays off when shadow rays are expensive, as
+// with dense meshes or curves, more than a lookup costs. Only the lighting is
+// cached: highlights still follow the view. The cache must be cleared
+// whenever the scene changes.
+pub struct ShadingCache {
+    pub cell_size: f32,
+    shards: Vec<RwLock<Cells>>,
+}
+
+impl ShadingCache {
+    pub fn new(cell_size: f32) -> Self {
+        ShadingCache {
+            cell_size: cell_size.max(1.0e-4),
+            shards: (0..SHARDS).map(|_| RwLock::new(Cells::default())).collect(),
+        }
+    }
+
+    fn key(&self, shape: usize, light: usize, point: Vec3<f32>) -> Key {
+        let cell = |x: f32| (x / self.cell_size).floor() as i32;
+        (shape, light, [cell(point.x), cell(point.y), cell(point.z)])
+    }
+
+    fn shard(&self, key: &Key) -> &RwLock<Cells> {
+        let mut hasher = CellHasher::default();
+        key.hash(&mut hasher);
+        &self.shards[(hasher.finish() >> 32) as usize % SHARDS]
+    }
+
+    // The light reaching the point on the shape, as a fraction from none to
+    // all of it, either from the cache or by calling `trace` and remembering
+    // the result. `trace` is called without holding any lock.
+    pub fn visibility<F>(&self, shape: usize, light: usize, point: Vec3<f32>, trace: F) -> f32
+    where
+        F: FnOnce() -> f32,
+    {
+        let key = self.key(shape, light, point);
+        let shard = self.shard(&key);
+
+        if let Some(&(total, count)) = shard.read().unwrap().get(&key) {
+            if count >= SAMPLES_PER_CELL {
+                return total / count as f32;
+            }
+        }
+
+        let visibility = trace();
+
+        let mut entries = shard.write().unwrap();
+        let entry = entries.entry(key).or_insert((0.0, 0));
+        if entry.1 < SAMPLES_PER_CELL {
+            entry.0 += visibility;
+            entry.1 += 1;
+        }
+
+        visibility
+    }
+
+    pub fn clear(&self) {
+        for shard in &self.shards {
+            shard.write().unwrap().clear();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cells_are_reused_once_full() {
+        let cache = ShadingCache::new(1.0);
+        let mut traced = 0;
+
+        for k in 0..10 {
+            let point = Vec3::new(0.1 * k as f32, 0.5, 0.5);
+            let visibility = cache.visibility(0, 0, point, || {
+                traced += 1;
+                if k % 2 == 0 { 1.0 } else { 0.0 }
+            });
+
+            if k >= SAMPLES_PER_CELL as usize {
+                assert_eq!(visibility, 0.5);
+            }
+        }
+        assert_eq!(traced, SAMPLES_PER_CELL);
+
+        // Other lights, shapes and cells are kept apart
+        cache.visibility(1, 0, Vec3::zero(), || 1.0);
+        cache.visibility(0, 1, Vec3::zero(), || 1.0);
+        cache.visibility(0, 0, Vec3::new(2.5, 0.5, 0.5), || 1.0);
+        assert_eq!(cache.len(), 4);
+
+        cache.clear();
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,156 @@
+use crate::geometry::{Ray, Vec3, cross, dot};
+
+use serde::{Deserialize, Serialize};
+
+// Vertical fields of view in radians. The default is about 57 degrees.
+pub const DEFAULT_FOV: f32 = 1.0;
+pub const MIN_FOV: f32 = 0.1;
+pub const MAX_FOV: f32 = 2.5;
+
+// Keep the camera just short of looking straight up or down, where the
+// right vector would become undefined
+const MAX_PITCH: f32 = 1.5;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Camera {
+    pub position: Vec3<f32>,
+    // Rotation about the world y axis, zero looking down -z
+    pub yaw: f32,
+    // Rotation above/below the horizon
+    pub pitch: f32,
+    // Vertical field of view in radians
+    pub fov: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera {
+            position: Vec3::zero(),
+            yaw: 0.0,
+            pitch: 0.0,
+            fov: DEFAULT_FOV,
+        }
+    }
+}
+
+impl Camera {
+    pub fn forward(&self) -> Vec3<f32> {
+        Vec3::new(
+            self.pitch.cos() * self.yaw.sin(),
+            self.pitch.sin(),
+            -self.pitch.cos() * self.yaw.cos(),
+        )
+    }
+
+    pub fn right(&self) -> Vec3<f32> {
+        cross(self.forward(), Vec3::new(0.0, 1.0, 0.0)).normalise()
+    }
+
+    pub fn up(&self) -> Vec3<f32> {
+        cross(self.right(), self.forward())
+    }
+
+    pub fn translate(&mut self, forward: f32, right: f32, up: f32) {
+        self.position = self.position
+            + self.forward() * forward
+            + self.right() * right
+            + Vec3::new(0.0, up, 0.0);
+    }
+
+    pub fn rotate(&mut self, yaw: f32, pitch: f32) {
+        self.yaw += yaw;
+        self.pitch = (self.pitch + pitch).clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    // Rotates about a point `distance` in front of the camera, keeping it
+    // centred in view
+    pub fn orbit(&mut self, yaw: f32, pitch: f32, distance: f32) {
+        let pivot = self.position + self.forward() * distance;
+        self.rotate(yaw, pitch);
+        self.position = pivot - self.forward() * distance;
+    }
+
+    pub fn set_fov(&mut self, fov: f32) {
+        self.fov = fov.clamp(MIN_FOV, MAX_FOV);
+    }
+
+    // Primary ray through the continuous image position (x, y), measured in
+    // pixels from the top left corner
+    pub fn ray_through(&self, x: f32, y: f32, width: usize, height: usize) -> Ray {
+        let (w, h) = (width as f32, height as f32);
+        let scale = (self.fov / 2.0).tan();
+
+        let x = (2.0 * x / w - 1.0) * scale * w / h;
+        let y = -(2.0 * y / h - 1.0) * scale;
+
+        let direction = (self.right() * x + self.up() * y + self.forward()).normalise();
+
+        Ray {
+            origin: self.position,
+            direction,
+        }
+    }
+
+    // The image position that `ray_through` would aim at to pass through the
+    // point, or none if it's behind the camera
+    pub fn project(&self, point: Vec3<f32>, width: usize, height: usize) -> Option<(f32, f32)> {
+        let (w, h) = (width as f32, height as f32);
+        let scale = (self.fov / 2.0).tan();
+
+        let offset = point - self.position;
+        let depth = dot(offset, self.forward());
+        if depth <= 0.0 {
+            return None;
+        }
+
+        let x = dot(offset, self.right()) / depth;
+        let y = dot(offset, self.up()) / depth;
+
+        Some((
+            (x / (scale * w / h) + 1.0) * w / 2.0,
+            (1.0 - y / scale) * h / 2.0,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_camera_looks_down_negative_z() {
+        let camera = Camera::default();
+        let ray = camera.ray_through(50.5, 50.5, 101, 101);
+        assert!((ray.direction - Vec3::new(0.0, 0.0, -1.0)).length() < 1.0e-6);
+        assert!((camera.right() - Vec3::new(1.0, 0.0, 0.0)).length() < 1.0e-6);
+        assert!((camera.up() - Vec3::new(0.0, 1.0, 0.0)).length() < 1.0e-6);
+    }
+
+    #[test]
+    fn orbit_keeps_pivot_in_view() {
+        let mut camera = Camera::default();
+        camera.orbit(0.5, 0.2, 10.0);
+        let pivot = camera.position + camera.forward() * 10.0;
+        assert!((pivot - Vec3::new(0.0, 0.0, -10.0)).length() < 1.0e-4);
+    }
+
+    #[test]
+    fn project_inverts_ray_through() {
+        let mut camera = Camera::default();
+        camera.rotate(0.3, -0.2);
+        camera.translate(1.0, 2.0, 3.0);
+
+        let ray = camera.ray_through(40.0, 25.0, 120, 80);
+        let (x, y) = camera.project(ray.origin + ray.direction * 7.0, 120, 80).unwrap();
+        assert!((x - 40.0).abs() < 1.0e-3 && (y - 25.0).abs() < 1.0e-3);
+
+        assert_eq!(camera.project(ray.origin - ray.direction, 120, 80), None);
+    }
+
+    #[test]
+    fn pitch_is_clamped() {
+        let mut camera = Camera::default();
+        camera.rotate(0.0, 10.0);
+        assert_eq!(camera.pitch, MAX_PITCH);
+    }
+}
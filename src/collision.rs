@@ -0,0 +1,146 @@
+use crate::geometry::{Vec3, dot};
+use crate::scene::Scene;
+
+use serde::{Deserialize, Serialize};
+
+// A simple stand-in for a shape's geometry in the simulation, centred on the
+// shape's `centre` so it follows the shape as it moves. Only shapes given one
+// collide, and only with each other, so a detailed mesh can take part as a
+// box around it without the simulation ever looking at its triangles.
+//
+//     colliders: { 3: Box(size: (4.0, 2.0, 4.0)) }
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Collider {
+    Sphere { radius: f32 },
+    // An axis-aligned box with sides of the given lengths
+    Box { size: Vec3<f32> },
+}
+
+impl Collider {
+    fn half_size(&self) -> Vec3<f32> {
+        match *self {
+            Collider::Sphere { radius } => Vec3::new(radius, radius, radius),
+            Collider::Box { size } => size * 0.5,
+        }
+    }
+}
+
+// Whether two colliders at the given centres overlap, and if so the direction
+// to push the first out of the second
+pub fn contact(a: Collider, a_centre: Vec3<f32>, b: Collider, b_centre: Vec3<f32>) -> Option<Vec3<f32>> {
+    match (a, b) {
+        (Collider::Sphere { radius: ra }, Collider::Sphere { radius: rb }) => {
+            let offset = a_centre - b_centre;
+            let distance = offset.length();
+            if distance >= ra + rb {
+                None
+            } else if distance > 0.0 {
+                Some(offset * (1.0 / distance))
+            } else {
+                Some(Vec3::new(0.0, 1.0, 0.0))
+            }
+        }
+        (Collider::Sphere { radius }, Collider::Box { size }) => sphere_box(a_centre, radius, b_centre, size * 0.5),
+        (Collider::Box { size }, Collider::Sphere { radius }) => {
+            sphere_box(b_centre, radius, a_centre, size * 0.5).map(|normal| normal * -1.0)
+        }
+        (Collider::Box { .. }, Collider::Box { .. }) => {
+            // Out along the axis the boxes overlap least on
+            let offset = a_centre - b_centre;
+            let reach = a.half_size() + b.half_size();
+            let overlaps = [reach.x - offset.x.abs(), reach.y - offset.y.abs(), reach.z - offset.z.abs()];
+            if overlaps.iter().any(|&overlap| overlap <= 0.0) {
+                return None;
+            }
+
+            let axis = (0..3).min_by(|&i, &j| overlaps[i].total_cmp(&overlaps[j])).expect("three axes");
+            Some(axis_normal(axis, [offset.x, offset.y, offset.z][axis]))
+        }
+    }
+}
+
+fn sphere_box(centre: Vec3<f32>, radius: f32, box_centre: Vec3<f32>, half_size: Vec3<f32>) -> Option<Vec3<f32>> {
+    let offset = centre - box_centre;
+    let closest = Vec3::new(
+        offset.x.clamp(-half_size.x, half_size.x),
+        offset.y.clamp(-half_size.y, half_size.y),
+        offset.z.clamp(-half_size.z, half_size.z),
+    );
+    let outside = offset - closest;
+    let distance = outside.length();
+    // Checked before the radius, so a point (of radius zero) inside still hits
+    if distance > 0.0 {
+        return if distance < radius { Some(outside * (1.0 / distance)) } else { None };
+    }
+
+    // The centre is inside the box, so out through the nearest face
+    let depths = [half_size.x - offset.x.abs(), half_size.y - offset.y.abs(), half_size.z - offset.z.abs()];
+    let axis = (0..3).min_by(|&i, &j| depths[i].total_cmp(&depths[j])).expect("three axes");
+    Some(axis_normal(axis, [offset.x, offset.y, offset.z][axis]))
+}
+
+fn axis_normal(axis: usize, sign: f32) -> Vec3<f32> {
+    let sign = if sign < 0.0 { -1.0 } else { 1.0 };
+    match axis {
+        0 => Vec3::new(sign, 0.0, 0.0),
+        1 => Vec3::new(0.0, sign, 0.0),
+        _ => Vec3::new(0.0, 0.0, sign),
+    }
+}
+
+// The direction to push a shape out of whatever other colliders it overlaps,
+// if it has a collider and overlaps any. Hidden shapes still collide.
+pub fn collision(scene: &Scene, shape: usize) -> Option<Vec3<f32>> {
+    let collider = *scene.colliders.get(&shape)?;
+    let centre = scene.shapes.get(shape)?.centre();
+
+    let push = scene
+        .colliders
+        .iter()
+        .filter(|&(&other, _)| other != shape)
+        .filter_map(|(&other, &other_collider)| {
+            let other_centre = scene.shapes.get(other)?.centre();
+            contact(collider, centre, other_collider, other_centre)
+        })
+        .fold(Vec3::zero(), |sum, normal| sum + normal);
+
+    if push.length() > 0.0 { Some(push.normalise()) } else { None }
+}
+
+// A velocity bounced off a surface with the given normal, unless it's already
+// heading away from it
+pub fn bounce(velocity: Vec3<f32>, normal: Vec3<f32>) -> Vec3<f32> {
+    let along = dot(velocity, normal);
+    if along < 0.0 { velocity - normal * (2.0 * along) } else { velocity }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contacts_push_apart() {
+        let sphere = Collider::Sphere { radius: 1.0 };
+        let cube = Collider::Box { size: Vec3::new(2.0, 2.0, 2.0) };
+
+        assert_eq!(contact(sphere, Vec3::new(1.5, 0.0, 0.0), sphere, Vec3::zero()), Some(Vec3::new(1.0, 0.0, 0.0)));
+        assert_eq!(contact(sphere, Vec3::new(2.5, 0.0, 0.0), sphere, Vec3::zero()), None);
+
+        assert_eq!(contact(sphere, Vec3::new(0.0, 1.5, 0.0), cube, Vec3::zero()), Some(Vec3::new(0.0, 1.0, 0.0)));
+        assert_eq!(contact(cube, Vec3::zero(), sphere, Vec3::new(0.0, 1.5, 0.0)), Some(Vec3::new(0.0, -1.0, 0.0)));
+        assert_eq!(contact(sphere, Vec3::new(1.8, 1.8, 0.0), cube, Vec3::zero()), None);
+        assert_eq!(contact(sphere, Vec3::new(0.0, 0.0, -0.5), cube, Vec3::zero()), Some(Vec3::new(0.0, 0.0, -1.0)));
+
+        assert_eq!(contact(cube, Vec3::new(0.5, 1.9, 0.0), cube, Vec3::zero()), Some(Vec3::new(0.0, 1.0, 0.0)));
+        assert_eq!(contact(cube, Vec3::new(0.0, 2.1, 0.0), cube, Vec3::zero()), None);
+
+        // Points inside a box hit it, through the nearest face
+        let point = Collider::Sphere { radius: 0.0 };
+        assert_eq!(contact(point, Vec3::new(0.2, 0.0, 0.9), cube, Vec3::zero()), Some(Vec3::new(0.0, 0.0, 1.0)));
+        assert_eq!(contact(cube, Vec3::zero(), point, Vec3::new(0.0, -0.9, 0.0)), Some(Vec3::new(0.0, 1.0, 0.0)));
+        assert_eq!(contact(point, Vec3::new(0.0, 1.5, 0.0), cube, Vec3::zero()), None);
+
+        assert_eq!(bounce(Vec3::new(1.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0)), Vec3::new(1.0, 1.0, 0.0));
+        assert_eq!(bounce(Vec3::new(1.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 0.0)), Vec3::new(1.0, 1.0, 0.0));
+    }
+}
@@ -0,0 +1,85 @@
+use crate::diagnostics::{self, Preview};
+use crate::framebuffer::{Framebuffer, to_pixel};
+use crate::geometry::Vec3;
+use crate::image::Image;
+
+// How close to the split, in pixels, grabs it for dragging
+pub const GRAB_DISTANCE: f32 = 8.0;
+
+const SPLIT_COLOUR: [u8; 3] = [255, 255, 255];
+
+// A reference image wiped over the left of the view up to a split that can be
+// dragged across it, for judging a change against how things looked before.
+// The reference is stretched to the view's size if it differs.
+pub struct Comparison {
+    pub reference: Image,
+    // Where the split is, as a fraction of the width
+    pub split: f32,
+}
+
+impl Comparison {
+    pub fn new(reference: Image) -> Self {
+        Comparison { reference, split: 0.5 }
+    }
+
+    // A copy of a rendered image, to compare later renders against
+    pub fn from_colours(colours: &[Vec3<f32>], width: usize, height: usize) -> Self {
+        Comparison::new(Image { width, height, texels: colours.to_vec() })
+    }
+
+    pub fn is_near_split(&self, x: f32, width: usize) -> bool {
+        (x - self.split * width as f32).abs() <= GRAB_DISTANCE
+    }
+
+    pub fn drag_to(&mut self, x: f32, width: usize) {
+        self.split = (x / width.max(1) as f32).clamp(0.0, 1.0);
+    }
+
+    // Draws the reference left of the split and a line along it, through the
+    // same preview transform as the view so the two sides match
+    pub fn draw(&self, framebuffer: &mut Framebuffer, preview: Preview) {
+        let (width, height) = (framebuffer.width, framebuffer.height);
+        let reference = &self.reference;
+        if reference.width == 0 || reference.height == 0 {
+            return;
+        }
+
+        let previewed = diagnostics::preview(preview, &reference.texels);
+        let texels = previewed.as_deref().unwrap_or(&reference.texels);
+        let split = ((self.split * width as f32) as usize).min(width);
+
+        for j in 0..height {
+            let y = j * reference.height / height;
+            for i in 0..split {
+                let x = i * reference.width / width;
+                framebuffer.put_pixel(i, j, to_pixel(texels[y * reference.width + x]));
+            }
+            if split < width {
+                framebuffer.put_pixel(split, j, SPLIT_COLOUR);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reference_fills_the_left_of_the_split() {
+        let white = vec![Vec3::new(1.0, 1.0, 1.0); 2 * 2];
+        let mut comparison = Comparison::from_colours(&white, 2, 2);
+        let mut framebuffer = Framebuffer::new(8, 4);
+        framebuffer.refresh_pixels();
+
+        comparison.drag_to(2.0, 8);
+        assert!(comparison.is_near_split(6.0, 8) && !comparison.is_near_split(20.0, 8));
+        comparison.draw(&mut framebuffer, Preview::Off);
+
+        let pixel = |i: usize, j: usize| &framebuffer.pixels[(j * 8 + i) * 3..(j * 8 + i) * 3 + 3];
+        assert_eq!(pixel(1, 3), [255, 255, 255]);
+        assert_eq!(pixel(2, 0), SPLIT_COLOUR);
+        assert_eq!(pixel(3, 0), [0, 0, 0]);
+        assert_eq!(framebuffer.colours[0], Vec3::zero());
+    }
+}
@@ -0,0 +1,283 @@
+use crate::Result;
+
+use std::fs;
+use std::net::UdpSocket;
+use std::path::Path;
+
+// Parameters that an external controller is allowed to drive. Indices refer to
+// positions in the `Scene` light/shape lists. The trace settings apply to the
+// main window.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Target {
+    LightIntensity(usize),
+    LightPosition(usize, Axis),
+    ShapeCentre(usize, Axis),
+    SphereRadius(usize),
+    MaxDepth,
+    RouletteStartDepth,
+    RouletteMinProbability,
+    LightSamples,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Binding {
+    pub address: String,
+    pub target: Target,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Binding {
+    // Controllers conventionally send normalised values in [0, 1], which are
+    // remapped onto the [min, max] range of the binding
+    pub fn map(&self, value: f32) -> f32 {
+        self.min + value * (self.max - self.min)
+    }
+}
+
+fn parse_index(s: Option<&str>, line: usize) -> Result<usize> {
+    s.and_then(|s| s.parse().ok())
+        .ok_or_else(|| format!("line {}: expected an index", line).into())
+}
+
+fn parse_axis(s: Option<&str>, line: usize) -> Result<Axis> {
+    match s {
+        Some("x") => Ok(Axis::X),
+        Some("y") => Ok(Axis::Y),
+        Some("z") => Ok(Axis::Z),
+        _ => Err(format!("line {}: expected an axis (x, y or z)", line).into()),
+    }
+}
+
+fn parse_target(s: &str, line: usize) -> Result<Target> {
+    let mut parts = s.split('.');
+
+    match parts.next() {
+        Some("light") => {
+            let index = parse_index(parts.next(), line)?;
+            match parts.next() {
+                Some("intensity") => Ok(Target::LightIntensity(index)),
+                Some("position") => Ok(Target::LightPosition(index, parse_axis(parts.next(), line)?)),
+                _ => Err(format!("line {}: unknown light parameter in `{}`", line, s).into()),
+            }
+        }
+        Some("shape") => {
+            let index = parse_index(parts.next(), line)?;
+            match parts.next() {
+                Some("centre") => Ok(Target::ShapeCentre(index, parse_axis(parts.next(), line)?)),
+                // Only meaningful for spheres, ignored for other shapes
+                Some("radius") => Ok(Target::SphereRadius(index)),
+                _ => Err(format!("line {}: unknown shape parameter in `{}`", line, s).into()),
+            }
+        }
+        Some("trace") => match parts.next() {
+            Some("max_depth") => Ok(Target::MaxDepth),
+            Some("rr_start_depth") => Ok(Target::RouletteStartDepth),
+            Some("rr_min_probability") => Ok(Target::RouletteMinProbability),
+            Some("light_samples") => Ok(Target::LightSamples),
+            _ => Err(format!("line {}: unknown trace setting in `{}`", line, s).into()),
+        },
+        _ => Err(format!("line {}: unknown target `{}`", line, s).into()),
+    }
+}
+
+// One binding per line: `<osc address> <target> <min> <max>`, e.g.
+//
+//     /1/fader1 light.0.intensity 0.0 3.0
+//     /1/xy/x   shape.2.centre.x -5.0 5.0
+//     /1/fader2 trace.max_depth 0 8
+//
+// Blank lines and lines starting with `#` are ignored.
+pub fn parse_bindings(source: &str) -> Result<Vec<Binding>> {
+    let mut bindings = Vec::new();
+
+    for (i, line) in source.lines().enumerate() {
+        let line_number = i + 1;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        if fields.len() != 4 {
+            return Err(format!("line {}: expected `<address> <target> <min> <max>`", line_number).into());
+        }
+
+        let min = fields[2].parse().map_err(|_| format!("line {}: invalid minimum", line_number))?;
+        let max = fields[3].parse().map_err(|_| format!("line {}: invalid maximum", line_number))?;
+
+        bindings.push(Binding {
+            address: fields[0].to_string(),
+            target: parse_target(fields[1], line_number)?,
+            min,
+            max,
+        });
+    }
+
+    Ok(bindings)
+}
+
+pub fn load_bindings<P: AsRef<Path>>(path: P) -> Result<Vec<Binding>> {
+    parse_bindings(&fs::read_to_string(path)?)
+}
+
+// OSC strings are null-terminated and padded to a multiple of four bytes
+fn read_osc_string(data: &[u8], offset: &mut usize) -> Option<String> {
+    let start = *offset;
+    let len = data.get(start..)?.iter().position(|&b| b == 0)?;
+    let s = std::str::from_utf8(&data[start..start + len]).ok()?.to_string();
+    *offset = start + (len + 4) / 4 * 4;
+    Some(s)
+}
+
+fn read_osc_u32(data: &[u8], offset: &mut usize) -> Option<u32> {
+    let bytes = data.get(*offset..*offset + 4)?;
+    *offset += 4;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+// Extracts the first numeric argument of each message in a packet. Bundles are
+// flattened and their time tags ignored, since values are applied immediately.
+pub fn parse_osc_packet(data: &[u8], messages: &mut Vec<(String, f32)>) {
+    if data.starts_with(b"#bundle\0") {
+        // Skip the bundle identifier and the 8 byte time tag
+        let mut offset = 16;
+
+        while let Some(size) = read_osc_u32(data, &mut offset) {
+            let end = offset + size as usize;
+            match data.get(offset..end) {
+                Some(element) => parse_osc_packet(element, messages),
+                None => return,
+            }
+            offset = end;
+        }
+
+        return;
+    }
+
+    let mut offset = 0;
+
+    let address = match read_osc_string(data, &mut offset) {
+        Some(address) if address.starts_with('/') => address,
+        _ => return,
+    };
+
+    let tags = match read_osc_string(data, &mut offset) {
+        Some(tags) if tags.starts_with(',') => tags,
+        _ => return,
+    };
+
+    let value = match tags.as_bytes().get(1) {
+        Some(b'f') => read_osc_u32(data, &mut offset).map(f32::from_bits),
+        Some(b'i') => read_osc_u32(data, &mut offset).map(|i| i as i32 as f32),
+        // Buttons often send `T`/`F` with no argument data
+        Some(b'T') => Some(1.0),
+        Some(b'F') => Some(0.0),
+        _ => None,
+    };
+
+    if let Some(value) = value {
+        messages.push((address, value));
+    }
+}
+
+pub struct OscListener {
+    socket: UdpSocket,
+    buffer: Vec<u8>,
+}
+
+impl OscListener {
+    pub fn bind(port: u16) -> Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", port))?;
+        socket.set_nonblocking(true)?;
+
+        Ok(OscListener {
+            socket,
+            buffer: vec![0; 4096],
+        })
+    }
+
+    // Drains all pending packets without blocking the render loop
+    pub fn poll(&mut self) -> Vec<(String, f32)> {
+        let mut messages = Vec::new();
+
+        while let Ok(size) = self.socket.recv(&mut self.buffer) {
+            parse_osc_packet(&self.buffer[..size], &mut messages);
+        }
+
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn osc_message(address: &str, tags: &str, args: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for s in &[address, tags] {
+            data.extend_from_slice(s.as_bytes());
+            data.push(0);
+            while data.len() % 4 != 0 {
+                data.push(0);
+            }
+        }
+        data.extend_from_slice(args);
+        data
+    }
+
+    #[test]
+    fn parse_float_message() {
+        let data = osc_message("/1/fader1", ",f", &0.25f32.to_bits().to_be_bytes());
+        let mut messages = Vec::new();
+        parse_osc_packet(&data, &mut messages);
+        assert_eq!(messages, vec![("/1/fader1".to_string(), 0.25)]);
+    }
+
+    #[test]
+    fn parse_bundle() {
+        let first = osc_message("/a", ",i", &3i32.to_be_bytes());
+        let second = osc_message("/b", ",T", &[]);
+
+        let mut data = b"#bundle\0".to_vec();
+        data.extend_from_slice(&[0; 8]);
+        for element in &[first, second] {
+            data.extend_from_slice(&(element.len() as u32).to_be_bytes());
+            data.extend_from_slice(element);
+        }
+
+        let mut messages = Vec::new();
+        parse_osc_packet(&data, &mut messages);
+        assert_eq!(messages, vec![("/a".to_string(), 3.0), ("/b".to_string(), 1.0)]);
+    }
+
+    #[test]
+    fn parse_binding_table() {
+        let source = "
+            # fader bank
+            /1/fader1 light.0.intensity 0.0 3.0
+            /1/xy/x shape.2.centre.x -5.0 5.0
+            /1/fader2 trace.rr_min_probability 0.0 1.0
+        ";
+        let bindings = parse_bindings(source).unwrap();
+        assert_eq!(bindings.len(), 3);
+        assert_eq!(bindings[2].target, Target::RouletteMinProbability);
+        assert_eq!(bindings[0].target, Target::LightIntensity(0));
+        assert_eq!(bindings[1].target, Target::ShapeCentre(2, Axis::X));
+        assert_eq!(bindings[1].map(0.5), 0.0);
+    }
+
+    #[test]
+    fn reject_unknown_target() {
+        assert!(parse_bindings("/a fog.density 0 1").is_err());
+    }
+}
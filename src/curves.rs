@@ -0,0 +1,344 @@
+use crate::bvh::Bvh;
+use crate::geometry::{Aabb, Hit, Intersect, Ray, Vec3, basis, dot};
+use crate::materials::Material;
+use crate::sampling;
+
+use serde::{Deserialize, Serialize};
+
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+// Times each curve is halved before its pieces are treated as straight
+const SUBDIVISIONS: u32 = 4;
+
+// Hits closer than this along the ray are ignored, so rays leaving a strand
+// don't hit it again
+const MIN_DISTANCE: f32 = 1.0e-3;
+
+// A cubic Bézier strand, tapering linearly in width from root to tip
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Curve {
+    pub points: [Vec3<f32>; 4],
+    pub root_width: f32,
+    pub tip_width: f32,
+}
+
+impl Curve {
+    pub fn point(&self, u: f32) -> Vec3<f32> {
+        let [p0, p1, p2, p3] = self.points;
+        let v = 1.0 - u;
+        p0 * (v * v * v) + p1 * (3.0 * v * v * u) + p2 * (3.0 * v * u * u) + p3 * (u * u * u)
+    }
+
+    // The direction of the curve at `u`, not normalised
+    pub fn tangent(&self, u: f32) -> Vec3<f32> {
+        let [p0, p1, p2, p3] = self.points;
+        let v = 1.0 - u;
+        (p1 - p0) * (3.0 * v * v) + (p2 - p1) * (6.0 * v * u) + (p3 - p2) * (3.0 * u * u)
+    }
+
+    pub fn width(&self, u: f32) -> f32 {
+        self.root_width + (self.tip_width - self.root_width) * u
+    }
+
+    // The curve lies within the hull of its control points
+    fn bounds(&self) -> Aabb {
+        let half = 0.5 * self.root_width.max(self.tip_width);
+        let bounds = Aabb::from_points(&self.points);
+        Aabb {
+            min: bounds.min - Vec3::new(half, half, half),
+            max: bounds.max + Vec3::new(half, half, half),
+        }
+    }
+}
+
+// Splits control points at the middle of the curve with de Casteljau's
+// algorithm
+fn halve(points: [Vec3<f32>; 4]) -> ([Vec3<f32>; 4], [Vec3<f32>; 4]) {
+    let [p0, p1, p2, p3] = points;
+    let mid = |a: Vec3<f32>, b: Vec3<f32>| (a + b) * 0.5;
+
+    let (a, b, c) = (mid(p0, p1), mid(p1, p2), mid(p2, p3));
+    let (d, e) = (mid(a, b), mid(b, c));
+    let f = mid(d, e);
+
+    ([p0, a, d, f], [f, e, c, p3])
+}
+
+// Intersects part of a curve, from `u0` to `u1`, whose control points have
+// been moved into a space where the ray starts at the origin and runs along
+// z. The strand is seen as a flat ribbon facing the ray, so a piece is hit if
+// it passes within half its width of the z axis. Returns the distance along
+// the ray and where along the curve it hit.
+fn intersect_piece(
+    curve: &Curve,
+    points: [Vec3<f32>; 4],
+    (u0, u1): (f32, f32),
+    depth: u32,
+    max_distance: f32,
+) -> Option<(f32, f32)> {
+    let half = 0.5 * curve.width(u0).max(curve.width(u1));
+    let bounds = Aabb::from_points(&points);
+
+    if bounds.min.x > half || bounds.max.x < -half || bounds.min.y > half || bounds.max.y < -half {
+        return None;
+    }
+    if bounds.max.z < MIN_DISTANCE || bounds.min.z > max_distance {
+        return None;
+    }
+
+    if depth == 0 {
+        // Closest approach of the straightened piece to the ray
+        let (start, direction) = (points[0], points[3] - points[0]);
+        let length = direction.x * direction.x + direction.y * direction.y;
+        let t = if length > 0.0 {
+            (-(start.x * direction.x + start.y * direction.y) / length).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+
+        let nearest = start + direction * t;
+        let u = u0 + (u1 - u0) * t;
+        let half = 0.5 * curve.width(u);
+
+        if nearest.x * nearest.x + nearest.y * nearest.y > half * half {
+            return None;
+        }
+        if nearest.z < MIN_DISTANCE || nearest.z > max_distance {
+            return None;
+        }
+
+        return Some((nearest.z, u));
+    }
+
+    let (first, second) = halve(points);
+    let middle = 0.5 * (u0 + u1);
+
+    let near = intersect_piece(curve, first, (u0, middle), depth - 1, max_distance);
+    let max_distance = near.map_or(max_distance, |(distance, _)| distance);
+    intersect_piece(curve, second, (middle, u1), depth - 1, max_distance).or(near)
+}
+
+// Hair, fur or grass: a set of strands sharing a material, shaded with a
+// model for thin fibres rather than as surfaces. Like a mesh, they're moved
+// around by offsetting rays so the BVH never needs rebuilding.
+//
+// In scene files:
+//
+//     Curves((curves: [(points: [...], root_width: 0.02, tip_width: 0.0)], material: (...)))
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "CurvesDescription", into = "CurvesDescription")]
+pub struct Curves {
+    pub material: Material,
+    pub position: Vec3<f32>,
+    curves: Arc<Vec<Curve>>,
+    bvh: Arc<Bvh>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CurvesDescription {
+    curves: Vec<Curve>,
+    material: Material,
+    #[serde(default)]
+    position: Vec3<f32>,
+}
+
+impl From<CurvesDescription> for Curves {
+    fn from(description: CurvesDescription) -> Self {
+        let mut curves = Curves::new(description.curves, description.material);
+        curves.position = description.position;
+        curves
+    }
+}
+
+impl From<Curves> for CurvesDescription {
+    fn from(curves: Curves) -> Self {
+        CurvesDescription {
+            curves: curves.curves.to_vec(),
+            material: curves.material,
+            position: curves.position,
+        }
+    }
+}
+
+impl Curves {
+    pub fn new(curves: Vec<Curve>, material: Material) -> Self {
+        let bounds: Vec<Aabb> = curves.iter().map(Curve::bounds).collect();
+
+        Curves {
+            material,
+            position: Vec3::zero(),
+            bvh: Arc::new(Bvh::build(&bounds)),
+            curves: Arc::new(curves),
+        }
+    }
+
+    // Strands sticking out all over a sphere, drooping a little under their
+    // own weight
+    pub fn fur_ball(centre: Vec3<f32>, radius: f32, count: usize, length: f32, width: f32, material: Material) -> Self {
+        let curves = (0..count)
+            .map(|k| {
+                let seed = sampling::seed(k, 0);
+                let (u1, u2) = (sampling::random(seed), sampling::random(seed.wrapping_add(1)));
+
+                // Uniformly over the sphere
+                let (z, phi) = (1.0 - 2.0 * u1, 2.0 * PI * u2);
+                let r = (1.0 - z * z).max(0.0).sqrt();
+                let out = Vec3::new(r * phi.cos(), z, r * phi.sin());
+
+                let root = centre + out * radius;
+                let droop = Vec3::new(0.0, -length, 0.0);
+
+                Curve {
+                    points: [
+                        root,
+                        root + out * (length / 3.0),
+                        root + out * (2.0 * length / 3.0) + droop * 0.1,
+                        root + out * length + droop * 0.3,
+                    ],
+                    root_width: width,
+                    tip_width: 0.0,
+                }
+            })
+            .collect();
+
+        Curves::new(curves, material)
+    }
+
+    // Blades growing up from a square patch of ground centred on `centre`,
+    // each leaning off in its own direction
+    pub fn grass(centre: Vec3<f32>, size: f32, count: usize, height: f32, width: f32, material: Material) -> Self {
+        let curves = (0..count)
+            .map(|k| {
+                let seed = sampling::seed(k, 1);
+                let random = |n: u64| sampling::random(seed.wrapping_add(n));
+
+                let root = centre + Vec3::new((random(0) - 0.5) * size, 0.0, (random(1) - 0.5) * size);
+                let height = height * (0.6 + 0.4 * random(2));
+                let angle = 2.0 * PI * random(3);
+                let lean = Vec3::new(angle.cos(), 0.0, angle.sin()) * (height * 0.4 * random(4));
+                let up = Vec3::new(0.0, height, 0.0);
+
+                Curve {
+                    points: [
+                        root,
+                        root + up * 0.4,
+                        root + up * 0.8 + lean * 0.5,
+                        root + up + lean,
+                    ],
+                    root_width: width,
+                    tip_width: 0.0,
+                }
+            })
+            .collect();
+
+        Curves::new(curves, material)
+    }
+
+    pub fn len(&self) -> usize {
+        self.curves.len()
+    }
+
+    // Control points are relative to the curves' own position
+    pub fn curves(&self) -> &[Curve] {
+        &self.curves
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.curves.is_empty()
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let bounds = self.bvh.bounds();
+        Aabb {
+            min: bounds.min + self.position,
+            max: bounds.max + self.position,
+        }
+    }
+}
+
+impl Intersect for Curves {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        let origin = ray.origin - self.position;
+        let local = Ray { origin, direction: ray.direction };
+        let (x_axis, y_axis) = basis(ray.direction);
+
+        let to_ray_space = |p: Vec3<f32>| {
+            let offset = p - origin;
+            Vec3::new(dot(offset, x_axis), dot(offset, y_axis), dot(offset, ray.direction))
+        };
+
+        let mut nearest = (0, 0.0);
+        let distance = self.bvh.intersect(&local, f32::MAX, |index, max_distance| {
+            let curve = &self.curves[index];
+            let points = curve.points.map(to_ray_space);
+            let (distance, u) = intersect_piece(curve, points, (0.0, 1.0), SUBDIVISIONS, max_distance)?;
+            nearest = (index, u);
+            Some(distance)
+        })?;
+
+        let (index, u) = nearest;
+        let tangent = self.curves[index].tangent(u).normalise();
+
+        // The ribbon faces the ray as far as it can while lying along the
+        // strand
+        let towards = -ray.direction;
+        let normal = towards - tangent * dot(towards, tangent);
+        let normal = if normal.length() > 1.0e-6 { normal.normalise() } else { towards };
+
+        Some(Hit::new(ray, distance, normal, self.material).with_tangent(tangent))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn straight(width: f32) -> Curve {
+        Curve {
+            points: [
+                Vec3::new(0.0, -1.0, -5.0),
+                Vec3::new(0.0, -0.3, -5.0),
+                Vec3::new(0.0, 0.3, -5.0),
+                Vec3::new(0.0, 1.0, -5.0),
+            ],
+            root_width: width,
+            tip_width: 0.0,
+        }
+    }
+
+    #[test]
+    fn ribbon_is_hit_within_its_width() {
+        let curves = Curves::new(vec![straight(0.2)], Material::default());
+        let ray = |x: f32, y: f32| Ray {
+            origin: Vec3::new(x, y, 0.0),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+
+        let hit = curves.ray_intersect(&ray(0.05, -0.5)).unwrap();
+        assert!((hit.distance - 5.0).abs() < 1.0e-4);
+        assert!((hit.tangent.unwrap() - Vec3::new(0.0, 1.0, 0.0)).length() < 1.0e-4);
+        assert!((hit.normal - Vec3::new(0.0, 0.0, 1.0)).length() < 1.0e-4);
+
+        // Wide enough at the root, but tapered to nothing near the tip
+        assert!(curves.ray_intersect(&ray(0.08, -0.9)).is_some());
+        assert!(curves.ray_intersect(&ray(0.08, 0.9)).is_none());
+        assert!(curves.ray_intersect(&ray(0.5, 0.0)).is_none());
+    }
+
+    #[test]
+    fn halving_keeps_the_curve() {
+        let curve = Curve {
+            points: [Vec3::zero(), Vec3::new(1.0, 2.0, 0.0), Vec3::new(3.0, -1.0, 1.0), Vec3::new(4.0, 0.0, 2.0)],
+            root_width: 0.0,
+            tip_width: 0.0,
+        };
+
+        let (first, second) = halve(curve.points);
+        let first = Curve { points: first, ..curve };
+        let second = Curve { points: second, ..curve };
+
+        assert!((first.point(0.5) - curve.point(0.25)).length() < 1.0e-5);
+        assert!((second.point(0.5) - curve.point(0.75)).length() < 1.0e-5);
+    }
+}
@@ -0,0 +1,260 @@
+use crate::Result;
+use crate::camera::Camera;
+use crate::framebuffer::Framebuffer;
+use crate::geometry::Vec3;
+use crate::render::{Renderer, Tracer, nearest_shape};
+use crate::scene::Scene;
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::thread;
+
+const MAGIC: &[u8; 8] = b"TRDEEP1\n";
+
+// What one surface contributes to a pixel: the range of distances along the
+// camera rays it was seen at, how much of the pixel it hides of what's behind
+// it, and its colour premultiplied by that alpha
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DeepSample {
+    pub front: f32,
+    pub back: f32,
+    pub alpha: f32,
+    pub colour: Vec3<f32>,
+}
+
+// An image keeping every surface seen in each pixel, front to back, rather
+// than flattening them into one colour. Anything composited into it later is
+// sorted in by depth, so it can pass between objects. Pixels or parts of
+// pixels where the background is seen are left transparent.
+pub struct DeepImage {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<Vec<DeepSample>>,
+}
+
+// Renders a deep image with the renderer's samples, mode and settings. The
+// samples in a pixel that hit the same shape are merged into one deep sample
+// covering the fraction of the pixel they hit.
+pub fn render(renderer: &Renderer, scene: &Scene, camera: &Camera, width: usize, height: usize) -> DeepImage {
+    let samples = renderer.sampling.sample_count(renderer.samples);
+    let tracer = Tracer::new(scene, renderer.mode, renderer.settings);
+
+    let pixel = |i: usize, j: usize| {
+        let mut surfaces: Vec<(usize, DeepSample)> = Vec::new();
+
+        for k in 0..samples {
+            let (dx, dy) = renderer.sampling.offset((i, j), width, k, samples);
+            let ray = camera.ray_through(i as f32 + dx, j as f32 + dy, width, height);
+
+            let (shape, hit) = match nearest_shape(&ray, scene) {
+                Some(nearest) => nearest,
+                None => continue,
+            };
+            let colour = tracer.shade(&ray, renderer.sample_seed(j * width + i, k));
+
+            match surfaces.iter_mut().find(|(s, _)| *s == shape) {
+                Some((_, sample)) => {
+                    sample.front = sample.front.min(hit.distance);
+                    sample.back = sample.back.max(hit.distance);
+                    sample.alpha += 1.0;
+                    sample.colour = sample.colour + colour;
+                }
+                None => surfaces.push((shape, DeepSample {
+                    front: hit.distance,
+                    back: hit.distance,
+                    alpha: 1.0,
+                    colour,
+                })),
+            }
+        }
+
+        let mut pixel: Vec<DeepSample> = surfaces.into_iter().map(|(_, sample)| sample).collect();
+        pixel.sort_by(|a, b| a.front.total_cmp(&b.front));
+
+        // Each surface covers its own part of the pixel, and what's behind
+        // only shows through what's in front, so its alpha is taken relative
+        // to what's left uncovered by the surfaces in front
+        let mut remaining = samples as f32;
+        for sample in &mut pixel {
+            let hits = sample.alpha;
+            sample.alpha = hits / remaining;
+            sample.colour = sample.colour * (1.0 / remaining);
+            remaining -= hits;
+        }
+
+        pixel
+    };
+
+    // Rows are shared out between threads in contiguous bands
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let rows_per_band = height.div_ceil(threads).max(1);
+    let mut pixels = vec![Vec::new(); width * height];
+
+    thread::scope(|scope| {
+        for (band, chunk) in pixels.chunks_mut(width * rows_per_band).enumerate() {
+            let pixel = &pixel;
+            scope.spawn(move || {
+                for (index, samples) in chunk.iter_mut().enumerate() {
+                    let index = band * rows_per_band * width + index;
+                    *samples = pixel(index % width, index / width);
+                }
+            });
+        }
+    });
+
+    DeepImage { width, height, pixels }
+}
+
+impl DeepImage {
+    // Composites each pixel's samples front to back over the background
+    pub fn flatten(&self, background: Vec3<f32>) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(self.width, self.height);
+
+        framebuffer.render(|i, j| {
+            let mut colour = Vec3::zero();
+            let mut remaining = 1.0;
+
+            for sample in &self.pixels[j * self.width + i] {
+                colour = colour + sample.colour * remaining;
+                remaining *= 1.0 - sample.alpha;
+            }
+
+            colour + background * remaining
+        });
+
+        framebuffer
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.pixels.iter().map(Vec::len).sum()
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        DeepImage::read(&fs::read(path)?)
+    }
+
+    // A small format of our own, all little endian: the magic line, the
+    // width and height as u32s, then for each pixel row by row from the top
+    // left a u32 count of its samples followed by each sample's front, back,
+    // alpha and premultiplied red, green and blue as f32s
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&(self.width as u32).to_le_bytes())?;
+        writer.write_all(&(self.height as u32).to_le_bytes())?;
+
+        for pixel in &self.pixels {
+            writer.write_all(&(pixel.len() as u32).to_le_bytes())?;
+
+            for sample in pixel {
+                let Vec3 { x, y, z } = sample.colour;
+                for value in [sample.front, sample.back, sample.alpha, x, y, z] {
+                    writer.write_all(&value.to_le_bytes())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn read(data: &[u8]) -> Result<Self> {
+        let mut rest = data.strip_prefix(MAGIC.as_slice()).ok_or("not a deep image")?;
+
+        let mut word = || -> Result<[u8; 4]> {
+            let (bytes, tail) = rest.split_first_chunk::<4>().ok_or("deep image is truncated")?;
+            rest = tail;
+            Ok(*bytes)
+        };
+
+        let width = u32::from_le_bytes(word()?) as usize;
+        let height = u32::from_le_bytes(word()?) as usize;
+        let mut pixels = Vec::with_capacity(width.saturating_mul(height).min(data.len()));
+
+        for _ in 0..width.saturating_mul(height) {
+            let count = u32::from_le_bytes(word()?) as usize;
+            let mut pixel = Vec::with_capacity(count.min(data.len()));
+
+            for _ in 0..count {
+                let mut value = || word().map(f32::from_le_bytes);
+                pixel.push(DeepSample {
+                    front: value()?,
+                    back: value()?,
+                    alpha: value()?,
+                    colour: Vec3::new(value()?, value()?, value()?),
+                });
+            }
+
+            pixels.push(pixel);
+        }
+
+        Ok(DeepImage { width, height, pixels })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Shape, Sphere};
+    use crate::materials::Material;
+
+    #[test]
+    fn edges_hold_both_surfaces_and_flatten_to_the_render() {
+        let mut scene = Scene::default_scene();
+        scene.shapes = vec![
+            Shape::Sphere(Sphere::new(Vec3::new(-1.0, 0.0, -8.0), 2.0, Material::default())),
+            Shape::Sphere(Sphere::new(Vec3::new(2.0, 0.0, -14.0), 3.0, Material::default())),
+        ];
+
+        let renderer = Renderer { samples: 16, ..Renderer::default() };
+        let camera = Camera::default();
+        let deep = render(&renderer, &scene, &camera, 32, 24);
+
+        assert!(deep.pixels.iter().any(|pixel| pixel.len() == 2));
+        for pixel in &deep.pixels {
+            assert!(pixel.iter().all(|sample| sample.alpha > 0.0 && sample.alpha <= 1.0));
+            assert!(pixel.windows(2).all(|pair| pair[0].front <= pair[1].front));
+        }
+
+        // Where the surfaces cover the whole pixel, compositing gives back
+        // the ordinary render
+        let flat = deep.flatten(Vec3::zero());
+        let colours = renderer.render_to_buffer(&scene, &camera, 32, 24);
+        let mut covered = 0;
+        for (index, pixel) in deep.pixels.iter().enumerate() {
+            if pixel.iter().any(|sample| sample.alpha == 1.0) {
+                assert!((flat.colours[index] - colours[index]).length() < 1.0e-4);
+                covered += 1;
+            }
+        }
+        assert!(covered > 0);
+    }
+
+    #[test]
+    fn round_trip() {
+        let sample = DeepSample {
+            front: 1.5,
+            back: 2.0,
+            alpha: 0.25,
+            colour: Vec3::new(0.1, 0.2, 0.3),
+        };
+        let image = DeepImage {
+            width: 2,
+            height: 1,
+            pixels: vec![vec![sample, sample], vec![]],
+        };
+
+        let mut data = Vec::new();
+        image.write(&mut data).unwrap();
+
+        let read = DeepImage::read(&data).unwrap();
+        assert_eq!(read.pixels, image.pixels);
+        assert!(DeepImage::read(&data[..data.len() - 1]).is_err());
+    }
+}
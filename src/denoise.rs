@@ -0,0 +1,172 @@
+use crate::camera::Camera;
+use crate::geometry::{Vec3, dot};
+use crate::render::{RenderMode, Renderer};
+use crate::scene::Scene;
+
+use std::thread;
+
+// Passes of the filter, each spreading its taps twice as far apart as the
+// last
+const ITERATIONS: u32 = 4;
+
+// How quickly each guide's weight falls off with difference from the centre
+// pixel. The colour falloff tightens with each pass, as the image gets
+// smoother.
+const SIGMA_COLOUR: f32 = 2.0;
+const SIGMA_NORMAL: f32 = 0.3;
+const SIGMA_DEPTH: f32 = 0.05;
+
+// The B3 spline kernel. Each of the 5 x 5 taps is weighted by the product of
+// the entries for its row and column.
+const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// Noise-free images of the first hit seen through each pixel centre, from the
+// normals and depth render modes, which tell the filter where edges are
+pub struct Guides {
+    pub width: usize,
+    pub height: usize,
+    pub normals: Vec<Vec3<f32>>,
+    pub depths: Vec<Vec3<f32>>,
+}
+
+impl Guides {
+    pub fn render(scene: &Scene, camera: &Camera, width: usize, height: usize) -> Self {
+        let guide = |mode| {
+            Renderer {
+                mode,
+                ..Renderer::default()
+            }
+            .render_to_buffer(scene, camera, width, height)
+        };
+
+        Guides {
+            width,
+            height,
+            normals: guide(RenderMode::Normals),
+            depths: guide(RenderMode::Depth),
+        }
+    }
+}
+
+// Smooths noise out of a progressively rendered image with the edge-avoiding
+// À-trous wavelet filter, averaging each pixel with neighbours that look like
+// the same surface in the guides
+pub fn a_trous(colours: &[Vec3<f32>], guides: &Guides) -> Vec<Vec3<f32>> {
+    let mut filtered = colours.to_vec();
+
+    for iteration in 0..ITERATIONS {
+        filtered = pass(&filtered, guides, 1 << iteration, SIGMA_COLOUR / (iteration + 1) as f32);
+    }
+
+    filtered
+}
+
+// One pass of the filter with taps `step` pixels apart, shared out over
+// threads by rows
+fn pass(colours: &[Vec3<f32>], guides: &Guides, step: usize, sigma_colour: f32) -> Vec<Vec3<f32>> {
+    let (width, height) = (guides.width, guides.height);
+    let mut output = vec![Vec3::zero(); width * height];
+
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let rows_per_thread = height.div_ceil(threads).max(1);
+
+    thread::scope(|scope| {
+        for (chunk, rows) in output.chunks_mut(rows_per_thread * width).enumerate() {
+            scope.spawn(move || {
+                for (index, out) in rows.iter_mut().enumerate() {
+                    let (i, j) = (index % width, chunk * rows_per_thread + index / width);
+                    *out = filter_pixel(colours, guides, i, j, step, sigma_colour);
+                }
+            });
+        }
+    });
+
+    output
+}
+
+fn filter_pixel(
+    colours: &[Vec3<f32>],
+    guides: &Guides,
+    i: usize,
+    j: usize,
+    step: usize,
+    sigma_colour: f32,
+) -> Vec3<f32> {
+    let (width, height) = (guides.width, guides.height);
+    let centre = j * width + i;
+
+    let mut sum = Vec3::zero();
+    let mut total = 0.0;
+
+    for (ky, &wy) in KERNEL.iter().enumerate() {
+        for (kx, &wx) in KERNEL.iter().enumerate() {
+            let x = i as isize + (kx as isize - 2) * step as isize;
+            let y = j as isize + (ky as isize - 2) * step as isize;
+            if x < 0 || y < 0 || x >= width as isize || y >= height as isize {
+                continue;
+            }
+
+            let tap = y as usize * width + x as usize;
+
+            let colour = colours[tap] - colours[centre];
+            let normal = guides.normals[tap] - guides.normals[centre];
+            let depth = guides.depths[tap].x - guides.depths[centre].x;
+
+            let weight = wx * wy
+                * (-dot(colour, colour) / (sigma_colour * sigma_colour)
+                    - dot(normal, normal) / (SIGMA_NORMAL * SIGMA_NORMAL)
+                    - depth.abs() / SIGMA_DEPTH)
+                    .exp();
+
+            sum = sum + colours[tap] * weight;
+            total += weight;
+        }
+    }
+
+    // The centre tap always has some weight
+    sum * (1.0 / total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flat_guides(width: usize, height: usize) -> Guides {
+        Guides {
+            width,
+            height,
+            normals: vec![Vec3::new(0.5, 1.0, 0.5); width * height],
+            depths: vec![Vec3::new(0.5, 0.5, 0.5); width * height],
+        }
+    }
+
+    #[test]
+    fn smooths_noise_on_one_surface() {
+        let (width, height) = (16, 16);
+        let colours: Vec<Vec3<f32>> = (0..width * height)
+            .map(|k| if k % 2 == 0 { Vec3::new(0.4, 0.4, 0.4) } else { Vec3::new(0.6, 0.6, 0.6) })
+            .collect();
+
+        let filtered = a_trous(&colours, &flat_guides(width, height));
+        assert!(filtered.iter().all(|c| (c.x - 0.5).abs() < 0.05));
+    }
+
+    #[test]
+    fn keeps_edges_between_surfaces() {
+        let (width, height) = (16, 16);
+        let mut guides = flat_guides(width, height);
+        let mut colours = vec![Vec3::new(0.1, 0.1, 0.1); width * height];
+
+        // The right half is a different surface further away
+        for j in 0..height {
+            for i in width / 2..width {
+                colours[j * width + i] = Vec3::new(0.9, 0.9, 0.9);
+                guides.depths[j * width + i] = Vec3::new(0.2, 0.2, 0.2);
+            }
+        }
+
+        let filtered = a_trous(&colours, &guides);
+        assert!((filtered[8 * width + width / 2 - 1].x - 0.1).abs() < 0.01);
+        assert!((filtered[8 * width + width / 2].x - 0.9).abs() < 0.01);
+    }
+}
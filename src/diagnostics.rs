@@ -0,0 +1,349 @@
+use crate::framebuffer::Framebuffer;
+use crate::geometry::Vec3;
+
+// Exposure and convergence diagnostics drawn over the displayed image. They
+// only change the display pixels, so the linear colours are left untouched.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Overlay {
+    None,
+    Histogram,
+    FalseColour,
+    // Heatmaps of the samples accumulated in each pixel and the noise
+    // estimated to be left in it
+    SampleCount,
+    Error,
+}
+
+impl Overlay {
+    pub fn next(self) -> Self {
+        match self {
+            Overlay::None => Overlay::Histogram,
+            Overlay::Histogram => Overlay::FalseColour,
+            Overlay::FalseColour => Overlay::SampleCount,
+            Overlay::SampleCount => Overlay::Error,
+            Overlay::Error => Overlay::None,
+        }
+    }
+}
+
+// Transforms of the displayed image that bring very dark or very bright
+// renders into view while lighting. Like the overlays they only change the
+// display pixels, so the linear colours that get saved are left as they are.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Preview {
+    Off,
+    // Scaled so the image's average (geometric mean) luminance is middle grey
+    AutoExposure,
+    // Luminances spread out evenly over the display range, brightest last,
+    // so every part of the image gets some contrast however far apart they are
+    Equalised,
+}
+
+impl Preview {
+    pub fn next(self) -> Self {
+        match self {
+            Preview::Off => Preview::AutoExposure,
+            Preview::AutoExposure => Preview::Equalised,
+            Preview::Equalised => Preview::Off,
+        }
+    }
+}
+
+// Luminance below which detail is considered crushed, and the band either
+// side of middle grey highlighted in the false colour view
+const CRUSHED: f32 = 0.02;
+const MIDDLE_GREY: (f32, f32) = (0.15, 0.22);
+
+// The histogram covers this range of exposure values (log2 luminance)
+const HISTOGRAM_BINS: usize = 64;
+const HISTOGRAM_MIN_EV: f32 = -8.0;
+const HISTOGRAM_MAX_EV: f32 = 2.0;
+const HISTOGRAM_HEIGHT: usize = 100;
+const HISTOGRAM_BAR_WIDTH: usize = 4;
+const HISTOGRAM_MARGIN: usize = 10;
+
+// Relative error shown at the hot end of the error heatmap
+const MAX_RELATIVE_ERROR: f32 = 0.1;
+
+// What auto exposure brings the average luminance to, and the resolution of
+// the histogram equalisation over the image's own range of exposures
+const AUTO_EXPOSURE_KEY: f32 = 0.18;
+const EQUALISE_BINS: usize = 256;
+
+pub fn luminance(colour: Vec3<f32>) -> f32 {
+    0.2126 * colour.x + 0.7152 * colour.y + 0.0722 * colour.z
+}
+
+pub fn apply(overlay: Overlay, framebuffer: &mut Framebuffer) {
+    match overlay {
+        Overlay::None => {}
+        Overlay::Histogram => draw_histogram(framebuffer),
+        Overlay::FalseColour => draw_false_colour(framebuffer),
+        Overlay::SampleCount => draw_sample_count(framebuffer),
+        Overlay::Error => draw_error(framebuffer),
+    }
+}
+
+// The colours to display in place of `colours`, or None to show them as
+// they are. Black pixels stay black, and the others keep their hue.
+pub fn preview(preview: Preview, colours: &[Vec3<f32>]) -> Option<Vec<Vec3<f32>>> {
+    let lit = || colours.iter().map(|&colour| luminance(colour)).filter(|&l| l > 0.0 && l.is_finite());
+
+    match preview {
+        Preview::Off => None,
+        Preview::AutoExposure => {
+            let (sum, count) = lit().fold((0.0, 0), |(sum, count), l| (sum + l.ln(), count + 1));
+            let average = if count == 0 { AUTO_EXPOSURE_KEY } else { (sum / count as f32).exp() };
+            let scale = AUTO_EXPOSURE_KEY / average;
+            Some(colours.iter().map(|&colour| colour * scale).collect())
+        }
+        Preview::Equalised => {
+            let (min, max) = lit().map(f32::log2).fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), ev| {
+                (min.min(ev), max.max(ev))
+            });
+            let range = (max - min).max(1.0e-6);
+            let bin = |l: f32| (l.log2() - min) / range * (EQUALISE_BINS - 1) as f32;
+
+            let mut cumulative = [0usize; EQUALISE_BINS];
+            let mut total = 0;
+            for l in lit() {
+                cumulative[bin(l).round() as usize] += 1;
+                total += 1;
+            }
+            for k in 1..EQUALISE_BINS {
+                cumulative[k] += cumulative[k - 1];
+            }
+
+            // The fraction of lit pixels at or below each luminance, taken
+            // between the bins either side so gradients stay smooth
+            let equalised = |l: f32| {
+                let position = bin(l).clamp(0.0, (EQUALISE_BINS - 1) as f32);
+                let (below, above) = (position.floor() as usize, position.ceil() as usize);
+                let t = position - below as f32;
+                (cumulative[below] as f32 * (1.0 - t) + cumulative[above] as f32 * t) / total.max(1) as f32
+            };
+
+            let equalised = colours.iter().map(|&colour| {
+                let l = luminance(colour);
+                if l > 0.0 && l.is_finite() { colour * (equalised(l) / l) } else { Vec3::zero() }
+            });
+            Some(equalised.collect())
+        }
+    }
+}
+
+// Any channel above one is out of display range (it only shows because
+// colours are scaled down by their largest channel)
+fn is_clipped(colour: Vec3<f32>) -> bool {
+    colour.x > 1.0 || colour.y > 1.0 || colour.z > 1.0
+}
+
+pub fn false_colour(colour: Vec3<f32>) -> [u8; 3] {
+    let l = luminance(colour);
+
+    if is_clipped(colour) {
+        [255, 0, 0]
+    } else if l < CRUSHED {
+        [0, 0, 255]
+    } else if (MIDDLE_GREY.0..=MIDDLE_GREY.1).contains(&l) {
+        [0, 200, 0]
+    } else {
+        let v = (255.0 * l.min(1.0)) as u8;
+        [v, v, v]
+    }
+}
+
+fn draw_false_colour(framebuffer: &mut Framebuffer) {
+    for j in 0..framebuffer.height {
+        for i in 0..framebuffer.width {
+            let colour = framebuffer.colours[j * framebuffer.width + i];
+            framebuffer.put_pixel(i, j, false_colour(colour));
+        }
+    }
+}
+
+// Blue through green and yellow to red as `t` goes from zero to one
+pub fn heat(t: f32) -> [u8; 3] {
+    let t = t.clamp(0.0, 1.0) * 3.0;
+    let ramp = |x: f32| (255.0 * x.clamp(0.0, 1.0)) as u8;
+
+    if t < 1.0 {
+        [0, ramp(t), ramp(1.0 - t)]
+    } else if t < 2.0 {
+        [ramp(t - 1.0), 255, 0]
+    } else {
+        [255, ramp(3.0 - t), 0]
+    }
+}
+
+// Sample counts on a log scale up to the most any pixel has
+fn draw_sample_count(framebuffer: &mut Framebuffer) {
+    let most = framebuffer.samples.iter().copied().max().unwrap_or(0).max(1);
+    let scale = 1.0 / (most as f32 + 1.0).log2();
+
+    for j in 0..framebuffer.height {
+        for i in 0..framebuffer.width {
+            let samples = framebuffer.samples[j * framebuffer.width + i];
+            framebuffer.put_pixel(i, j, heat((samples as f32 + 1.0).log2() * scale));
+        }
+    }
+}
+
+// Standard error of a pixel's mean luminance relative to the luminance itself,
+// estimated from the spread of its samples
+pub fn relative_error(mean: Vec3<f32>, square: Vec3<f32>, samples: usize) -> f32 {
+    if samples < 2 {
+        return f32::INFINITY;
+    }
+
+    let variance = Vec3::new(
+        (square.x - mean.x * mean.x).max(0.0),
+        (square.y - mean.y * mean.y).max(0.0),
+        (square.z - mean.z * mean.z).max(0.0),
+    );
+    let error = (luminance(variance) / (samples - 1) as f32).sqrt();
+
+    error / luminance(mean).max(CRUSHED)
+}
+
+fn draw_error(framebuffer: &mut Framebuffer) {
+    for j in 0..framebuffer.height {
+        for i in 0..framebuffer.width {
+            let index = j * framebuffer.width + i;
+            let error = relative_error(
+                framebuffer.colours[index],
+                framebuffer.squares[index],
+                framebuffer.samples[index],
+            );
+            framebuffer.put_pixel(i, j, heat(error / MAX_RELATIVE_ERROR));
+        }
+    }
+}
+
+// Counts of pixels per exposure bin, plus the number clipped and crushed
+pub struct Histogram {
+    pub bins: [usize; HISTOGRAM_BINS],
+    pub clipped: usize,
+    pub crushed: usize,
+}
+
+pub fn histogram(colours: &[Vec3<f32>]) -> Histogram {
+    let mut histogram = Histogram {
+        bins: [0; HISTOGRAM_BINS],
+        clipped: 0,
+        crushed: 0,
+    };
+
+    let range = HISTOGRAM_MAX_EV - HISTOGRAM_MIN_EV;
+
+    for &colour in colours {
+        let l = luminance(colour);
+
+        if is_clipped(colour) {
+            histogram.clipped += 1;
+        }
+        if l < CRUSHED {
+            histogram.crushed += 1;
+        }
+
+        let ev = l.max(f32::MIN_POSITIVE).log2();
+        let bin = ((ev - HISTOGRAM_MIN_EV) / range * HISTOGRAM_BINS as f32)
+            .clamp(0.0, (HISTOGRAM_BINS - 1) as f32);
+        histogram.bins[bin as usize] += 1;
+    }
+
+    histogram
+}
+
+fn draw_histogram(framebuffer: &mut Framebuffer) {
+    let histogram = histogram(&framebuffer.colours);
+
+    let width = HISTOGRAM_BINS * HISTOGRAM_BAR_WIDTH;
+    if framebuffer.width < width + 2 * HISTOGRAM_MARGIN
+        || framebuffer.height < HISTOGRAM_HEIGHT + 2 * HISTOGRAM_MARGIN
+    {
+        return;
+    }
+
+    let left = HISTOGRAM_MARGIN;
+    let bottom = framebuffer.height - HISTOGRAM_MARGIN;
+    let peak = histogram.bins.iter().copied().max().unwrap_or(0).max(1);
+
+    // Bars for the darkest/brightest bins are tinted when pixels are being
+    // crushed/clipped
+    for (bin, &count) in histogram.bins.iter().enumerate() {
+        let bar = count * HISTOGRAM_HEIGHT / peak;
+
+        let colour = if bin == 0 && histogram.crushed > 0 {
+            [80, 80, 255]
+        } else if bin == HISTOGRAM_BINS - 1 && histogram.clipped > 0 {
+            [255, 80, 80]
+        } else {
+            [230, 230, 230]
+        };
+
+        for x in 0..HISTOGRAM_BAR_WIDTH {
+            let i = left + bin * HISTOGRAM_BAR_WIDTH + x;
+
+            for y in 0..HISTOGRAM_HEIGHT {
+                let j = bottom - 1 - y;
+                let pixel = if y < bar { colour } else { [20, 20, 20] };
+                framebuffer.put_pixel(i, j, pixel);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn false_colour_bands() {
+        assert_eq!(false_colour(Vec3::new(2.0, 0.5, 0.5)), [255, 0, 0]);
+        assert_eq!(false_colour(Vec3::new(0.0, 0.01, 0.0)), [0, 0, 255]);
+        assert_eq!(false_colour(Vec3::new(0.18, 0.18, 0.18)), [0, 200, 0]);
+    }
+
+    #[test]
+    fn error_falls_with_samples() {
+        assert_eq!(heat(0.0), [0, 0, 255]);
+        assert_eq!(heat(1.0), [255, 0, 0]);
+
+        let mean = Vec3::new(0.5, 0.5, 0.5);
+        let square = Vec3::new(0.5, 0.5, 0.5);
+        assert_eq!(relative_error(mean, square, 1), f32::INFINITY);
+        assert!(relative_error(mean, square, 16) > relative_error(mean, square, 64));
+        assert_eq!(relative_error(mean, Vec3::new(0.25, 0.25, 0.25), 16), 0.0);
+    }
+
+    #[test]
+    fn previews_bring_dark_images_into_range() {
+        let dark: Vec<Vec3<f32>> = (1..=100).map(|k| Vec3::new(1.0, 1.0, 1.0) * (k as f32 * 1.0e-6)).collect();
+        assert_eq!(preview(Preview::Off, &dark), None);
+
+        let exposed = preview(Preview::AutoExposure, &dark).unwrap();
+        let average = (exposed.iter().map(|&c| luminance(c).ln()).sum::<f32>() / 100.0).exp();
+        assert!((average - AUTO_EXPOSURE_KEY).abs() < 1.0e-3);
+
+        // Evenly spread, in order, from nearly black to white
+        let equalised = preview(Preview::Equalised, &dark).unwrap();
+        assert!(equalised.windows(2).all(|pair| luminance(pair[0]) <= luminance(pair[1])));
+        assert!((luminance(equalised[99]) - 1.0).abs() < 1.0e-4);
+        assert!((luminance(equalised[49]) - 0.5).abs() < 0.15);
+
+        let black = preview(Preview::Equalised, &[Vec3::zero()]).unwrap();
+        assert_eq!(black, vec![Vec3::zero()]);
+    }
+
+    #[test]
+    fn histogram_counts_every_pixel() {
+        let colours = vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.5, 0.5, 0.5), Vec3::new(4.0, 4.0, 4.0)];
+        let histogram = histogram(&colours);
+        assert_eq!(histogram.bins.iter().sum::<usize>(), 3);
+        assert_eq!(histogram.clipped, 1);
+        assert_eq!(histogram.crushed, 1);
+        assert_eq!(histogram.bins[0], 1);
+        assert_eq!(histogram.bins[HISTOGRAM_BINS - 1], 1);
+    }
+}
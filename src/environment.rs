@@ -0,0 +1,328 @@
+use crate::Result;
+use crate::diagnostics::luminance;
+use crate::geometry::Vec3;
+use crate::image::{self, Image};
+use crate::panorama::{self, Projection};
+use crate::render::TraceSettings;
+use crate::scene;
+
+use serde::{Deserialize, Serialize};
+
+use std::cell::Cell;
+use std::convert::TryFrom;
+use std::f32::consts::PI;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::Arc;
+
+// Height of the latlong panorama rendered from a scene used as an
+// environment, and the samples per texel
+const SCENE_HEIGHT: usize = 512;
+const SCENE_SAMPLES: usize = 4;
+
+// Scenes used as environments can have scenes as environments in turn, but
+// only so deep, which also stops a scene from being its own environment
+const MAX_SCENE_NESTING: usize = 4;
+
+thread_local! {
+    static SCENE_NESTING: Cell<usize> = const { Cell::new(0) };
+}
+
+// An equirectangular map of the light arriving from every direction, sampled
+// by rays that escape the scene. Like meshes, the image is shared between
+// clones and scene files only store the path it's loaded from:
+//
+//     environment: Some("envmap.hdr")
+//
+// Radiance HDR and binary PPM images are supported; JPEGs such as the
+// reference project's envmap.jpg need converting first, e.g. with
+// `convert envmap.jpg envmap.ppm`. A RON or JSON scene file can be given
+// instead, which is rendered to a panorama from its origin on loading, so
+// one scene can be seen around another.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Environment {
+    pub path: String,
+    image: Arc<Image>,
+    distribution: Arc<Distribution>,
+}
+
+// How likely each texel is to be picked when sampling directions towards
+// the environment's light, in proportion to its luminance and the solid
+// angle it covers, which shrinks towards the poles. The luminance is
+// averaged over the texel as lookups see it, blended with its neighbours,
+// so that a bright texel's light spilling over into them is sampled too. Rows are picked first
+// from the marginal CDF, and then a texel from the row's own CDF. A total of
+// zero means the environment is black and has no light to sample.
+#[derive(Debug)]
+struct Distribution {
+    rows: Vec<f32>,
+    columns: Vec<Vec<f32>>,
+    total: f32,
+}
+
+impl Distribution {
+    fn new(image: &Image) -> Self {
+        let (width, height) = (image.width, image.height);
+        let mut rows = vec![0.0];
+        let mut columns = Vec::with_capacity(height);
+
+        let average = |x: usize, y: usize| {
+            let offsets = [(0.25, 0.25), (0.75, 0.25), (0.25, 0.75), (0.75, 0.75)];
+            let total: f32 = offsets.iter().map(|&(dx, dy)| luminance(bilinear(image, x as f32 + dx, y as f32 + dy))).sum();
+            total.max(0.0) / offsets.len() as f32
+        };
+
+        for y in 0..height {
+            let solid_angle = (PI * (y as f32 + 0.5) / height as f32).sin();
+            let mut cdf = vec![0.0];
+            for x in 0..width {
+                let weight = average(x, y) * solid_angle;
+                cdf.push(cdf[x] + weight);
+            }
+
+            rows.push(rows[y] + cdf[width]);
+            columns.push(cdf);
+        }
+
+        let total = rows[height];
+        Distribution { rows, columns, total }
+    }
+
+    // Picks an interval of the CDF with the random number and returns its
+    // index and where in it the number fell
+    fn pick(cdf: &[f32], u: f32) -> (usize, f32) {
+        let target = u * cdf[cdf.len() - 1];
+        let index = cdf.partition_point(|&c| c <= target).clamp(1, cdf.len() - 1) - 1;
+        let width = cdf[index + 1] - cdf[index];
+        let offset = if width > 0.0 { (target - cdf[index]) / width } else { 0.5 };
+        (index, offset.clamp(0.0, 1.0))
+    }
+}
+
+impl std::fmt::Debug for Environment {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Environment")
+            .field("path", &self.path)
+            .field("width", &self.image.width)
+            .field("height", &self.image.height)
+            .finish()
+    }
+}
+
+impl TryFrom<String> for Environment {
+    type Error = String;
+
+    fn try_from(path: String) -> std::result::Result<Self, String> {
+        Environment::load(&path).map_err(|e| format!("failed to load environment `{}`: {}", path, e))
+    }
+}
+
+impl From<Environment> for String {
+    fn from(environment: Environment) -> Self {
+        environment.path
+    }
+}
+
+impl Environment {
+    pub fn new(image: Image) -> Self {
+        Environment {
+            path: String::new(),
+            distribution: Arc::new(Distribution::new(&image)),
+            image: Arc::new(image),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let image = match Path::new(path).extension().and_then(OsStr::to_str) {
+            Some("ron") | Some("json") => render_scene(path)?,
+            _ => image::load(path)?,
+        };
+
+        if image.width == 0 || image.height == 0 {
+            return Err("environment map is empty".into());
+        }
+
+        let mut environment = Environment::new(image);
+        environment.path = path.to_string();
+        Ok(environment)
+    }
+
+    // Looks up the colour in the given (unit) direction. Longitude wraps
+    // around with -z at the centre of the image, and latitude runs from +y at
+    // the top to -y at the bottom. Neighbouring texels are blended bilinearly.
+    pub fn sample(&self, direction: Vec3<f32>) -> Vec3<f32> {
+        let (width, height) = (self.image.width, self.image.height);
+
+        let u = 0.5 + direction.x.atan2(-direction.z) / (2.0 * PI);
+        let v = direction.y.clamp(-1.0, 1.0).acos() / PI;
+
+        bilinear(&self.image, u * width as f32, v * height as f32)
+    }
+
+    // Picks a direction towards the environment's light from two random
+    // numbers, more likely the brighter the environment is that way, along
+    // with the probability density of having picked it per unit solid
+    // angle. None if the environment is black.
+    pub fn sample_direction(&self, u1: f32, u2: f32) -> Option<(Vec3<f32>, f32)> {
+        let distribution = &self.distribution;
+        if distribution.total <= 0.0 {
+            return None;
+        }
+
+        let (y, fy) = Distribution::pick(&distribution.rows, u1);
+        let (x, fx) = Distribution::pick(&distribution.columns[y], u2);
+
+        let u = (x as f32 + fx) / self.image.width as f32;
+        let v = (y as f32 + fy) / self.image.height as f32;
+        let (azimuth, polar) = ((u - 0.5) * 2.0 * PI, v * PI);
+        let direction = Vec3::new(polar.sin() * azimuth.sin(), polar.cos(), -polar.sin() * azimuth.cos());
+
+        Some((direction, self.pdf(direction)))
+    }
+
+    // The probability density per unit solid angle of `sample_direction`
+    // picking the given (unit) direction
+    pub fn pdf(&self, direction: Vec3<f32>) -> f32 {
+        let (width, height) = (self.image.width, self.image.height);
+        let distribution = &self.distribution;
+        if distribution.total <= 0.0 {
+            return 0.0;
+        }
+
+        let u = 0.5 + direction.x.atan2(-direction.z) / (2.0 * PI);
+        let v = direction.y.clamp(-1.0, 1.0).acos() / PI;
+        let x = ((u * width as f32) as usize).min(width - 1);
+        let y = ((v * height as f32) as usize).min(height - 1);
+
+        // Texels are picked in proportion to their weight and then uniformly
+        // over their area in the image, which maps onto the sphere with a
+        // stretch of 2 pi squared times the sine of the polar angle
+        let columns = &distribution.columns[y];
+        let weight = columns[x + 1] - columns[x];
+        let sine = (v * PI).sin();
+        if sine <= 0.0 {
+            return 0.0;
+        }
+        weight / distribution.total * (width * height) as f32 / (2.0 * PI * PI * sine)
+    }
+}
+
+// The image at a point given in texels from its top left corner, blending
+// the four nearest texels. Columns wrap around, and rows stop at the edges.
+fn bilinear(image: &Image, x: f32, y: f32) -> Vec3<f32> {
+    let (width, height) = (image.width, image.height);
+    let texel = |x: usize, y: usize| image.texels[y * width + x];
+
+    let x = x - 0.5;
+    let y = (y - 0.5).clamp(0.0, (height - 1) as f32);
+
+    let (fx, fy) = (x - x.floor(), y - y.floor());
+
+    let x0 = (x.floor() as isize).rem_euclid(width as isize) as usize;
+    let x1 = (x0 + 1) % width;
+    let y0 = y.floor() as usize;
+    let y1 = (y0 + 1).min(height - 1);
+
+    let top = texel(x0, y0) * (1.0 - fx) + texel(x1, y0) * fx;
+    let bottom = texel(x0, y1) * (1.0 - fx) + texel(x1, y1) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+// Everything seen from the origin of the scene in the file, as a latlong
+// image
+fn render_scene(path: &str) -> Result<Image> {
+    let nesting = SCENE_NESTING.with(Cell::get);
+    if nesting >= MAX_SCENE_NESTING {
+        return Err(format!("scenes used as environments are nested more than {} deep", MAX_SCENE_NESTING).into());
+    }
+
+    SCENE_NESTING.with(|n| n.set(nesting + 1));
+    let scene = scene::load(path);
+    SCENE_NESTING.with(|n| n.set(nesting));
+
+    let mut scene = scene?;
+    scene.sanitise();
+
+    let size = Projection::LatLong.size(SCENE_HEIGHT);
+    let rendered = panorama::render(&scene, Vec3::zero(), Projection::LatLong, size, SCENE_SAMPLES, TraceSettings::default());
+
+    Ok(Image {
+        width: rendered.width,
+        height: rendered.height,
+        texels: rendered.colours,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_by_direction() {
+        // Four columns of distinct colours, two rows (sky and ground)
+        let colour = |x: usize, y: usize| Vec3::new(x as f32, y as f32, 0.0);
+        let image = Image {
+            width: 4,
+            height: 2,
+            texels: (0..2).flat_map(|y| (0..4).map(move |x| colour(x, y))).collect(),
+        };
+        let environment = Environment::new(image);
+
+        // Straight ahead is the middle of the image, between columns 1 and 2
+        let ahead = environment.sample(Vec3::new(0.0, 0.0, -1.0));
+        assert!((ahead.x - 1.5).abs() < 1e-5);
+        assert!((ahead.y - 0.5).abs() < 1e-5);
+
+        let up = environment.sample(Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(up.y, 0.0);
+
+        // Behind wraps around between the last and first columns
+        let behind = environment.sample(Vec3::new(0.0, 0.0, 1.0));
+        assert!((behind.x - 1.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn sampling_favours_bright_texels_and_matches_its_pdf() {
+        // A dim sky with one bright texel
+        let (width, height) = (16, 8);
+        let mut texels = vec![Vec3::new(0.1, 0.1, 0.1); width * height];
+        texels[2 * width + 5] = Vec3::new(100.0, 100.0, 100.0);
+        let environment = Environment::new(Image { width, height, texels });
+
+        let count = 4000;
+        let mut bright = 0;
+        for k in 0..count {
+            let (u1, u2) = ((k as f32 + 0.5) / count as f32, ((k * 7919) % count) as f32 / count as f32);
+            let (direction, pdf) = environment.sample_direction(u1, u2).unwrap();
+            assert!((direction.length() - 1.0).abs() < 1.0e-4);
+            assert!((pdf - environment.pdf(direction)).abs() <= 1.0e-3 * pdf);
+            if luminance(environment.sample(direction)) > 10.0 {
+                bright += 1;
+            }
+        }
+        assert!(bright > count / 2);
+
+        // The density integrates to one over the sphere
+        let n = 200;
+        let mut total = 0.0;
+        for j in 0..n {
+            for i in 0..2 * n {
+                let polar = PI * (j as f32 + 0.5) / n as f32;
+                let azimuth = PI * (i as f32 + 0.5) / n as f32;
+                let direction = Vec3::new(polar.sin() * azimuth.sin(), polar.cos(), polar.sin() * azimuth.cos());
+                total += environment.pdf(direction) * polar.sin() * (PI / n as f32) * (PI / n as f32);
+            }
+        }
+        assert!((total - 1.0).abs() < 0.02, "pdf integrates to {}", total);
+    }
+
+    #[test]
+    fn scene_environments_nest_only_so_deep() {
+        SCENE_NESTING.with(|n| n.set(MAX_SCENE_NESTING));
+        let error = Environment::load("nested.ron").unwrap_err();
+        SCENE_NESTING.with(|n| n.set(0));
+
+        assert!(error.to_string().contains("nested"));
+    }
+}
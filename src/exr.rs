@@ -0,0 +1,283 @@
+use crate::Result;
+use crate::framebuffer::Framebuffer;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+const VERSION: u32 = 2;
+const SINGLE_PART_TILED: u32 = 0x200;
+const LONG_NAMES: u32 = 0x400;
+const MULTI_PART: u32 = 0x1000;
+
+// Square tiles this size are what compositors read when only part of the
+// image is on screen
+pub const TILE_SIZE: usize = 64;
+
+// The part holding the main image, whose channels are plain R, G and B
+pub const BEAUTY: &str = "rgba";
+
+// Pixel type of every channel: 32-bit float
+const FLOAT: i32 = 2;
+
+// One image in a file, stored as its own part. Parts other than the beauty
+// have their channels named after them, such as `diffuse.R`, which
+// compositors show as a layer.
+pub struct Part<'a> {
+    pub name: &'a str,
+    pub framebuffer: &'a Framebuffer,
+}
+
+impl Part<'_> {
+    // Sorted by name, the order channels are stored in, with the component
+    // of the colour each holds
+    fn channels(&self) -> Vec<(String, usize)> {
+        ["B", "G", "R"]
+            .iter()
+            .zip(&[2, 1, 0])
+            .map(|(channel, &component)| match self.name {
+                BEAUTY => (channel.to_string(), component),
+                layer => (format!("{}.{}", layer, channel), component),
+            })
+            .collect()
+    }
+
+    fn tiles(&self) -> (usize, usize) {
+        let (width, height) = (self.framebuffer.width, self.framebuffer.height);
+        (width.div_ceil(TILE_SIZE), height.div_ceil(TILE_SIZE))
+    }
+
+    fn header(&self, multi_part: bool) -> Vec<u8> {
+        let (width, height) = (self.framebuffer.width as i32, self.framebuffer.height as i32);
+        let mut header = Vec::new();
+
+        let mut channels = Vec::new();
+        for (name, _) in self.channels() {
+            channels.extend_from_slice(name.as_bytes());
+            channels.push(0);
+            channels.extend_from_slice(&FLOAT.to_le_bytes());
+            // Not perceptually linear, three reserved bytes, then no
+            // subsampling in x or y
+            channels.extend_from_slice(&[0, 0, 0, 0]);
+            channels.extend_from_slice(&1i32.to_le_bytes());
+            channels.extend_from_slice(&1i32.to_le_bytes());
+        }
+        channels.push(0);
+
+        let window: Vec<u8> = [0, 0, width - 1, height - 1].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let mut tiles = Vec::new();
+        tiles.extend_from_slice(&(TILE_SIZE as u32).to_le_bytes());
+        tiles.extend_from_slice(&(TILE_SIZE as u32).to_le_bytes());
+        // One level, rounding down
+        tiles.push(0);
+
+        attribute(&mut header, "channels", "chlist", &channels);
+        // No compression
+        attribute(&mut header, "compression", "compression", &[0]);
+        attribute(&mut header, "dataWindow", "box2i", &window);
+        attribute(&mut header, "displayWindow", "box2i", &window);
+        // Increasing y
+        attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+        attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+        attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+        attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+        attribute(&mut header, "tiles", "tiledesc", &tiles);
+        if multi_part {
+            let (across, down) = self.tiles();
+            attribute(&mut header, "name", "string", self.name.as_bytes());
+            attribute(&mut header, "type", "string", b"tiledimage");
+            attribute(&mut header, "chunkCount", "int", &((across * down) as i32).to_le_bytes());
+        }
+        header.push(0);
+
+        header
+    }
+
+    // Every tile, row by row, each with its position and pixels: the values
+    // of each channel in turn for each of the tile's lines
+    fn chunks(&self, part: usize, multi_part: bool) -> Vec<Vec<u8>> {
+        let framebuffer = self.framebuffer;
+        let channels = self.channels();
+        let (across, down) = self.tiles();
+        let mut chunks = Vec::with_capacity(across * down);
+
+        for ty in 0..down {
+            for tx in 0..across {
+                let (x0, y0) = (tx * TILE_SIZE, ty * TILE_SIZE);
+                let (x1, y1) = ((x0 + TILE_SIZE).min(framebuffer.width), (y0 + TILE_SIZE).min(framebuffer.height));
+
+                let mut pixels = Vec::with_capacity((x1 - x0) * (y1 - y0) * channels.len() * 4);
+                for y in y0..y1 {
+                    for &(_, component) in &channels {
+                        for colour in &framebuffer.colours[y * framebuffer.width + x0..y * framebuffer.width + x1] {
+                            let value = [colour.x, colour.y, colour.z][component];
+                            pixels.extend_from_slice(&value.to_le_bytes());
+                        }
+                    }
+                }
+
+                let mut chunk = Vec::with_capacity(pixels.len() + 24);
+                if multi_part {
+                    chunk.extend_from_slice(&(part as i32).to_le_bytes());
+                }
+                for value in [tx as i32, ty as i32, 0, 0, pixels.len() as i32] {
+                    chunk.extend_from_slice(&value.to_le_bytes());
+                }
+                chunk.extend_from_slice(&pixels);
+                chunks.push(chunk);
+            }
+        }
+
+        chunks
+    }
+}
+
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+// Writes the parts as an uncompressed, tiled OpenEXR file of 32-bit float
+// channels, as a single-part file if there's only one and a multi-part file
+// otherwise
+pub fn write<W: Write>(writer: &mut W, parts: &[Part]) -> Result<()> {
+    if parts.is_empty() {
+        return Err("an EXR file needs at least one part".into());
+    }
+    if parts.iter().any(|part| part.framebuffer.width == 0 || part.framebuffer.height == 0) {
+        return Err("can't write an empty image as EXR".into());
+    }
+
+    let multi_part = parts.len() > 1;
+    let mut flags = if multi_part { MULTI_PART } else { SINGLE_PART_TILED };
+    if parts.iter().flat_map(Part::channels).any(|(name, _)| name.len() > 31) {
+        flags |= LONG_NAMES;
+    }
+    let headers: Vec<Vec<u8>> = parts.iter().map(|part| part.header(multi_part)).collect();
+    let chunks: Vec<Vec<Vec<u8>>> =
+        parts.iter().enumerate().map(|(index, part)| part.chunks(index, multi_part)).collect();
+
+    // Multi-part headers end with an empty one, and the offset tables of
+    // every part come before any chunks
+    let chunk_count: usize = chunks.iter().map(Vec::len).sum();
+    let mut offset = 8 + headers.iter().map(Vec::len).sum::<usize>() + multi_part as usize + chunk_count * 8;
+
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&(VERSION | flags).to_le_bytes())?;
+    for header in &headers {
+        writer.write_all(header)?;
+    }
+    if multi_part {
+        writer.write_all(&[0])?;
+    }
+    for chunk in chunks.iter().flatten() {
+        writer.write_all(&(offset as u64).to_le_bytes())?;
+        offset += chunk.len();
+    }
+    for chunk in chunks.iter().flatten() {
+        writer.write_all(chunk)?;
+    }
+
+    Ok(())
+}
+
+pub fn save<P: AsRef<Path>>(parts: &[Part], path: P) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write(&mut writer, parts)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Vec3;
+
+    use std::convert::TryInto;
+
+    fn string(data: &[u8], offset: &mut usize) -> String {
+        let end = *offset + data[*offset..].iter().position(|&b| b == 0).unwrap();
+        let s = String::from_utf8(data[*offset..end].to_vec()).unwrap();
+        *offset = end + 1;
+        s
+    }
+
+    // Skips a header's attributes, returning the names of the channels
+    fn read_header(data: &[u8], offset: &mut usize) -> Vec<String> {
+        let mut channels = Vec::new();
+        loop {
+            let name = string(data, offset);
+            if name.is_empty() {
+                return channels;
+            }
+            string(data, offset);
+            let size = i32::from_le_bytes(data[*offset..*offset + 4].try_into().unwrap()) as usize;
+            let value = &data[*offset + 4..*offset + 4 + size];
+            if name == "channels" {
+                // Each name is followed by 16 bytes describing the channel
+                let mut at = 0;
+                while value[at] != 0 {
+                    channels.push(string(value, &mut at));
+                    at += 16;
+                }
+            }
+            *offset += 4 + size;
+        }
+    }
+
+    #[test]
+    fn multi_part_tiles_point_at_their_pixels() {
+        let mut beauty = Framebuffer::new(70, 3);
+        beauty.set(65, 2, Vec3::new(0.25, 0.5, 4.0));
+        let diffuse = Framebuffer::new(70, 3);
+        let parts = [Part { name: BEAUTY, framebuffer: &beauty }, Part { name: "diffuse", framebuffer: &diffuse }];
+
+        let mut data = Vec::new();
+        write(&mut data, &parts).unwrap();
+        assert_eq!(data[0..4], MAGIC);
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 2 | MULTI_PART);
+
+        let mut offset = 8;
+        assert_eq!(read_header(&data, &mut offset), ["B", "G", "R"]);
+        assert_eq!(read_header(&data, &mut offset), ["diffuse.B", "diffuse.G", "diffuse.R"]);
+        assert_eq!(data[offset], 0);
+        offset += 1;
+
+        // Two tiles across in each part
+        let offsets: Vec<usize> = (0..4)
+            .map(|k| u64::from_le_bytes(data[offset + k * 8..offset + k * 8 + 8].try_into().unwrap()) as usize)
+            .collect();
+        let int = |at: usize| i32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        assert_eq!(offsets[0], offset + 32);
+        assert_eq!((int(offsets[1]), int(offsets[1] + 4), int(offsets[1] + 8)), (0, 1, 0));
+        assert_eq!((int(offsets[3]), int(offsets[3] + 4)), (1, 1));
+
+        // The second tile is 6 wide and 3 tall, with B, G and R for each line
+        let size = int(offsets[1] + 20) as usize;
+        assert_eq!(size, 6 * 3 * 3 * 4);
+        let pixels = offsets[1] + 24;
+        let float = |at: usize| f32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let line = pixels + 2 * 3 * 6 * 4;
+        assert_eq!((float(line + 4), float(line + 24 + 4), float(line + 48 + 4)), (4.0, 0.5, 0.25));
+        assert_eq!(offsets[3] + 24 + size, data.len());
+    }
+
+    #[test]
+    fn single_part_is_tiled() {
+        let framebuffer = Framebuffer::new(1, 1);
+        let mut data = Vec::new();
+        write(&mut data, &[Part { name: BEAUTY, framebuffer: &framebuffer }]).unwrap();
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 2 | SINGLE_PART_TILED);
+
+        let mut offset = 8;
+        read_header(&data, &mut offset);
+        let chunk = u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap()) as usize;
+        assert_eq!(chunk, offset + 8);
+        assert_eq!(data.len(), chunk + 20 + 12);
+    }
+}
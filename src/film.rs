@@ -0,0 +1,239 @@
+use crate::Result;
+use crate::camera::Camera;
+use crate::framebuffer::{Framebuffer, Tile};
+use crate::geometry::Vec3;
+use crate::render::{RenderMode, TraceSettings};
+
+use std::convert::TryInto;
+use std::fs;
+use std::path::Path;
+
+const MAGIC: &[u8] = b"TRFILM2\n";
+
+// Films saved before environment samples were added, which are read as
+// having none
+const MAGIC_V1: &[u8] = b"TRFILM1\n";
+
+// Everything needed to pick a progressive render up where it left off,
+// besides the scene and the accumulated image itself
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Film {
+    pub camera: Camera,
+    pub mode: RenderMode,
+    pub settings: TraceSettings,
+    pub accumulated: usize,
+    pub region: Option<Tile>,
+    pub region_accumulated: usize,
+}
+
+fn mode_to_byte(mode: RenderMode) -> u8 {
+    match mode {
+        RenderMode::Shaded => 0,
+        RenderMode::Normals => 1,
+        RenderMode::Depth => 2,
+        RenderMode::Clay => 3,
+    }
+}
+
+fn mode_from_byte(byte: u8) -> Result<RenderMode> {
+    match byte {
+        0 => Ok(RenderMode::Shaded),
+        1 => Ok(RenderMode::Normals),
+        2 => Ok(RenderMode::Depth),
+        3 => Ok(RenderMode::Clay),
+        _ => Err(format!("unknown render mode {} in film", byte).into()),
+    }
+}
+
+fn put_f32(data: &mut Vec<u8>, value: f32) {
+    data.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(data: &mut Vec<u8>, value: usize) {
+    data.extend_from_slice(&(value as u64).to_le_bytes());
+}
+
+fn put_vec3(data: &mut Vec<u8>, v: Vec3<f32>) {
+    put_f32(data, v.x);
+    put_f32(data, v.y);
+    put_f32(data, v.z);
+}
+
+// Reads little-endian values from the front of the data, failing if it runs
+// out
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let bytes = self.data.get(self.offset..self.offset + N).ok_or("truncated film")?;
+        self.offset += N;
+        Ok(bytes.try_into()?)
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.bytes()?))
+    }
+
+    fn u64(&mut self) -> Result<usize> {
+        Ok(u64::from_le_bytes(self.bytes()?) as usize)
+    }
+
+    fn vec3(&mut self) -> Result<Vec3<f32>> {
+        Ok(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+}
+
+// Writes the film and the linear colours, squared samples and sample counts
+// of the framebuffer in a compact binary form
+pub fn save<P: AsRef<Path>>(film: &Film, framebuffer: &Framebuffer, path: P) -> Result<()> {
+    let mut data = MAGIC.to_vec();
+
+    let Camera { position, yaw, pitch, fov } = film.camera;
+    put_vec3(&mut data, position);
+    put_f32(&mut data, yaw);
+    put_f32(&mut data, pitch);
+    put_f32(&mut data, fov);
+
+    data.push(mode_to_byte(film.mode));
+
+    let settings = film.settings;
+    put_u64(&mut data, settings.max_depth as usize);
+    put_u64(&mut data, settings.rr_start_depth as usize);
+    put_f32(&mut data, settings.rr_min_probability);
+    put_u64(&mut data, settings.light_samples);
+    put_u64(&mut data, settings.environment_samples);
+
+    put_u64(&mut data, film.accumulated);
+    put_u64(&mut data, film.region_accumulated);
+
+    match film.region {
+        Some(Tile { x, y, width, height }) => {
+            data.push(1);
+            for value in [x, y, width, height] {
+                put_u64(&mut data, value);
+            }
+        }
+        None => data.push(0),
+    }
+
+    put_u64(&mut data, framebuffer.width);
+    put_u64(&mut data, framebuffer.height);
+
+    for index in 0..framebuffer.width * framebuffer.height {
+        put_vec3(&mut data, framebuffer.colours[index]);
+        put_vec3(&mut data, framebuffer.squares[index]);
+        put_u64(&mut data, framebuffer.samples[index]);
+    }
+
+    fs::write(path, data)?;
+    Ok(())
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<(Film, Framebuffer)> {
+    let data = fs::read(path)?;
+
+    let version = match &data[..MAGIC.len().min(data.len())] {
+        magic if magic == MAGIC => 2,
+        magic if magic == MAGIC_V1 => 1,
+        _ => return Err("not a tinyraytracer film".into()),
+    };
+
+    let mut reader = Reader { data: &data, offset: MAGIC.len() };
+
+    let camera = Camera {
+        position: reader.vec3()?,
+        yaw: reader.f32()?,
+        pitch: reader.f32()?,
+        fov: reader.f32()?,
+    };
+
+    let mode = mode_from_byte(reader.bytes::<1>()?[0])?;
+
+    let settings = TraceSettings {
+        max_depth: reader.u64()? as u32,
+        rr_start_depth: reader.u64()? as u32,
+        rr_min_probability: reader.f32()?,
+        light_samples: reader.u64()?,
+        environment_samples: if version >= 2 { reader.u64()? } else { 0 },
+    };
+
+    let accumulated = reader.u64()?;
+    let region_accumulated = reader.u64()?;
+
+    let region = match reader.bytes::<1>()?[0] {
+        0 => None,
+        _ => Some(Tile {
+            x: reader.u64()?,
+            y: reader.u64()?,
+            width: reader.u64()?,
+            height: reader.u64()?,
+        }),
+    };
+
+    let (width, height) = (reader.u64()?, reader.u64()?);
+
+    // Check the size against what's left before allocating for it
+    let pixel_size = 2 * 3 * 4 + 8;
+    if width.checked_mul(height).and_then(|n| n.checked_mul(pixel_size)) != Some(data.len() - reader.offset) {
+        return Err("film size doesn't match its pixel data".into());
+    }
+
+    let mut framebuffer = Framebuffer::new(width, height);
+
+    for j in 0..height {
+        for i in 0..width {
+            let index = j * width + i;
+            let colour = reader.vec3()?;
+            framebuffer.set(i, j, colour);
+            framebuffer.squares[index] = reader.vec3()?;
+            framebuffer.samples[index] = reader.u64()?;
+        }
+    }
+
+    let film = Film {
+        camera,
+        mode,
+        settings,
+        accumulated,
+        region,
+        region_accumulated,
+    };
+
+    Ok((film, framebuffer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut framebuffer = Framebuffer::new(3, 2);
+        for k in 0..3 {
+            framebuffer.accumulate(framebuffer.bounds(), k, |i, j| Vec3::new(i as f32, j as f32, k as f32));
+        }
+
+        let film = Film {
+            camera: Camera { yaw: 0.5, ..Camera::default() },
+            mode: RenderMode::Clay,
+            settings: TraceSettings { light_samples: 3, ..TraceSettings::default() },
+            accumulated: 3,
+            region: Some(Tile { x: 1, y: 0, width: 2, height: 1 }),
+            region_accumulated: 7,
+        };
+
+        let path = std::env::temp_dir().join("tinyraytracer-film-round-trip.film");
+        save(&film, &framebuffer, &path).unwrap();
+        let (loaded, loaded_framebuffer) = load(&path).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(loaded, film);
+        assert_eq!(loaded_framebuffer.colours, framebuffer.colours);
+        assert_eq!(loaded_framebuffer.squares, framebuffer.squares);
+        assert_eq!(loaded_framebuffer.samples, vec![3; 6]);
+        assert_eq!(loaded_framebuffer.pixels, framebuffer.pixels);
+    }
+}
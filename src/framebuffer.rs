@@ -0,0 +1,462 @@
+use crate::geometry::Vec3;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+pub const TILE_SIZE: usize = 32;
+
+// The linear colour of every pixel, alongside tightly packed RGB24 pixels
+// matching the layout SDL expects for an RGB24 streaming texture. Pixels built
+// up with `accumulate` also keep their sample count and the mean of their
+// squared samples, from which the noise left in them can be estimated.
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    pub colours: Vec<Vec3<f32>>,
+    pub pixels: Vec<u8>,
+    pub samples: Vec<usize>,
+    pub squares: Vec<Vec3<f32>>,
+    // How many threads to trace with, one per core if not set. Each pixel's
+    // colour depends only on where it is, so this changes nothing but speed.
+    pub threads: Option<usize>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Tile {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+fn clamp(x: f32, min: f32, max: f32) -> f32 {
+    if x < min {
+        min
+    } else if x > max {
+        max
+    } else {
+        x
+    }
+}
+
+fn clamp_to_u8(x: f32, min: f32, max: f32) -> u8 {
+    (255.0 * clamp(x, min, max)) as u8
+}
+
+pub fn to_pixel(mut v: Vec3<f32>) -> [u8; 3] {
+    let max = v.x.max(v.y.max(v.z));
+    if max > 1.0 {
+        v = v * (1.0/max);
+    }
+
+    [
+        clamp_to_u8(v.x, 0.0, 1.0),
+        clamp_to_u8(v.y, 0.0, 1.0),
+        clamp_to_u8(v.z, 0.0, 1.0),
+    ]
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Framebuffer {
+            width,
+            height,
+            colours: vec![Vec3::zero(); width * height],
+            pixels: vec![0; width * height * 3],
+            samples: vec![0; width * height],
+            squares: vec![Vec3::zero(); width * height],
+            threads: None,
+        }
+    }
+
+    // Threads to share out `jobs` tiles between
+    pub fn worker_count(&self, jobs: usize) -> usize {
+        let threads = self.threads.unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+        threads.max(1).min(jobs.max(1))
+    }
+
+    pub fn pitch(&self) -> usize {
+        self.width * 3
+    }
+
+    // The whole image as a single rectangle
+    pub fn bounds(&self) -> Tile {
+        Tile { x: 0, y: 0, width: self.width, height: self.height }
+    }
+
+    // Splits a rectangle of the image into tiles
+    pub fn tiles_in(&self, region: Tile) -> Vec<Tile> {
+        let mut tiles = Vec::new();
+
+        for y in (region.y..region.y + region.height).step_by(TILE_SIZE) {
+            for x in (region.x..region.x + region.width).step_by(TILE_SIZE) {
+                tiles.push(Tile {
+                    x,
+                    y,
+                    width: TILE_SIZE.min(region.x + region.width - x),
+                    height: TILE_SIZE.min(region.y + region.height - y),
+                });
+            }
+        }
+
+        tiles
+    }
+
+    pub fn set(&mut self, i: usize, j: usize, colour: Vec3<f32>) {
+        let index = j * self.width + i;
+        self.colours[index] = colour;
+        self.pixels[index * 3..index * 3 + 3].copy_from_slice(&to_pixel(colour));
+    }
+
+    // Overwrites only the displayed pixel, leaving the linear colour intact, for
+    // overlays drawn on top of the image
+    pub fn put_pixel(&mut self, i: usize, j: usize, pixel: [u8; 3]) {
+        let index = (j * self.width + i) * 3;
+        self.pixels[index..index + 3].copy_from_slice(&pixel);
+    }
+
+    fn write_tile(&mut self, tile: Tile, colours: &[Vec3<f32>]) {
+        for (row, src) in colours.chunks(tile.width).enumerate() {
+            for (column, &colour) in src.iter().enumerate() {
+                self.set(tile.x + column, tile.y + row, colour);
+            }
+        }
+    }
+
+    // Traces every pixel by calling `shade(i, j)`, handing out tiles to a pool
+    // of scoped worker threads. Each worker renders its tiles into a local
+    // buffer, and the buffers are returned once all threads are done.
+    fn trace<F>(&self, region: Tile, shade: F) -> Vec<(Tile, Vec<Vec3<f32>>)>
+    where
+        F: Fn(usize, usize) -> Vec3<f32> + Sync,
+    {
+        let tiles = self.tiles_in(region);
+        let next_tile = AtomicUsize::new(0);
+
+        let threads = self.worker_count(tiles.len());
+
+        let rendered: Vec<Vec<(Tile, Vec<Vec3<f32>>)>> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut done = Vec::new();
+
+                        loop {
+                            let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                            let tile = match tiles.get(index) {
+                                Some(&tile) => tile,
+                                None => break,
+                            };
+
+                            let mut colours = Vec::with_capacity(tile.width * tile.height);
+
+                            for j in tile.y..tile.y + tile.height {
+                                for i in tile.x..tile.x + tile.width {
+                                    colours.push(shade(i, j));
+                                }
+                            }
+
+                            done.push((tile, colours));
+                        }
+
+                        done
+                    })
+                })
+                .collect();
+
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("render worker panicked"))
+                .collect()
+        });
+
+        rendered.into_iter().flatten().collect()
+    }
+
+    pub fn render<F>(&mut self, shade: F)
+    where
+        F: Fn(usize, usize) -> Vec3<f32> + Sync,
+    {
+        for (tile, colours) in self.trace(self.bounds(), shade) {
+            self.write_tile(tile, &colours);
+        }
+    }
+
+    // One step of a coarse-to-fine preview of a first sample of every pixel.
+    // Pixels on a grid `stride` apart are shaded and each fills the block
+    // below and to the right of it, except those on the coarser grid `done`
+    // apart (if any) rendered by the step before, which are kept. Halving the
+    // stride each step down to one leaves the same image as `accumulate` with
+    // no samples accumulated, for about the same work in total.
+    pub fn refine<F>(&mut self, stride: usize, done: usize, shade: F)
+    where
+        F: Fn(usize, usize) -> Vec3<f32> + Sync,
+    {
+        let stride = stride.max(1);
+        let grid = Tile {
+            x: 0,
+            y: 0,
+            width: self.width.div_ceil(stride),
+            height: self.height.div_ceil(stride),
+        };
+
+        let on_done_grid = |i: usize, j: usize| done > 0 && i.is_multiple_of(done) && j.is_multiple_of(done);
+        let rendered = self.trace(grid, |gi, gj| {
+            let (i, j) = (gi * stride, gj * stride);
+            if on_done_grid(i, j) {
+                self.colours[j * self.width + i]
+            } else {
+                shade(i, j)
+            }
+        });
+
+        for (tile, colours) in rendered {
+            for (row, src) in colours.chunks(tile.width).enumerate() {
+                for (column, &colour) in src.iter().enumerate() {
+                    let (i, j) = ((tile.x + column) * stride, (tile.y + row) * stride);
+
+                    for y in j..(j + stride).min(self.height) {
+                        for x in i..(i + stride).min(self.width) {
+                            self.set(x, y, colour);
+                        }
+                    }
+
+                    let index = j * self.width + i;
+                    self.squares[index] = Vec3::new(colour.x * colour.x, colour.y * colour.y, colour.z * colour.z);
+                    self.samples[index] = 1;
+                }
+            }
+        }
+    }
+
+    // Averages a new sample of every pixel in the region into the image,
+    // given the number of samples already accumulated there. With none the
+    // region is overwritten.
+    pub fn accumulate<F>(&mut self, region: Tile, accumulated: usize, shade: F)
+    where
+        F: Fn(usize, usize) -> Vec3<f32> + Sync,
+    {
+        let weight = 1.0 / (accumulated + 1) as f32;
+
+        for (tile, colours) in self.trace(region, shade) {
+            for (row, src) in colours.chunks(tile.width).enumerate() {
+                for (column, &colour) in src.iter().enumerate() {
+                    let (i, j) = (tile.x + column, tile.y + row);
+                    let index = j * self.width + i;
+                    let previous = self.colours[index];
+                    self.set(i, j, previous + (colour - previous) * weight);
+
+                    let square = Vec3::new(colour.x * colour.x, colour.y * colour.y, colour.z * colour.z);
+                    self.squares[index] = self.squares[index] + (square - self.squares[index]) * weight;
+                    self.samples[index] = accumulated + 1;
+                }
+            }
+        }
+    }
+
+    // Draws the one pixel wide border of a rectangle over the displayed
+    // pixels, leaving out any part of it outside the image
+    pub fn outline(&mut self, rect: Tile, pixel: [u8; 3]) {
+        if rect.width == 0 || rect.height == 0 || rect.x >= self.width || rect.y >= self.height {
+            return;
+        }
+
+        let (right, bottom) = (rect.x + rect.width - 1, rect.y + rect.height - 1);
+        let (last_column, last_row) = (right.min(self.width - 1), bottom.min(self.height - 1));
+
+        for i in rect.x..=last_column {
+            self.put_pixel(i, rect.y, pixel);
+            if bottom == last_row {
+                self.put_pixel(i, bottom, pixel);
+            }
+        }
+        for j in rect.y..=last_row {
+            self.put_pixel(rect.x, j, pixel);
+            if right == last_column {
+                self.put_pixel(right, j, pixel);
+            }
+        }
+    }
+
+    // Draws a one pixel wide line between two image positions over the
+    // displayed pixels, clipped to the image
+    pub fn line(&mut self, a: (f32, f32), b: (f32, f32), pixel: [u8; 3]) {
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+        let (right, bottom) = (self.width as f32, self.height as f32);
+
+        // Liang-Barsky: narrow the range of t in a + t * (b - a) to the part
+        // inside each edge in turn
+        let mut range = (0.0f32, 1.0f32);
+        for &(p, q) in &[(-dx, a.0), (dx, right - a.0), (-dy, a.1), (dy, bottom - a.1)] {
+            if p == 0.0 {
+                if q < 0.0 {
+                    return;
+                }
+            } else if p < 0.0 {
+                range.0 = range.0.max(q / p);
+            } else {
+                range.1 = range.1.min(q / p);
+            }
+        }
+        if range.0 > range.1 {
+            return;
+        }
+
+        let start = (a.0 + range.0 * dx, a.1 + range.0 * dy);
+        let steps = ((range.1 - range.0) * dx.abs().max(dy.abs())).ceil() as usize;
+
+        for k in 0..=steps {
+            let t = if steps == 0 { 0.0 } else { k as f32 / steps as f32 };
+            let i = (start.0 + t * (range.1 - range.0) * dx) as usize;
+            let j = (start.1 + t * (range.1 - range.0) * dy) as usize;
+
+            if i < self.width && j < self.height {
+                self.put_pixel(i, j, pixel);
+            }
+        }
+    }
+
+    // Displays other colours in place of the image, such as a filtered copy
+    // of it, leaving the linear colours intact
+    pub fn show(&mut self, colours: &[Vec3<f32>]) {
+        for (pixel, &colour) in self.pixels.chunks_mut(3).zip(colours) {
+            pixel.copy_from_slice(&to_pixel(colour));
+        }
+    }
+
+    // Regenerates the displayed pixels from the linear colours, clearing
+    // anything drawn over them
+    pub fn refresh_pixels(&mut self) {
+        for (pixel, &colour) in self.pixels.chunks_mut(3).zip(&self.colours) {
+            pixel.copy_from_slice(&to_pixel(colour));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_cover_framebuffer() {
+        let framebuffer = Framebuffer::new(70, 40);
+        let tiles = framebuffer.tiles_in(framebuffer.bounds());
+        assert_eq!(tiles.len(), 6);
+        let area: usize = tiles.iter().map(|t| t.width * t.height).sum();
+        assert_eq!(area, 70 * 40);
+        assert_eq!(tiles[2], Tile { x: 64, y: 0, width: 6, height: 32 });
+    }
+
+    #[test]
+    fn render_writes_every_pixel() {
+        let mut framebuffer = Framebuffer::new(45, 33);
+        framebuffer.render(|i, j| Vec3::new(i as f32 / 45.0, j as f32 / 33.0, 1.0));
+
+        for j in 0..33 {
+            for i in 0..45 {
+                let offset = j * framebuffer.pitch() + i * 3;
+                let expected = to_pixel(Vec3::new(i as f32 / 45.0, j as f32 / 33.0, 1.0));
+                assert_eq!(&framebuffer.pixels[offset..offset + 3], &expected);
+            }
+        }
+    }
+
+    #[test]
+    fn refining_ends_at_the_full_image() {
+        let shade = |i: usize, j: usize| Vec3::new(i as f32, j as f32, 0.0);
+        let shaded = AtomicUsize::new(0);
+
+        let mut framebuffer = Framebuffer::new(45, 33);
+        framebuffer.refine(8, 0, |i, j| {
+            shaded.fetch_add(1, Ordering::Relaxed);
+            shade(i, j)
+        });
+        // Blocks stand in for the pixels not yet shaded
+        assert_eq!(framebuffer.colours[3 * 45 + 5], shade(0, 0));
+        assert_eq!(framebuffer.colours[44], shade(40, 0));
+
+        for (stride, done) in [(4, 8), (2, 4), (1, 2)] {
+            framebuffer.refine(stride, done, |i, j| {
+                shaded.fetch_add(1, Ordering::Relaxed);
+                shade(i, j)
+            });
+        }
+
+        let mut full = Framebuffer::new(45, 33);
+        full.accumulate(full.bounds(), 0, shade);
+        assert_eq!(framebuffer.colours, full.colours);
+        assert_eq!(framebuffer.samples, full.samples);
+        assert_eq!(shaded.into_inner(), 45 * 33);
+    }
+
+    #[test]
+    fn accumulate_averages_samples() {
+        let mut framebuffer = Framebuffer::new(3, 2);
+
+        for n in 0..4 {
+            framebuffer.accumulate(framebuffer.bounds(), n, |_, _| Vec3::new(n as f32, 0.0, 0.0));
+        }
+
+        assert!(framebuffer.colours.iter().all(|c| c.x == 1.5));
+    }
+
+    #[test]
+    fn accumulate_tracks_samples_and_squares() {
+        let mut framebuffer = Framebuffer::new(2, 2);
+        for (k, value) in [1.0, 3.0].iter().enumerate() {
+            framebuffer.accumulate(framebuffer.bounds(), k, |_, _| Vec3::new(*value, 0.0, 0.0));
+        }
+
+        assert_eq!(framebuffer.samples, vec![2; 4]);
+        assert_eq!(framebuffer.squares[0].x, 5.0);
+    }
+
+    #[test]
+    fn accumulate_region_only() {
+        let mut framebuffer = Framebuffer::new(80, 50);
+        let region = Tile { x: 30, y: 10, width: 40, height: 35 };
+
+        assert_eq!(framebuffer.tiles_in(region).len(), 4);
+
+        framebuffer.accumulate(region, 0, |_, _| Vec3::new(1.0, 1.0, 1.0));
+
+        let lit = framebuffer.colours.iter().filter(|c| c.x == 1.0).count();
+        assert_eq!(lit, 40 * 35);
+        assert_eq!(framebuffer.colours[10 * 80 + 30].x, 1.0);
+        assert_eq!(framebuffer.colours[10 * 80 + 29].x, 0.0);
+    }
+
+    #[test]
+    fn line_is_clipped_to_image() {
+        let mut framebuffer = Framebuffer::new(10, 10);
+        framebuffer.line((-5.0, 5.5), (1.0e6, 5.5), [255, 0, 0]);
+
+        let red = framebuffer.pixels.chunks(3).filter(|pixel| pixel == &[255, 0, 0]).count();
+        assert_eq!(red, 10);
+
+        framebuffer.line((-5.0, -5.0), (-1.0, 20.0), [0, 255, 0]);
+        assert!(framebuffer.pixels.chunks(3).all(|pixel| pixel != [0, 255, 0]));
+    }
+
+    #[test]
+    fn outlines_are_clipped_to_the_image() {
+        let mut framebuffer = Framebuffer::new(6, 4);
+        let pixel = [255, 0, 0];
+        framebuffer.outline(Tile { x: 3, y: 2, width: 10, height: 10 }, pixel);
+
+        let lit = |framebuffer: &Framebuffer, i: usize, j: usize| {
+            let offset = j * framebuffer.pitch() + i * 3;
+            framebuffer.pixels[offset..offset + 3] == pixel
+        };
+        // Only the top and left edges are inside
+        for j in 0..4 {
+            for i in 0..6 {
+                let on_edge = (j == 2 && i >= 3) || (i == 3 && j >= 2);
+                assert_eq!(lit(&framebuffer, i, j), on_edge, "({}, {})", i, j);
+            }
+        }
+
+        framebuffer.outline(Tile { x: 6, y: 0, width: 2, height: 2 }, [0, 255, 0]);
+        assert!(framebuffer.pixels.iter().skip(1).step_by(3).all(|&g| g == 0));
+    }
+}
@@ -1,8 +1,13 @@
 use std::ops::{Add, Div, Mul, Sub, Neg};
 use num_traits::{Float, Zero};
 use crate::materials::Material;
+use crate::curves::Curves;
+use crate::mesh::Mesh;
+use crate::points::PointCloud;
+use crate::scatter::Scatter;
+use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Vec2<T> {
     pub x: T,
     pub y: T,
@@ -41,7 +46,7 @@ impl<T: Zero> Default for Vec2<T> {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Vec3<T> {
     pub x: T,
     pub y: T,
@@ -74,6 +79,13 @@ impl<T: Zero> Vec3<T> {
     {
         self / self.length()
     }
+
+    pub fn is_finite(self) -> bool
+    where
+        T: Float,
+    {
+        self.x.is_finite() && self.y.is_finite() && self.z.is_finite()
+    }
 }
 
 impl<T: Zero> Default for Vec3<T> {
@@ -89,6 +101,29 @@ where
     lhs.x * rhs.x + lhs.y * rhs.y + lhs.z * rhs.z
 }
 
+pub fn cross<T>(lhs: Vec3<T>, rhs: Vec3<T>) -> Vec3<T>
+where
+    T: Float,
+{
+    Vec3 {
+        x: lhs.y * rhs.z - lhs.z * rhs.y,
+        y: lhs.z * rhs.x - lhs.x * rhs.z,
+        z: lhs.x * rhs.y - lhs.y * rhs.x,
+    }
+}
+
+// Two unit vectors perpendicular to a unit vector and each other
+pub fn basis(normal: Vec3<f32>) -> (Vec3<f32>, Vec3<f32>) {
+    let axis = if normal.z.abs() < 0.9 {
+        Vec3::new(0.0, 0.0, 1.0)
+    } else {
+        Vec3::new(1.0, 0.0, 0.0)
+    };
+
+    let tangent = cross(normal, axis).normalise();
+    (tangent, cross(tangent, normal))
+}
+
 pub fn reflect(incident: Vec3<f32>, normal: Vec3<f32>) -> Vec3<f32> {
     incident - 2.0*dot(incident, normal)*normal
 }
@@ -171,17 +206,131 @@ impl<T: Zero + Sub<Output = T>> Neg for Vec3<T> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 pub struct Ray {
     pub origin: Vec3<f32>,
     pub direction: Vec3<f32>,
 }
 
-#[derive(Debug)]
+// Axis-aligned bounding box
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3<f32>,
+    pub max: Vec3<f32>,
+}
+
+impl Aabb {
+    // An empty box, which acts as the identity for `union`
+    pub fn empty() -> Self {
+        Aabb {
+            min: Vec3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Vec3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn from_points(points: &[Vec3<f32>]) -> Self {
+        points.iter().fold(Aabb::empty(), |aabb, &point| aabb.grow(point))
+    }
+
+    pub fn grow(self, point: Vec3<f32>) -> Self {
+        Aabb {
+            min: Vec3::new(self.min.x.min(point.x), self.min.y.min(point.y), self.min.z.min(point.z)),
+            max: Vec3::new(self.max.x.max(point.x), self.max.y.max(point.y), self.max.z.max(point.z)),
+        }
+    }
+
+    pub fn union(self, other: Aabb) -> Self {
+        self.grow(other.min).grow(other.max)
+    }
+
+    pub fn centre(&self) -> Vec3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn size(&self) -> Vec3<f32> {
+        self.max - self.min
+    }
+
+    // Slab test, returning the distances at which the ray enters and leaves
+    // the box if it does so in front of the origin
+    pub fn ray_intersect(&self, ray: &Ray) -> Option<(f32, f32)> {
+        let mut near = 0.0f32;
+        let mut far = f32::INFINITY;
+
+        for &(origin, direction, min, max) in &[
+            (ray.origin.x, ray.direction.x, self.min.x, self.max.x),
+            (ray.origin.y, ray.direction.y, self.min.y, self.max.y),
+            (ray.origin.z, ray.direction.z, self.min.z, self.max.z),
+        ] {
+            let inverse = 1.0 / direction;
+            let mut t0 = (min - origin) * inverse;
+            let mut t1 = (max - origin) * inverse;
+
+            if t0 > t1 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+
+            near = near.max(t0);
+            far = far.min(t1);
+
+            if near > far {
+                return None;
+            }
+        }
+
+        Some((near, far))
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Hit {
+    pub distance: f32,
+    pub point: Vec3<f32>,
+    pub normal: Vec3<f32>,
+    pub material: Material,
+    // Direction along a strand of hair, which is shaded as a fibre rather
+    // than a surface
+    pub tangent: Option<Vec3<f32>>,
+}
+
+impl Hit {
+    pub fn new(ray: &Ray, distance: f32, normal: Vec3<f32>, material: Material) -> Self {
+        Hit {
+            distance,
+            point: ray.origin + ray.direction * distance,
+            normal,
+            material,
+            tangent: None,
+        }
+    }
+
+    pub fn with_tangent(mut self, tangent: Vec3<f32>) -> Self {
+        self.tangent = Some(tangent);
+        self
+    }
+}
+
+pub trait Intersect {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit>;
+}
+
+// Flat surfaces are shaded from whichever side the ray arrives
+pub fn facing(normal: Vec3<f32>, ray: &Ray) -> Vec3<f32> {
+    if dot(normal, ray.direction) > 0.0 {
+        -normal
+    } else {
+        normal
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Sphere {
     pub centre: Vec3<f32>,
     pub radius: f32,
     pub material: Material,
+    // Distance moved per sixtieth of a second while the scene runs
+    #[serde(default)]
+    pub velocity: Vec3<f32>,
 }
 
 impl Sphere {
@@ -190,16 +339,27 @@ impl Sphere {
             centre,
             radius,
             material,
+            velocity: Vec3::zero(),
         }
     }
 
+    pub fn with_velocity(mut self, velocity: Vec3<f32>) -> Self {
+        self.velocity = velocity;
+        self
+    }
+
     // TODO understand this and make it more idiomatic in Rust
-    pub fn ray_intersect(&self, ray: &Ray) -> Option<f32> {
+    pub fn ray_distance(&self, ray: &Ray) -> Option<f32> {
+        // Spheres with no radius or a position that isn't finite are never hit
+        if self.radius.is_nan() || self.radius <= 0.0 {
+            return None;
+        }
+
         let l = self.centre - ray.origin;
         let tca = dot(l, ray.direction);
         let d2 = dot(l, l) - tca * tca;
 
-        if d2 > self.radius * self.radius {
+        if d2.is_nan() || d2 > self.radius * self.radius {
             return None;
         }
 
@@ -219,10 +379,356 @@ impl Sphere {
     }
 }
 
+impl Intersect for Sphere {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        let distance = self.ray_distance(ray)?;
+        let point = ray.origin + ray.direction * distance;
+        Some(Hit::new(ray, distance, (point - self.centre).normalise(), self.material))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Plane {
+    pub point: Vec3<f32>,
+    pub normal: Vec3<f32>,
+    pub material: Material,
+    // Half-size of a finite plane along its tangent and bitangent (which are
+    // the world x and z axes for a horizontal plane), or None if unbounded
+    #[serde(default)]
+    pub extent: Option<Vec2<f32>>,
+}
+
+impl Plane {
+    pub fn new(point: Vec3<f32>, normal: Vec3<f32>, material: Material) -> Self {
+        Plane {
+            point,
+            normal: normal.normalise(),
+            material,
+            extent: None,
+        }
+    }
+
+    pub fn with_extent(mut self, extent: Vec2<f32>) -> Self {
+        self.extent = Some(extent);
+        self
+    }
+
+    pub fn tangents(&self) -> (Vec3<f32>, Vec3<f32>) {
+        basis(self.normal.normalise())
+    }
+}
+
+impl Intersect for Plane {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        let normal = self.normal.normalise();
+        let denominator = dot(ray.direction, normal);
+
+        // Planes with no normal or a point that isn't finite are never hit
+        if denominator.is_nan() || denominator.abs() < 1.0e-6 {
+            return None;
+        }
+
+        let distance = dot(self.point - ray.origin, normal) / denominator;
+
+        if distance.is_nan() || distance < 0.0 {
+            return None;
+        }
+
+        let hit = Hit::new(ray, distance, facing(normal, ray), self.material);
+
+        if let Some(extent) = self.extent {
+            let (tangent, bitangent) = self.tangents();
+            let offset = hit.point - self.point;
+
+            if dot(offset, tangent).abs() > extent.x || dot(offset, bitangent).abs() > extent.y {
+                return None;
+            }
+        }
+
+        Some(hit)
+    }
+}
+
+// Möller-Trumbore intersection, returning the distance and the barycentric
+// coordinates (u, v) of the hit relative to the second and third vertices
+pub fn ray_triangle(ray: &Ray, vertices: [Vec3<f32>; 3]) -> Option<(f32, f32, f32)> {
+    const EPSILON: f32 = 1.0e-7;
+
+    let [a, b, c] = vertices;
+    let edge1 = b - a;
+    let edge2 = c - a;
+
+    let p = cross(ray.direction, edge2);
+    let determinant = dot(edge1, p);
+
+    if determinant.abs() < EPSILON {
+        return None;
+    }
+
+    let inverse = 1.0 / determinant;
+    let s = ray.origin - a;
+    let u = dot(s, p) * inverse;
+
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = cross(s, edge1);
+    let v = dot(ray.direction, q) * inverse;
+
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let distance = dot(edge2, q) * inverse;
+
+    if distance > EPSILON {
+        Some((distance, u, v))
+    } else {
+        None
+    }
+}
+
+pub fn triangle_normal(vertices: [Vec3<f32>; 3]) -> Vec3<f32> {
+    let [a, b, c] = vertices;
+    cross(b - a, c - a).normalise()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Triangle {
+    pub vertices: [Vec3<f32>; 3],
+    pub material: Material,
+}
+
+impl Triangle {
+    pub fn normal(&self) -> Vec3<f32> {
+        triangle_normal(self.vertices)
+    }
+}
+
+impl Intersect for Triangle {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        let (distance, _, _) = ray_triangle(ray, self.vertices)?;
+        Some(Hit::new(ray, distance, facing(self.normal(), ray), self.material))
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Shape {
+    Sphere(Sphere),
+    Plane(Plane),
+    Triangle(Triangle),
+    Mesh(Mesh),
+    Points(PointCloud),
+    Curves(Curves),
+    Scatter(Scatter),
+}
+
+impl Shape {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Shape::Sphere(_) => "sphere",
+            Shape::Plane(_) => "plane",
+            Shape::Triangle(_) => "triangle",
+            Shape::Mesh(_) => "mesh",
+            Shape::Points(_) => "point cloud",
+            Shape::Curves(_) => "curves",
+            Shape::Scatter(_) => "scatter",
+        }
+    }
+
+    pub fn material(&self) -> &Material {
+        match self {
+            Shape::Sphere(sphere) => &sphere.material,
+            Shape::Plane(plane) => &plane.material,
+            Shape::Triangle(triangle) => &triangle.material,
+            Shape::Mesh(mesh) => &mesh.material,
+            Shape::Points(cloud) => &cloud.material,
+            Shape::Curves(curves) => &curves.material,
+            Shape::Scatter(scatter) => scatter.prototype.material(),
+        }
+    }
+
+    pub fn material_mut(&mut self) -> &mut Material {
+        match self {
+            Shape::Sphere(sphere) => &mut sphere.material,
+            Shape::Plane(plane) => &mut plane.material,
+            Shape::Triangle(triangle) => &mut triangle.material,
+            Shape::Mesh(mesh) => &mut mesh.material,
+            Shape::Points(cloud) => &mut cloud.material,
+            Shape::Curves(curves) => &mut curves.material,
+            Shape::Scatter(scatter) => scatter.prototype.material_mut(),
+        }
+    }
+
+    // Reference point used when moving a shape around: the centre of a
+    // sphere, the anchor point of a plane, the centroid of a triangle or the
+    // centre of the bounds of anything else
+    pub fn centre(&self) -> Vec3<f32> {
+        match self {
+            Shape::Sphere(sphere) => sphere.centre,
+            Shape::Plane(plane) => plane.point,
+            Shape::Triangle(triangle) => {
+                let [a, b, c] = triangle.vertices;
+                (a + b + c) * (1.0 / 3.0)
+            }
+            Shape::Mesh(mesh) => mesh.bounds().centre(),
+            Shape::Points(cloud) => cloud.bounds().centre(),
+            Shape::Curves(curves) => curves.bounds().centre(),
+            Shape::Scatter(scatter) => scatter.bounds().centre(),
+        }
+    }
+
+    // Why the shape can't be rendered, if it can't: spheres and point clouds
+    // need a radius, planes a normal, triangles some area, and everything a
+    // finite position. Such shapes are never hit by rays.
+    pub fn problem(&self) -> Option<&'static str> {
+        let finite = match self {
+            Shape::Sphere(sphere) => sphere.centre.is_finite() && sphere.velocity.is_finite(),
+            Shape::Plane(plane) => plane.point.is_finite(),
+            Shape::Triangle(triangle) => triangle.vertices.iter().all(|v| v.is_finite()),
+            Shape::Mesh(mesh) => mesh.position.is_finite(),
+            Shape::Points(cloud) => cloud.position.is_finite(),
+            Shape::Curves(curves) => curves.position.is_finite(),
+            Shape::Scatter(scatter) => scatter.position.is_finite(),
+        };
+        if !finite {
+            return Some("a position that isn't finite");
+        }
+
+        match self {
+            Shape::Sphere(Sphere { radius, .. }) | Shape::Points(PointCloud { radius, .. })
+                if *radius <= 0.0 || !radius.is_finite() =>
+            {
+                Some("no radius")
+            }
+            Shape::Plane(plane) if plane.normal.length() == 0.0 || !plane.normal.is_finite() => Some("no normal"),
+            Shape::Triangle(triangle) => {
+                let [a, b, c] = triangle.vertices;
+                if cross(b - a, c - a).length() == 0.0 { Some("no area") } else { None }
+            }
+            Shape::Scatter(scatter) => scatter.prototype.problem(),
+            _ => None,
+        }
+    }
+
+    // Distance moved per sixtieth of a second by the simulation
+    pub fn velocity(&self) -> Vec3<f32> {
+        match self {
+            Shape::Sphere(sphere) => sphere.velocity,
+            _ => Vec3::zero(),
+        }
+    }
+
+    // Only spheres move, so this does nothing to anything else
+    pub fn set_velocity(&mut self, velocity: Vec3<f32>) {
+        if let Shape::Sphere(sphere) = self {
+            sphere.velocity = velocity;
+        }
+    }
+
+    pub fn translate(&mut self, offset: Vec3<f32>) {
+        match self {
+            Shape::Sphere(sphere) => sphere.centre = sphere.centre + offset,
+            Shape::Plane(plane) => plane.point = plane.point + offset,
+            Shape::Triangle(triangle) => {
+                for vertex in &mut triangle.vertices {
+                    *vertex = *vertex + offset;
+                }
+            }
+            Shape::Mesh(mesh) => mesh.position = mesh.position + offset,
+            Shape::Points(cloud) => cloud.position = cloud.position + offset,
+            Shape::Curves(curves) => curves.position = curves.position + offset,
+            Shape::Scatter(scatter) => scatter.position = scatter.position + offset,
+        }
+    }
+}
+
+impl Intersect for Shape {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        match self {
+            Shape::Sphere(sphere) => sphere.ray_intersect(ray),
+            Shape::Plane(plane) => plane.ray_intersect(ray),
+            Shape::Triangle(triangle) => triangle.ray_intersect(ray),
+            Shape::Mesh(mesh) => mesh.ray_intersect(ray),
+            Shape::Points(cloud) => cloud.ray_intersect(ray),
+            Shape::Curves(curves) => curves.ray_intersect(ray),
+            Shape::Scatter(scatter) => scatter.ray_intersect(ray),
+        }
+    }
+}
+
+impl From<Sphere> for Shape {
+    fn from(sphere: Sphere) -> Self {
+        Shape::Sphere(sphere)
+    }
+}
+
+impl From<Plane> for Shape {
+    fn from(plane: Plane) -> Self {
+        Shape::Plane(plane)
+    }
+}
+
+impl From<Triangle> for Shape {
+    fn from(triangle: Triangle) -> Self {
+        Shape::Triangle(triangle)
+    }
+}
+
+impl From<Mesh> for Shape {
+    fn from(mesh: Mesh) -> Self {
+        Shape::Mesh(mesh)
+    }
+}
+
+impl From<PointCloud> for Shape {
+    fn from(cloud: PointCloud) -> Self {
+        Shape::Points(cloud)
+    }
+}
+
+impl From<Curves> for Shape {
+    fn from(curves: Curves) -> Self {
+        Shape::Curves(curves)
+    }
+}
+
+impl From<Scatter> for Shape {
+    fn from(scatter: Scatter) -> Self {
+        Shape::Scatter(scatter)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn degenerate_shapes_are_never_hit() {
+        let ray = Ray {
+            origin: Vec3::new(0.0, 0.0, 5.0),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+        let material = Material::default();
+        let nan = Vec3::new(f32::NAN, 0.0, 0.0);
+
+        let shapes: Vec<Shape> = vec![
+            Sphere::new(Vec3::zero(), 0.0, material).into(),
+            Sphere::new(Vec3::zero(), -1.0, material).into(),
+            Sphere::new(nan, 1.0, material).into(),
+            Plane::new(Vec3::zero(), Vec3::zero(), material).into(),
+            Plane::new(nan, Vec3::new(0.0, 0.0, 1.0), material).into(),
+            Triangle { vertices: [Vec3::zero(), Vec3::new(1.0, 0.0, 0.0), Vec3::new(2.0, 0.0, 0.0)], material }.into(),
+            Triangle { vertices: [nan, Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)], material }.into(),
+        ];
+
+        for shape in &shapes {
+            assert!(shape.ray_intersect(&ray).is_none());
+            assert!(shape.problem().is_some());
+        }
+    }
+
     #[test]
     fn add_f32() {
         let v1: Vec3<f32> = Vec3 {
@@ -267,6 +773,61 @@ mod tests {
         );
     }
 
+    #[test]
+    fn cross_basis() {
+        let x: Vec3<f32> = Vec3::new(1.0, 0.0, 0.0);
+        let y: Vec3<f32> = Vec3::new(0.0, 1.0, 0.0);
+        assert_eq!(cross(x, y), Vec3::new(0.0, 0.0, 1.0));
+        assert_eq!(cross(y, x), Vec3::new(0.0, 0.0, -1.0));
+    }
+
+    fn ray(origin: Vec3<f32>, direction: Vec3<f32>) -> Ray {
+        Ray {
+            origin,
+            direction: direction.normalise(),
+        }
+    }
+
+    #[test]
+    fn finite_plane() {
+        let plane = Plane::new(Vec3::new(0.0, -4.0, -20.0), Vec3::new(0.0, 1.0, 0.0), Material::default())
+            .with_extent(Vec2::new(10.0, 10.0));
+
+        let hit = plane.ray_intersect(&ray(Vec3::new(0.0, 0.0, -20.0), Vec3::new(0.0, -1.0, 0.0))).unwrap();
+        assert_eq!(hit.distance, 4.0);
+        assert_eq!(hit.normal, Vec3::new(0.0, 1.0, 0.0));
+
+        assert!(plane.ray_intersect(&ray(Vec3::new(15.0, 0.0, -20.0), Vec3::new(0.0, -1.0, 0.0))).is_none());
+        assert!(plane.ray_intersect(&ray(Vec3::new(0.0, 0.0, -20.0), Vec3::new(0.0, 1.0, 0.0))).is_none());
+    }
+
+    #[test]
+    fn triangle_hit_and_miss() {
+        let triangle = Triangle {
+            vertices: [
+                Vec3::new(-1.0, -1.0, -5.0),
+                Vec3::new(1.0, -1.0, -5.0),
+                Vec3::new(0.0, 1.0, -5.0),
+            ],
+            material: Material::default(),
+        };
+
+        let hit = triangle.ray_intersect(&ray(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0))).unwrap();
+        assert!((hit.distance - 5.0).abs() < 1.0e-6);
+        // The normal faces back towards the ray origin
+        assert_eq!(hit.normal, Vec3::new(0.0, 0.0, 1.0));
+
+        assert!(triangle.ray_intersect(&ray(Vec3::zero(), Vec3::new(1.0, 1.0, -1.0))).is_none());
+    }
+
+    #[test]
+    fn aabb_slab_test() {
+        let aabb = Aabb::from_points(&[Vec3::new(-1.0, -1.0, -6.0), Vec3::new(1.0, 1.0, -4.0)]);
+        assert_eq!(aabb.ray_intersect(&ray(Vec3::zero(), Vec3::new(0.0, 0.0, -1.0))), Some((4.0, 6.0)));
+        assert!(aabb.ray_intersect(&ray(Vec3::zero(), Vec3::new(0.0, 0.0, 1.0))).is_none());
+        assert!(aabb.ray_intersect(&ray(Vec3::zero(), Vec3::new(1.0, 0.0, -1.0))).is_none());
+    }
+
     #[test]
     fn mul_f32_vec() {
         let x: f32 = 3.5;
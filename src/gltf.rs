@@ -0,0 +1,533 @@
+use crate::Result;
+use crate::camera::Camera;
+use crate::geometry::{Shape, Vec3};
+use crate::materials::Material;
+use crate::scene::Scene;
+use crate::tessellate::{self, Surface};
+
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Accessor component types and buffer view targets from the glTF spec
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+// Primitive modes
+const POINTS: u32 = 0;
+const LINES: u32 = 1;
+
+const GLB_MAGIC: u32 = 0x4654_6c67;
+const GLB_JSON: u32 = 0x4e4f_534a;
+const GLB_BIN: u32 = 0x004e_4942;
+
+// The parts of glTF 2.0 needed to describe a scene, named as in the spec
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Gltf {
+    asset: Asset,
+    scene: usize,
+    scenes: Vec<GltfScene>,
+    nodes: Vec<Node>,
+    meshes: Vec<GltfMesh>,
+    materials: Vec<GltfMaterial>,
+    cameras: Vec<GltfCamera>,
+    accessors: Vec<Accessor>,
+    buffer_views: Vec<BufferView>,
+    buffers: Vec<Buffer>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    extensions_used: Vec<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extensions: Option<Extensions>,
+}
+
+#[derive(Serialize)]
+struct Asset {
+    version: &'static str,
+    generator: &'static str,
+}
+
+#[derive(Serialize)]
+struct GltfScene {
+    nodes: Vec<usize>,
+}
+
+#[derive(Serialize)]
+struct Node {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mesh: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    camera: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    translation: Option<[f32; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rotation: Option<[f32; 4]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extensions: Option<NodeExtensions>,
+}
+
+#[derive(Serialize)]
+struct GltfMesh {
+    name: String,
+    primitives: Vec<Primitive>,
+}
+
+#[derive(Serialize)]
+struct Primitive {
+    attributes: BTreeMap<&'static str, usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    indices: Option<usize>,
+    material: usize,
+    mode: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GltfMaterial {
+    pbr_metallic_roughness: Pbr,
+    double_sided: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Pbr {
+    base_color_factor: [f32; 4],
+    metallic_factor: f32,
+    roughness_factor: f32,
+}
+
+#[derive(Serialize)]
+struct GltfCamera {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    perspective: Perspective,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Perspective {
+    yfov: f32,
+    znear: f32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Accessor {
+    buffer_view: usize,
+    component_type: u32,
+    count: usize,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min: Option<[f32; 3]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max: Option<[f32; 3]>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BufferView {
+    buffer: usize,
+    byte_offset: usize,
+    byte_length: usize,
+    target: u32,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Buffer {
+    byte_length: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uri: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Extensions {
+    #[serde(rename = "KHR_lights_punctual")]
+    lights_punctual: Lights,
+}
+
+#[derive(Serialize)]
+struct Lights {
+    lights: Vec<PointLight>,
+}
+
+#[derive(Serialize)]
+struct PointLight {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    intensity: f32,
+}
+
+#[derive(Serialize)]
+struct NodeExtensions {
+    #[serde(rename = "KHR_lights_punctual")]
+    lights_punctual: LightIndex,
+}
+
+#[derive(Serialize)]
+struct LightIndex {
+    light: usize,
+}
+
+// Gathers the binary data of every accessor into one buffer
+struct Builder {
+    gltf: Gltf,
+    data: Vec<u8>,
+}
+
+impl Builder {
+    fn view(&mut self, bytes: &[u8], target: u32) -> usize {
+        while !self.data.len().is_multiple_of(4) {
+            self.data.push(0);
+        }
+
+        self.gltf.buffer_views.push(BufferView {
+            buffer: 0,
+            byte_offset: self.data.len(),
+            byte_length: bytes.len(),
+            target,
+        });
+        self.data.extend_from_slice(bytes);
+        self.gltf.buffer_views.len() - 1
+    }
+
+    fn accessor(&mut self, accessor: Accessor) -> usize {
+        self.gltf.accessors.push(accessor);
+        self.gltf.accessors.len() - 1
+    }
+
+    fn vec3s(&mut self, values: &[Vec3<f32>], bounded: bool) -> usize {
+        let bytes: Vec<u8> = values
+            .iter()
+            .flat_map(|v| [v.x, v.y, v.z])
+            .flat_map(f32::to_le_bytes)
+            .collect();
+        let buffer_view = self.view(&bytes, ARRAY_BUFFER);
+
+        // Positions must give their bounds
+        let (min, max) = if bounded {
+            let min = values.iter().fold([f32::MAX; 3], |m, v| [m[0].min(v.x), m[1].min(v.y), m[2].min(v.z)]);
+            let max = values.iter().fold([f32::MIN; 3], |m, v| [m[0].max(v.x), m[1].max(v.y), m[2].max(v.z)]);
+            (Some(min), Some(max))
+        } else {
+            (None, None)
+        };
+
+        self.accessor(Accessor { buffer_view, component_type: FLOAT, count: values.len(), kind: "VEC3", min, max })
+    }
+
+    fn surface(&mut self, surface: &Surface, material: usize) -> Primitive {
+        let mut attributes = BTreeMap::new();
+        attributes.insert("POSITION", self.vec3s(&surface.positions, true));
+        attributes.insert("NORMAL", self.vec3s(&surface.normals, false));
+
+        if !surface.texcoords.is_empty() {
+            // glTF's texture coordinates run down the image
+            let bytes: Vec<u8> = surface
+                .texcoords
+                .iter()
+                .flat_map(|uv| [uv.x, 1.0 - uv.y])
+                .flat_map(f32::to_le_bytes)
+                .collect();
+            let buffer_view = self.view(&bytes, ARRAY_BUFFER);
+            let accessor = Accessor {
+                buffer_view,
+                component_type: FLOAT,
+                count: surface.texcoords.len(),
+                kind: "VEC2",
+                min: None,
+                max: None,
+            };
+            attributes.insert("TEXCOORD_0", self.accessor(accessor));
+        }
+
+        let bytes: Vec<u8> = surface.triangles.iter().flatten().flat_map(|i| i.to_le_bytes()).collect();
+        let buffer_view = self.view(&bytes, ELEMENT_ARRAY_BUFFER);
+        let indices = self.accessor(Accessor {
+            buffer_view,
+            component_type: UNSIGNED_INT,
+            count: surface.triangles.len() * 3,
+            kind: "SCALAR",
+            min: None,
+            max: None,
+        });
+
+        Primitive { attributes, indices: Some(indices), material, mode: 4 }
+    }
+
+    // Unindexed points or line segments, optionally coloured per vertex
+    fn vertices(&mut self, positions: &[Vec3<f32>], colours: Option<&[Vec3<f32>]>, material: usize, mode: u32) -> Primitive {
+        let mut attributes = BTreeMap::new();
+        attributes.insert("POSITION", self.vec3s(positions, true));
+        if let Some(colours) = colours {
+            attributes.insert("COLOR_0", self.vec3s(colours, false));
+        }
+
+        Primitive { attributes, indices: None, material, mode }
+    }
+
+    fn material(&mut self, material: &Material) -> usize {
+        let colour = material.diffuse_colour;
+
+        // Sharper highlights mean a smoother surface, and mirrors are metals
+        self.gltf.materials.push(GltfMaterial {
+            pbr_metallic_roughness: Pbr {
+                base_color_factor: [colour.x.clamp(0.0, 1.0), colour.y.clamp(0.0, 1.0), colour.z.clamp(0.0, 1.0), 1.0],
+                metallic_factor: material.reflectivity.clamp(0.0, 1.0),
+                roughness_factor: (2.0 / (material.specular_exponent.max(0.0) + 2.0)).sqrt(),
+            },
+            double_sided: false,
+        });
+        self.gltf.materials.len() - 1
+    }
+
+    fn node(&mut self, node: Node) -> usize {
+        self.gltf.nodes.push(node);
+        let index = self.gltf.nodes.len() - 1;
+        self.gltf.scenes[0].nodes.push(index);
+        index
+    }
+}
+
+// The rotation turning glTF's camera, which looks down -z with y up, to look
+// the way the camera does, as a quaternion (x, y, z, w)
+fn camera_rotation(camera: &Camera) -> [f32; 4] {
+    let (a, c1) = ((-camera.yaw / 2.0).sin(), (-camera.yaw / 2.0).cos());
+    let (b, c2) = ((camera.pitch / 2.0).sin(), (camera.pitch / 2.0).cos());
+
+    // Turning about y by the yaw, then about x by the pitch
+    [c1 * b, c2 * a, -a * b, c1 * c2]
+}
+
+fn build(scene: &Scene, camera: &Camera) -> Builder {
+    let mut builder = Builder {
+        gltf: Gltf {
+            asset: Asset { version: "2.0", generator: "tinyraytracer" },
+            scene: 0,
+            scenes: vec![GltfScene { nodes: Vec::new() }],
+            nodes: Vec::new(),
+            meshes: Vec::new(),
+            materials: Vec::new(),
+            cameras: Vec::new(),
+            accessors: Vec::new(),
+            buffer_views: Vec::new(),
+            buffers: Vec::new(),
+            extensions_used: Vec::new(),
+            extensions: None,
+        },
+        data: Vec::new(),
+    };
+
+    for (index, shape) in scene.shapes.iter().enumerate() {
+        if !scene.is_visible(index) {
+            continue;
+        }
+
+        let material = builder.material(shape.material());
+        let primitive = match shape {
+            Shape::Points(cloud) => {
+                let positions: Vec<Vec3<f32>> = cloud.points().iter().map(|&p| p + cloud.position).collect();
+                builder.vertices(&positions, cloud.colours(), material, POINTS)
+            }
+            Shape::Curves(curves) => {
+                // Each segment of a polyline is a separate pair of points
+                let positions: Vec<Vec3<f32>> = tessellate::polylines(curves)
+                    .iter()
+                    .flat_map(|line| line.windows(2).flat_map(|pair| [pair[0], pair[1]]))
+                    .collect();
+                builder.vertices(&positions, None, material, LINES)
+            }
+            // Scatters of points or curves are left out
+            _ => match tessellate::surface(shape) {
+                Some(surface) => builder.surface(&surface, material),
+                None => continue,
+            },
+        };
+
+        let name = format!("{} {}", shape.name(), index);
+        builder.gltf.meshes.push(GltfMesh { name: name.clone(), primitives: vec![primitive] });
+        let mesh = builder.gltf.meshes.len() - 1;
+        builder.node(Node { name, mesh: Some(mesh), camera: None, translation: None, rotation: None, extensions: None });
+    }
+
+    // Negative lights have no equivalent, so they're left out
+    let mut lights = Vec::new();
+    for (index, light) in scene.lights.iter().enumerate() {
+        if light.intensity <= 0.0 {
+            continue;
+        }
+
+        lights.push(PointLight { kind: "point", intensity: light.intensity });
+        let p = light.position;
+        builder.node(Node {
+            name: format!("light {}", index),
+            mesh: None,
+            camera: None,
+            translation: Some([p.x, p.y, p.z]),
+            rotation: None,
+            extensions: Some(NodeExtensions { lights_punctual: LightIndex { light: lights.len() - 1 } }),
+        });
+    }
+    if !lights.is_empty() {
+        builder.gltf.extensions_used.push("KHR_lights_punctual");
+        builder.gltf.extensions = Some(Extensions { lights_punctual: Lights { lights } });
+    }
+
+    builder.gltf.cameras.push(GltfCamera {
+        kind: "perspective",
+        perspective: Perspective { yfov: camera.fov, znear: 0.01 },
+    });
+    let p = camera.position;
+    builder.node(Node {
+        name: "camera".to_string(),
+        mesh: None,
+        camera: Some(0),
+        translation: Some([p.x, p.y, p.z]),
+        rotation: Some(camera_rotation(camera)),
+        extensions: None,
+    });
+
+    builder
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+
+    for chunk in data.chunks(3) {
+        let bytes = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let bits = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+
+        for k in 0..4 {
+            if k <= chunk.len() {
+                encoded.push(ALPHABET[(bits >> (18 - 6 * k) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+
+    encoded
+}
+
+// Writes the scene as glTF text with its buffer embedded in it
+pub fn write_gltf<W: Write>(writer: &mut W, scene: &Scene, camera: &Camera) -> Result<()> {
+    let Builder { mut gltf, data } = build(scene, camera);
+    gltf.buffers.push(Buffer {
+        byte_length: data.len(),
+        uri: Some(format!("data:application/octet-stream;base64,{}", base64(&data))),
+    });
+
+    writer.write_all(serde_json::to_string_pretty(&gltf)?.as_bytes())?;
+    Ok(())
+}
+
+// Writes the scene as binary glTF, with the JSON and buffer as chunks padded
+// to four bytes
+pub fn write_glb<W: Write>(writer: &mut W, scene: &Scene, camera: &Camera) -> Result<()> {
+    let Builder { mut gltf, mut data } = build(scene, camera);
+    gltf.buffers.push(Buffer { byte_length: data.len(), uri: None });
+
+    let mut json = serde_json::to_string(&gltf)?.into_bytes();
+    while !json.len().is_multiple_of(4) {
+        json.push(b' ');
+    }
+    while !data.len().is_multiple_of(4) {
+        data.push(0);
+    }
+
+    let length = 12 + 8 + json.len() + 8 + data.len();
+    for word in [GLB_MAGIC, 2, length as u32, json.len() as u32, GLB_JSON] {
+        writer.write_all(&word.to_le_bytes())?;
+    }
+    writer.write_all(&json)?;
+    for word in [data.len() as u32, GLB_BIN] {
+        writer.write_all(&word.to_le_bytes())?;
+    }
+    writer.write_all(&data)?;
+    Ok(())
+}
+
+// Exports the visible shapes, lights and camera as .gltf or .glb, for opening
+// in Blender and the like. Spheres and planes are tessellated, unbounded
+// planes cut down to a large square, point clouds written as points and
+// curves as line segments. Materials become their nearest metallic-roughness
+// equivalent, with patterns left out.
+pub fn export<P: AsRef<Path>>(scene: &Scene, camera: &Camera, path: P) -> Result<()> {
+    let path = path.as_ref();
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    match path.extension().and_then(OsStr::to_str) {
+        Some("gltf") => write_gltf(&mut writer, scene, camera)?,
+        Some("glb") => write_glb(&mut writer, scene, camera)?,
+        _ => return Err(format!("unsupported glTF format for `{}` (use .gltf or .glb)", path.display()).into()),
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::cross;
+
+    #[test]
+    fn camera_rotation_matches_its_view() {
+        let mut camera = Camera::default();
+        camera.rotate(0.7, -0.3);
+
+        // Rotating -z by the quaternion
+        let [x, y, z, w] = camera_rotation(&camera);
+        let (q, v) = (Vec3::new(x, y, z), Vec3::new(0.0, 0.0, -1.0));
+        let t = cross(q, v) * 2.0;
+        let forward = v + t * w + cross(q, t);
+
+        assert!((forward - camera.forward()).length() < 1.0e-5);
+    }
+
+    #[test]
+    fn buffer_holds_every_visible_shape() {
+        let mut scene = Scene::default_scene();
+        scene.hidden.insert(0);
+        let builder = build(&scene, &Camera::default());
+
+        let meshes = scene.shapes.len() - 1;
+        assert_eq!(builder.gltf.meshes.len(), meshes);
+        assert_eq!(builder.gltf.materials.len(), meshes);
+
+        let end = builder.gltf.buffer_views.iter().map(|view| view.byte_offset + view.byte_length).max();
+        assert_eq!(end, Some(builder.data.len()));
+        assert!(builder.gltf.buffer_views.iter().all(|view| view.byte_offset.is_multiple_of(4)));
+    }
+
+    #[test]
+    fn base64_pads_partial_chunks() {
+        assert_eq!(base64(b"Man"), "TWFu");
+        assert_eq!(base64(b"Ma"), "TWE=");
+        assert_eq!(base64(b"M"), "TQ==");
+    }
+
+    #[test]
+    fn glb_chunks_are_aligned() {
+        let mut data = Vec::new();
+        write_glb(&mut data, &Scene::default_scene(), &Camera::default()).unwrap();
+
+        let word = |at: usize| u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+        assert_eq!(&data[0..4], b"glTF");
+        assert_eq!(word(8) as usize, data.len());
+
+        let json = word(12) as usize;
+        assert!(json.is_multiple_of(4));
+        assert_eq!(&data[20 + json + 4..20 + json + 8], b"BIN\0");
+    }
+}
@@ -0,0 +1,110 @@
+use crate::Result;
+use crate::animation;
+use crate::camera::Camera;
+use crate::framebuffer::Framebuffer;
+use crate::geometry::Vec3;
+use crate::render::Renderer;
+use crate::scene::Scene;
+
+// Parameters are nudged by this fraction of their size either way, or by
+// MIN_STEP if they're smaller than one
+pub const RELATIVE_STEP: f32 = 1.0e-2;
+pub const MIN_STEP: f32 = 1.0e-2;
+
+// How each pixel's colour changes with a parameter, per unit of the
+// parameter. Parameters are numbers in the scene or camera given by their
+// animation path, such as `lights[0].intensity` or `spheres[1].centre.x`.
+pub struct Gradient {
+    pub parameter: String,
+    pub value: f32,
+    pub step: f32,
+    pub colours: Vec<Vec3<f32>>,
+}
+
+impl Gradient {
+    // The parameter reduced to letters, digits and underscores, which image
+    // formats and compositors are happy with as a layer name
+    pub fn name(&self) -> String {
+        let name: String = self.parameter.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+        format!("d_{}", name.trim_matches('_'))
+    }
+
+    pub fn framebuffer(&self, width: usize, height: usize) -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(width, height);
+        framebuffer.render(|i, j| self.colours[j * width + i]);
+        framebuffer
+    }
+}
+
+// Renders the image with each parameter nudged up and down in turn, taking
+// central differences of the two. Both renders trace the same random
+// numbers, so the noise in them mostly cancels and what's left is the
+// parameter's effect. Edges moving with a parameter show up as a band a pixel
+// wide, as finite differences of a step do.
+pub fn render(
+    renderer: &Renderer,
+    scene: &Scene,
+    camera: &Camera,
+    parameters: &[String],
+    width: usize,
+    height: usize,
+) -> Result<Vec<Gradient>> {
+    let mut gradients = Vec::with_capacity(parameters.len());
+
+    for parameter in parameters {
+        let (mut nudged, mut nudged_camera) = (scene.clone(), *camera);
+        let value = animation::get(&mut nudged, &mut nudged_camera, parameter)?;
+        let step = (value.abs() * RELATIVE_STEP).max(MIN_STEP);
+
+        let mut render_at = |value: f32| -> Result<Framebuffer> {
+            animation::set(&mut nudged, &mut nudged_camera, parameter, value)?;
+            let mut framebuffer = Framebuffer::new(width, height);
+            renderer.render(&mut framebuffer, &nudged_camera, &nudged);
+            Ok(framebuffer)
+        };
+        let above = render_at(value + step)?;
+        let below = render_at(value - step)?;
+
+        let scale = 1.0 / (2.0 * step);
+        let colours = above.colours.iter().zip(&below.colours).map(|(&a, &b)| (a - b) * scale).collect();
+        gradients.push(Gradient { parameter: parameter.clone(), value, step, colours });
+    }
+
+    Ok(gradients)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Sphere, Vec2};
+    use crate::materials::Material;
+    use crate::scene::Light;
+
+    #[test]
+    fn brighter_light_has_a_positive_gradient() {
+        let mut scene = Scene::default_scene();
+        let material = Material::new(Vec2::new(1.0, 0.0), Vec3::new(1.0, 1.0, 1.0), 1.0);
+        scene.shapes = vec![Sphere::new(Vec3::new(0.0, 0.0, -5.0), 1.0, material).into()];
+        scene.lights = vec![Light::new(Vec3::new(0.0, 0.0, 10.0), 1.0)];
+        let (camera, renderer) = (Camera::default(), Renderer::default());
+
+        let parameters = ["lights[0].intensity".to_string(), "spheres[0].centre.x".to_string()];
+        let gradients = render(&renderer, &scene, &camera, &parameters, 32, 32).unwrap();
+        assert_eq!(gradients[0].name(), "d_lights_0__intensity");
+
+        // Lit diffuse colour is proportional to intensity, and the background
+        // doesn't change at all
+        let centre = 16 * 32 + 16;
+        let mut base = Framebuffer::new(32, 32);
+        renderer.render(&mut base, &camera, &scene);
+        assert!((gradients[0].colours[centre] - base.colours[centre]).length() < 1.0e-3);
+        assert_eq!(gradients[0].colours[0], Vec3::zero());
+
+        // Moving the sphere sideways changes its edges, not its middle
+        let moved = &gradients[1].colours;
+        assert!(moved.iter().any(|c| c.length() > 1.0));
+        assert!(moved[centre].length() < moved.iter().map(|c| c.length()).fold(0.0, f32::max));
+
+        assert!(render(&renderer, &scene, &camera, &["lights[3].intensity".to_string()], 4, 4).is_err());
+    }
+}
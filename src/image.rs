@@ -0,0 +1,433 @@
+use crate::Result;
+use crate::exr;
+use crate::framebuffer::Framebuffer;
+use crate::geometry::Vec3;
+
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// A decoded image as linear floating point colours, row by row from the top
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub texels: Vec<Vec3<f32>>,
+}
+
+// Reads a Radiance HDR or PPM image depending on the extension
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Image> {
+    let path = path.as_ref();
+    let data = fs::read(path)?;
+
+    match path.extension().and_then(OsStr::to_str) {
+        Some("hdr") => read_hdr(&data),
+        Some("ppm") => read_ppm(&data),
+        _ => Err(format!("unsupported image format for `{}` (use .hdr or .ppm)", path.display()).into()),
+    }
+}
+
+// Writes the framebuffer as PNG, binary PPM or, keeping its linear colours
+// unclamped, Radiance HDR or tiled OpenEXR depending on the extension
+pub fn save<P: AsRef<Path>>(framebuffer: &Framebuffer, path: P) -> Result<()> {
+    let path = path.as_ref();
+
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    match path.extension().and_then(OsStr::to_str) {
+        Some("png") => write_png(&mut writer, framebuffer)?,
+        Some("ppm") => write_ppm(&mut writer, framebuffer)?,
+        Some("hdr") => write_hdr(&mut writer, framebuffer)?,
+        Some("exr") => exr::write(&mut writer, &[exr::Part { name: exr::BEAUTY, framebuffer }])?,
+        _ => return Err(format!("unsupported image format for `{}` (use .png, .ppm, .hdr or .exr)", path.display()).into()),
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn write_ppm<W: Write>(writer: &mut W, framebuffer: &Framebuffer) -> Result<()> {
+    write!(writer, "P6\n{} {}\n255\n", framebuffer.width, framebuffer.height)?;
+    writer.write_all(&framebuffer.pixels)?;
+    Ok(())
+}
+
+// Splits off the next whitespace separated token of a PPM header, skipping
+// comments
+fn ppm_token<'a>(data: &'a [u8], offset: &mut usize) -> Result<&'a str> {
+    loop {
+        while data.get(*offset).is_some_and(u8::is_ascii_whitespace) {
+            *offset += 1;
+        }
+
+        if data.get(*offset) == Some(&b'#') {
+            while data.get(*offset).is_some_and(|&b| b != b'\n') {
+                *offset += 1;
+            }
+        } else {
+            break;
+        }
+    }
+
+    let start = *offset;
+    while data.get(*offset).is_some_and(|b| !b.is_ascii_whitespace()) {
+        *offset += 1;
+    }
+
+    match std::str::from_utf8(&data[start..*offset]) {
+        Ok(token) if !token.is_empty() => Ok(token),
+        _ => Err("truncated PPM header".into()),
+    }
+}
+
+pub fn read_ppm(data: &[u8]) -> Result<Image> {
+    let mut offset = 0;
+
+    if ppm_token(data, &mut offset)? != "P6" {
+        return Err("only binary (P6) PPM images are supported".into());
+    }
+
+    let width: usize = ppm_token(data, &mut offset)?.parse()?;
+    let height: usize = ppm_token(data, &mut offset)?.parse()?;
+    let max_value: f32 = ppm_token(data, &mut offset)?.parse()?;
+
+    if max_value > 255.0 {
+        return Err("16-bit PPM images are not supported".into());
+    }
+
+    // A single whitespace byte separates the header from the pixel data
+    let pixels = data
+        .get(offset + 1..offset + 1 + width * height * 3)
+        .ok_or("truncated PPM pixel data")?;
+
+    let texels = pixels
+        .chunks(3)
+        .map(|p| Vec3::new(p[0] as f32, p[1] as f32, p[2] as f32) * (1.0 / max_value))
+        .collect();
+
+    Ok(Image { width, height, texels })
+}
+
+fn rgbe_to_colour(rgbe: [u8; 4]) -> Vec3<f32> {
+    if rgbe[3] == 0 {
+        return Vec3::zero();
+    }
+
+    let scale = 2.0f32.powi(rgbe[3] as i32 - (128 + 8));
+    Vec3::new(rgbe[0] as f32 + 0.5, rgbe[1] as f32 + 0.5, rgbe[2] as f32 + 0.5) * scale
+}
+
+// The inverse of `rgbe_to_colour`, sharing an exponent between the channels
+// that fits the brightest
+fn colour_to_rgbe(colour: Vec3<f32>) -> [u8; 4] {
+    let max = colour.x.max(colour.y).max(colour.z);
+    if max < 1.0e-32 {
+        return [0; 4];
+    }
+
+    // max = mantissa * 2^exponent with the mantissa in [0.5, 1)
+    let exponent = max.log2().floor() as i32 + 1;
+    let scale = 2.0f32.powi(8 - exponent);
+    let byte = |c: f32| (c.max(0.0) * scale).min(255.0) as u8;
+
+    [byte(colour.x), byte(colour.y), byte(colour.z), (exponent + 128).clamp(0, 255) as u8]
+}
+
+// Writes the linear colours as a Radiance HDR image, run-length encoded
+// without any runs where the width allows it, so every channel of a scanline
+// is written as literals
+pub fn write_hdr<W: Write>(writer: &mut W, framebuffer: &Framebuffer) -> Result<()> {
+    let (width, height) = (framebuffer.width, framebuffer.height);
+    write!(writer, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", height, width)?;
+
+    for row in framebuffer.colours.chunks(width.max(1)) {
+        let scanline: Vec<[u8; 4]> = row.iter().map(|&colour| colour_to_rgbe(colour)).collect();
+
+        if !(8..0x8000).contains(&width) {
+            for pixel in &scanline {
+                writer.write_all(pixel)?;
+            }
+            continue;
+        }
+
+        writer.write_all(&[2, 2, (width >> 8) as u8, (width & 0xff) as u8])?;
+        for channel in 0..4 {
+            let values: Vec<u8> = scanline.iter().map(|pixel| pixel[channel]).collect();
+            for literal in values.chunks(128) {
+                writer.write_all(&[literal.len() as u8])?;
+                writer.write_all(literal)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Reads one scanline of RGBE pixels, either flat or in the run-length
+// encoding used by most Radiance files
+fn read_hdr_scanline(data: &[u8], offset: &mut usize, width: usize) -> Result<Vec<[u8; 4]>> {
+    let truncated = || "truncated HDR pixel data";
+
+    let header = data.get(*offset..*offset + 4).ok_or_else(truncated)?;
+    let run_length_encoded = (8..0x8000).contains(&width)
+        && header[0] == 2
+        && header[1] == 2
+        && ((header[2] as usize) << 8 | header[3] as usize) == width;
+
+    let mut scanline = vec![[0; 4]; width];
+
+    if !run_length_encoded {
+        for pixel in &mut scanline {
+            let bytes = data.get(*offset..*offset + 4).ok_or_else(truncated)?;
+            pixel.copy_from_slice(bytes);
+            *offset += 4;
+        }
+        return Ok(scanline);
+    }
+
+    *offset += 4;
+
+    // Each channel is stored separately as a sequence of runs and literals
+    for channel in 0..4 {
+        let mut x = 0;
+
+        while x < width {
+            let count = *data.get(*offset).ok_or_else(truncated)? as usize;
+            *offset += 1;
+
+            if count > 128 {
+                let count = count - 128;
+                let value = *data.get(*offset).ok_or_else(truncated)?;
+                *offset += 1;
+
+                for pixel in scanline.get_mut(x..x + count).ok_or("bad HDR run length")? {
+                    pixel[channel] = value;
+                }
+                x += count;
+            } else {
+                let values = data.get(*offset..*offset + count).ok_or_else(truncated)?;
+                *offset += count;
+
+                for (pixel, &value) in scanline.get_mut(x..x + count).ok_or("bad HDR run length")?.iter_mut().zip(values) {
+                    pixel[channel] = value;
+                }
+                x += count;
+            }
+
+            if count == 0 {
+                return Err("bad HDR run length".into());
+            }
+        }
+    }
+
+    Ok(scanline)
+}
+
+pub fn read_hdr(data: &[u8]) -> Result<Image> {
+    let mut offset = 0;
+    let next_line = |offset: &mut usize| -> Result<String> {
+        let end = data[*offset..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or("truncated HDR header")?;
+        let line = String::from_utf8_lossy(&data[*offset..*offset + end]).into_owned();
+        *offset += end + 1;
+        Ok(line)
+    };
+
+    if !next_line(&mut offset)?.starts_with("#?") {
+        return Err("not a Radiance HDR image".into());
+    }
+
+    // Header variables end at a blank line
+    loop {
+        let line = next_line(&mut offset)?;
+        if line.is_empty() {
+            break;
+        }
+        if line.starts_with("FORMAT=") && line != "FORMAT=32-bit_rle_rgbe" {
+            return Err(format!("unsupported HDR pixel format `{}`", line).into());
+        }
+    }
+
+    // Only the standard orientation, top to bottom and left to right, is
+    // supported
+    let resolution = next_line(&mut offset)?;
+    let fields: Vec<&str> = resolution.split_whitespace().collect();
+
+    let (height, width) = match fields.as_slice() {
+        ["-Y", height, "+X", width] => (height.parse::<usize>()?, width.parse::<usize>()?),
+        _ => return Err(format!("unsupported HDR orientation `{}`", resolution).into()),
+    };
+
+    let mut texels = Vec::with_capacity(width * height);
+
+    for _ in 0..height {
+        let scanline = read_hdr_scanline(data, &mut offset, width)?;
+        texels.extend(scanline.into_iter().map(rgbe_to_colour));
+    }
+
+    Ok(Image { width, height, texels })
+}
+
+pub fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+
+    for (n, entry) in table.iter_mut().enumerate() {
+        let mut c = n as u32;
+        for _ in 0..8 {
+            c = if c & 1 != 0 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+        }
+        *entry = c;
+    }
+
+    table
+}
+
+pub fn crc32(table: &[u32; 256], chunks: &[&[u8]]) -> u32 {
+    let mut crc = 0xffff_ffff;
+
+    for chunk in chunks {
+        for &b in *chunk {
+            crc = table[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
+    }
+
+    crc ^ 0xffff_ffff
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
+
+fn write_png_chunk<W: Write>(writer: &mut W, table: &[u32; 256], kind: &[u8; 4], data: &[u8]) -> Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    writer.write_all(&crc32(table, &[kind, data]).to_be_bytes())?;
+    Ok(())
+}
+
+// Minimal PNG encoder: 8-bit RGB, no filtering, and the image data stored in
+// uncompressed deflate blocks. Files are larger than a real encoder would
+// produce but need no dependencies.
+pub fn write_png<W: Write>(writer: &mut W, framebuffer: &Framebuffer) -> Result<()> {
+    const MAX_STORED_BLOCK: usize = 65535;
+
+    let table = crc32_table();
+
+    writer.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(framebuffer.width as u32).to_be_bytes());
+    header.extend_from_slice(&(framebuffer.height as u32).to_be_bytes());
+    // Bit depth, colour type (RGB), compression, filter and interlace methods
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_png_chunk(writer, &table, b"IHDR", &header)?;
+
+    // Each scanline is prefixed by its filter type, zero meaning none
+    let mut raw = Vec::with_capacity((framebuffer.pitch() + 1) * framebuffer.height);
+    for row in framebuffer.pixels.chunks(framebuffer.pitch()) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let blocks = raw.chunks(MAX_STORED_BLOCK).count();
+    for (i, block) in raw.chunks(MAX_STORED_BLOCK).enumerate() {
+        zlib.push(if i + 1 == blocks { 1 } else { 0 });
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    write_png_chunk(writer, &table, b"IDAT", &zlib)?;
+    write_png_chunk(writer, &table, b"IEND", &[])?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ppm_header() {
+        let framebuffer = Framebuffer::new(3, 2);
+        let mut data = Vec::new();
+        write_ppm(&mut data, &framebuffer).unwrap();
+        assert!(data.starts_with(b"P6\n3 2\n255\n"));
+        assert_eq!(data.len(), 11 + 3 * 2 * 3);
+    }
+
+    #[test]
+    fn ppm_round_trip() {
+        let mut framebuffer = Framebuffer::new(2, 1);
+        framebuffer.set(1, 0, Vec3::new(1.0, 0.0, 0.2));
+
+        let mut data = Vec::new();
+        write_ppm(&mut data, &framebuffer).unwrap();
+
+        let image = read_ppm(&data).unwrap();
+        assert_eq!((image.width, image.height), (2, 1));
+        assert_eq!(image.texels[0], Vec3::zero());
+        assert_eq!(image.texels[1].x, 1.0);
+    }
+
+    #[test]
+    fn hdr_run_length_scanline() {
+        let mut data = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 8\n".to_vec();
+        data.extend_from_slice(&[2, 2, 0, 8]);
+        // Red: a run of 8; green: 8 literals; blue: a run of 8; exponent: a run
+        data.extend_from_slice(&[128 + 8, 128]);
+        data.extend_from_slice(&[8, 0, 0, 0, 0, 64, 64, 64, 64]);
+        data.extend_from_slice(&[128 + 8, 0]);
+        data.extend_from_slice(&[128 + 8, 129]);
+
+        let image = read_hdr(&data).unwrap();
+        assert_eq!(image.texels.len(), 8);
+        assert_eq!(image.texels[0], Vec3::new(1.0 + 1.0 / 256.0, 1.0 / 256.0, 1.0 / 256.0));
+        assert_eq!(image.texels[7].y, 0.5 + 1.0 / 256.0);
+    }
+
+    #[test]
+    fn hdr_round_trip() {
+        for width in [3, 200] {
+            let mut framebuffer = Framebuffer::new(width, 2);
+            framebuffer.set(1, 1, Vec3::new(40.0, 0.5, 0.0));
+
+            let mut data = Vec::new();
+            write_hdr(&mut data, &framebuffer).unwrap();
+            let image = read_hdr(&data).unwrap();
+
+            assert_eq!((image.width, image.height), (width, 2));
+            let texel = image.texels[width + 1];
+            assert!((texel.x - 40.0).abs() < 0.2 && (texel.y - 0.5).abs() < 0.2 && texel.z < 0.2);
+            assert_eq!(image.texels[0], Vec3::zero());
+        }
+    }
+
+    #[test]
+    fn png_checksums() {
+        let table = crc32_table();
+        assert_eq!(crc32(&table, &[b"IEND"]), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+    }
+
+    #[test]
+    fn png_ends_with_iend() {
+        let framebuffer = Framebuffer::new(4, 4);
+        let mut data = Vec::new();
+        write_png(&mut data, &framebuffer).unwrap();
+        assert!(data.starts_with(b"\x89PNG\r\n\x1a\n"));
+        assert!(data.ends_with(b"IEND\xae\x42\x60\x82"));
+    }
+}
@@ -0,0 +1,64 @@
+//! The core of tinyraytracer: scene description, geometry, materials and a
+//! CPU ray tracer, independent of any windowing. The interactive SDL viewer is
+//! a binary built on top of this library.
+//!
+//! Rendering a scene to a buffer of linear colours:
+//!
+//! ```no_run
+//! use tinyraytracer::camera::Camera;
+//! use tinyraytracer::render::Renderer;
+//! use tinyraytracer::scene::Scene;
+//!
+//! let scene = Scene::default_scene();
+//! let colours = Renderer::default().render_to_buffer(&scene, &Camera::default(), 320, 240);
+//! assert_eq!(colours.len(), 320 * 240);
+//! ```
+
+pub mod animation;
+pub mod aov;
+pub mod archive;
+pub mod autosave;
+pub mod bake;
+pub mod budget;
+pub mod bvh;
+pub mod cache;
+pub mod camera;
+pub mod collision;
+pub mod compare;
+pub mod curves;
+pub mod deep;
+pub mod denoise;
+pub mod diagnostics;
+pub mod environment;
+pub mod exr;
+pub mod film;
+pub mod framebuffer;
+pub mod geometry;
+pub mod gltf;
+pub mod gradient;
+pub mod image;
+pub mod lights;
+pub mod marching;
+pub mod materials;
+pub mod mesh;
+pub mod motion;
+pub mod obj;
+pub mod palette;
+pub mod panorama;
+pub mod patch;
+pub mod points;
+pub mod probes;
+pub mod queue;
+pub mod ray_tree;
+pub mod render;
+pub mod sampling;
+pub mod scatter;
+pub mod scene;
+pub mod simulation;
+pub mod simplify;
+pub mod sky;
+pub mod tessellate;
+pub mod triggers;
+pub mod wavefront;
+
+pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
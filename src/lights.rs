@@ -0,0 +1,187 @@
+use crate::geometry::Vec3;
+use crate::scene::{Light, Links};
+
+use serde::{Deserialize, Serialize};
+
+// Chooses lights at random in proportion to their power, so that shading a
+// point in a scene with many lights only has to trace shadow rays towards a
+// few of them. Lights with negative intensity are chosen by magnitude.
+#[derive(Clone, Debug)]
+pub struct LightSampler {
+    // Running totals of normalised power, ending at 1 (or 0 if no light can be
+    // chosen)
+    cdf: Vec<f32>,
+}
+
+impl LightSampler {
+    pub fn new(lights: &[Light]) -> Self {
+        Self::with_filter(lights, |_| true)
+    }
+
+    // Samples only the lights for which `include(index)` is true, never
+    // choosing the others
+    pub fn with_filter<F: Fn(usize) -> bool>(lights: &[Light], include: F) -> Self {
+        let powers: Vec<f32> = lights
+            .iter()
+            .enumerate()
+            .map(|(i, light)| if include(i) { light.intensity.abs() } else { 0.0 })
+            .collect();
+
+        let total: f32 = powers.iter().sum();
+        let included = (0..lights.len()).filter(|&i| include(i)).count();
+
+        // Without any power to go on every included light is equally likely
+        let mut sum = 0.0;
+        let cdf = powers
+            .iter()
+            .enumerate()
+            .map(|(i, &power)| {
+                sum += if total > 0.0 {
+                    power / total
+                } else if include(i) {
+                    1.0 / included as f32
+                } else {
+                    0.0
+                };
+                sum
+            })
+            .collect();
+
+        LightSampler { cdf }
+    }
+
+    // Probability of choosing the given light
+    pub fn pdf(&self, light: usize) -> f32 {
+        let previous = if light == 0 { 0.0 } else { self.cdf[light - 1] };
+        self.cdf[light] - previous
+    }
+
+    // Index of the light chosen by the uniform random number `u` in [0, 1), or
+    // none if there's nothing to choose from
+    pub fn sample(&self, u: f32) -> Option<usize> {
+        if *self.cdf.last()? <= 0.0 {
+            return None;
+        }
+
+        Some(self.cdf.partition_point(|&c| c <= u).min(self.cdf.len() - 1))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Region {
+    Sphere { centre: Vec3<f32>, radius: f32 },
+    Box { min: Vec3<f32>, max: Vec3<f32> },
+}
+
+impl Region {
+    // Distance from the point to the region's surface, negative inside
+    pub fn signed_distance(&self, point: Vec3<f32>) -> f32 {
+        match *self {
+            Region::Sphere { centre, radius } => (point - centre).length() - radius,
+            Region::Box { min, max } => {
+                let centre = (min + max) * 0.5;
+                let half = (max - min) * 0.5;
+                let d = point - centre;
+                let q = Vec3::new(d.x.abs() - half.x, d.y.abs() - half.y, d.z.abs() - half.z);
+
+                let outside = Vec3::new(q.x.max(0.0), q.y.max(0.0), q.z.max(0.0)).length();
+                let inside = q.x.max(q.y.max(q.z)).min(0.0);
+                outside + inside
+            }
+        }
+    }
+}
+
+// A volume that takes away a fraction `strength` of the direct light reaching
+// points inside it, fading out over `falloff` beyond its surface. Its `lights`
+// links choose which lights it blocks.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Blocker {
+    pub region: Region,
+    pub strength: f32,
+    #[serde(default)]
+    pub falloff: f32,
+    #[serde(default)]
+    pub lights: Links,
+}
+
+impl Blocker {
+    // Fraction of light `light` that still reaches `point`
+    pub fn transmission(&self, light: usize, point: Vec3<f32>) -> f32 {
+        if !self.lights.allows(light) {
+            return 1.0;
+        }
+
+        let distance = self.region.signed_distance(point);
+
+        let coverage = if distance <= 0.0 {
+            1.0
+        } else if distance < self.falloff {
+            // Smoothstep from the surface out to the edge of the falloff
+            let t = 1.0 - distance / self.falloff;
+            t * t * (3.0 - 2.0 * t)
+        } else {
+            0.0
+        };
+
+        1.0 - self.strength.clamp(0.0, 1.0) * coverage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_in_proportion_to_power() {
+        let lights: Vec<Light> = [1.0, 0.0, 3.0]
+            .iter()
+            .map(|&intensity| Light::new(Vec3::zero(), intensity))
+            .collect();
+        let sampler = LightSampler::new(&lights);
+
+        assert_eq!(sampler.pdf(0), 0.25);
+        assert_eq!(sampler.pdf(1), 0.0);
+        assert_eq!(sampler.pdf(2), 0.75);
+
+        assert_eq!(sampler.sample(0.0), Some(0));
+        assert_eq!(sampler.sample(0.24), Some(0));
+        assert_eq!(sampler.sample(0.25), Some(2));
+        assert_eq!(sampler.sample(0.999), Some(2));
+    }
+
+    #[test]
+    fn filtered_lights_are_never_chosen() {
+        let lights: Vec<Light> = (0..4).map(|_| Light::new(Vec3::zero(), 0.0)).collect();
+
+        let sampler = LightSampler::with_filter(&lights, |i| i == 1 || i == 3);
+        assert_eq!(sampler.pdf(0), 0.0);
+        assert_eq!(sampler.pdf(1), 0.5);
+        assert_eq!(sampler.sample(0.0), Some(1));
+        assert_eq!(sampler.sample(0.6), Some(3));
+
+        let none = LightSampler::with_filter(&lights, |_| false);
+        assert_eq!(none.sample(0.5), None);
+    }
+
+    #[test]
+    fn blocker_fades_out() {
+        let blocker = Blocker {
+            region: Region::Box {
+                min: Vec3::new(-1.0, -1.0, -1.0),
+                max: Vec3::new(1.0, 1.0, 1.0),
+            },
+            strength: 0.8,
+            falloff: 2.0,
+            lights: Links::default(),
+        };
+
+        assert!((blocker.transmission(0, Vec3::zero()) - 0.2).abs() < 1e-6);
+        assert!((blocker.transmission(0, Vec3::new(2.0, 0.0, 0.0)) - 0.6).abs() < 1e-6);
+        assert_eq!(blocker.transmission(0, Vec3::new(0.0, 3.5, 0.0)), 1.0);
+
+        let mut unlinked = blocker.clone();
+        unlinked.lights.except.insert(0);
+        assert_eq!(unlinked.transmission(0, Vec3::zero()), 1.0);
+    }
+}
@@ -1,261 +1,1613 @@
-mod geometry;
-mod materials;
+mod control;
+mod view;
 
-use crate::geometry::{Ray, Sphere, Vec2, Vec3, dot, reflect};
-use crate::materials::Material;
+use crate::control::{Axis, Binding, OscListener, Target};
+use crate::view::{MAX_RENDER_SCALE, MIN_RENDER_SCALE, Resolution, View};
 
-use sdl2::pixels::Color;
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::render::Canvas;
-use sdl2::video::Window;
+use tinyraytracer::Result;
+use tinyraytracer::animation;
+use tinyraytracer::autosave::{self, Autosave, Snapshot};
+use tinyraytracer::bake::{self, BakeSettings};
+use tinyraytracer::budget::{self, MemoryBudget};
+use tinyraytracer::cache::ShadingCache;
+use tinyraytracer::camera::{Camera, DEFAULT_FOV, MAX_FOV, MIN_FOV};
+use tinyraytracer::compare::Comparison;
+use tinyraytracer::deep;
+use tinyraytracer::denoise::{self, Guides};
+use tinyraytracer::diagnostics::{self, Overlay, Preview};
+use tinyraytracer::environment::Environment;
+use tinyraytracer::exr;
+use tinyraytracer::film;
+use tinyraytracer::framebuffer::{Framebuffer, Tile};
+use tinyraytracer::geometry::{Shape, Vec3};
+use tinyraytracer::gltf;
+use tinyraytracer::gradient;
+use tinyraytracer::image;
+use tinyraytracer::motion::{self, MotionVectors};
+use tinyraytracer::obj;
+use tinyraytracer::palette;
+use tinyraytracer::panorama::{self, Projection};
+use tinyraytracer::patch;
+use tinyraytracer::probes::Probes;
+use tinyraytracer::queue::{Job, Priority, RenderQueue, Status};
+use tinyraytracer::ray_tree::{self, RayTree};
+use tinyraytracer::render::{self, RenderMode, Renderer, TraceSettings, Tracer};
+use tinyraytracer::sampling::{self, Sampling};
+use tinyraytracer::scene::{self, Scene};
+use tinyraytracer::simulation::Simulation;
+use tinyraytracer::triggers;
+use tinyraytracer::wavefront;
 
-use std::time::Instant;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, KeyboardState, Scancode};
+use sdl2::mouse::MouseButton;
 
-type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const NANOS_PER_SEC: u32 = 1_000_000_000;
 
+const TITLE: &str = "tinyraytracer-rs";
+const SECOND_TITLE: &str = "tinyraytracer-rs (second view)";
+
 const WIDTH: i32 = 1024;
 const HEIGHT: i32 = 768;
-const FOV: f32 = (std::f32::consts::PI / 2.0) as u32 as f32;
 
-const BACKGROUND_COLOUR: Vec3<f32> = Vec3 {
-    x: 0.2,
-    y: 0.7,
-    z: 0.8,
-};
+// Camera movement per update tick and rotation per pixel of mouse motion
+const CAMERA_SPEED: f32 = 0.2;
+const CAMERA_TURN_SPEED: f32 = 0.02;
+const MOUSE_SENSITIVITY: f32 = 0.003;
+const ZOOM_SPEED: f32 = 0.05;
+
+// Change in render scale per press of - or =
+const RENDER_SCALE_STEP: f32 = 0.25;
+
+// Change in field of view per press of [ or ], in degrees
+const FOV_STEP: f32 = 5.0;
+
+// Touch input: finger motion is reported as a fraction of the window size and
+// pinches as a fraction of the diagonal
+const TOUCH_ORBIT_SPEED: f32 = 3.0;
+const TOUCH_ORBIT_DISTANCE: f32 = 15.0;
+const PINCH_ZOOM_SPEED: f32 = 4.0;
+
+const DEFAULT_OSC_PORT: u16 = 9000;
+
+// Beauty shots are rendered at this multiple of the window resolution along
+// each axis, with this many samples per pixel
+const BEAUTY_SCALE: usize = 4;
+const BEAUTY_SAMPLES: usize = 16;
+
+// The interactive view stops refining once this many samples per pixel have
+// been averaged
+const MAX_ACCUMULATED_SAMPLES: usize = 256;
+
+// A region render refines faster and further, since it covers fewer pixels
+const REGION_SAMPLES_PER_FRAME: usize = 4;
+const MAX_REGION_SAMPLES: usize = 1024;
+
+// Drags shorter than this many pixels count as clicks
+const DRAG_THRESHOLD: f32 = 4.0;
+
+const REGION_OUTLINE: [u8; 3] = [255, 200, 0];
+
+// Stride of the coarsest grid of pixels in the preview of a first sample, and
+// how long a frame can spend refining the preview before it's shown
+const PREVIEW_STRIDE: usize = 8;
+const PREVIEW_BUDGET: Duration = Duration::from_millis(30);
 
-struct Light {
-    position: Vec3<f32>,
-    intensity: f32,
+// Size of the cells shadows are cached in, in world units
+const SHADING_CACHE_CELL: f32 = 0.1;
+
+// Colours in the palettes shapes are recoloured from, and spheres in the
+// spheres test scene
+const PALETTE_SIZE: usize = 6;
+
+// Frames per second of exported animations
+const EXPORT_FRAME_RATE: u32 = 60;
+const SPHERE_FIELD_COUNT: usize = 200;
+
+fn set_axis(v: &mut Vec3<f32>, axis: Axis, value: f32) {
+    match axis {
+        Axis::X => v.x = value,
+        Axis::Y => v.y = value,
+        Axis::Z => v.z = value,
+    }
 }
 
-impl Light {
-    fn new(position: Vec3<f32>, intensity: f32) -> Self {
-        Light {
-            position,
-            intensity,
+fn apply_control(
+    state: &mut Scene,
+    settings: &mut TraceSettings,
+    bindings: &[Binding],
+    address: &str,
+    value: f32,
+) {
+    for binding in bindings.iter().filter(|b| b.address == address) {
+        let value = binding.map(value);
+
+        // Bindings referring to objects that don't exist are silently ignored
+        match binding.target {
+            Target::LightIntensity(i) => {
+                if let Some(light) = state.lights.get_mut(i) {
+                    light.intensity = value;
+                }
+            }
+            Target::LightPosition(i, axis) => {
+                if let Some(light) = state.lights.get_mut(i) {
+                    set_axis(&mut light.position, axis, value);
+                }
+            }
+            Target::ShapeCentre(i, axis) => {
+                if let Some(shape) = state.shapes.get_mut(i) {
+                    let mut centre = shape.centre();
+                    set_axis(&mut centre, axis, value);
+                    shape.translate(centre - shape.centre());
+                }
+            }
+            Target::SphereRadius(i) => {
+                if let Some(Shape::Sphere(sphere)) = state.shapes.get_mut(i) {
+                    sphere.radius = value;
+                }
+            }
+            Target::MaxDepth => settings.max_depth = value.round().max(0.0) as u32,
+            Target::RouletteStartDepth => settings.rr_start_depth = value.round().max(0.0) as u32,
+            Target::RouletteMinProbability => settings.rr_min_probability = value.clamp(0.01, 1.0),
+            Target::LightSamples => settings.light_samples = value.round().max(0.0) as usize,
         }
     }
 }
 
-struct State {
-    spheres: Vec<Sphere>,
-    lights: Vec<Light>,
+// Moves the camera by `ticks` updates' worth at the reference rate, so it
+// moves as fast whatever the simulation's update rate
+fn update_camera(camera: &mut Camera, keyboard: &KeyboardState, ticks: f32) {
+    let axis = |positive, negative| {
+        let mut value = 0.0;
+        if keyboard.is_scancode_pressed(positive) {
+            value += 1.0;
+        }
+        if keyboard.is_scancode_pressed(negative) {
+            value -= 1.0;
+        }
+        value * ticks
+    };
+
+    camera.translate(
+        axis(Scancode::W, Scancode::S) * CAMERA_SPEED,
+        axis(Scancode::D, Scancode::A) * CAMERA_SPEED,
+        axis(Scancode::E, Scancode::Q) * CAMERA_SPEED,
+    );
+
+    camera.rotate(
+        axis(Scancode::Right, Scancode::Left) * CAMERA_TURN_SPEED,
+        axis(Scancode::Up, Scancode::Down) * CAMERA_TURN_SPEED,
+    );
 }
 
-fn clamp(x: f32, min: f32, max: f32) -> f32 {
-    if x < min {
-        min
-    } else if x > max {
-        max
-    } else {
-        x
+// Number keys step the trace settings of the focused window down and up in
+// pairs: 1/2 the maximum depth, 3/4 the depth Russian roulette starts from,
+// 5/6 its minimum survival probability and 7/8 the number of lights sampled
+// per shading point
+fn adjust_settings(settings: &mut TraceSettings, keycode: Keycode) {
+    match keycode {
+        Keycode::Num1 => settings.max_depth = settings.max_depth.saturating_sub(1),
+        Keycode::Num2 => settings.max_depth += 1,
+        Keycode::Num3 => settings.rr_start_depth = settings.rr_start_depth.saturating_sub(1),
+        Keycode::Num4 => settings.rr_start_depth += 1,
+        Keycode::Num5 => settings.rr_min_probability = (settings.rr_min_probability - 0.05).max(0.05),
+        Keycode::Num6 => settings.rr_min_probability = (settings.rr_min_probability + 0.05).min(1.0),
+        Keycode::Num7 => settings.light_samples = settings.light_samples.saturating_sub(1),
+        Keycode::Num8 => settings.light_samples += 1,
+        _ => {}
     }
 }
 
-fn clamp_to_u8(x: f32, min: f32, max: f32) -> u8 {
-    (255.0 * clamp(x, min, max)) as u8
+fn timestamped_path(prefix: &str, extension: &str) -> Result<String> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    Ok(format!("{}-{}.{}", prefix, timestamp, extension))
+}
+
+fn save_screenshot(framebuffer: &Framebuffer) -> Result<String> {
+    let path = timestamped_path("screenshot", "png")?;
+    image::save(framebuffer, &path)?;
+    Ok(path)
+}
+
+// The motion over the last frame, as seen in the main view
+fn save_motion(view: &View, state: &Scene, (previous, ticks): (Camera, f32)) -> Result<String> {
+    let path = timestamped_path("motion", "flo")?;
+    let (width, height) = (view.framebuffer.width, view.framebuffer.height);
+    MotionVectors::new(state, &view.camera, &previous, ticks, width, height).save(&path)?;
+    Ok(path)
+}
+
+// Renders a high quality still of a snapshot of the scene on a background
+// thread, so the interactive window keeps running in the meantime. Errors are
+// returned as strings since boxed errors can't be sent between threads.
+fn spawn_beauty_shot(
+    state: Scene,
+    camera: Camera,
+    settings: TraceSettings,
+    width: usize,
+    height: usize,
+) -> JoinHandle<std::result::Result<String, String>> {
+    thread::spawn(move || {
+        let mut framebuffer = Framebuffer::new(width * BEAUTY_SCALE, height * BEAUTY_SCALE);
+        let renderer = Renderer {
+            samples: BEAUTY_SAMPLES,
+            settings,
+            ..Renderer::default()
+        };
+        renderer.render(&mut framebuffer, &camera, &state);
+
+        let path = timestamped_path("beauty", "png").map_err(|e| e.to_string())?;
+        image::save(&framebuffer, &path).map_err(|e| e.to_string())?;
+        Ok(path)
+    })
 }
 
-fn scene_intersect(ray: &Ray, spheres: &[Sphere]) -> Option<(Vec3<f32>, Vec3<f32>, Material)> {
-    let mut spheres_distance = std::f32::MAX;
+// Adds one more sample per pixel to the view's accumulated image, unless it
+// already has plenty, in which case the pixels are just refreshed ready for
+// overlays to be drawn over them again. The first sample is refined coarse to
+// fine for as long as the frame's budget allows, carrying on next frame. With
+// a region set, the rest of the image stops at a single preview sample while
+// the region gets several samples per frame. Reflection probes, when given,
+// stand in for traced reflections, and a shading cache for most shadow rays.
+// Samples are seeded as a headless render with the given seed would seed them.
+fn render_view(
+    view: &mut View,
+    state: &Scene,
+    scene_changed: bool,
+    seed: u64,
+    probes: Option<&Probes>,
+    cache: Option<&ShadingCache>,
+) {
+    view.restart_if_changed(scene_changed);
+
+    let (width, height) = (view.framebuffer.width, view.framebuffer.height);
+    let camera = view.camera;
+    let mut tracer = Tracer::new(state, view.mode, view.settings);
+    if let Some(probes) = probes {
+        tracer = tracer.with_probes(probes);
+    }
+    if let Some(cache) = cache {
+        tracer = tracer.with_cache(cache);
+    }
+    let bounds = view.framebuffer.bounds();
+
+    let add_sample = |framebuffer: &mut Framebuffer, region, k| {
+        // Each pixel's samples are shifted by blue noise, so the first few
+        // give fine grain rather than jagged edges. The shifts don't change
+        // from frame to frame, so a moving scene doesn't shimmer.
+        framebuffer.accumulate(region, k, |i, j| {
+            let (dx, dy) = sampling::dithered_r2(i, j, k);
+            let ray = camera.ray_through(i as f32 + dx, j as f32 + dy, width, height);
+            tracer.shade(&ray, sampling::seed(j * width + i, k) ^ seed)
+        });
+    };
+
+    match view.region() {
+        _ if view.accumulated == 0 => {
+            // Anything that restarts accumulation may change the guides
+            view.guides = None;
+            view.denoised_at = None;
 
-    let mut hit = Vec3::default();
-    let mut normal = Vec3::default();
-    let mut material = Material::default();
+            let start = Instant::now();
+
+            while view.preview_stride != 1 && start.elapsed() < PREVIEW_BUDGET {
+                let done = view.preview_stride;
+                let stride = if done == 0 { PREVIEW_STRIDE } else { done / 2 };
+
+                view.framebuffer.refine(stride, done, |i, j| {
+                    let (dx, dy) = sampling::dithered_r2(i, j, 0);
+                    let ray = camera.ray_through(i as f32 + dx, j as f32 + dy, width, height);
+                    tracer.shade(&ray, sampling::seed(j * width + i, 0) ^ seed)
+                });
+                view.preview_stride = stride;
+            }
 
-    for sphere in spheres {
-        if let Some(distance) = sphere.ray_intersect(ray) {
-            if distance < spheres_distance {
-                spheres_distance = distance;
-                hit = ray.origin + ray.direction * distance;
-                normal = (hit - sphere.centre).normalise();
-                material = sphere.material;
+            if view.preview_stride == 1 {
+                view.accumulated = 1;
+                view.region_accumulated = 1;
             }
         }
+        Some(region) => {
+            let samples = REGION_SAMPLES_PER_FRAME.min(MAX_REGION_SAMPLES.saturating_sub(view.region_accumulated));
+
+            for _ in 0..samples {
+                add_sample(&mut view.framebuffer, region, view.region_accumulated);
+                view.region_accumulated += 1;
+            }
+
+            // Pixels outside the region aren't rewritten, so clear anything
+            // drawn over them last frame
+            view.framebuffer.refresh_pixels();
+        }
+        None if view.accumulated < MAX_ACCUMULATED_SAMPLES => {
+            add_sample(&mut view.framebuffer, bounds, view.accumulated);
+            view.accumulated += 1;
+        }
+        None => view.framebuffer.refresh_pixels(),
     }
 
-    const MAX_DISTANCE: f32 = 1000.0;
+    if view.denoise {
+        show_denoised(view, state);
+    }
+    show_preview(view);
+}
+
+// Displays the view's image through its preview transform, if it has one
+fn show_preview(view: &mut View) {
+    let colours = if view.denoise { &view.denoised } else { &view.framebuffer.colours };
+    if let Some(shown) = diagnostics::preview(view.preview, colours) {
+        view.framebuffer.show(&shown);
+    }
+}
+
+// Displays the view's image with the noise filtered out, refiltering only
+// when more samples have been added since last time
+fn show_denoised(view: &mut View, state: &Scene) {
+    let (width, height) = (view.framebuffer.width, view.framebuffer.height);
+    let samples = (view.accumulated, view.region_accumulated);
+
+    if view.denoised_at != Some(samples) {
+        if view.guides.as_ref().is_none_or(|guides| (guides.width, guides.height) != (width, height)) {
+            view.guides = Some(Guides::render(state, &view.camera, width, height));
+        }
+
+        let guides = view.guides.as_ref().unwrap();
+        view.denoised = denoise::a_trous(&view.framebuffer.colours, guides);
+        view.denoised_at = Some(samples);
+    }
+
+    view.framebuffer.show(&view.denoised);
+}
+
+// The rectangle spanned by two corners given in framebuffer pixels
+fn rect_between(a: (f32, f32), b: (f32, f32)) -> Tile {
+    let (x0, x1) = (a.0.min(b.0).max(0.0) as usize, a.0.max(b.0).max(0.0) as usize);
+    let (y0, y1) = (a.1.min(b.1).max(0.0) as usize, a.1.max(b.1).max(0.0) as usize);
+
+    Tile { x: x0, y: y0, width: x1 - x0 + 1, height: y1 - y0 + 1 }
+}
+
+// Every ray traced for the view's pixel at a position given in framebuffer
+// pixels
+fn record_view_pixel(view: &View, state: &Scene, (x, y): (f32, f32)) -> RayTree {
+    let (width, height) = (view.framebuffer.width, view.framebuffer.height);
+    let (x, y) = ((x as usize).min(width - 1), (y as usize).min(height - 1));
+
+    let renderer = Renderer {
+        mode: view.mode,
+        settings: view.settings,
+        ..Renderer::default()
+    };
+    renderer.record_pixel(state, &view.camera, width, height, x, y)
+}
+
+// Exports every ray traced for the view's pixel under the mouse cursor, for
+// looking at in a 3D viewer
+fn save_ray_tree(view: &View, state: &Scene, mouse: (i32, i32)) -> Result<String> {
+    let tree = record_view_pixel(view, state, view.pixel_at(mouse.0, mouse.1));
+
+    let path = timestamped_path("rays", "obj")?;
+    ray_tree::save(&tree, &path)?;
+    Ok(path)
+}
+
+// Saves the scene as it is now alongside the main view's accumulated image,
+// to be resumed later with --resume
+fn save_session(view: &View, state: &Scene) -> Result<(String, String)> {
+    let scene_path = timestamped_path("session", "ron")?;
+    let film_path = timestamped_path("session", "film")?;
+
+    scene::save(state, &scene_path)?;
+    film::save(&view.film(), &view.framebuffer, &film_path)?;
+
+    Ok((scene_path, film_path))
+}
+
+// Asks on the terminal whether to restore the snapshot left by a session that
+// crashed, discarding it if not
+fn offer_autosave(path: &str) -> Result<Option<Snapshot>> {
+    let snapshot = match autosave::load(path) {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            eprintln!("warning: ignoring unreadable autosave {}: {}", path, e);
+            return Ok(None);
+        }
+    };
+
+    let minutes = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs().saturating_sub(snapshot.saved_at) / 60;
+    let source = snapshot.source.as_deref().unwrap_or("a built-in scene");
+    print!("found an autosave of {} from {} minutes ago, left by a session that didn't exit cleanly. ", source, minutes);
+    print!("Restore it? [y/N] ");
+    io::stdout().flush()?;
 
-    if spheres_distance < MAX_DISTANCE {
-        Some((hit, normal, material))
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    if answer.trim().eq_ignore_ascii_case("y") {
+        Ok(Some(snapshot))
     } else {
-        None
+        fs::remove_file(path)?;
+        Ok(None)
     }
 }
 
-fn cast_ray(ray: &Ray, spheres: &[Sphere], lights: &[Light]) -> Option<Vec3<f32>> {
-    if let Some((point, normal, material)) = scene_intersect(ray, spheres) {
-        let mut diffuse_intensity = 0.0;
-        let mut specular_intensity = 0.0;
+fn print_selection(state: &Scene, selected: Option<usize>) {
+    match selected {
+        Some(i) => {
+            let hidden = if state.is_visible(i) { "" } else { " (hidden)" };
+            println!("selected shape {}: {}{}", i, state.shapes[i].name(), hidden);
+        }
+        None => println!("nothing selected"),
+    }
+}
 
-        for light in lights {
-            let light_direction = (light.position - point).normalise();
-            let light_distance = (light.position - point).length();
+struct Options {
+    scene: Option<String>,
+    // A built-in scene to use when no scene file is given
+    test_scene: Option<String>,
+    dump_scene: Option<String>,
+    // Patches applied to the scene as it's loaded, in order
+    patches: Vec<String>,
+    // A scene to compare the loaded one with, printing what differs
+    diff: Option<String>,
+    // Where to write the differences as a patch
+    write_patch: Option<String>,
+    bindings: Option<String>,
+    osc_port: u16,
+    output: Option<String>,
+    ray_tree: Option<String>,
+    // Render everything around the camera in this projection
+    panorama: Option<Projection>,
+    // Reproject an image from one projection to another instead of rendering
+    convert: Option<(String, Projection, Projection)>,
+    // Index of a mesh to bake a texture for instead of rendering
+    bake: Option<usize>,
+    bake_settings: BakeSettings,
+    // Where to write the motion of the scene over one update tick
+    motion: Option<String>,
+    // Where to write a deep image keeping every surface seen in each pixel
+    deep: Option<String>,
+    // Animation paths of numbers to render the image's gradients with respect
+    // to, alongside the image
+    gradients: Vec<String>,
+    // Where to write the scene as .gltf, .glb or .obj instead of rendering
+    export: Option<String>,
+    // How many frames of animation to render, numbered after the output
+    frames: Option<usize>,
+    // Make every other exported frame from its neighbours' motion vectors
+    interpolate_frames: bool,
+    explain: bool,
+    pixel: Option<(usize, usize)>,
+    width: usize,
+    height: usize,
+    samples: usize,
+    sampling: Sampling,
+    settings: TraceSettings,
+    // Vertical field of view in radians, given in degrees on the command line
+    fov: f32,
+    resolution: Resolution,
+    // Mixed into every sample's random numbers, headless and interactive
+    seed: u64,
+    // Images already come out the same for the same scene, seed and settings
+    // whatever the number of threads. Deterministic mode makes that hold for
+    // interactive views too, by refusing to turn on the shading cache (what
+    // it holds depends on the order threads reach it), and gives each
+    // exported frame its own seed derived from the seed and the frame number,
+    // so its noise isn't repeated from frame to frame.
+    deterministic: bool,
+    // Threads to render headless images with, one per core by default
+    threads: Option<usize>,
+    // How often to snapshot the session while it changes, if at all
+    autosave: Option<Duration>,
+    // Megabytes the scene's meshes may take, dropping levels not in use to
+    // keep to it
+    memory_budget: Option<usize>,
+    resume: Option<String>,
+    // An image to compare the view against, wiped over it
+    reference: Option<String>,
+    environment: Option<String>,
+    clay: bool,
+    // Render headless frames with the queue-based tracer
+    wavefront: bool,
+    // Replace physically impossible materials rather than just warning
+    normalise_materials: bool,
+    // Recolour every shape from a palette, given as a seed for generated
+    // colours or an image to take them from
+    palette: Option<String>,
+    simulation: Simulation,
+}
 
-            let shadow_origin = if dot(light_direction, normal) < 0.0 {
-                point - normal*1.0e-3
-            } else {
-                point + normal*1.0e-3
-            };
+fn parse_args() -> Result<Options> {
+    let mut options = Options {
+        scene: None,
+        test_scene: None,
+        dump_scene: None,
+        patches: Vec::new(),
+        diff: None,
+        write_patch: None,
+        bindings: None,
+        osc_port: DEFAULT_OSC_PORT,
+        output: None,
+        ray_tree: None,
+        panorama: None,
+        convert: None,
+        bake: None,
+        bake_settings: BakeSettings::default(),
+        motion: None,
+        deep: None,
+        gradients: Vec::new(),
+        export: None,
+        frames: None,
+        interpolate_frames: false,
+        explain: false,
+        pixel: None,
+        width: WIDTH as usize,
+        height: HEIGHT as usize,
+        samples: 1,
+        sampling: Sampling::R2,
+        settings: TraceSettings::default(),
+        fov: DEFAULT_FOV,
+        resolution: Resolution::Scale(1.0),
+        seed: 0,
+        deterministic: false,
+        threads: None,
+        memory_budget: None,
+        autosave: Some(autosave::DEFAULT_INTERVAL),
+        resume: None,
+        reference: None,
+        environment: None,
+        clay: false,
+        wavefront: false,
+        normalise_materials: false,
+        palette: None,
+        simulation: Simulation::default(),
+    };
 
-            let shadow_ray = Ray {
-                origin: shadow_origin,
-                direction: light_direction,
-            };
+    let mut args = std::env::args().skip(1);
 
-            if let Some((shadow_point, _, _)) = scene_intersect(&shadow_ray, spheres) {
-                if (shadow_point - shadow_origin).length() < light_distance {
-                    continue;
-                }
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bindings" => {
+                options.bindings = Some(args.next().ok_or("--bindings requires a path")?);
+            }
+            "--osc-port" => {
+                options.osc_port = args.next().ok_or("--osc-port requires a port")?.parse()?;
+            }
+            "--test-scene" => {
+                options.test_scene = Some(args.next().ok_or("--test-scene requires fur-ball, grass, field, switch or spheres")?);
+            }
+            "--dump-scene" => {
+                options.dump_scene = Some(args.next().ok_or("--dump-scene requires a path")?);
+            }
+            "--patch" => {
+                options.patches.push(args.next().ok_or("--patch requires a path")?);
+            }
+            "--diff" => {
+                options.diff = Some(args.next().ok_or("--diff requires a scene path")?);
+            }
+            "--write-patch" => {
+                options.write_patch = Some(args.next().ok_or("--write-patch requires a path")?);
+            }
+            "--output" => {
+                options.output = Some(args.next().ok_or("--output requires a path")?);
+            }
+            "--ray-tree" => {
+                options.ray_tree = Some(args.next().ok_or("--ray-tree requires a path")?);
+            }
+            "--panorama" => {
+                options.panorama = Some(args.next().ok_or("--panorama requires a projection")?.parse()?);
+            }
+            "--convert" => {
+                let usage = "--convert requires an image and the projections to convert from and to";
+                let path = args.next().ok_or(usage)?;
+                let from = args.next().ok_or(usage)?.parse()?;
+                let to = args.next().ok_or(usage)?.parse()?;
+                options.convert = Some((path, from, to));
+            }
+            "--bake" => {
+                options.bake = Some(args.next().ok_or("--bake requires a shape index")?.parse()?);
+            }
+            "--bake-mode" => {
+                options.bake_settings.mode = args.next().ok_or("--bake-mode requires ao or lightmap")?.parse()?;
+            }
+            "--bake-size" => {
+                options.bake_settings.size = args.next().ok_or("--bake-size requires a value")?.parse()?;
+            }
+            "--bake-samples" => {
+                options.bake_settings.samples = args.next().ok_or("--bake-samples requires a value")?.parse()?;
+            }
+            "--motion" => {
+                options.motion = Some(args.next().ok_or("--motion requires a path")?);
+            }
+            "--deep" => {
+                options.deep = Some(args.next().ok_or("--deep requires a path")?);
+            }
+            "--export" => {
+                options.export = Some(args.next().ok_or("--export requires a path")?);
+            }
+            "--gradient" => {
+                options.gradients.push(args.next().ok_or("--gradient requires a path such as lights[0].intensity")?);
+            }
+            "--frames" => {
+                options.frames = Some(args.next().ok_or("--frames requires a number")?.parse()?);
+            }
+            "--interpolate-frames" => options.interpolate_frames = true,
+            "--pixel" => {
+                let pixel = args.next().ok_or("--pixel requires a position x,y")?;
+                let (x, y) = pixel.split_once(',').ok_or("--pixel requires a position x,y")?;
+                options.pixel = Some((x.trim().parse()?, y.trim().parse()?));
+            }
+            "--width" => {
+                options.width = args.next().ok_or("--width requires a value")?.parse()?;
+            }
+            "--height" => {
+                options.height = args.next().ok_or("--height requires a value")?.parse()?;
+            }
+            "--seed" => {
+                options.seed = args.next().ok_or("--seed requires a number")?.parse()?;
+            }
+            "--deterministic" => options.deterministic = true,
+            "--threads" => {
+                let threads: usize = args.next().ok_or("--threads requires a number")?.parse()?;
+                options.threads = Some(threads.max(1));
+            }
+            "--autosave" => {
+                let seconds: u64 = args.next().ok_or("--autosave requires a number of seconds, or 0 for none")?.parse()?;
+                options.autosave = Some(Duration::from_secs(seconds)).filter(|_| seconds > 0);
+            }
+            "--memory-budget" => {
+                let megabytes: usize = args.next().ok_or("--memory-budget requires a size in megabytes")?.parse()?;
+                options.memory_budget = Some(megabytes * budget::MEGABYTE);
+            }
+            "--samples" => {
+                options.samples = args.next().ok_or("--samples requires a value")?.parse()?;
+            }
+            "--fov" => {
+                let degrees: f32 = args.next().ok_or("--fov requires a value in degrees")?.parse()?;
+                options.fov = degrees.to_radians().clamp(MIN_FOV, MAX_FOV);
+            }
+            "--resolution" => {
+                options.resolution = args.next().ok_or("--resolution requires a preset or percentage")?.parse()?;
+            }
+            "--resume" => {
+                options.resume = Some(args.next().ok_or("--resume requires a film path")?);
+            }
+            "--reference" => {
+                options.reference = Some(args.next().ok_or("--reference requires an image path")?);
+            }
+            "--environment" => {
+                options.environment = Some(args.next().ok_or("--environment requires a path")?);
+            }
+            "--sampling" => {
+                options.sampling = args.next().ok_or("--sampling requires r2, jittered, random or blue-noise")?.parse()?;
+            }
+            "--max-depth" => {
+                options.settings.max_depth = args.next().ok_or("--max-depth requires a value")?.parse()?;
             }
+            "--rr-start-depth" => {
+                options.settings.rr_start_depth = args.next().ok_or("--rr-start-depth requires a value")?.parse()?;
+            }
+            "--rr-min-probability" => {
+                options.settings.rr_min_probability =
+                    args.next().ok_or("--rr-min-probability requires a value")?.parse()?;
+            }
+            "--light-samples" => {
+                options.settings.light_samples = args.next().ok_or("--light-samples requires a value")?.parse()?;
+            }
+            "--environment-samples" => {
+                options.settings.environment_samples =
+                    args.next().ok_or("--environment-samples requires a value")?.parse()?;
+            }
+            "--clay" => options.clay = true,
+            "--wavefront" => options.wavefront = true,
+            "--update-rate" => {
+                let rate = args.next().ok_or("--update-rate requires updates per second")?.parse()?;
+                options.simulation = Simulation { updates_per_second: rate, ..options.simulation }.clamped();
+            }
+            "--substeps" => {
+                let substeps = args.next().ok_or("--substeps requires a number")?.parse()?;
+                options.simulation = Simulation { substeps, ..options.simulation }.clamped();
+            }
+            "--normalise-materials" => options.normalise_materials = true,
+            "--palette" => {
+                options.palette = Some(args.next().ok_or("--palette requires a seed or an image")?);
+            }
+            "--explain" => options.explain = true,
+            _ if arg.starts_with("--") => return Err(format!("unrecognised argument `{}`", arg).into()),
+            _ if options.scene.is_none() => options.scene = Some(arg),
+            _ => return Err(format!("unexpected argument `{}`", arg).into()),
+        }
+    }
 
-            diffuse_intensity +=
-                light.intensity * 0.0f32.max(dot(light_direction, normal));
+    let exr_output = options.output.as_deref().is_some_and(|path| path.ends_with(".exr"));
+    if !options.gradients.is_empty() && !exr_output {
+        return Err("--gradient requires an --output ending .exr, which keeps the gradients' signs".into());
+    }
+    if options.write_patch.is_some() && options.diff.is_none() {
+        return Err("--write-patch requires --diff".into());
+    }
 
-            let reflection = reflect(-light_direction, normal);
-            specular_intensity +=
-                0.0f32.max(dot(-reflection, ray.direction))
-                .powf(material.specular_exponent) * light.intensity;
+    Ok(options)
+}
+
+// An environment given on the command line replaces the scene's own
+fn load_scene(options: &Options) -> Result<Scene> {
+    let mut state = match (&options.scene, options.test_scene.as_deref()) {
+        (Some(path), _) => scene::load(path)?,
+        (None, Some("fur-ball")) => Scene::fur_ball_scene(),
+        (None, Some("grass")) => Scene::grass_scene(),
+        (None, Some("field")) => Scene::field_scene(),
+        (None, Some("switch")) => Scene::switch_scene(),
+        (None, Some("spheres")) => palette::sphere_field(SPHERE_FIELD_COUNT, &palette::harmonious(PALETTE_SIZE, 0), 0),
+        (None, Some(name)) => {
+            return Err(format!("unknown test scene `{}` (expected fur-ball, grass, field, switch or spheres)", name).into());
         }
+        (None, None) => Scene::default_scene(),
+    };
+
+    for path in &options.patches {
+        state = patch::apply(&state, &patch::load(path)?).map_err(|e| format!("applying {}: {}", path, e))?;
+    }
 
-        Some(material.diffuse_colour * diffuse_intensity * material.albedo.x
-             + Vec3::new(1.0, 1.0, 1.0) * specular_intensity * material.albedo.y)
+    if let Some(palette) = &options.palette {
+        let colours = match palette.parse::<u64>() {
+            Ok(seed) => palette::harmonious(PALETTE_SIZE, seed),
+            Err(_) => palette::from_image(&image::load(palette)?, PALETTE_SIZE),
+        };
+        palette::assign(&mut state, &colours, 0);
+    }
+
+    let mut warnings = state.sanitise();
+    warnings.extend(state.check_materials(options.normalise_materials));
+    warnings.extend(animation::problems(&state));
+    warnings.extend(triggers::problems(&state));
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+
+    if let Some(path) = &options.environment {
+        state.environment = Some(Environment::load(path)?);
+    }
+
+    Ok(state)
+}
+
+// The seed of an exported frame, which in deterministic mode depends on
+// the frame as well as the seed given
+fn frame_seed(options: &Options, frame: usize) -> u64 {
+    if options.deterministic {
+        sampling::frame_seed(options.seed, frame)
     } else {
-        None
+        options.seed
+    }
+}
+
+// Drops mesh levels to keep to the budget, if there is one, reporting when
+// the scene goes over it or back within it
+fn keep_to_budget(budget: &mut Option<MemoryBudget>, over: &mut bool, state: &mut Scene) {
+    if let Some(budget) = budget {
+        let usage = budget.enforce(state);
+        if usage.exceeded() != *over {
+            *over = usage.exceeded();
+            if *over {
+                eprintln!("warning: over the memory budget, using {}", usage);
+            } else {
+                println!("back within the memory budget, using {}", usage);
+            }
+        }
     }
 }
 
-fn update(state: &mut State, _dt: f64) {
-    //println!("dt = {}", dt);
-    state.spheres[0].centre.x += 0.05;
-    state.spheres[1].centre.y += 0.05;
-    state.spheres[2].centre.z += 0.05;
-    state.spheres[3].centre.z -= 0.05;
+// e.g. render.0012.png for frame 12 of render.png
+fn frame_path(path: &str, frame: usize) -> PathBuf {
+    let path = Path::new(path);
+    let stem = path.file_stem().and_then(OsStr::to_str).unwrap_or("render");
+    let extension = path.extension().and_then(OsStr::to_str).unwrap_or("png");
+    path.with_file_name(format!("{}.{:04}.{}", stem, frame, extension))
 }
 
-fn render(
-    canvas: &mut Canvas<Window>,
-    spheres: &[Sphere],
-    lights: &[Light],
+// Renders the animation and simulation frame by frame at EXPORT_FRAME_RATE.
+// Interpolating traces only the even frames (and the last), making each odd
+// frame from the frames either side by following the motion vectors between
+// them, which halves the tracing. Motion vectors only see the camera and
+// shapes' velocities, so shapes moved by animation tracks blur instead.
+fn export_frames(
+    options: &Options,
+    renderer: &Renderer,
+    state: &mut Scene,
+    camera: &mut Camera,
+    (width, height): (usize, usize),
+    path: &str,
+    frames: usize,
 ) -> Result<()> {
-    let mut v = BACKGROUND_COLOUR;
-
-    let max = v.x.max(v.y.max(v.z));
-    if max > 1.0 {
-        v = v * (1.0/max);
-    }
-
-    let pixel: [u8; 3] = [
-        clamp_to_u8(v.x, 0.0, 1.0),
-        clamp_to_u8(v.y, 0.0, 1.0),
-        clamp_to_u8(v.z, 0.0, 1.0),
-    ];
-
-    canvas.set_draw_color(Color::RGB(pixel[0], pixel[1], pixel[2]));
-    canvas.clear();
-
-    //for j in 0..HEIGHT {
-        //for i in 0..WIDTH {
-    (0..WIDTH).for_each(|i| {
-        (0..HEIGHT).for_each(|j| {
-            let (w, h) = (WIDTH as f32, HEIGHT as f32);
-            let x = (2.0 * (i as f32 + 0.5) / w - 1.0) * (FOV / 2.0).tan() * w / h;
-            let y = -(2.0 * (j as f32 + 0.5) / h - 1.0) * (FOV / 2.0).tan();
-
-            let origin = Vec3 {
-                x: 0.0,
-                y: 0.0,
-                z: 0.0,
-            };
-
-            let direction = Vec3 { x, y, z: -1.0 }.normalise();
-            let ray = Ray { origin, direction };
-
-            if let Some(mut v) = cast_ray(&ray, spheres, lights) {
-                let max = v.x.max(v.y.max(v.z));
-                if max > 1.0 {
-                    v = v * (1.0/max);
-                }
+    let step = Simulation { updates_per_second: EXPORT_FRAME_RATE, ..options.simulation };
+    let mut traced: Option<(Framebuffer, Camera)> = None;
+    let mut traced_count = 0;
+    let (mut budget, mut over_budget) = (options.memory_budget.map(MemoryBudget::new), false);
 
-                let pixel: [u8; 3] = [
-                    clamp_to_u8(v.x, 0.0, 1.0),
-                    clamp_to_u8(v.y, 0.0, 1.0),
-                    clamp_to_u8(v.z, 0.0, 1.0),
-                ];
+    for frame in 0..frames {
+        if frame > 0 {
+            let time = frame as f32 / EXPORT_FRAME_RATE as f32;
+            step.update(state);
+            triggers::fire(state, camera, time);
+            animation::animate(state, camera, time);
+        }
+        if options.interpolate_frames && frame % 2 == 1 && frame + 1 < frames {
+            continue;
+        }
 
-                canvas.set_draw_color(Color::RGB(pixel[0], pixel[1], pixel[2]));
-                canvas.draw_point(sdl2::rect::Point::new(i, j)).unwrap();
+        state.select_lods(camera.position);
+        keep_to_budget(&mut budget, &mut over_budget, state);
+        let renderer = Renderer { seed: frame_seed(options, frame), ..*renderer };
+        let mut framebuffer = Framebuffer::new(width, height);
+        framebuffer.threads = options.threads;
+        if options.wavefront {
+            wavefront::render(&renderer, &mut framebuffer, camera, state);
+        } else {
+            renderer.render(&mut framebuffer, camera, state);
+        }
+        traced_count += 1;
+
+        match &traced {
+            Some((previous, previous_camera)) if options.interpolate_frames && frame % 2 == 0 => {
+                let ticks = 2.0 * step.ticks_per_update();
+                let motion = MotionVectors::new(state, camera, previous_camera, ticks, width, height);
+                image::save(&motion::interpolate(previous, &framebuffer, &motion), frame_path(path, frame - 1))?;
             }
-        });
-    });
+            _ => {}
+        }
+
+        image::save(&framebuffer, frame_path(path, frame))?;
+        traced = Some((framebuffer, *camera));
+    }
 
-    canvas.present();
+    println!("wrote {} frames to {} ({} traced)", frames, frame_path(path, 0).display(), traced_count);
     Ok(())
 }
 
 fn main() -> Result<()> {
+    let options = parse_args()?;
+
+    let mut state = load_scene(&options)?;
+
+    // Print how another scene differs from this one, as changes that turn
+    // this into that, optionally saving them as a patch to apply with --patch
+    if let Some(other) = &options.diff {
+        let changes = patch::diff(&state, &scene::load(other)?)?;
+        for line in patch::summarise(&state, &changes)? {
+            println!("{}", line);
+        }
+        if let Some(path) = &options.write_patch {
+            patch::save(&changes, path)?;
+        }
+        return Ok(());
+    }
+
+    // Write out the scene (e.g. the built-in default) as a starting point for
+    // editing, without opening a window
+    if let Some(path) = &options.dump_scene {
+        return scene::save(&state, path);
+    }
+
+    let mut camera = Camera {
+        fov: options.fov,
+        ..Camera::default()
+    };
+
+    // Still images are of the animation's first moment
+    triggers::fire(&mut state, &mut camera, 0.0);
+    animation::animate(&mut state, &mut camera, 0.0);
+
+    let renderer = Renderer {
+        samples: options.samples,
+        sampling: options.sampling,
+        mode: if options.clay { RenderMode::Clay } else { RenderMode::Shaded },
+        settings: options.settings,
+        seed: frame_seed(&options, 0),
+    };
+
+    // Headless renders take the resolution as a scale of the given size, or
+    // in place of it
+    let (width, height) = match options.resolution {
+        Resolution::Scale(scale) => (
+            ((options.width as f32 * scale).round() as usize).max(1),
+            ((options.height as f32 * scale).round() as usize).max(1),
+        ),
+        Resolution::Fixed(width, height) => (width, height),
+    };
+
+    // Export or explain the rays traced for one pixel (the centre by default),
+    // exporting as OBJ, PLY or JSON
+    if options.ray_tree.is_some() || options.explain {
+        let (x, y) = options.pixel.unwrap_or((width / 2, height / 2));
+        if x >= width || y >= height {
+            return Err(format!("pixel {},{} is outside the image", x, y).into());
+        }
+
+        let tree = renderer.record_pixel(&state, &camera, width, height, x, y);
+        if options.explain {
+            print!("{}", tree.explain(&state));
+        }
+        if let Some(path) = &options.ray_tree {
+            ray_tree::save(&tree, path)?;
+        }
+        return Ok(());
+    }
+
+    // Panoramas and reprojections are as tall as the given height, and as
+    // wide as their projection needs
+    if let Some((input, from, to)) = &options.convert {
+        let path = options.output.as_ref().ok_or("--convert requires --output")?;
+        let converted = panorama::convert(&image::load(input)?, *from, *to, to.size(options.height));
+        return image::save(&converted, path);
+    }
+
+    if let Some(projection) = options.panorama {
+        let path = options.output.as_ref().ok_or("--panorama requires --output")?;
+        let size = projection.size(options.height);
+        let rendered = panorama::render(&state, camera.position, projection, size, options.samples, options.settings);
+        return image::save(&rendered, path);
+    }
+
+    // Bake occlusion or lighting into a texture over a mesh's UV layout, for
+    // use elsewhere
+    if let Some(shape) = options.bake {
+        let path = options.output.as_ref().ok_or("--bake requires --output")?;
+        let texture = bake::bake(&state, shape, options.bake_settings)?;
+        return image::save(&texture, path);
+    }
+
+    if let Some(path) = &options.export {
+        match Path::new(path).extension().and_then(OsStr::to_str) {
+            Some("obj") => obj::export(&state, path)?,
+            _ => gltf::export(&state, &camera, path)?,
+        }
+        println!("exported scene to {}", path);
+        return Ok(());
+    }
+
+    // Motion vectors as .flo for other tools, or visualised as an image. The
+    // camera is still, so only animated shapes move.
+    if let Some(path) = &options.motion {
+        MotionVectors::new(&state, &camera, &camera, 1.0, width, height).save(path)?;
+        if options.output.is_none() {
+            return Ok(());
+        }
+    }
+
+    if let Some(path) = &options.deep {
+        let image = deep::render(&renderer, &state, &camera, width, height);
+        image.save(path)?;
+        println!("wrote {} deep samples to {}", image.sample_count(), path);
+        if options.output.is_none() {
+            return Ok(());
+        }
+    }
+
+    if let Some(frames) = options.frames {
+        let path = options.output.as_ref().ok_or("--frames requires --output")?;
+        return export_frames(&options, &renderer, &mut state, &mut camera, (width, height), path, frames);
+    }
+
+    // Headless mode: render a single frame to disk without opening a window
+    if let Some(path) = &options.output {
+        state.select_lods(camera.position);
+        let mut budget = options.memory_budget.map(MemoryBudget::new);
+        keep_to_budget(&mut budget, &mut false, &mut state);
+        let mut framebuffer = Framebuffer::new(width, height);
+        framebuffer.threads = options.threads;
+        if options.wavefront {
+            wavefront::render(&renderer, &mut framebuffer, &camera, &state);
+        } else {
+            renderer.render(&mut framebuffer, &camera, &state);
+        }
+
+        // EXR keeps the scene's extra outputs and any gradients in the same
+        // file, each in its own part
+        if Path::new(path).extension().and_then(OsStr::to_str) == Some("exr") {
+            let mut extra: Vec<(String, Framebuffer)> = state
+                .outputs
+                .iter()
+                .map(|output| {
+                    let mut framebuffer = Framebuffer::new(width, height);
+                    framebuffer.threads = options.threads;
+                    renderer.render_output(&mut framebuffer, &camera, &state, &output.expression);
+                    (output.name.clone(), framebuffer)
+                })
+                .collect();
+            for gradient in gradient::render(&renderer, &state, &camera, &options.gradients, width, height)? {
+                println!("{} = {} (step {})", gradient.parameter, gradient.value, gradient.step);
+                extra.push((gradient.name(), gradient.framebuffer(width, height)));
+            }
+
+            let mut parts = vec![exr::Part { name: exr::BEAUTY, framebuffer: &framebuffer }];
+            for (name, framebuffer) in &extra {
+                parts.push(exr::Part { name, framebuffer });
+            }
+            exr::save(&parts, path)?;
+            println!("wrote {} parts to {}", parts.len(), path);
+            return Ok(());
+        }
+        image::save(&framebuffer, path)?;
+
+        // The scene's extra outputs go alongside, e.g. render.diffuse.png
+        for output in &state.outputs {
+            let path = Path::new(path);
+            let stem = path.file_stem().and_then(OsStr::to_str).unwrap_or("render");
+            let extension = path.extension().and_then(OsStr::to_str).unwrap_or("png");
+            let output_path = path.with_file_name(format!("{}.{}.{}", stem, output.name, extension));
+
+            renderer.render_output(&mut framebuffer, &camera, &state, &output.expression);
+            image::save(&framebuffer, &output_path)?;
+            println!("wrote {} ({}) to {}", output.name, output.expression, output_path.display());
+        }
+        return Ok(());
+    }
+
+    // A snapshot left by a crashed session is offered back before this
+    // session's first autosave replaces it
+    if options.autosave.is_some() && Path::new(autosave::PATH).exists() {
+        if let Some(snapshot) = offer_autosave(autosave::PATH)? {
+            state = snapshot.scene;
+            camera = snapshot.camera;
+            println!("restored the autosave");
+        }
+    }
+
+    // External controller input is only enabled when a binding table is given
+    let mut controls = match &options.bindings {
+        Some(path) => {
+            let bindings = control::load_bindings(path)?;
+            let listener = OscListener::bind(options.osc_port)?;
+            println!("listening for OSC on port {}", options.osc_port);
+            Some((listener, bindings))
+        }
+        None => None,
+    };
+
     let sdl_context = sdl2::init()?;
     let video_subsystem = sdl_context.video()?;
 
+    // With high-DPI enabled the drawable area can be larger than the window
+    // size in screen coordinates, so the framebuffer always follows the
+    // canvas output size rather than WIDTH x HEIGHT
     let window = video_subsystem
-        .window("tinyraytracer-rs", WIDTH as u32, HEIGHT as u32)
+        .window(TITLE, WIDTH as u32, HEIGHT as u32)
         .opengl()
+        .allow_highdpi()
+        .resizable()
         .position_centered()
         .build()?;
 
-    let mut canvas = window
+    let canvas = window
         .into_canvas()
         //.present_vsync()
         .build()?;
 
-    let mut event_pump = sdl_context.event_pump()?;
+    let texture_creator = canvas.texture_creator();
+    let mut view = View::new(canvas, &texture_creator, camera, RenderMode::Shaded, options.resolution)?;
 
-    let ivory = Material::new(Vec2::new(0.6, 0.3), Vec3::new(0.4, 0.4, 0.3), 50.0);
-    let red_rubber = Material::new(Vec2::new(0.9, 0.1), Vec3::new(0.3, 0.1, 0.1), 10.0);
-
-    let mut state = State {
-        spheres: vec![
-            Sphere::new(Vec3::new(-3.0, 0.0, -16.0), 2.0, ivory),
-            Sphere::new(Vec3::new(-1.0, -1.5, -12.0), 2.0, red_rubber),
-            Sphere::new(Vec3::new(1.5, -0.5, -18.0), 3.0, red_rubber),
-            Sphere::new(Vec3::new(7.0, 5.0, -18.0), 4.0, ivory),
-        ],
-        lights: vec![
-            Light::new(Vec3::new(-20.0, 20.0,  20.0), 1.5),
-            Light::new(Vec3::new( 30.0, 50.0, -25.0), 1.8),
-            Light::new(Vec3::new( 30.0, 20.0,  30.0), 1.7),
-        ]
-    };
+    // A second window, toggled with V, showing its own camera and by default
+    // a debug view of the same scene. It's created up front and hidden so its
+    // texture creator can live as long as the main one.
+    let second_canvas = video_subsystem
+        .window(SECOND_TITLE, WIDTH as u32 / 2, HEIGHT as u32 / 2)
+        .opengl()
+        .allow_highdpi()
+        .resizable()
+        .hidden()
+        .build()?
+        .into_canvas()
+        .build()?;
+
+    let second_texture_creator = second_canvas.texture_creator();
+    let mut second_view = View::new(
+        second_canvas,
+        &second_texture_creator,
+        camera,
+        RenderMode::Normals,
+        Resolution::Scale(1.0),
+    )?;
+    let mut second_view_visible = false;
+
+    let mut fingers: u32 = 0;
+
+    let mut overlay = Overlay::None;
+
+    // A reference image shown left of a split dragged across the main view,
+    // either given on the command line or a copy of an earlier render taken
+    // with Z, and shown or hidden with L
+    let mut comparison = options.reference.as_ref().map(image::load).transpose()?.map(Comparison::new);
+    let mut comparing = comparison.is_some();
+    let mut dragging_split = false;
 
-    let target_updates_per_second = 60;
-    let seconds_per_update = 1.0 / target_updates_per_second as f64;
+    let mut beauty_shot: Option<JoinHandle<std::result::Result<String, String>>> = None;
+
+    // Offline renders of the focused view queued with F7, listed with F8.
+    // F9 changes the priority of the last job still waiting and F10 cancels
+    // it. Progress is printed with the frame rate.
+    let mut queue = RenderQueue::new();
+
+    let (mut budget, mut over_budget) = (options.memory_budget.map(MemoryBudget::new), false);
+
+    let mut autosave = options.autosave.map(|interval| Autosave::new(autosave::PATH, interval, options.scene.clone()));
+
+    // Index of the shape picked with the left mouse button or Tab, which can
+    // be isolated or hidden
+    let mut selected: Option<usize> = None;
+
+    // Corners of the rectangle being dragged out with the left mouse button
+    let mut drag: Option<((f32, f32), (f32, f32))> = None;
+
+    // With the inspector on, clicking a pixel explains how its colour was
+    // worked out and draws its rays over the view until the next click
+    let mut inspector = false;
+    let mut inspected: Option<RayTree> = None;
+
+    // Last known cursor position in the main window
+    let mut mouse = (0, 0);
+
+    // Reflection probes, toggled with J, trade accurate reflections for speed.
+    // They're baked again whenever the scene changes.
+    let mut probes: Option<Probes> = None;
+    let mut probes_changed = false;
+
+    // Likewise the shading cache, toggled with Y, which is emptied whenever
+    // the scene changes
+    let mut cache: Option<ShadingCache> = None;
+    let mut cache_changed = false;
+
+    // Pausing the animation lets the views accumulate samples of a still scene
+    let mut paused = false;
+
+    // A resumed film only carries on accumulating while the scene stays as it
+    // was saved, so the animation starts paused
+    if let Some(path) = &options.resume {
+        let (film, framebuffer) = film::load(path)?;
+        view.resume(film, framebuffer, &texture_creator)?;
+        paused = true;
+        println!("resumed {} at {} samples (paused)", path, film.accumulated);
+    }
+
+    let mut event_pump = sdl_context.event_pump()?;
+
+    let mut simulation = options.simulation;
 
     let mut previous_time = Instant::now();
     let mut delta: f64 = 0.0;
+    let mut last_motion = (view.camera, 0.0);
 
     let mut frames: u32 = 0;
     let mut updates: u32 = 0;
+    // Seconds the animation has run for while not paused
+    let mut animation_time: f64 = 0.0;
 
     let mut timer = Instant::now();
 
     'running: loop {
+        let mut scene_changed = false;
+        let camera_before_events = view.camera;
+
         for event in event_pump.poll_iter() {
+            if let Event::MouseMotion { x, y, window_id, .. } = &event {
+                if *window_id == view.window_id() {
+                    mouse = (*x, *y);
+                }
+            }
+
             match event {
                 Event::Quit {..} |
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                     break 'running
                 },
-                // TODO implement this!
-                Event::KeyDown { keycode: Some(Keycode::S), .. } => {
-                    unimplemented!("Saving screenshot");
+                Event::Window { window_id, win_event: WindowEvent::Close, .. } => {
+                    if window_id == view.window_id() {
+                        break 'running
+                    }
+                    second_view.canvas.window_mut().hide();
+                    second_view_visible = false;
+                },
+                // The second view starts from wherever the main camera is
+                Event::KeyDown { keycode: Some(Keycode::V), .. } => {
+                    second_view_visible = !second_view_visible;
+                    if second_view_visible {
+                        // The scene may have moved on while it was hidden
+                        second_view.set_camera(view.camera);
+                        second_view.restart();
+                        second_view.canvas.window_mut().show();
+                    } else {
+                        second_view.canvas.window_mut().hide();
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::C), .. } => {
+                    second_view.set_camera(view.camera);
+                },
+                // Cycle the render mode of whichever window has focus
+                Event::KeyDown { keycode: Some(Keycode::M), window_id, .. } => {
+                    if window_id == second_view.window_id() {
+                        second_view.mode = second_view.mode.next();
+                    } else {
+                        view.mode = view.mode.next();
+                    }
+                },
+                // Toggle the focused window between its shading and clay
+                Event::KeyDown { keycode: Some(Keycode::G), window_id, .. } => {
+                    // Only the field is borrowed: the two views' lifetimes differ
+                    let mode = if window_id == second_view.window_id() {
+                        &mut second_view.mode
+                    } else {
+                        &mut view.mode
+                    };
+                    *mode = if *mode == RenderMode::Clay { RenderMode::Shaded } else { RenderMode::Clay };
+                },
+                Event::KeyDown { keycode: Some(keycode), window_id, .. }
+                    if matches!(keycode, Keycode::Num1 | Keycode::Num2 | Keycode::Num3 | Keycode::Num4
+                                       | Keycode::Num5 | Keycode::Num6 | Keycode::Num7 | Keycode::Num8) =>
+                {
+                    let settings = if window_id == second_view.window_id() {
+                        &mut second_view.settings
+                    } else {
+                        &mut view.settings
+                    };
+                    adjust_settings(settings, keycode);
+                    println!("trace settings: {}", settings);
+                },
+                // Screenshots live on F12 since S moves the camera
+                Event::KeyDown { keycode: Some(Keycode::F12), .. } => {
+                    // Screenshots are of the image as rendered, not previewed
+                    if view.preview != Preview::Off {
+                        let colours = if view.denoise { view.denoised.clone() } else { view.framebuffer.colours.clone() };
+                        view.framebuffer.show(&colours);
+                    }
+                    match save_screenshot(&view.framebuffer) {
+                        Ok(path) => println!("saved screenshot to {}", path),
+                        Err(e) => eprintln!("failed to save screenshot: {}", e),
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::F6), .. } => {
+                    match save_motion(&view, &state, last_motion) {
+                        Ok(path) => println!("saved motion vectors to {}", path),
+                        Err(e) => eprintln!("failed to save motion vectors: {}", e),
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::T), .. } => {
+                    match save_ray_tree(&view, &state, mouse) {
+                        Ok(path) => println!("saved ray tree to {}", path),
+                        Err(e) => eprintln!("failed to save ray tree: {}", e),
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::X), .. } => {
+                    inspector = !inspector;
+                    inspected = None;
+                    println!("inspector {}", if inspector { "on, click a pixel to explain it" } else { "off" });
+                },
+                // Step the field of view of the main window, or go back to
+                // the one it started with
+                Event::KeyDown { keycode: Some(Keycode::LeftBracket), .. } => {
+                    view.zoom(-FOV_STEP.to_radians());
+                },
+                Event::KeyDown { keycode: Some(Keycode::RightBracket), .. } => {
+                    view.zoom(FOV_STEP.to_radians());
+                },
+                Event::KeyDown { keycode: Some(Keycode::Backslash), .. } => {
+                    view.set_target_fov(options.fov);
+                },
+                // Step the main window's render scale, or cycle through the
+                // resolution presets
+                Event::KeyDown { keycode: Some(keycode @ (Keycode::Minus | Keycode::Equals)), .. } => {
+                    let scale = match view.resolution() {
+                        Resolution::Scale(scale) => scale,
+                        Resolution::Fixed(..) => 1.0,
+                    };
+                    let step = if keycode == Keycode::Minus { -RENDER_SCALE_STEP } else { RENDER_SCALE_STEP };
+                    let resolution = Resolution::Scale((scale + step).clamp(MIN_RENDER_SCALE, MAX_RENDER_SCALE));
+
+                    view.set_resolution(resolution, &texture_creator)?;
+                    println!("rendering at {}", resolution);
+                },
+                Event::KeyDown { keycode: Some(Keycode::F5), .. } => {
+                    let resolution = view.resolution().next_preset();
+                    view.set_resolution(resolution, &texture_creator)?;
+                    println!("rendering at {}", resolution);
+                },
+                Event::KeyDown { keycode: Some(Keycode::N), window_id, .. } => {
+                    let denoise = if window_id == second_view.window_id() {
+                        &mut second_view.denoise
+                    } else {
+                        &mut view.denoise
+                    };
+                    *denoise = !*denoise;
+                    println!("denoising {}", if *denoise { "on" } else { "off" });
+                },
+                Event::KeyDown { keycode: Some(Keycode::J), .. } => {
+                    probes = match probes {
+                        Some(_) => None,
+                        None => Some(Probes::bake(&state, view.settings)),
+                    };
+                    probes_changed = true;
+                    println!("reflection probes {}", if probes.is_some() { "on" } else { "off" });
+                },
+                Event::KeyDown { keycode: Some(Keycode::Y), .. } if options.deterministic => {
+                    println!("the shading cache stays off in deterministic mode");
+                },
+                Event::KeyDown { keycode: Some(Keycode::Y), .. } => {
+                    cache = match cache {
+                        Some(_) => None,
+                        None => Some(ShadingCache::new(SHADING_CACHE_CELL)),
+                    };
+                    cache_changed = true;
+                    println!("shading cache {}", if cache.is_some() { "on" } else { "off" });
+                },
+                Event::KeyDown { keycode: Some(Keycode::K), .. } => {
+                    match save_session(&view, &state) {
+                        Ok((scene_path, film_path)) => println!("saved session to {} and {}", scene_path, film_path),
+                        Err(e) => eprintln!("failed to save session: {}", e),
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::P), .. } => {
+                    paused = !paused;
+                    println!("{}", if paused { "paused" } else { "resumed" });
+                },
+                // Halve or double the update rate, or take away or add a
+                // substep to each update
+                Event::KeyDown { keycode: Some(keycode @ (Keycode::Comma | Keycode::Period)), .. } => {
+                    let rate = simulation.updates_per_second;
+                    let rate = if keycode == Keycode::Comma { rate / 2 } else { rate * 2 };
+                    simulation = Simulation { updates_per_second: rate, ..simulation }.clamped();
+                    println!("simulation: {}", simulation);
+                },
+                Event::KeyDown { keycode: Some(keycode @ (Keycode::Semicolon | Keycode::Quote)), .. } => {
+                    let substeps = simulation.substeps;
+                    let substeps = if keycode == Keycode::Semicolon { substeps.saturating_sub(1) } else { substeps + 1 };
+                    simulation = Simulation { substeps, ..simulation }.clamped();
+                    println!("simulation: {}", simulation);
+                },
+                // Cycle the focused window's display preview, on F since E
+                // moves the camera up
+                Event::KeyDown { keycode: Some(Keycode::F), window_id, .. } => {
+                    let preview = if window_id == second_view.window_id() {
+                        &mut second_view.preview
+                    } else {
+                        &mut view.preview
+                    };
+                    *preview = preview.next();
+                    println!("preview: {:?}", preview);
+                },
+                Event::KeyDown { keycode: Some(Keycode::Z), .. } => {
+                    let colours = if view.denoise { &view.denoised } else { &view.framebuffer.colours };
+                    let (width, height) = (view.framebuffer.width, view.framebuffer.height);
+                    let split = comparison.as_ref().map_or(0.5, |c| c.split);
+                    comparison = Some(Comparison { split, ..Comparison::from_colours(colours, width, height) });
+                    comparing = true;
+                    println!("comparing against the current render");
+                },
+                Event::KeyDown { keycode: Some(Keycode::L), .. } if comparison.is_some() => {
+                    comparing = !comparing;
+                    println!("comparison {}", if comparing { "on" } else { "off" });
+                },
+                Event::KeyDown { keycode: Some(Keycode::O), .. } => {
+                    overlay = overlay.next();
+                    println!("overlay: {:?}", overlay);
+                },
+                Event::KeyDown { keycode: Some(Keycode::B), .. } => {
+                    if beauty_shot.is_some() {
+                        println!("a beauty shot is already being rendered");
+                    } else {
+                        println!("rendering beauty shot in the background");
+                        beauty_shot = Some(spawn_beauty_shot(
+                            state.clone(),
+                            view.camera,
+                            view.settings,
+                            view.framebuffer.width,
+                            view.framebuffer.height,
+                        ));
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::F7), window_id, .. } => {
+                    let focused = if window_id == second_view.window_id() { &second_view } else { &view };
+                    let path = match timestamped_path(&format!("render-{}", queue.next_id()), "png") {
+                        Ok(path) => path,
+                        Err(e) => {
+                            eprintln!("failed to queue render: {}", e);
+                            continue;
+                        }
+                    };
+                    let job = Job {
+                        scene: state.clone(),
+                        camera: focused.camera,
+                        renderer: Renderer {
+                            samples: BEAUTY_SAMPLES,
+                            mode: focused.mode,
+                            settings: focused.settings,
+                            seed: options.seed,
+                            ..Renderer::default()
+                        },
+                        width: focused.framebuffer.width * BEAUTY_SCALE,
+                        height: focused.framebuffer.height * BEAUTY_SCALE,
+                        path,
+                    };
+                    let id = queue.push(job, Priority::Normal);
+                    println!("queued render #{}", id);
+                },
+                Event::KeyDown { keycode: Some(Keycode::F8), .. } => {
+                    let lines = queue.describe();
+                    if lines.is_empty() {
+                        println!("the render queue is empty");
+                    }
+                    for line in lines {
+                        println!("{}", line);
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::F9), .. } => {
+                    match queue.last_waiting() {
+                        Some(id) => {
+                            let priority = queue.priority(id).unwrap_or(Priority::Normal).next();
+                            queue.set_priority(id, priority);
+                            println!("render #{} priority: {}", id, priority);
+                        }
+                        None => println!("no queued render is waiting"),
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::F10), .. } => {
+                    match queue.last_waiting() {
+                        Some(id) if queue.cancel(id) => println!("cancelled render #{}", id),
+                        _ => println!("no queued render is waiting"),
+                    }
+                },
+                // Reload the scene from disk, keeping the current one if the
+                // file fails to parse so a typo doesn't end the session
+                Event::KeyDown { keycode: Some(Keycode::R), .. } if options.scene.is_some() => {
+                    match load_scene(&options) {
+                        Ok(reloaded) => {
+                            state = reloaded;
+                            scene_changed = true;
+                            selected = selected.filter(|&i| i < state.shapes.len());
+                            println!("reloaded scene");
+                        }
+                        Err(e) => eprintln!("failed to reload scene: {}", e),
+                    }
+                },
+                // Dragging the comparison's split moves it
+                Event::MouseButtonDown { mouse_btn: MouseButton::Left, x, y, window_id, .. }
+                    if window_id == view.window_id()
+                        && comparing
+                        && comparison
+                            .as_ref()
+                            .is_some_and(|c| c.is_near_split(view.pixel_at(x, y).0, view.framebuffer.width)) =>
+                {
+                    dragging_split = true;
+                },
+                Event::MouseMotion { x, y, .. } if dragging_split => {
+                    if let Some(comparison) = comparison.as_mut() {
+                        comparison.drag_to(view.pixel_at(x, y).0, view.framebuffer.width);
+                    }
+                },
+                Event::MouseButtonUp { mouse_btn: MouseButton::Left, .. } if dragging_split => {
+                    dragging_split = false;
+                },
+                // Left clicking selects the shape under the cursor, while
+                // dragging marks out a region to refine
+                Event::MouseButtonDown { mouse_btn: MouseButton::Left, x, y, window_id, .. }
+                    if window_id == view.window_id() =>
+                {
+                    let start = view.pixel_at(x, y);
+                    drag = Some((start, start));
+                },
+                Event::MouseMotion { x, y, .. } if drag.is_some() => {
+                    let end = view.pixel_at(x, y);
+                    drag = drag.map(|(start, _)| (start, end));
+                },
+                Event::MouseButtonUp { mouse_btn: MouseButton::Left, x, y, .. } if drag.is_some() => {
+                    let end = view.pixel_at(x, y);
+                    let (start, _) = drag.take().unwrap();
+
+                    if (end.0 - start.0).abs().max((end.1 - start.1).abs()) >= DRAG_THRESHOLD {
+                        view.set_region(Some(rect_between(start, end)));
+                    } else if inspector {
+                        let tree = record_view_pixel(&view, &state, end);
+                        print!("{}", tree.explain(&state));
+                        inspected = Some(tree);
+                    } else {
+                        let (width, height) = (view.framebuffer.width, view.framebuffer.height);
+                        let ray = view.camera.ray_through(end.0, end.1, width, height);
+
+                        selected = render::nearest_shape(&ray, &state).map(|(i, _)| i);
+                        print_selection(&state, selected);
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => {
+                    view.set_region(None);
+                },
+                Event::KeyDown { keycode: Some(Keycode::Tab), .. } if !state.shapes.is_empty() => {
+                    selected = Some(selected.map_or(0, |i| (i + 1) % state.shapes.len()));
+                    print_selection(&state, selected);
+                },
+                // Solo the selected shape, or hide just it
+                Event::KeyDown { keycode: Some(Keycode::I), .. } => {
+                    if let Some(i) = selected {
+                        state.isolate(i);
+                        scene_changed = true;
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::H), .. } => {
+                    if let Some(i) = selected {
+                        state.toggle_hidden(i);
+                        scene_changed = true;
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::U), .. } => {
+                    state.hidden.clear();
+                    scene_changed = true;
+                },
+                // Mouse-look while the right button is held
+                Event::MouseMotion { mousestate, xrel, yrel, .. } if mousestate.right() => {
+                    view.camera.rotate(
+                        xrel as f32 * MOUSE_SENSITIVITY,
+                        -yrel as f32 * MOUSE_SENSITIVITY,
+                    );
+                },
+                Event::MouseWheel { y, .. } => {
+                    view.zoom(y as f32 * ZOOM_SPEED);
+                },
+                Event::FingerDown { .. } => fingers += 1,
+                Event::FingerUp { .. } => fingers = fingers.saturating_sub(1),
+                // One finger orbits, two fingers pinch to zoom
+                Event::FingerMotion { dx, dy, .. } if fingers == 1 => {
+                    view.camera.orbit(
+                        -dx * TOUCH_ORBIT_SPEED,
+                        dy * TOUCH_ORBIT_SPEED,
+                        TOUCH_ORBIT_DISTANCE,
+                    );
+                },
+                Event::MultiGesture { d_dist, num_fingers: 2, .. } => {
+                    view.zoom(d_dist * PINCH_ZOOM_SPEED);
+                },
+                // Resizing or moving to a display with a different pixel
+                // density changes the drawable size
+                Event::Window { window_id, win_event: WindowEvent::SizeChanged(..), .. } => {
+                    if window_id == second_view.window_id() {
+                        second_view.resize(&second_texture_creator)?;
+                    } else {
+                        view.resize(&texture_creator)?;
+                    }
                 },
                 _ => {}
             }
         }
 
+        if beauty_shot.as_ref().is_some_and(JoinHandle::is_finished) {
+            match beauty_shot.take().map(JoinHandle::join) {
+                Some(Ok(Ok(path))) => println!("saved beauty shot to {}", path),
+                Some(Ok(Err(e))) => eprintln!("failed to save beauty shot: {}", e),
+                _ => eprintln!("beauty shot render panicked"),
+            }
+        }
+
+        for id in queue.poll() {
+            match queue.status(id) {
+                Some(Status::Failed(e)) => eprintln!("queued render #{} failed: {}", id, e),
+                _ => println!("finished queued render #{}", id),
+            }
+        }
+
+        if let Some((listener, bindings)) = &mut controls {
+            for (address, value) in listener.poll() {
+                apply_control(&mut state, &mut view.settings, bindings, &address, value);
+                scene_changed = true;
+            }
+        }
+
+        // Only the user's own changes are worth autosaving, not the
+        // simulation's or animation's, which happen every update
+        let mut edited = scene_changed || view.camera != camera_before_events;
+
+        let seconds_per_update = simulation.seconds_per_update();
         let current_time = Instant::now();
         delta += current_time
             .duration_since(previous_time)
@@ -263,13 +1615,73 @@ fn main() -> Result<()> {
 
         previous_time = current_time;
 
+        // The camera and number of animation ticks since the last frame
+        last_motion = (view.camera, 0.0);
+
         while delta >= 1.0 {
-            update(&mut state, delta);
+            let camera_before_update = view.camera;
+            update_camera(&mut view.camera, &event_pump.keyboard_state(), simulation.ticks_per_update());
+            edited |= view.camera != camera_before_update;
+            view.ease_fov();
+            second_view.ease_fov();
+            if !paused {
+                scene_changed |= simulation.update(&mut state);
+                animation_time += seconds_per_update;
+                scene_changed |= triggers::fire(&mut state, &mut view.camera, animation_time as f32);
+                scene_changed |= animation::animate(&mut state, &mut view.camera, animation_time as f32);
+                last_motion.1 += simulation.ticks_per_update();
+            }
             updates += 1;
             delta -= 1.0;
         }
 
-        render(&mut canvas, &state.spheres, &state.lights)?;
+        if scene_changed && probes.is_some() {
+            probes = Some(Probes::bake(&state, view.settings));
+        }
+        if let Some(cache) = cache.as_ref().filter(|_| scene_changed) {
+            cache.clear();
+        }
+        scene_changed |= state.select_lods(view.camera.position);
+        keep_to_budget(&mut budget, &mut over_budget, &mut state);
+
+        if let Some(autosave) = &mut autosave {
+            if edited {
+                autosave.mark_changed();
+            }
+            if let Err(e) = autosave.update(&state, &view.camera, Instant::now()) {
+                eprintln!("failed to autosave: {}", e);
+            }
+        }
+
+        let view_changed = scene_changed || probes_changed || cache_changed;
+        probes_changed = false;
+        cache_changed = false;
+
+        render_view(&mut view, &state, view_changed, options.seed, probes.as_ref(), cache.as_ref());
+        if let Some(comparison) = comparison.as_ref().filter(|_| comparing) {
+            comparison.draw(&mut view.framebuffer, view.preview);
+        }
+        diagnostics::apply(overlay, &mut view.framebuffer);
+
+        if let Some(tree) = &inspected {
+            tree.draw(&mut view.framebuffer, &view.camera);
+        }
+
+        if let Some((start, end)) = drag {
+            view.framebuffer.outline(rect_between(start, end), REGION_OUTLINE);
+        } else if let Some(region) = view.region() {
+            view.framebuffer.outline(region, REGION_OUTLINE);
+        }
+
+        view.show_fov(TITLE)?;
+        view.present()?;
+
+        if second_view_visible {
+            render_view(&mut second_view, &state, view_changed, options.seed, probes.as_ref(), cache.as_ref());
+            second_view.show_fov(SECOND_TITLE)?;
+            second_view.present()?;
+        }
+
         frames += 1;
 
         let timer_now = Instant::now();
@@ -277,11 +1689,19 @@ fn main() -> Result<()> {
         if timer_now.duration_since(timer).as_secs() >= 1 {
             timer = timer_now;
             println!("updates: {}, frames: {}", updates, frames);
+            if let Some(id) = queue.running() {
+                let progress = queue.progress(id).unwrap_or(0.0) * 100.0;
+                println!("rendering queued #{}: {:.0}% ({} waiting)", id, progress, queue.waiting());
+            }
 
             updates = 0;
             frames = 0;
         }
     }
 
+    if let Some(autosave) = autosave {
+        autosave.finish()?;
+    }
+
     Ok(())
 }
@@ -0,0 +1,179 @@
+use crate::geometry::{Aabb, Vec3, cross, dot};
+use crate::lights::Region;
+use crate::materials::Material;
+use crate::mesh::Mesh;
+
+use std::collections::HashMap;
+
+// The six tetrahedra each grid cell is split into, as corners of the cell
+// numbered by their x, y and z offsets in bits 0, 1 and 2. Each runs along
+// the diagonal from corner 0 to corner 7 by way of one face diagonal, so
+// neighbouring cells split their shared faces the same way and the surface
+// has no cracks.
+const TETRAHEDRA: [[usize; 4]; 6] = [
+    [0, 1, 3, 7],
+    [0, 1, 5, 7],
+    [0, 2, 3, 7],
+    [0, 2, 6, 7],
+    [0, 4, 5, 7],
+    [0, 4, 6, 7],
+];
+
+// Polygonises the surface where `field` is zero inside `bounds`, taking it
+// as negative inside, as with signed distances. The bounds are divided into
+// `resolution` cells along each axis, and each cell into tetrahedra rather
+// than using a full marching cubes case table, which gives more triangles
+// but no ambiguous cases. Vertices are shared between triangles, and
+// triangles are wound anticlockwise seen from outside.
+pub fn polygonise<F>(field: F, bounds: Aabb, resolution: usize) -> (Vec<Vec3<f32>>, Vec<[usize; 3]>)
+where
+    F: Fn(Vec3<f32>) -> f32,
+{
+    let n = resolution.max(1);
+    let size = bounds.max - bounds.min;
+    let point = |x: usize, y: usize, z: usize| {
+        bounds.min
+            + Vec3::new(
+                size.x * x as f32 / n as f32,
+                size.y * y as f32 / n as f32,
+                size.z * z as f32 / n as f32,
+            )
+    };
+
+    let index = |x: usize, y: usize, z: usize| (z * (n + 1) + y) * (n + 1) + x;
+    let mut values = vec![0.0; (n + 1) * (n + 1) * (n + 1)];
+    for z in 0..=n {
+        for y in 0..=n {
+            for x in 0..=n {
+                values[index(x, y, z)] = field(point(x, y, z));
+            }
+        }
+    }
+
+    let mut vertices: Vec<Vec3<f32>> = Vec::new();
+    let mut faces = Vec::new();
+
+    // Vertices lie on the edges between grid points, keyed by the points at
+    // either end
+    let mut edges: HashMap<(usize, usize), usize> = HashMap::new();
+
+    for z in 0..n {
+        for y in 0..n {
+            for x in 0..n {
+                let corner = |c: usize| {
+                    let (cx, cy, cz) = (x + (c & 1), y + (c >> 1 & 1), z + (c >> 2 & 1));
+                    (index(cx, cy, cz), point(cx, cy, cz))
+                };
+
+                for tetrahedron in &TETRAHEDRA {
+                    let corners = tetrahedron.map(corner);
+                    let (inside, outside): (Vec<_>, Vec<_>) = corners.iter().partition(|c| values[c.0] < 0.0);
+
+                    let mut crossing = |a: (usize, Vec3<f32>), b: (usize, Vec3<f32>)| {
+                        *edges.entry((a.0.min(b.0), a.0.max(b.0))).or_insert_with(|| {
+                            let (va, vb) = (values[a.0], values[b.0]);
+                            vertices.push(a.1 + (b.1 - a.1) * (va / (va - vb)));
+                            vertices.len() - 1
+                        })
+                    };
+
+                    let polygon: Vec<usize> = match (inside.len(), outside.len()) {
+                        (1, 3) => outside.iter().map(|&&o| crossing(*inside[0], o)).collect(),
+                        (3, 1) => inside.iter().map(|&&i| crossing(i, *outside[0])).collect(),
+                        // Going round the four edges between the two pairs
+                        (2, 2) => vec![
+                            crossing(*inside[0], *outside[0]),
+                            crossing(*inside[0], *outside[1]),
+                            crossing(*inside[1], *outside[1]),
+                            crossing(*inside[1], *outside[0]),
+                        ],
+                        _ => Vec::new(),
+                    };
+                    if polygon.is_empty() {
+                        continue;
+                    }
+
+                    // Facing away from the inside corners
+                    let centre = |corners: &[&(usize, Vec3<f32>)]| {
+                        corners.iter().fold(Vec3::zero(), |sum, c| sum + c.1) * (1.0 / corners.len() as f32)
+                    };
+                    let out = centre(&outside) - centre(&inside);
+
+                    for k in 1..polygon.len() - 1 {
+                        let [a, b, c] = [polygon[0], polygon[k], polygon[k + 1]];
+                        let normal = cross(vertices[b] - vertices[a], vertices[c] - vertices[a]);
+                        faces.push(if dot(normal, out) >= 0.0 { [a, b, c] } else { [a, c, b] });
+                    }
+                }
+            }
+        }
+    }
+
+    (vertices, faces)
+}
+
+// A triangle mesh of the region's surface, which the renderer can trace
+// through its BVH instead of evaluating the distance along each ray
+pub fn region_mesh(region: &Region, resolution: usize, material: Material) -> Mesh {
+    let bounds = match *region {
+        Region::Sphere { centre, radius } => Aabb {
+            min: centre - Vec3::new(radius, radius, radius),
+            max: centre + Vec3::new(radius, radius, radius),
+        },
+        Region::Box { min, max } => Aabb { min, max },
+    };
+
+    // Padded by a cell so the surface is closed where it meets the bounds
+    let cell = (bounds.max - bounds.min) * (1.0 / resolution.max(1) as f32);
+    let padded = Aabb {
+        min: bounds.min - cell,
+        max: bounds.max + cell,
+    };
+
+    let (vertices, faces) = polygonise(|p| region.signed_distance(p), padded, resolution + 2);
+    Mesh::new(vertices, faces, material)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sphere_is_closed_and_faces_outwards() {
+        let centre = Vec3::new(1.0, -2.0, 0.5);
+        let sphere = |p: Vec3<f32>| (p - centre).length() - 1.0;
+        let bounds = Aabb {
+            min: centre - Vec3::new(1.5, 1.5, 1.5),
+            max: centre + Vec3::new(1.5, 1.5, 1.5),
+        };
+        let (vertices, faces) = polygonise(sphere, bounds, 16);
+
+        assert!(!faces.is_empty());
+        assert!(vertices.iter().all(|&v| sphere(v).abs() < 0.05));
+
+        // Every edge is shared by exactly two triangles, running opposite ways
+        let mut edges = HashMap::new();
+        for &[a, b, c] in &faces {
+            for edge in [(a, b), (b, c), (c, a)] {
+                *edges.entry(edge).or_insert(0) += 1;
+            }
+
+            let normal = cross(vertices[b] - vertices[a], vertices[c] - vertices[a]);
+            assert!(dot(normal, vertices[a] - centre) > 0.0);
+        }
+        assert!(edges.iter().all(|(&(a, b), &count)| count == 1 && edges.get(&(b, a)) == Some(&1)));
+    }
+
+    #[test]
+    fn region_mesh_covers_the_region() {
+        let region = Region::Box {
+            min: Vec3::new(-1.0, -1.0, -1.0),
+            max: Vec3::new(1.0, 2.0, 1.0),
+        };
+        let mesh = region_mesh(&region, 8, Material::default());
+        let bounds = mesh.bounds();
+
+        assert!((bounds.min - Vec3::new(-1.0, -1.0, -1.0)).length() < 0.1);
+        assert!((bounds.max - Vec3::new(1.0, 2.0, 1.0)).length() < 0.1);
+    }
+}
@@ -1,10 +1,26 @@
 use crate::geometry::{Vec2, Vec3};
+use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug)]
+// Procedural variation of the diffuse colour over a surface
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Pattern {
+    #[default]
+    Solid,
+    // A 3D checkerboard alternating between the diffuse colour and
+    // `other_colour` in cubes of side `size`
+    Checkerboard { other_colour: Vec3<f32>, size: f32 },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Material {
     pub albedo: Vec2<f32>,
     pub diffuse_colour: Vec3<f32>,
     pub specular_exponent: f32,
+    #[serde(default)]
+    pub pattern: Pattern,
+    // Fraction of the colour seen in the mirror direction that's added on top
+    #[serde(default)]
+    pub reflectivity: f32,
 }
 
 impl Default for Material {
@@ -13,6 +29,8 @@ impl Default for Material {
             albedo: Vec2::new(1.0, 0.0),
             diffuse_colour: Self::DEFAULT_COLOUR,
             specular_exponent: 1.0,
+            pattern: Pattern::Solid,
+            reflectivity: 0.0,
         }
     }
 }
@@ -25,6 +43,136 @@ impl Material {
     };
 
     pub fn new(albedo: Vec2<f32>, diffuse_colour: Vec3<f32>, specular_exponent: f32) -> Self {
-        Material { albedo, diffuse_colour, specular_exponent }
+        Material {
+            albedo,
+            diffuse_colour,
+            specular_exponent,
+            pattern: Pattern::Solid,
+            reflectivity: 0.0,
+        }
+    }
+
+    pub fn with_reflectivity(mut self, reflectivity: f32) -> Self {
+        self.reflectivity = reflectivity;
+        self
+    }
+
+    // A plain grey diffuse for judging lighting and geometry on their own
+    pub fn clay() -> Self {
+        Material::new(Vec2::new(0.8, 0.1), Vec3::new(0.3, 0.3, 0.3), 10.0)
+    }
+
+    pub fn with_pattern(mut self, pattern: Pattern) -> Self {
+        self.pattern = pattern;
+        self
+    }
+
+    // The largest fraction of the light arriving on the surface that it sends
+    // back out, in whichever colour channel reflects most. The diffuse term
+    // reflects its colour scaled by the diffuse albedo, the highlight the
+    // specular albedo times the share of a Phong lobe of its exponent
+    // relative to a diffuse lobe, and the mirror term its reflectivity.
+    // Anything above one makes more light than it receives.
+    pub fn reflectance(&self) -> f32 {
+        let brightest = |c: Vec3<f32>| c.x.max(c.y).max(c.z);
+        let colour = match self.pattern {
+            Pattern::Solid => brightest(self.diffuse_colour),
+            Pattern::Checkerboard { other_colour, .. } => brightest(self.diffuse_colour).max(brightest(other_colour)),
+        };
+
+        self.albedo.x * colour + self.albedo.y * self.specular_share() + self.reflectivity
+    }
+
+    fn specular_share(&self) -> f32 {
+        2.0 / (self.specular_exponent.max(0.0) + 2.0)
+    }
+
+    // What's physically impossible about the material, if anything: negative
+    // or overbright colours, negative albedos or reflectivity, or reflecting
+    // more light than arrives
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let in_range = |c: Vec3<f32>| [c.x, c.y, c.z].iter().all(|v| (0.0..=1.0).contains(v));
+
+        if !in_range(self.diffuse_colour) {
+            problems.push(format!("diffuse colour {:?} outside 0 to 1", self.diffuse_colour));
+        }
+        if let Pattern::Checkerboard { other_colour, .. } = self.pattern {
+            if !in_range(other_colour) {
+                problems.push(format!("checkerboard colour {:?} outside 0 to 1", other_colour));
+            }
+        }
+        if self.albedo.x < 0.0 || self.albedo.y < 0.0 || self.reflectivity < 0.0 {
+            problems.push("negative albedo or reflectivity".to_string());
+        }
+
+        let reflectance = self.reflectance();
+        if reflectance > 1.0 {
+            problems.push(format!("reflects {:.2} times the light it receives", reflectance));
+        }
+
+        problems
+    }
+
+    // The nearest physically possible material: colours clamped to 0 to 1,
+    // albedos and reflectivity to no less than 0, and then all three scaled
+    // down together so no more light is reflected than arrives. Highlights
+    // and mirror reflections keep their balance with the diffuse colour.
+    pub fn normalised(&self) -> Material {
+        let clamp = |c: Vec3<f32>| Vec3::new(c.x.clamp(0.0, 1.0), c.y.clamp(0.0, 1.0), c.z.clamp(0.0, 1.0));
+
+        let mut material = *self;
+        material.diffuse_colour = clamp(material.diffuse_colour);
+        if let Pattern::Checkerboard { other_colour, size } = material.pattern {
+            material.pattern = Pattern::Checkerboard { other_colour: clamp(other_colour), size };
+        }
+        material.albedo = Vec2::new(material.albedo.x.max(0.0), material.albedo.y.max(0.0));
+        material.reflectivity = material.reflectivity.max(0.0);
+
+        let reflectance = material.reflectance();
+        if reflectance > 1.0 {
+            let scale = 1.0 / reflectance;
+            material.albedo = Vec2::new(material.albedo.x * scale, material.albedo.y * scale);
+            material.reflectivity *= scale;
+        }
+
+        material
+    }
+
+    pub fn colour_at(&self, point: Vec3<f32>) -> Vec3<f32> {
+        match self.pattern {
+            Pattern::Solid => self.diffuse_colour,
+            Pattern::Checkerboard { other_colour, size } => {
+                let cell = (point.x / size).floor() + (point.y / size).floor() + (point.z / size).floor();
+                if cell as i64 % 2 == 0 {
+                    self.diffuse_colour
+                } else {
+                    other_colour
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overbright_materials_are_normalised_to_reflect_no_more_than_they_receive() {
+        assert!(Material::default().problems().is_empty());
+
+        let glowing = Material::new(Vec2::new(1.5, 2.0), Vec3::new(1.2, 0.5, -0.1), 2.0).with_reflectivity(0.5);
+        assert_eq!(glowing.problems().len(), 2);
+
+        let normalised = glowing.normalised();
+        assert!(normalised.problems().is_empty());
+        assert!((normalised.reflectance() - 1.0).abs() < 1.0e-5);
+        assert_eq!(normalised.diffuse_colour, Vec3::new(1.0, 0.5, 0.0));
+
+        // The terms keep their proportions
+        let ratio = |m: &Material| (m.albedo.y / m.albedo.x, m.reflectivity / m.albedo.x);
+        let (a, b) = (ratio(&glowing), ratio(&normalised));
+        assert!((a.0 - b.0).abs() < 1.0e-5 && (a.1 - b.1).abs() < 1.0e-5);
     }
 }
@@ -0,0 +1,635 @@
+use crate::Result;
+use crate::bvh::Bvh;
+use crate::diagnostics::luminance;
+use crate::geometry::{Aabb, Hit, Intersect, Ray, Vec2, Vec3, facing, ray_triangle, triangle_normal};
+use crate::image::{self, Image};
+use crate::materials::Material;
+use crate::simplify::{self, Target};
+
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+use std::fs;
+use std::mem::size_of;
+use std::sync::Arc;
+
+#[derive(Debug)]
+struct MeshData {
+    vertices: Vec<Vec3<f32>>,
+    faces: Vec<[usize; 3]>,
+    texcoords: Vec<Vec2<f32>>,
+    texcoord_faces: Vec<Option<[usize; 3]>>,
+    bvh: Bvh,
+}
+
+impl MeshData {
+    fn new(obj: Obj) -> Self {
+        let Obj { vertices, faces, texcoords, texcoord_faces } = obj;
+
+        let bounds: Vec<Aabb> = faces
+            .iter()
+            .map(|face| Aabb::from_points(&[vertices[face[0]], vertices[face[1]], vertices[face[2]]]))
+            .collect();
+
+        MeshData {
+            bvh: Bvh::build(&bounds),
+            vertices,
+            faces,
+            texcoords,
+            texcoord_faces,
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        self.vertices.len() * size_of::<Vec3<f32>>()
+            + self.faces.len() * size_of::<[usize; 3]>()
+            + self.texcoords.len() * size_of::<Vec2<f32>>()
+            + self.texcoord_faces.len() * size_of::<Option<[usize; 3]>>()
+            + self.bvh.bytes()
+    }
+}
+
+// A simplified version of a mesh, used instead of it from `distance` away
+//
+//     (distance: 30.0, triangles: Some(5000))
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Lod {
+    pub distance: f32,
+    #[serde(default)]
+    pub triangles: Option<usize>,
+    #[serde(default)]
+    pub error: Option<f32>,
+}
+
+impl Lod {
+    fn target(&self) -> Target {
+        Target { triangles: self.triangles, error: self.error }
+    }
+}
+
+// A mesh made from the brightness of an image, as a grid `size` across in x
+// and z centred on the mesh's position, rising to `height` where the image
+// is white
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Heightfield {
+    pub size: Vec2<f32>,
+    pub height: f32,
+}
+
+// A triangle mesh loaded from an OBJ file, or made from a heightfield image.
+// The geometry is shared between clones, and the mesh is moved around by
+// offsetting rays rather than its vertices so the BVH never needs rebuilding.
+//
+// In scene files a mesh is described by the path it's loaded from, and
+// optionally how far to simplify it on loading and simpler levels of detail
+// to switch to as the camera moves away:
+//
+//     Mesh((path: "duck.obj", material: (...), position: (x: 0.0, y: 0.0, z: -10.0),
+//           decimate: Some((triangles: Some(50000))), lods: [(distance: 30.0, triangles: Some(5000))]))
+//
+// or by the image it's made from:
+//
+//     Mesh((path: "terrain.ppm", heightfield: Some((size: (x: 40.0, y: 40.0), height: 5.0)), material: (...)))
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "MeshDescription", into = "MeshDescription")]
+pub struct Mesh {
+    pub path: String,
+    pub material: Material,
+    pub position: Vec3<f32>,
+    pub heightfield: Option<Heightfield>,
+    pub decimate: Option<Target>,
+    pub lods: Vec<Lod>,
+    // The full mesh followed by one per LOD, nearest first, and which of
+    // them is in use. Levels not in use can be dropped to save memory, to be
+    // loaded again when they're next needed.
+    levels: Vec<Option<Arc<MeshData>>>,
+    level: usize,
+    // When each level was last in use, as counted by a memory budget
+    used: Vec<u64>,
+    // The full mesh's bounds, kept for when it's been dropped
+    local_bounds: Aabb,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MeshDescription {
+    path: String,
+    material: Material,
+    #[serde(default)]
+    position: Vec3<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    heightfield: Option<Heightfield>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    decimate: Option<Target>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    lods: Vec<Lod>,
+}
+
+impl TryFrom<MeshDescription> for Mesh {
+    type Error = String;
+
+    fn try_from(description: MeshDescription) -> std::result::Result<Self, String> {
+        let mesh = match description.heightfield {
+            Some(heightfield) => Mesh::from_heightfield(&description.path, heightfield, description.material),
+            None => Mesh::from_obj(&description.path, description.material),
+        };
+        let mut mesh = mesh.map_err(|e| format!("failed to load mesh `{}`: {}", description.path, e))?;
+        mesh.position = description.position;
+        if let Some(target) = description.decimate {
+            mesh = mesh.decimated(target);
+        }
+        Ok(mesh.with_lods(description.lods))
+    }
+}
+
+impl From<Mesh> for MeshDescription {
+    fn from(mesh: Mesh) -> Self {
+        MeshDescription {
+            path: mesh.path,
+            material: mesh.material,
+            position: mesh.position,
+            heightfield: mesh.heightfield,
+            decimate: mesh.decimate,
+            lods: mesh.lods,
+        }
+    }
+}
+
+// OBJ indices are 1-based, or negative to count back from the most recently
+// defined vertex
+fn resolve_index(index: i64, count: usize, line: usize) -> Result<usize> {
+    let resolved = if index < 0 {
+        count as i64 + index
+    } else {
+        index - 1
+    };
+
+    if resolved < 0 || resolved >= count as i64 {
+        return Err(format!("line {}: face index {} out of range", line, index).into());
+    }
+
+    Ok(resolved as usize)
+}
+
+fn parse_index(field: &str, vertex_count: usize, line: usize) -> Result<usize> {
+    // The position index of `v/vt/vn`
+    let index: i64 = field
+        .split('/')
+        .next()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| format!("line {}: invalid face index `{}`", line, field))?;
+
+    resolve_index(index, vertex_count, line)
+}
+
+// The texture coordinate index of `v/vt/vn`, if there is one
+fn parse_texcoord_index(field: &str, texcoord_count: usize, line: usize) -> Result<Option<usize>> {
+    match field.split('/').nth(1).filter(|s| !s.is_empty()) {
+        Some(s) => {
+            let index = s.parse().map_err(|_| format!("line {}: invalid face index `{}`", line, field))?;
+            resolve_index(index, texcoord_count, line).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+// Texture coordinates are kept per triangle, for those whose corners all have
+// them
+#[derive(Clone, Debug, Default)]
+pub struct Obj {
+    pub vertices: Vec<Vec3<f32>>,
+    pub faces: Vec<[usize; 3]>,
+    pub texcoords: Vec<Vec2<f32>>,
+    pub texcoord_faces: Vec<Option<[usize; 3]>>,
+}
+
+// Parses the vertex positions, texture coordinates and faces of an OBJ file,
+// triangulating polygons as fans. Everything else (normals, groups,
+// materials) is ignored.
+pub fn parse_obj(source: &str) -> Result<Obj> {
+    let mut obj = Obj::default();
+
+    for (i, line) in source.lines().enumerate() {
+        let line_number = i + 1;
+        let mut fields = line.split_whitespace();
+
+        match fields.next() {
+            Some("v") => {
+                let coordinates = fields
+                    .take(3)
+                    .map(str::parse)
+                    .collect::<std::result::Result<Vec<f32>, _>>()
+                    .map_err(|_| format!("line {}: invalid vertex", line_number))?;
+
+                if coordinates.len() != 3 {
+                    return Err(format!("line {}: vertex needs three coordinates", line_number).into());
+                }
+                if !coordinates.iter().all(|c| c.is_finite()) {
+                    return Err(format!("line {}: vertex coordinates must be finite", line_number).into());
+                }
+
+                obj.vertices.push(Vec3::new(coordinates[0], coordinates[1], coordinates[2]));
+            }
+            Some("vt") => {
+                // A missing v defaults to zero, and w is ignored
+                let u = fields.next().and_then(|s| s.parse().ok());
+                let v = fields.next().map_or(Some(0.0), |s| s.parse().ok());
+
+                match (u, v) {
+                    (Some(u), Some(v)) if f32::is_finite(u) && f32::is_finite(v) => obj.texcoords.push(Vec2::new(u, v)),
+                    _ => return Err(format!("line {}: invalid texture coordinate", line_number).into()),
+                }
+            }
+            Some("f") => {
+                let fields: Vec<&str> = fields.collect();
+
+                let indices = fields
+                    .iter()
+                    .map(|field| parse_index(field, obj.vertices.len(), line_number))
+                    .collect::<Result<Vec<usize>>>()?;
+
+                let texcoord_indices = fields
+                    .iter()
+                    .map(|field| parse_texcoord_index(field, obj.texcoords.len(), line_number))
+                    .collect::<Result<Option<Vec<usize>>>>()?;
+
+                if indices.len() < 3 {
+                    return Err(format!("line {}: face needs at least three vertices", line_number).into());
+                }
+
+                for k in 1..indices.len() - 1 {
+                    obj.faces.push([indices[0], indices[k], indices[k + 1]]);
+                    obj.texcoord_faces.push(texcoord_indices.as_ref().map(|t| [t[0], t[k], t[k + 1]]));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(obj)
+}
+
+// A vertex for every texel of the image, with texture coordinates running
+// across it, and two triangles facing up between every four
+pub fn heightfield_obj(image: &Image, heightfield: Heightfield) -> Result<Obj> {
+    let (width, height) = (image.width, image.height);
+    if width < 2 || height < 2 {
+        return Err("a heightfield needs at least 2 by 2 texels".into());
+    }
+
+    let mut obj = Obj::default();
+    for j in 0..height {
+        for i in 0..width {
+            let (u, v) = (i as f32 / (width - 1) as f32, j as f32 / (height - 1) as f32);
+            let y = luminance(image.texels[j * width + i]) * heightfield.height;
+            obj.vertices.push(Vec3::new((u - 0.5) * heightfield.size.x, y, (v - 0.5) * heightfield.size.y));
+            obj.texcoords.push(Vec2::new(u, 1.0 - v));
+        }
+    }
+
+    for j in 0..height - 1 {
+        for i in 0..width - 1 {
+            let corner = j * width + i;
+            for face in [[corner, corner + width, corner + width + 1], [corner, corner + width + 1, corner + 1]] {
+                obj.faces.push(face);
+                obj.texcoord_faces.push(Some(face));
+            }
+        }
+    }
+
+    Ok(obj)
+}
+
+impl Mesh {
+    pub fn new(vertices: Vec<Vec3<f32>>, faces: Vec<[usize; 3]>, material: Material) -> Self {
+        let texcoord_faces = vec![None; faces.len()];
+        Mesh::from_parts(
+            Obj {
+                vertices,
+                faces,
+                texcoords: Vec::new(),
+                texcoord_faces,
+            },
+            material,
+        )
+    }
+
+    fn from_parts(obj: Obj, material: Material) -> Self {
+        let data = MeshData::new(obj);
+        Mesh {
+            path: String::new(),
+            material,
+            position: Vec3::zero(),
+            heightfield: None,
+            decimate: None,
+            lods: Vec::new(),
+            local_bounds: data.bvh.bounds(),
+            levels: vec![Some(Arc::new(data))],
+            level: 0,
+            used: vec![0],
+        }
+    }
+
+    pub fn from_heightfield(path: &str, heightfield: Heightfield, material: Material) -> Result<Self> {
+        let obj = heightfield_obj(&image::load(path)?, heightfield)?;
+
+        let mut mesh = Mesh::from_parts(obj, material);
+        mesh.path = path.to_string();
+        mesh.heightfield = Some(heightfield);
+        Ok(mesh)
+    }
+
+    // The full mesh's geometry, as it was loaded, which is always there while
+    // the levels are being built
+    fn obj(&self) -> Obj {
+        let data = self.levels[0].as_ref().expect("the full mesh is loaded");
+        Obj {
+            vertices: data.vertices.clone(),
+            faces: data.faces.clone(),
+            texcoords: data.texcoords.clone(),
+            texcoord_faces: data.texcoord_faces.clone(),
+        }
+    }
+
+    fn data(&self) -> &MeshData {
+        self.levels[self.level].as_ref().expect("the level in use is loaded")
+    }
+
+    // The mesh simplified as far as the target, in place of the full mesh
+    pub fn decimated(mut self, target: Target) -> Self {
+        let data = MeshData::new(simplify::decimate(&self.obj(), target));
+        self.local_bounds = data.bvh.bounds();
+        self.levels = vec![Some(Arc::new(data))];
+        self.level = 0;
+        self.decimate = Some(target);
+
+        let lods = std::mem::take(&mut self.lods);
+        self.with_lods(lods)
+    }
+
+    // Builds the simpler levels of detail, each simplified from the full mesh
+    pub fn with_lods(mut self, mut lods: Vec<Lod>) -> Self {
+        lods.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        let obj = self.obj();
+
+        self.levels.truncate(1);
+        let simplified = lods.iter().map(|lod| Some(Arc::new(MeshData::new(simplify::decimate(&obj, lod.target())))));
+        self.levels.extend(simplified);
+        self.used = vec![0; self.levels.len()];
+        self.lods = lods;
+        self.level = 0;
+        self
+    }
+
+    // Switches to the level of detail for a camera at `eye`, by its distance
+    // from the nearest point of the mesh's bounds, returning whether it
+    // changed. A level that's been dropped is loaded again first, and if
+    // that fails the mesh stays as it is.
+    pub fn select_lod(&mut self, eye: Vec3<f32>) -> bool {
+        let bounds = self.bounds();
+        let nearest = Vec3::new(
+            eye.x.clamp(bounds.min.x, bounds.max.x),
+            eye.y.clamp(bounds.min.y, bounds.max.y),
+            eye.z.clamp(bounds.min.z, bounds.max.z),
+        );
+        let distance = (eye - nearest).length();
+
+        let level = self.lods.iter().take_while(|lod| lod.distance <= distance).count();
+        if self.reload(level).is_err() {
+            return false;
+        }
+        let changed = level != self.level;
+        self.level = level;
+        changed
+    }
+
+    // Which level of detail is in use, zero being the full mesh
+    pub fn lod(&self) -> usize {
+        self.level
+    }
+
+    pub fn level_count(&self) -> usize {
+        self.levels.len()
+    }
+
+    // An identity shared by clones of a loaded level, and its size in bytes
+    pub fn loaded(&self, level: usize) -> Option<(usize, usize)> {
+        let data = self.levels.get(level)?.as_ref()?;
+        Some((Arc::as_ptr(data) as usize, data.bytes()))
+    }
+
+    pub fn last_used(&self, level: usize) -> u64 {
+        self.used[level]
+    }
+
+    pub fn mark_used(&mut self, tick: u64) {
+        self.used[self.level] = tick;
+    }
+
+    // Levels can be dropped unless they're in use or are a full mesh that
+    // didn't come from a file, which couldn't be loaded again
+    pub fn can_evict(&self, level: usize) -> bool {
+        level != self.level && (level != 0 || !self.path.is_empty())
+    }
+
+    // Whether the two meshes' levels are made from the same full mesh, so
+    // can be shared between them
+    fn same_source(&self, other: &Mesh) -> bool {
+        let same_full = match (&self.levels[0], &other.levels[0]) {
+            (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        };
+        let same_file = !self.path.is_empty()
+            && self.path == other.path
+            && self.heightfield == other.heightfield
+            && self.decimate == other.decimate;
+        self.lods == other.lods && (same_full || same_file)
+    }
+
+    // Takes the other mesh's copy of every level both have loaded, if they
+    // come from the same source, so the levels are only held once
+    pub fn share_levels(&mut self, other: &Mesh) {
+        if !self.same_source(other) {
+            return;
+        }
+        for (mine, theirs) in self.levels.iter_mut().zip(&other.levels) {
+            if let (Some(mine), Some(theirs)) = (mine, theirs) {
+                *mine = Arc::clone(theirs);
+            }
+        }
+    }
+
+    pub fn evict(&mut self, level: usize) -> bool {
+        self.can_evict(level) && self.levels[level].take().is_some()
+    }
+
+    // Loads a dropped level again: the full mesh from its file, simplified as
+    // on loading, and the others simplified from the full mesh
+    pub fn reload(&mut self, level: usize) -> Result<()> {
+        if self.levels[level].is_some() {
+            return Ok(());
+        }
+
+        let data = if level == 0 {
+            let mut full = match self.heightfield {
+                Some(heightfield) => Mesh::from_heightfield(&self.path, heightfield, self.material)?,
+                None => Mesh::from_obj(&self.path, self.material)?,
+            };
+            if let Some(target) = self.decimate {
+                full = full.decimated(target);
+            }
+            full.levels.swap_remove(0)
+        } else {
+            self.reload(0)?;
+            Some(Arc::new(MeshData::new(simplify::decimate(&self.obj(), self.lods[level - 1].target()))))
+        };
+        self.levels[level] = data;
+        Ok(())
+    }
+
+    pub fn from_obj(path: &str, material: Material) -> Result<Self> {
+        let obj = parse_obj(&fs::read_to_string(path)?)?;
+
+        let mut mesh = Mesh::from_parts(obj, material);
+        mesh.path = path.to_string();
+        Ok(mesh)
+    }
+
+    pub fn face_count(&self) -> usize {
+        self.data().faces.len()
+    }
+
+    pub fn triangle(&self, face: usize) -> [Vec3<f32>; 3] {
+        let [a, b, c] = self.data().faces[face];
+        let vertices = &self.data().vertices;
+        [vertices[a], vertices[b], vertices[c]]
+    }
+
+    // The texture coordinates of the triangle's corners, if it has them
+    pub fn triangle_texcoords(&self, face: usize) -> Option<[Vec2<f32>; 3]> {
+        let [a, b, c] = self.data().texcoord_faces[face]?;
+        let texcoords = &self.data().texcoords;
+        Some([texcoords[a], texcoords[b], texcoords[c]])
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        // Every level covers the same space, near enough, so selecting one
+        // never depends on which is in use
+        let bounds = self.local_bounds;
+        Aabb {
+            min: bounds.min + self.position,
+            max: bounds.max + self.position,
+        }
+    }
+}
+
+impl Intersect for Mesh {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        let local = Ray {
+            origin: ray.origin - self.position,
+            direction: ray.direction,
+        };
+
+        let mut nearest_face = 0;
+        let distance = self.data().bvh.intersect(&local, f32::MAX, |face, max_distance| {
+            let (distance, _, _) = ray_triangle(&local, self.triangle(face))?;
+            if distance < max_distance {
+                nearest_face = face;
+                Some(distance)
+            } else {
+                None
+            }
+        })?;
+
+        let normal = triangle_normal(self.triangle(nearest_face));
+        Some(Hit::new(ray, distance, facing(normal, ray), self.material))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUAD: &str = "
+        # a unit square facing +z, as a single polygon
+        v -1 -1 0
+        v 1 -1 0
+        v 1 1 0
+        v -1 1 0
+        vt 0 0
+        f 1/1 2/1 3/1 -1/1
+    ";
+
+    #[test]
+    fn parse_and_triangulate() {
+        let obj = parse_obj(QUAD).unwrap();
+        assert_eq!(obj.vertices.len(), 4);
+        assert_eq!(obj.faces, vec![[0, 1, 2], [0, 2, 3]]);
+        assert_eq!(obj.texcoord_faces, vec![Some([0, 0, 0]); 2]);
+    }
+
+    #[test]
+    fn reject_out_of_range_index() {
+        assert!(parse_obj("v 0 0 0\nf 1 2 3").is_err());
+    }
+
+    #[test]
+    fn reject_non_finite_values() {
+        assert!(parse_obj("v 0 nan 0").is_err());
+        assert!(parse_obj("v 0 0 inf").is_err());
+        assert!(parse_obj("vt 0.5 -inf").is_err());
+    }
+
+    #[test]
+    fn intersect_offset_mesh() {
+        let obj = parse_obj(QUAD).unwrap();
+        let mut mesh = Mesh::new(obj.vertices, obj.faces, Material::default());
+        mesh.position = Vec3::new(0.0, 0.0, -5.0);
+
+        let ray = Ray {
+            origin: Vec3::new(0.5, 0.5, 0.0),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+
+        let hit = mesh.ray_intersect(&ray).unwrap();
+        assert_eq!(hit.distance, 5.0);
+        assert_eq!(hit.normal, Vec3::new(0.0, 0.0, 1.0));
+    }
+
+    #[test]
+    fn heightfield_faces_up() {
+        let texels = (0..12).map(|k| if k == 5 { Vec3::new(1.0, 1.0, 1.0) } else { Vec3::zero() }).collect();
+        let image = Image { width: 4, height: 3, texels };
+        let obj = heightfield_obj(&image, Heightfield { size: Vec2::new(6.0, 4.0), height: 2.0 }).unwrap();
+
+        assert_eq!((obj.vertices.len(), obj.faces.len()), (12, 12));
+        assert_eq!(obj.vertices[0], Vec3::new(-3.0, 0.0, -2.0));
+        assert!((obj.vertices[5].y - 2.0).abs() < 1.0e-5);
+        assert!(obj.faces.iter().all(|face| triangle_normal(face.map(|v| obj.vertices[v])).y > 0.0));
+    }
+
+    #[test]
+    fn lods_switch_with_distance() {
+        // A fan of triangles around the origin, which simplifies to a single
+        // square's worth
+        let mut vertices = vec![Vec3::zero()];
+        vertices.extend((0..16).map(|k| {
+            let angle = k as f32 * std::f32::consts::PI / 8.0;
+            Vec3::new(angle.cos(), angle.sin(), 0.0)
+        }));
+        let faces = (0..16).map(|k| [0, k + 1, (k + 1) % 16 + 1]).collect();
+        let lod = Lod { distance: 10.0, triangles: Some(4), error: None };
+        let mut mesh = Mesh::new(vertices, faces, Material::default()).with_lods(vec![lod]);
+
+        assert!(!mesh.select_lod(Vec3::new(0.0, 0.0, 5.0)));
+        assert_eq!(mesh.face_count(), 16);
+        assert!(mesh.select_lod(Vec3::new(0.0, 0.0, 20.0)));
+        assert_eq!(mesh.lod(), 1);
+        assert!(mesh.face_count() <= 4);
+
+        let ray = Ray {
+            origin: Vec3::new(0.1, 0.1, 20.0),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+        assert!(mesh.ray_intersect(&ray).is_some());
+    }
+}
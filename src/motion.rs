@@ -0,0 +1,285 @@
+use crate::Result;
+use crate::camera::Camera;
+use crate::framebuffer::Framebuffer;
+use crate::geometry::Vec3;
+use crate::render::nearest_shape;
+use crate::scene::Scene;
+
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Magic number at the start of a Middlebury .flo file, which reads as "PIEH"
+// in ASCII
+const FLO_TAG: f32 = 202021.25;
+
+// Per pixel screen-space motion: how far, in pixels, what's seen at each pixel
+// centre has moved since the previous frame, with y down the image. A pixel at
+// (x, y) was at (x - dx, y - dy) in the previous frame.
+pub struct MotionVectors {
+    pub width: usize,
+    pub height: usize,
+    pub vectors: Vec<(f32, f32)>,
+}
+
+impl MotionVectors {
+    // The motion seen from `camera` since the frame seen from `previous`, with
+    // every shape moved back by `ticks` update ticks of its velocity. Where
+    // nothing is hit the background is treated as infinitely far away, so
+    // only the camera turning moves it.
+    pub fn new(scene: &Scene, camera: &Camera, previous: &Camera, ticks: f32, width: usize, height: usize) -> Self {
+        let mut framebuffer = Framebuffer::new(width, height);
+
+        framebuffer.render(|i, j| {
+            let (x, y) = (i as f32 + 0.5, j as f32 + 0.5);
+            let ray = camera.ray_through(x, y, width, height);
+
+            let before = match nearest_shape(&ray, scene) {
+                Some((shape, hit)) => hit.point - scene.shapes[shape].velocity() * ticks,
+                None => previous.position + ray.direction,
+            };
+
+            match previous.project(before, width, height) {
+                Some((px, py)) => Vec3::new(x - px, y - py, 0.0),
+                // Came from behind the previous camera, so has no position
+                // in the previous frame
+                None => Vec3::zero(),
+            }
+        });
+
+        MotionVectors {
+            width,
+            height,
+            vectors: framebuffer.colours.iter().map(|v| (v.x, v.y)).collect(),
+        }
+    }
+
+    // Looks up where each pixel was in the previous frame's colours, for
+    // accumulating over time. Pixels that came from outside the previous
+    // frame have nothing to reuse.
+    pub fn reproject(&self, previous: &[Vec3<f32>]) -> Vec<Option<Vec3<f32>>> {
+        let (width, height) = (self.width, self.height);
+
+        (0..height)
+            .flat_map(|j| (0..width).map(move |i| (i, j)))
+            .map(|(i, j)| {
+                let (dx, dy) = self.vectors[j * width + i];
+                let x = (i as f32 + 0.5 - dx).floor();
+                let y = (j as f32 + 0.5 - dy).floor();
+
+                if x < 0.0 || y < 0.0 || x >= width as f32 || y >= height as f32 {
+                    None
+                } else {
+                    Some(previous[y as usize * width + x as usize])
+                }
+            })
+            .collect()
+    }
+
+    // The usual optical flow colour wheel: the direction of motion as hue and
+    // its size, relative to the largest, as saturation
+    pub fn visualise(&self) -> Framebuffer {
+        let largest = self
+            .vectors
+            .iter()
+            .map(|&(dx, dy)| (dx * dx + dy * dy).sqrt())
+            .fold(0.0, f32::max)
+            .max(1.0e-6);
+
+        let mut framebuffer = Framebuffer::new(self.width, self.height);
+        framebuffer.render(|i, j| {
+            let (dx, dy) = self.vectors[j * self.width + i];
+            let hue = (-dy).atan2(-dx) / std::f32::consts::PI * 3.0 + 3.0;
+            let saturation = ((dx * dx + dy * dy).sqrt() / largest).min(1.0);
+            hsv(hue, saturation)
+        });
+
+        framebuffer
+    }
+
+    // Writes the vectors as a Middlebury .flo file, which optical flow and
+    // frame interpolation tools read, or their visualisation as an image
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+
+        if path.extension().and_then(OsStr::to_str) != Some("flo") {
+            return crate::image::save(&self.visualise(), path);
+        }
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_flo(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn write_flo<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&FLO_TAG.to_le_bytes())?;
+        writer.write_all(&(self.width as i32).to_le_bytes())?;
+        writer.write_all(&(self.height as i32).to_le_bytes())?;
+
+        for &(dx, dy) in &self.vectors {
+            writer.write_all(&dx.to_le_bytes())?;
+            writer.write_all(&dy.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+// A frame halfway between two others, given the motion from `previous` to
+// `next` as seen from `next`. Each pixel takes the motion at the same pixel
+// of `next` as its own, which holds wherever motion is smooth, and blends the
+// two frames sampled half of it either side. Where one side falls outside its
+// frame the other is used alone.
+pub fn interpolate(previous: &Framebuffer, next: &Framebuffer, motion: &MotionVectors) -> Framebuffer {
+    let mut framebuffer = Framebuffer::new(next.width, next.height);
+
+    framebuffer.render(|i, j| {
+        let (dx, dy) = motion.vectors[j * motion.width + i];
+        let (x, y) = (i as f32 + 0.5, j as f32 + 0.5);
+        let before = sample(previous, x - dx * 0.5, y - dy * 0.5);
+        let after = sample(next, x + dx * 0.5, y + dy * 0.5);
+
+        match (before, after) {
+            (Some(before), Some(after)) => (before + after) * 0.5,
+            (Some(colour), None) | (None, Some(colour)) => colour,
+            (None, None) => next.colours[j * next.width + i],
+        }
+    });
+
+    framebuffer
+}
+
+// Bilinear lookup at a position in pixels, or None outside the image
+fn sample(framebuffer: &Framebuffer, x: f32, y: f32) -> Option<Vec3<f32>> {
+    let (width, height) = (framebuffer.width, framebuffer.height);
+    if !(x >= 0.0 && y >= 0.0 && x <= width as f32 && y <= height as f32) {
+        return None;
+    }
+
+    let (x, y) = ((x - 0.5).clamp(0.0, (width - 1) as f32), (y - 0.5).clamp(0.0, (height - 1) as f32));
+    let (i, j) = (x as usize, y as usize);
+    let (i1, j1) = ((i + 1).min(width - 1), (j + 1).min(height - 1));
+    let (fx, fy) = (x - i as f32, y - j as f32);
+    let at = |i: usize, j: usize| framebuffer.colours[j * width + i];
+
+    let top = at(i, j) * (1.0 - fx) + at(i1, j) * fx;
+    let bottom = at(i, j1) * (1.0 - fx) + at(i1, j1) * fx;
+    Some(top * (1.0 - fy) + bottom * fy)
+}
+
+// Hue in [0, 6) around the wheel from red, at full value
+fn hsv(hue: f32, saturation: f32) -> Vec3<f32> {
+    let sector = (hue.floor() as i32).rem_euclid(6);
+    let f = hue - hue.floor();
+    let (p, q, t) = (1.0 - saturation, 1.0 - saturation * f, 1.0 - saturation * (1.0 - f));
+
+    match sector {
+        0 => Vec3::new(1.0, t, p),
+        1 => Vec3::new(q, 1.0, p),
+        2 => Vec3::new(p, 1.0, t),
+        3 => Vec3::new(p, q, 1.0),
+        4 => Vec3::new(t, p, 1.0),
+        _ => Vec3::new(1.0, p, q),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Shape, Sphere};
+    use crate::materials::Material;
+    use crate::render::Renderer;
+
+    fn sphere_scene(velocity: Vec3<f32>) -> Scene {
+        let sphere = Sphere::new(Vec3::new(0.0, 0.0, -10.0), 2.0, Material::default()).with_velocity(velocity);
+
+        let mut scene = Scene::default_scene();
+        scene.shapes = vec![Shape::Sphere(sphere)];
+        scene
+    }
+
+    #[test]
+    fn moving_sphere_moves_only_its_own_pixels() {
+        let scene = sphere_scene(Vec3::new(0.5, 0.0, 0.0));
+        let camera = Camera::default();
+        let motion = MotionVectors::new(&scene, &camera, &camera, 1.0, 64, 64);
+
+        let (dx, dy) = motion.vectors[32 * 64 + 32];
+        assert!(dx > 0.5 && dy.abs() < 1.0e-3);
+        assert_eq!(motion.vectors[0], (0.0, 0.0));
+    }
+
+    #[test]
+    fn turning_camera_moves_the_background() {
+        let scene = sphere_scene(Vec3::zero());
+        let previous = Camera::default();
+        let mut camera = previous;
+        camera.rotate(0.01, 0.0);
+
+        let motion = MotionVectors::new(&scene, &camera, &previous, 1.0, 64, 64);
+        let centre = motion.vectors[32 * 64 + 32];
+
+        // Turning on the spot, near and far things alike were seen in the
+        // same direction from where the vectors point back to
+        for &(i, j) in &[(0, 0), (32, 32), (60, 10)] {
+            let (dx, dy) = motion.vectors[j * 64 + i];
+            assert!(dx < -0.1);
+
+            let (x, y) = (i as f32 + 0.5, j as f32 + 0.5);
+            let now = camera.ray_through(x, y, 64, 64).direction;
+            let before = previous.ray_through(x - dx, y - dy, 64, 64).direction;
+            assert!((now - before).length() < 1.0e-4);
+        }
+
+        // Following the vectors back lands on the previous frame's pixels
+        let colours: Vec<Vec3<f32>> = (0..64 * 64).map(|k| Vec3::new(k as f32, 0.0, 0.0)).collect();
+        let reprojected = motion.reproject(&colours);
+        let (x, y) = (32.5 - centre.0, 32.5 - centre.1);
+        assert_eq!(reprojected[32 * 64 + 32], Some(colours[y as usize * 64 + x as usize]));
+    }
+
+    #[test]
+    fn interpolated_frame_follows_the_motion() {
+        let (width, height) = (64, 64);
+        let camera = Camera::default();
+        let renderer = Renderer::default();
+        let mut scene = sphere_scene(Vec3::new(0.5, 0.0, 0.0));
+        let mut frames = Vec::new();
+        for _ in 0..3 {
+            let mut framebuffer = Framebuffer::new(width, height);
+            renderer.render(&mut framebuffer, &camera, &scene);
+            frames.push(framebuffer);
+            scene.shapes[0].translate(Vec3::new(0.5, 0.0, 0.0));
+        }
+        scene.shapes[0].translate(Vec3::new(-0.5, 0.0, 0.0));
+
+        // Closer to the traced middle frame than simply blending its neighbours
+        let motion = MotionVectors::new(&scene, &camera, &camera, 2.0, width, height);
+        let interpolated = interpolate(&frames[0], &frames[2], &motion);
+        let error = |colours: &dyn Fn(usize) -> Vec3<f32>| -> f32 {
+            (0..width * height).map(|k| (colours(k) - frames[1].colours[k]).length()).sum()
+        };
+        let interpolated_error = error(&|k| interpolated.colours[k]);
+        let blended_error = error(&|k| (frames[0].colours[k] + frames[2].colours[k]) * 0.5);
+        assert!(interpolated_error < blended_error * 0.5, "{} vs {}", interpolated_error, blended_error);
+    }
+
+    #[test]
+    fn flo_header() {
+        let motion = MotionVectors {
+            width: 2,
+            height: 1,
+            vectors: vec![(1.0, -2.0), (0.0, 0.5)],
+        };
+
+        let mut data = Vec::new();
+        motion.write_flo(&mut data).unwrap();
+
+        assert_eq!(&data[0..4], b"PIEH");
+        assert_eq!(data.len(), 12 + 2 * 8);
+        assert_eq!(i32::from_le_bytes([data[4], data[5], data[6], data[7]]), 2);
+        assert_eq!(f32::from_le_bytes([data[16], data[17], data[18], data[19]]), -2.0);
+    }
+}
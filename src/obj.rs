@@ -0,0 +1,152 @@
+use crate::Result;
+use crate::geometry::Shape;
+use crate::materials::Material;
+use crate::scene::Scene;
+use crate::tessellate;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Writes the visible shapes as OBJ, each as its own object using the
+// material of the same index in the MTL file named `mtl`. Spheres and planes
+// are tessellated, and curves written as polylines. Point clouds are left
+// out, as few tools read OBJ points.
+pub fn write_obj<W: Write>(writer: &mut W, scene: &Scene, mtl: &str) -> Result<()> {
+    writeln!(writer, "mtllib {}", mtl)?;
+
+    // OBJ indices are 1-based and count over the whole file
+    let (mut positions, mut normals, mut texcoords) = (1, 1, 1);
+
+    for (index, shape) in scene.shapes.iter().enumerate() {
+        if !scene.is_visible(index) || matches!(shape, Shape::Points(_)) {
+            continue;
+        }
+
+        writeln!(writer, "o {}_{}", shape.name(), index)?;
+        writeln!(writer, "usemtl material_{}", index)?;
+
+        if let Shape::Curves(curves) = shape {
+            for line in tessellate::polylines(curves) {
+                for p in &line {
+                    writeln!(writer, "v {} {} {}", p.x, p.y, p.z)?;
+                }
+                write!(writer, "l")?;
+                for k in 0..line.len() {
+                    write!(writer, " {}", positions + k)?;
+                }
+                writeln!(writer)?;
+                positions += line.len();
+            }
+            continue;
+        }
+
+        // Scatters of points or curves are left empty
+        let Some(surface) = tessellate::surface(shape) else { continue };
+        for p in &surface.positions {
+            writeln!(writer, "v {} {} {}", p.x, p.y, p.z)?;
+        }
+        for n in &surface.normals {
+            writeln!(writer, "vn {} {} {}", n.x, n.y, n.z)?;
+        }
+        for uv in &surface.texcoords {
+            writeln!(writer, "vt {} {}", uv.x, uv.y)?;
+        }
+
+        let textured = !surface.texcoords.is_empty();
+        for triangle in &surface.triangles {
+            write!(writer, "f")?;
+            for &corner in triangle {
+                let corner = corner as usize;
+                if textured {
+                    write!(writer, " {}/{}/{}", positions + corner, texcoords + corner, normals + corner)?;
+                } else {
+                    write!(writer, " {}//{}", positions + corner, normals + corner)?;
+                }
+            }
+            writeln!(writer)?;
+        }
+
+        positions += surface.positions.len();
+        normals += surface.normals.len();
+        texcoords += surface.texcoords.len();
+    }
+
+    Ok(())
+}
+
+// One material per shape, as its nearest Phong equivalent. Patterns are left
+// out, so checkerboards come out in their diffuse colour.
+pub fn write_mtl<W: Write>(writer: &mut W, scene: &Scene) -> Result<()> {
+    for (index, shape) in scene.shapes.iter().enumerate() {
+        if !scene.is_visible(index) {
+            continue;
+        }
+
+        let Material { albedo, diffuse_colour: kd, specular_exponent, reflectivity, .. } = *shape.material();
+        let kd = kd * albedo.x;
+
+        writeln!(writer, "newmtl material_{}", index)?;
+        writeln!(writer, "Kd {} {} {}", kd.x, kd.y, kd.z)?;
+        writeln!(writer, "Ks {0} {0} {0}", albedo.y)?;
+        writeln!(writer, "Ns {}", specular_exponent)?;
+        if reflectivity > 0.0 {
+            // Illumination model 3 adds mirror reflections weighted by Ks
+            writeln!(writer, "Ka {0} {0} {0}", reflectivity)?;
+            writeln!(writer, "illum 3")?;
+        } else {
+            writeln!(writer, "illum 2")?;
+        }
+        writeln!(writer)?;
+    }
+
+    Ok(())
+}
+
+// Writes the scene's geometry to `path` and its materials next to it, with
+// the extension changed to .mtl
+pub fn export<P: AsRef<Path>>(scene: &Scene, path: P) -> Result<()> {
+    let path = path.as_ref();
+    let mtl_path = path.with_extension("mtl");
+    let mtl = mtl_path.file_name().ok_or("OBJ export needs a file name")?.to_string_lossy();
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_obj(&mut writer, scene, &mtl)?;
+    writer.flush()?;
+
+    let mut writer = BufWriter::new(File::create(&mtl_path)?);
+    write_mtl(&mut writer, scene)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::parse_obj;
+
+    #[test]
+    fn export_reads_back_as_the_tessellated_shapes() {
+        let mut scene = Scene::default_scene();
+        scene.hidden.insert(1);
+
+        let mut data = Vec::new();
+        write_obj(&mut data, &scene, "scene.mtl").unwrap();
+        let obj = parse_obj(&String::from_utf8(data).unwrap()).unwrap();
+
+        let surfaces: Vec<_> = scene
+            .shapes
+            .iter()
+            .enumerate()
+            .filter(|&(index, _)| scene.is_visible(index))
+            .filter_map(|(_, shape)| tessellate::surface(shape))
+            .collect();
+        let vertices: usize = surfaces.iter().map(|surface| surface.positions.len()).sum();
+        let triangles: usize = surfaces.iter().map(|surface| surface.triangles.len()).sum();
+
+        assert_eq!(obj.vertices.len(), vertices);
+        assert_eq!(obj.faces.len(), triangles);
+        assert_eq!(obj.faces.iter().flatten().max(), Some(&(vertices - 1)));
+    }
+}
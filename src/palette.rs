@@ -0,0 +1,206 @@
+use crate::geometry::{Shape, Sphere, Vec2, Vec3};
+use crate::image::Image;
+use crate::materials::Material;
+use crate::sampling;
+use crate::scene::Scene;
+
+// The golden angle as a fraction of a turn, which spaces out any number of
+// hues so that no two neighbours are alike
+const GOLDEN_TURN: f32 = 0.381_966;
+
+// Texels an image's palette is found from at most, spread evenly over it
+const MAX_PALETTE_TEXELS: usize = 16_384;
+
+const PALETTE_ITERATIONS: usize = 16;
+
+// Converts a hue in turns, and saturation and value from 0 to 1, into RGB
+pub fn hsv(hue: f32, saturation: f32, value: f32) -> Vec3<f32> {
+    let h = hue.rem_euclid(1.0) * 6.0;
+    let c = value * saturation;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = value - c;
+    Vec3::new(r + m, g + m, b + m)
+}
+
+// The hue in turns, and saturation and value from 0 to 1, of an RGB colour
+pub fn to_hsv(colour: Vec3<f32>) -> (f32, f32, f32) {
+    let (r, g, b) = (colour.x, colour.y, colour.z);
+    let max = r.max(g).max(b);
+    let c = max - r.min(g).min(b);
+    if c <= 0.0 {
+        return (0.0, 0.0, max);
+    }
+
+    let h = if max == r {
+        (g - b) / c
+    } else if max == g {
+        (b - r) / c + 2.0
+    } else {
+        (r - g) / c + 4.0
+    };
+    ((h / 6.0).rem_euclid(1.0), c / max, max)
+}
+
+// `count` colours with hues spaced around the wheel by the golden angle from
+// a starting hue picked by `seed`. Saturation and value vary a little around
+// moderate levels, which keep colours apart without any looking garish.
+pub fn harmonious(count: usize, seed: u64) -> Vec<Vec3<f32>> {
+    let random = |n: u64| sampling::random(sampling::seed(seed as usize, n as usize));
+    let start = random(0);
+
+    (0..count)
+        .map(|k| {
+            let k = k as u64;
+            let saturation = 0.45 + 0.25 * random(3 * k + 1);
+            let value = 0.55 + 0.3 * random(3 * k + 2);
+            hsv(start + GOLDEN_TURN * k as f32, saturation, value)
+        })
+        .collect()
+}
+
+// The `count` colours that best represent the image, found by k-means over
+// its texels, most common first. Texels are clamped to 0 to 1, as HDR
+// highlights would otherwise pull colours out of range.
+pub fn from_image(image: &Image, count: usize) -> Vec<Vec3<f32>> {
+    let clamp = |c: Vec3<f32>| Vec3::new(c.x.clamp(0.0, 1.0), c.y.clamp(0.0, 1.0), c.z.clamp(0.0, 1.0));
+    let step = image.texels.len().div_ceil(MAX_PALETTE_TEXELS).max(1);
+    let texels: Vec<Vec3<f32>> = image.texels.iter().step_by(step).map(|&c| clamp(c)).collect();
+    if texels.is_empty() || count == 0 {
+        return Vec::new();
+    }
+
+    let distance = |a: Vec3<f32>, b: Vec3<f32>| (a - b).length();
+    let nearest = |centres: &[Vec3<f32>], c: Vec3<f32>| {
+        (0..centres.len())
+            .min_by(|&a, &b| distance(centres[a], c).total_cmp(&distance(centres[b], c)))
+            .expect("there's always a centre")
+    };
+
+    // Starting from the first texel, each centre is the texel furthest from
+    // those chosen so far, which finds small but distinct areas of colour
+    let mut centres = vec![texels[0]];
+    while centres.len() < count {
+        let gap = |c: Vec3<f32>| distance(centres[nearest(&centres, c)], c);
+        let furthest = texels
+            .iter()
+            .copied()
+            .max_by(|&a, &b| gap(a).total_cmp(&gap(b)))
+            .expect("there are texels");
+        if centres.contains(&furthest) {
+            break;
+        }
+        centres.push(furthest);
+    }
+
+    let mut counts = vec![0; centres.len()];
+    for _ in 0..PALETTE_ITERATIONS {
+        let mut sums = vec![Vec3::zero(); centres.len()];
+        counts = vec![0; centres.len()];
+
+        for &texel in &texels {
+            let centre = nearest(&centres, texel);
+            sums[centre] = sums[centre] + texel;
+            counts[centre] += 1;
+        }
+        for (centre, (&sum, &n)) in centres.iter_mut().zip(sums.iter().zip(&counts)) {
+            if n > 0 {
+                *centre = sum * (1.0 / n as f32);
+            }
+        }
+    }
+
+    let mut ranked: Vec<_> = centres.into_iter().zip(counts).collect();
+    ranked.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    ranked.into_iter().map(|(colour, _)| colour).collect()
+}
+
+// A random material in one of the palette's colours: mostly matte or glossy,
+// with the odd mirror. Every one reflects no more light than it receives.
+pub fn random_material(palette: &[Vec3<f32>], seed: u64) -> Material {
+    let random = |n: u64| sampling::random(seed.wrapping_add(n));
+    let colour = match palette.len() {
+        0 => Vec3::new(0.5, 0.5, 0.5),
+        n => palette[((random(0) * n as f32) as usize).min(n - 1)],
+    };
+
+    let kind = random(1);
+    let material = if kind < 0.5 {
+        Material::new(Vec2::new(0.9, 0.05), colour, 10.0)
+    } else if kind < 0.85 {
+        Material::new(Vec2::new(0.6, 0.3 + 0.5 * random(2)), colour, 50.0 + 450.0 * random(3))
+    } else {
+        Material::new(Vec2::new(0.1, 5.0), colour, 1000.0).with_reflectivity(0.6 + 0.2 * random(2))
+    };
+
+    material.normalised()
+}
+
+// Gives every shape in the scene a random material from the palette, in
+// place of whatever it had
+pub fn assign(scene: &mut Scene, palette: &[Vec3<f32>], seed: u64) {
+    for (index, shape) in scene.shapes.iter_mut().enumerate() {
+        *shape.material_mut() = random_material(palette, sampling::seed(index, seed as usize));
+    }
+}
+
+// A field of `count` randomly sized spheres over the default scene's floor,
+// coloured from the palette, for stress tests that still look good
+pub fn sphere_field(count: usize, palette: &[Vec3<f32>], seed: u64) -> Scene {
+    let mut scene = Scene::default_scene();
+    scene.shapes.retain(|shape| matches!(shape, Shape::Plane(_)));
+
+    for k in 0..count {
+        let random = |n: u64| sampling::random(sampling::seed(k, seed as usize).wrapping_add(n));
+        let radius = 0.2 + 0.6 * random(0);
+        let centre = Vec3::new(-10.0 + 20.0 * random(1), -4.0 + radius, -10.5 - 19.0 * random(2));
+        scene.shapes.push(Sphere::new(centre, radius, random_material(palette, sampling::seed(k, seed as usize))).into());
+    }
+
+    scene
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hsv_primaries() {
+        assert_eq!(hsv(0.0, 1.0, 1.0), Vec3::new(1.0, 0.0, 0.0));
+        assert_eq!(hsv(1.0 / 3.0, 1.0, 1.0), Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(hsv(0.5, 0.0, 0.5), Vec3::new(0.5, 0.5, 0.5));
+
+        let colour = Vec3::new(0.2, 0.6, 0.45);
+        let (h, s, v) = to_hsv(colour);
+        assert!((hsv(h, s, v) - colour).length() < 1.0e-5);
+    }
+
+    #[test]
+    fn image_palette_finds_its_colours_by_area() {
+        let (red, blue) = (Vec3::new(0.8, 0.1, 0.1), Vec3::new(0.1, 0.2, 0.9));
+        let texels = (0..100).map(|k| if k < 70 { red } else { blue }).collect();
+        let image = Image { width: 10, height: 10, texels };
+
+        let palette = from_image(&image, 2);
+        assert_eq!(palette.len(), 2);
+        assert!((palette[0] - red).length() < 1.0e-5 && (palette[1] - blue).length() < 1.0e-5);
+    }
+
+    #[test]
+    fn random_materials_are_physically_possible() {
+        let palette = harmonious(6, 7);
+        assert!(palette.iter().all(|c| [c.x, c.y, c.z].iter().all(|v| (0.0..=1.0).contains(v))));
+
+        let scene = sphere_field(50, &palette, 3);
+        assert_eq!(scene.shapes.len(), 51);
+        assert!(scene.shapes.iter().all(|shape| shape.material().problems().is_empty()));
+    }
+}
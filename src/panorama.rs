@@ -0,0 +1,304 @@
+use crate::framebuffer::Framebuffer;
+use crate::geometry::{Ray, Vec3};
+use crate::image::Image;
+use crate::render::{RenderMode, TraceSettings, Tracer};
+use crate::sampling;
+use crate::scene::Scene;
+
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+// The face of a cube a direction points into, and its position on that face
+// with both coordinates in [-1, 1], laid out as in OpenGL cube maps (which
+// appear mirrored when viewed from inside). Faces are +x, -x, +y, -y, +z, -z
+// in that order.
+pub fn cube_face(direction: Vec3<f32>) -> (usize, f32, f32) {
+    let Vec3 { x, y, z } = direction;
+    let (ax, ay, az) = (x.abs(), y.abs(), z.abs());
+
+    if ax >= ay && ax >= az {
+        if x > 0.0 { (0, -z / ax, -y / ax) } else { (1, z / ax, -y / ax) }
+    } else if ay >= az {
+        if y > 0.0 { (2, x / ay, z / ay) } else { (3, x / ay, -z / ay) }
+    } else if z > 0.0 {
+        (4, x / az, -y / az)
+    } else {
+        (5, -x / az, -y / az)
+    }
+}
+
+// The inverse of `cube_face`, up to length
+pub fn cube_direction(face: usize, u: f32, v: f32) -> Vec3<f32> {
+    match face {
+        0 => Vec3::new(1.0, -v, -u),
+        1 => Vec3::new(-1.0, -v, u),
+        2 => Vec3::new(u, 1.0, v),
+        3 => Vec3::new(u, -1.0, -v),
+        4 => Vec3::new(u, -v, 1.0),
+        _ => Vec3::new(-u, -v, -1.0),
+    }
+}
+
+// Ways of laying every direction around a point out flat in an image
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    // Equirectangular, as environment maps are: longitude across the image
+    // with -z in the middle, and latitude down it from +y
+    LatLong,
+    // The six faces of a cube side by side, in the order of `cube_face`
+    Cube,
+    // An equidistant fisheye looking down -z, with this field of view in
+    // radians across its circle
+    Fisheye(f32),
+}
+
+impl FromStr for Projection {
+    type Err = String;
+
+    // `latlong`, `cube` or `fisheye`, which covers a hemisphere unless given
+    // a field of view in degrees, such as `fisheye:220`
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let error = || format!("unknown projection `{}` (expected latlong, cube or fisheye[:degrees])", s);
+
+        match s.split_once(':') {
+            None if s == "latlong" => Ok(Projection::LatLong),
+            None if s == "cube" => Ok(Projection::Cube),
+            None if s == "fisheye" => Ok(Projection::Fisheye(PI)),
+            Some(("fisheye", degrees)) => {
+                let degrees: f32 = degrees.parse().map_err(|_| error())?;
+                if degrees > 0.0 && degrees <= 360.0 {
+                    Ok(Projection::Fisheye(degrees.to_radians()))
+                } else {
+                    Err(error())
+                }
+            }
+            _ => Err(error()),
+        }
+    }
+}
+
+impl Projection {
+    // The natural size of an image in this projection with the given height
+    pub fn size(self, height: usize) -> (usize, usize) {
+        match self {
+            Projection::LatLong => (2 * height, height),
+            Projection::Cube => (6 * height, height),
+            Projection::Fisheye(_) => (height, height),
+        }
+    }
+
+    // The (unit) direction seen at a position in the image, with both
+    // coordinates in [0, 1] from the top left, or none if nothing's seen
+    // there
+    pub fn direction(self, u: f32, v: f32) -> Option<Vec3<f32>> {
+        match self {
+            Projection::LatLong => {
+                let (longitude, polar) = ((u - 0.5) * 2.0 * PI, v * PI);
+                Some(Vec3::new(
+                    polar.sin() * longitude.sin(),
+                    polar.cos(),
+                    -polar.sin() * longitude.cos(),
+                ))
+            }
+            Projection::Cube => {
+                let face = ((u * 6.0) as usize).min(5);
+                let u = (u * 6.0 - face as f32) * 2.0 - 1.0;
+                Some(cube_direction(face, u, v * 2.0 - 1.0).normalise())
+            }
+            Projection::Fisheye(fov) => {
+                let (x, y) = (2.0 * u - 1.0, 1.0 - 2.0 * v);
+                let r = (x * x + y * y).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+                if r == 0.0 {
+                    return Some(Vec3::new(0.0, 0.0, -1.0));
+                }
+
+                let angle = r * fov / 2.0;
+                Some(Vec3::new(angle.sin() * x / r, angle.sin() * y / r, -angle.cos()))
+            }
+        }
+    }
+
+    // Where in the image the (unit) direction is seen, or none if it's
+    // outside a fisheye's view
+    pub fn position(self, direction: Vec3<f32>) -> Option<(f32, f32)> {
+        match self {
+            Projection::LatLong => Some((
+                0.5 + direction.x.atan2(-direction.z) / (2.0 * PI),
+                direction.y.clamp(-1.0, 1.0).acos() / PI,
+            )),
+            Projection::Cube => {
+                let (face, u, v) = cube_face(direction);
+                Some(((face as f32 + (u + 1.0) / 2.0) / 6.0, (v + 1.0) / 2.0))
+            }
+            Projection::Fisheye(fov) => {
+                let angle = (-direction.z).clamp(-1.0, 1.0).acos();
+                if angle > fov / 2.0 {
+                    return None;
+                }
+
+                let sideways = (direction.x * direction.x + direction.y * direction.y).sqrt();
+                let r = angle / (fov / 2.0);
+                let (x, y) = if sideways > 0.0 {
+                    (r * direction.x / sideways, r * direction.y / sideways)
+                } else {
+                    (0.0, 0.0)
+                };
+
+                Some(((x + 1.0) / 2.0, (1.0 - y) / 2.0))
+            }
+        }
+    }
+
+    // Bilinearly filtered colour at a position in an image in this
+    // projection. Filtering wraps around a latlong image and stays within
+    // each face of a cube.
+    fn sample(self, image: &Image, u: f32, v: f32) -> Vec3<f32> {
+        let (width, height) = (image.width, image.height);
+        let x = u * width as f32 - 0.5;
+        let y = (v * height as f32 - 0.5).clamp(0.0, (height - 1) as f32);
+
+        let column = |x: isize| match self {
+            Projection::LatLong => x.rem_euclid(width as isize) as usize,
+            Projection::Cube => {
+                let face_width = (width / 6).max(1);
+                let face = ((u * 6.0) as usize).min(5);
+                let first = face * face_width;
+                (x.max(first as isize) as usize).min(first + face_width - 1).min(width - 1)
+            }
+            Projection::Fisheye(_) => (x.max(0) as usize).min(width - 1),
+        };
+
+        let (x0, x1) = (column(x.floor() as isize), column(x.floor() as isize + 1));
+        let (y0, y1) = (y.floor() as usize, (y.floor() as usize + 1).min(height - 1));
+        let (fx, fy) = (x - x.floor(), y - y.floor());
+
+        let texel = |x: usize, y: usize| image.texels[y * width + x];
+        let top = texel(x0, y0) * (1.0 - fx) + texel(x1, y0) * fx;
+        let bottom = texel(x0, y1) * (1.0 - fx) + texel(x1, y1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+// Renders everything around a point in the scene in the given projection,
+// averaging `samples` rays spread over each pixel. Rendered as a latlong and
+// saved as HDR, this gives an environment map of the scene.
+pub fn render(
+    scene: &Scene,
+    position: Vec3<f32>,
+    projection: Projection,
+    (width, height): (usize, usize),
+    samples: usize,
+    settings: TraceSettings,
+) -> Framebuffer {
+    let tracer = Tracer::new(scene, RenderMode::Shaded, settings);
+    let samples = samples.max(1);
+
+    let mut framebuffer = Framebuffer::new(width, height);
+    framebuffer.render(|i, j| {
+        let mut colour = Vec3::zero();
+
+        for k in 0..samples {
+            let (dx, dy) = sampling::r2(k);
+            let (u, v) = ((i as f32 + dx) / width as f32, (j as f32 + dy) / height as f32);
+
+            if let Some(direction) = projection.direction(u, v) {
+                let ray = Ray { origin: position, direction };
+                colour = colour + tracer.cast_ray(&ray, 0, sampling::seed(j * width + i, k));
+            }
+        }
+
+        colour * (1.0 / samples as f32)
+    });
+
+    framebuffer
+}
+
+// Reprojects an image from one projection to another, such as a fisheye
+// photo to a latlong or a latlong to cube faces
+pub fn convert(image: &Image, from: Projection, to: Projection, (width, height): (usize, usize)) -> Framebuffer {
+    let mut framebuffer = Framebuffer::new(width, height);
+
+    if image.width == 0 || image.height == 0 {
+        return framebuffer;
+    }
+
+    framebuffer.render(|i, j| {
+        let (u, v) = ((i as f32 + 0.5) / width as f32, (j as f32 + 0.5) / height as f32);
+
+        to.direction(u, v)
+            .and_then(|direction| from.position(direction))
+            .map_or(Vec3::zero(), |(u, v)| from.sample(image, u, v))
+    });
+
+    framebuffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cube_face_inverts_cube_direction() {
+        for face in 0..6 {
+            for &(u, v) in &[(0.0, 0.0), (0.5, -0.25), (-0.9, 0.7)] {
+                let (f, u2, v2) = cube_face(cube_direction(face, u, v).normalise());
+                assert_eq!(f, face);
+                assert!((u - u2).abs() < 1.0e-5 && (v - v2).abs() < 1.0e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn position_inverts_direction() {
+        for projection in [Projection::LatLong, Projection::Cube, Projection::Fisheye(3.5)] {
+            for &(u, v) in &[(0.52, 0.5), (0.3, 0.6), (0.55, 0.2), (0.1, 0.45)] {
+                let direction = projection.direction(u, v).unwrap();
+                let (u2, v2) = projection.position(direction).unwrap();
+                assert!((u - u2).abs() < 1.0e-4 && (v - v2).abs() < 1.0e-4, "{:?} at {}, {}", projection, u, v);
+            }
+        }
+
+        let fisheye = Projection::Fisheye(PI);
+        assert_eq!(fisheye.direction(0.0, 0.0), None);
+        assert_eq!(fisheye.position(Vec3::new(0.0, 0.0, 1.0)), None);
+    }
+
+    #[test]
+    fn latlong_survives_a_trip_through_cube_faces() {
+        // Brighter towards +y and +x, so every direction's colour differs
+        let (width, height) = Projection::LatLong.size(32);
+        let colour = |direction: Vec3<f32>| Vec3::new(direction.y + 1.0, direction.x + 1.0, 1.0);
+        let texels = (0..height)
+            .flat_map(|j| (0..width).map(move |i| (i, j)))
+            .map(|(i, j)| {
+                let u = (i as f32 + 0.5) / width as f32;
+                let v = (j as f32 + 0.5) / height as f32;
+                colour(Projection::LatLong.direction(u, v).unwrap())
+            })
+            .collect();
+        let image = Image { width, height, texels };
+
+        let cube = convert(&image, Projection::LatLong, Projection::Cube, Projection::Cube.size(16));
+        let cube = Image { width: cube.width, height: cube.height, texels: cube.colours };
+        let back = convert(&cube, Projection::Cube, Projection::LatLong, (width, height));
+
+        // Away from the poles, where latlong texels are squeezed together
+        for j in height / 4..3 * height / 4 {
+            for i in 0..width {
+                assert!((back.colours[j * width + i] - image.texels[j * width + i]).length() < 0.1);
+            }
+        }
+    }
+
+    #[test]
+    fn parse_projections() {
+        assert_eq!("cube".parse(), Ok(Projection::Cube));
+        assert_eq!("fisheye".parse(), Ok(Projection::Fisheye(PI)));
+        assert_eq!("fisheye:90".parse(), Ok(Projection::Fisheye(PI / 2.0)));
+        assert!("fisheye:0".parse::<Projection>().is_err());
+        assert!("mercator".parse::<Projection>().is_err());
+    }
+}
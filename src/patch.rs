@@ -0,0 +1,324 @@
+use crate::Result;
+use crate::scene::Scene;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::fs;
+use std::path::Path;
+
+// One change to a scene, in the form of a JSON Patch (RFC 6902) operation so
+// patches can be read and written by other tools too. Paths are JSON Pointers
+// into the scene as it's saved as JSON, such as `/shapes/2/Sphere/radius`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Operation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+}
+
+fn escape(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+fn unescape(token: &str) -> String {
+    token.replace("~1", "/").replace("~0", "~")
+}
+
+// The changes that turn `a` into `b`. Objects are compared field by field,
+// and arrays by lining up the elements they share, so a shape added or
+// removed in the middle of the list doesn't read as every later shape
+// changing. Elements left over between shared ones are changed into each
+// other where there are some on both sides.
+pub fn diff_values(a: &Value, b: &Value) -> Vec<Operation> {
+    let mut patch = Vec::new();
+    diff_into(a, b, "", &mut patch);
+    patch
+}
+
+fn diff_into(a: &Value, b: &Value, path: &str, patch: &mut Vec<Operation>) {
+    match (a, b) {
+        _ if a == b => {}
+        (Value::Object(a), Value::Object(b)) => {
+            for (key, value) in a {
+                let path = format!("{}/{}", path, escape(key));
+                match b.get(key) {
+                    Some(other) => diff_into(value, other, &path, patch),
+                    None => patch.push(Operation::Remove { path }),
+                }
+            }
+            for (key, value) in b.iter().filter(|(key, _)| !a.contains_key(*key)) {
+                patch.push(Operation::Add { path: format!("{}/{}", path, escape(key)), value: value.clone() });
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => diff_arrays(a, b, path, patch),
+        _ => patch.push(Operation::Replace { path: path.to_string(), value: b.clone() }),
+    }
+}
+
+fn diff_arrays(a: &[Value], b: &[Value], path: &str, patch: &mut Vec<Operation>) {
+    // Longest common subsequence, by the length of what's shared after each
+    // pair of positions
+    let mut shared = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            shared[i][j] = if a[i] == b[j] { shared[i + 1][j + 1] + 1 } else { shared[i + 1][j].max(shared[i][j + 1]) };
+        }
+    }
+
+    // Walk both arrays, tracking where each element is in the array as the
+    // operations so far have left it
+    let (mut i, mut j, mut index) = (0, 0, 0);
+    while i < a.len() || j < b.len() {
+        let (start_i, start_j) = (i, j);
+        while i < a.len() && j < b.len() && a[i] != b[j] {
+            if shared[i + 1][j] >= shared[i][j + 1] { i += 1 } else { j += 1 }
+        }
+        if i == a.len() || j == b.len() {
+            (i, j) = (a.len(), b.len());
+        }
+
+        let (removed, added) = (&a[start_i..i], &b[start_j..j]);
+        let paired = removed.len().min(added.len());
+        for k in 0..paired {
+            diff_into(&removed[k], &added[k], &format!("{}/{}", path, index + k), patch);
+        }
+        for _ in paired..removed.len() {
+            patch.push(Operation::Remove { path: format!("{}/{}", path, index + paired) });
+        }
+        for (k, value) in added.iter().enumerate().skip(paired) {
+            patch.push(Operation::Add { path: format!("{}/{}", path, index + k), value: value.clone() });
+        }
+        index += added.len();
+
+        if i < a.len() && j < b.len() {
+            (i, j, index) = (i + 1, j + 1, index + 1);
+        }
+    }
+}
+
+fn tokens(path: &str) -> Result<Vec<String>> {
+    match path.strip_prefix('/') {
+        Some(rest) => Ok(rest.split('/').map(unescape).collect()),
+        None if path.is_empty() => Ok(Vec::new()),
+        None => Err(format!("`{}` isn't a JSON pointer", path).into()),
+    }
+}
+
+fn array_index(token: &str, len: usize, appending: bool) -> Result<usize> {
+    if appending && token == "-" {
+        return Ok(len);
+    }
+    match token.parse::<usize>() {
+        Ok(index) if index < len + appending as usize => Ok(index),
+        _ => Err(format!("no element `{}` in an array of {}", token, len).into()),
+    }
+}
+
+// The value at a path
+pub fn lookup<'a>(value: &'a Value, path: &str) -> Result<&'a Value> {
+    tokens(path)?.iter().try_fold(value, |value, token| {
+        let found = match value {
+            Value::Object(object) => object.get(token.as_str()),
+            Value::Array(array) => array_index(token, array.len(), false).ok().map(|index| &array[index]),
+            _ => None,
+        };
+        found.ok_or_else(|| format!("nothing at `{}`", path).into())
+    })
+}
+
+fn apply_operation(value: &mut Value, operation: &Operation) -> Result<()> {
+    let path = match operation {
+        Operation::Add { path, .. } | Operation::Remove { path } | Operation::Replace { path, .. } => path,
+    };
+    let mut tokens = tokens(path)?;
+    let last = match tokens.pop() {
+        Some(last) => last,
+        None => {
+            return match operation {
+                Operation::Add { value: new, .. } | Operation::Replace { value: new, .. } => {
+                    *value = new.clone();
+                    Ok(())
+                }
+                Operation::Remove { .. } => Err("can't remove the whole scene".into()),
+            };
+        }
+    };
+
+    let mut parent = value;
+    for token in &tokens {
+        parent = match parent {
+            Value::Object(object) => object.get_mut(token.as_str()),
+            Value::Array(array) => match array_index(token, array.len(), false) {
+                Ok(index) => array.get_mut(index),
+                Err(_) => None,
+            },
+            _ => None,
+        }
+        .ok_or_else(|| format!("nothing at `{}`", path))?;
+    }
+
+    match (parent, operation) {
+        (Value::Object(object), Operation::Add { value, .. }) => {
+            object.insert(last, value.clone());
+        }
+        (Value::Object(object), Operation::Replace { value, .. }) if object.contains_key(last.as_str()) => {
+            object.insert(last, value.clone());
+        }
+        (Value::Object(object), Operation::Remove { .. }) if object.contains_key(last.as_str()) => {
+            object.remove(last.as_str());
+        }
+        (Value::Array(array), Operation::Add { value, .. }) => {
+            let index = array_index(&last, array.len(), true)?;
+            array.insert(index, value.clone());
+        }
+        (Value::Array(array), Operation::Replace { value, .. }) => {
+            let index = array_index(&last, array.len(), false)?;
+            array[index] = value.clone();
+        }
+        (Value::Array(array), Operation::Remove { .. }) => {
+            let index = array_index(&last, array.len(), false)?;
+            array.remove(index);
+        }
+        _ => return Err(format!("nothing at `{}`", path).into()),
+    }
+
+    Ok(())
+}
+
+// Applies every operation in turn, stopping at the first that doesn't fit
+pub fn apply_values(value: &mut Value, patch: &[Operation]) -> Result<()> {
+    patch.iter().try_for_each(|operation| apply_operation(value, operation))
+}
+
+// A short form of a value for describing changes: numbers, switches and
+// names in full, and the kind of anything bigger
+fn brief(value: &Value) -> String {
+    match value {
+        Value::Array(array) => format!("[{} items]", array.len()),
+        // Enums such as shapes are saved as an object with the variant's name
+        // as its only key
+        Value::Object(object) if object.len() == 1 => object.keys().next().cloned().unwrap_or_default(),
+        Value::Object(object) => format!("{{{} fields}}", object.len()),
+        value => value.to_string(),
+    }
+}
+
+// A line describing each change, for people rather than tools
+pub fn describe(value: &Value, patch: &[Operation]) -> Result<Vec<String>> {
+    let mut value = value.clone();
+    let mut lines = Vec::with_capacity(patch.len());
+
+    for operation in patch {
+        lines.push(match operation {
+            Operation::Add { path, value } => format!("+ {}: {}", path, brief(value)),
+            Operation::Remove { path } => format!("- {}: {}", path, brief(lookup(&value, path)?)),
+            Operation::Replace { path, value: new } => {
+                format!("~ {}: {} -> {}", path, brief(lookup(&value, path)?), brief(new))
+            }
+        });
+        apply_operation(&mut value, operation)?;
+    }
+
+    Ok(lines)
+}
+
+// The changes from one scene to another, as it'd save them
+pub fn diff(a: &Scene, b: &Scene) -> Result<Vec<Operation>> {
+    Ok(diff_values(&serde_json::to_value(a)?, &serde_json::to_value(b)?))
+}
+
+// Describes the changes a patch makes to a scene
+pub fn summarise(scene: &Scene, patch: &[Operation]) -> Result<Vec<String>> {
+    describe(&serde_json::to_value(scene)?, patch)
+}
+
+// The scene with a patch applied, loading anything the patch adds or changes
+// the path of as if it had been in the scene file
+pub fn apply(scene: &Scene, patch: &[Operation]) -> Result<Scene> {
+    let mut value = serde_json::to_value(scene)?;
+    apply_values(&mut value, patch)?;
+    let mut patched: Scene = serde_json::from_value(value)?;
+    patched.build_scatters()?;
+    Ok(patched)
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Operation>> {
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+pub fn save<P: AsRef<Path>>(patch: &[Operation], path: P) -> Result<()> {
+    fs::write(path, serde_json::to_string_pretty(patch)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Map;
+
+    fn object(fields: &[(&str, Value)]) -> Value {
+        let mut object = Map::new();
+        for (key, value) in fields {
+            object.insert(key.to_string(), value.clone());
+        }
+        Value::Object(object)
+    }
+
+    fn sphere(radius: f64) -> Value {
+        object(&[("Sphere", object(&[("radius", Value::from(radius))]))])
+    }
+
+    fn scene(shapes: &[f64], fov: Option<f64>) -> Value {
+        let mut fields = vec![("shapes", Value::Array(shapes.iter().map(|&r| sphere(r)).collect()))];
+        if let Some(fov) = fov {
+            fields.push(("fov", Value::from(fov)));
+        }
+        object(&fields)
+    }
+
+    #[test]
+    fn diff_then_apply_gives_the_other_scene() {
+        let cases = [
+            (scene(&[1.0, 2.0, 3.0], None), scene(&[1.0, 3.0], Some(0.5))),
+            (scene(&[1.0, 3.0], Some(0.5)), scene(&[1.0, 2.0, 3.0], None)),
+            (scene(&[1.0, 2.0, 3.0], Some(0.5)), scene(&[4.0, 2.0, 5.0, 6.0], Some(0.7))),
+            (scene(&[], None), scene(&[1.0, 2.0], None)),
+        ];
+
+        for (a, b) in &cases {
+            let patch = diff_values(a, b);
+            let mut patched = a.clone();
+            apply_values(&mut patched, &patch).unwrap();
+            assert_eq!(&patched, b, "{:?}", patch);
+        }
+
+        // Taking a shape out of the middle is just that, not a change to every
+        // shape after it
+        let (a, b) = &cases[0];
+        let patch = diff_values(a, b);
+        assert_eq!(patch[0], Operation::Remove { path: "/shapes/1".to_string() });
+        let lines = describe(a, &patch).unwrap();
+        assert_eq!(lines, ["- /shapes/1: Sphere", "+ /fov: 0.5"]);
+
+        let patch = diff_values(&cases[2].0, &cases[2].1);
+        let lines = describe(&cases[2].0, &patch).unwrap();
+        assert!(lines.contains(&"~ /shapes/0/Sphere/radius: 1.0 -> 4.0".to_string()), "{:?}", lines);
+    }
+
+    #[test]
+    fn patches_that_dont_fit_are_refused() {
+        let mut value = scene(&[1.0], None);
+        assert!(apply_values(&mut value, &[Operation::Remove { path: "/shapes/3".to_string() }]).is_err());
+        assert!(apply_values(&mut value, &[Operation::Replace { path: "/fov".to_string(), value: Value::from(1.0) }]).is_err());
+        assert!(apply_values(&mut value, &[Operation::Remove { path: "shapes".to_string() }]).is_err());
+
+        let append = Operation::Add { path: "/shapes/-".to_string(), value: sphere(2.0) };
+        apply_values(&mut value, &[append]).unwrap();
+        assert_eq!(value, scene(&[1.0, 2.0], None));
+        assert_eq!(lookup(&value, "/shapes/1/Sphere/radius").unwrap(), &Value::from(2.0));
+        assert_eq!(escape("a/b~c"), "a~1b~0c");
+        assert_eq!(unescape("a~1b~0c"), "a/b~c");
+    }
+}
@@ -0,0 +1,529 @@
+use crate::Result;
+use crate::bvh::Bvh;
+use crate::geometry::{Aabb, Hit, Intersect, Ray, Sphere, Vec3};
+use crate::materials::Material;
+
+use serde::{Deserialize, Serialize};
+
+use std::convert::{TryFrom, TryInto};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug)]
+struct PointData {
+    points: Vec<Vec3<f32>>,
+    // Per-point colours, if the file has them
+    colours: Option<Vec<Vec3<f32>>>,
+    bvh: Bvh,
+}
+
+// A point cloud, such as scan data, with every point drawn as a small sphere
+// in its own colour, or the material's where the file has none. Like a mesh,
+// the points are shared between clones and moved around by offsetting rays.
+//
+// In scene files a point cloud is described by the PLY or LAS file it's
+// loaded from:
+//
+//     Points((path: "scan.ply", radius: 0.02, material: (...), position: (x: 0.0, y: 0.0, z: -10.0)))
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "PointCloudDescription", into = "PointCloudDescription")]
+pub struct PointCloud {
+    pub path: String,
+    pub radius: f32,
+    pub material: Material,
+    pub position: Vec3<f32>,
+    data: Arc<PointData>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PointCloudDescription {
+    path: String,
+    radius: f32,
+    material: Material,
+    #[serde(default)]
+    position: Vec3<f32>,
+}
+
+impl TryFrom<PointCloudDescription> for PointCloud {
+    type Error = String;
+
+    fn try_from(description: PointCloudDescription) -> std::result::Result<Self, String> {
+        let mut cloud = PointCloud::load(&description.path, description.radius, description.material)
+            .map_err(|e| format!("failed to load point cloud `{}`: {}", description.path, e))?;
+        cloud.position = description.position;
+        Ok(cloud)
+    }
+}
+
+impl From<PointCloud> for PointCloudDescription {
+    fn from(cloud: PointCloud) -> Self {
+        PointCloudDescription {
+            path: cloud.path,
+            radius: cloud.radius,
+            material: cloud.material,
+            position: cloud.position,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Points {
+    pub positions: Vec<Vec3<f32>>,
+    pub colours: Option<Vec<Vec3<f32>>>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+}
+
+// Types of the PLY scalar properties, by size in bytes when stored in binary
+fn ply_type_size(name: &str) -> Option<usize> {
+    match name {
+        "char" | "uchar" | "int8" | "uint8" => Some(1),
+        "short" | "ushort" | "int16" | "uint16" => Some(2),
+        "int" | "uint" | "float" | "int32" | "uint32" | "float32" => Some(4),
+        "double" | "float64" => Some(8),
+        _ => None,
+    }
+}
+
+fn ply_binary_value(ty: &str, bytes: &[u8]) -> f64 {
+    match ty {
+        "char" | "int8" => bytes[0] as i8 as f64,
+        "uchar" | "uint8" => bytes[0] as f64,
+        "short" | "int16" => i16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+        "ushort" | "uint16" => u16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+        "int" | "int32" => i32::from_le_bytes(bytes[..4].try_into().unwrap()) as f64,
+        "uint" | "uint32" => u32::from_le_bytes(bytes[..4].try_into().unwrap()) as f64,
+        "float" | "float32" => f32::from_le_bytes(bytes[..4].try_into().unwrap()) as f64,
+        _ => f64::from_le_bytes(bytes[..8].try_into().unwrap()),
+    }
+}
+
+// Colours stored as integers run up to the largest value of their type
+fn ply_colour_scale(ty: &str) -> f64 {
+    match ty {
+        "uchar" | "uint8" => 255.0,
+        "ushort" | "uint16" => 65535.0,
+        _ => 1.0,
+    }
+}
+
+// Reads the positions and any red, green and blue of the vertices in an ASCII
+// or little-endian binary PLY file. Faces and other elements are ignored, as
+// long as they come after the vertices.
+pub fn parse_ply(data: &[u8]) -> Result<Points> {
+    let header_end = data
+        .windows(11)
+        .position(|w| w == b"end_header\n")
+        .ok_or("PLY file has no end_header")?
+        + 11;
+    let header = std::str::from_utf8(&data[..header_end])?;
+
+    let mut lines = header.lines();
+    if lines.next().map(str::trim) != Some("ply") {
+        return Err("not a PLY file".into());
+    }
+
+    let mut format = None;
+    let mut vertex_count = None;
+    // Names and types of the vertex properties in order
+    let mut properties: Vec<(String, String)> = Vec::new();
+    let mut in_vertex = false;
+    let mut before_vertex = 0;
+
+    for line in lines {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        match fields.as_slice() {
+            ["format", "ascii", _] => format = Some(PlyFormat::Ascii),
+            ["format", "binary_little_endian", _] => format = Some(PlyFormat::BinaryLittleEndian),
+            ["format", other, _] => return Err(format!("unsupported PLY format `{}`", other).into()),
+            ["element", "vertex", count] => {
+                vertex_count = Some(count.parse::<usize>()?);
+                in_vertex = true;
+            }
+            ["element", _, count] => {
+                if vertex_count.is_none() && count.parse::<usize>()? > 0 {
+                    before_vertex += 1;
+                }
+                in_vertex = false;
+            }
+            ["property", "list", ..] if in_vertex => return Err("list properties on vertices aren't supported".into()),
+            ["property", ty, name] if in_vertex => {
+                ply_type_size(ty).ok_or_else(|| format!("unknown PLY property type `{}`", ty))?;
+                properties.push((name.to_string(), ty.to_string()));
+            }
+            _ => {}
+        }
+    }
+
+    let format = format.ok_or("PLY file has no format")?;
+    let count = vertex_count.ok_or("PLY file has no vertices")?;
+    if before_vertex > 0 {
+        return Err("PLY elements before the vertices aren't supported".into());
+    }
+
+    let find = |name: &str| properties.iter().position(|(n, _)| n == name);
+    let position = [find("x"), find("y"), find("z")];
+    let [x, y, z] = match position {
+        [Some(x), Some(y), Some(z)] => [x, y, z],
+        _ => return Err("PLY vertices need x, y and z".into()),
+    };
+    let colour = match [find("red"), find("green"), find("blue")] {
+        [Some(r), Some(g), Some(b)] => Some([r, g, b]),
+        _ => None,
+    };
+
+    // Every vertex's properties as numbers, in the order they're declared
+    let mut rows: Vec<Vec<f64>> = Vec::with_capacity(count.min(data.len()));
+
+    match format {
+        PlyFormat::Ascii => {
+            let body = std::str::from_utf8(&data[header_end..])?;
+            let mut tokens = body.split_whitespace();
+
+            for _ in 0..count {
+                let row = (0..properties.len())
+                    .map(|_| {
+                        let token = tokens.next().ok_or("PLY file has too few vertices")?;
+                        token.parse().map_err(|_| format!("invalid PLY value `{}`", token))
+                    })
+                    .collect::<std::result::Result<_, String>>()?;
+                rows.push(row);
+            }
+        }
+        PlyFormat::BinaryLittleEndian => {
+            let stride: usize = properties.iter().map(|(_, ty)| ply_type_size(ty).unwrap()).sum();
+            let body = &data[header_end..];
+            if count.checked_mul(stride).is_none_or(|size| size > body.len()) {
+                return Err("PLY file has too few vertices".into());
+            }
+
+            for vertex in body.chunks_exact(stride).take(count) {
+                let mut offset = 0;
+                let row = properties
+                    .iter()
+                    .map(|(_, ty)| {
+                        let size = ply_type_size(ty).unwrap();
+                        offset += size;
+                        ply_binary_value(ty, &vertex[offset - size..offset])
+                    })
+                    .collect();
+                rows.push(row);
+            }
+        }
+    }
+
+    let positions: Vec<Vec3<f32>> = rows
+        .iter()
+        .map(|row| Vec3::new(row[x] as f32, row[y] as f32, row[z] as f32))
+        .collect();
+    if !positions.iter().all(|p| p.x.is_finite() && p.y.is_finite() && p.z.is_finite()) {
+        return Err("PLY vertex positions must be finite".into());
+    }
+
+    let colours = colour.map(|[r, g, b]| {
+        let scale = |k: usize| ply_colour_scale(&properties[k].1);
+        rows.iter()
+            .map(|row| {
+                Vec3::new(
+                    (row[r] / scale(r)) as f32,
+                    (row[g] / scale(g)) as f32,
+                    (row[b] / scale(b)) as f32,
+                )
+            })
+            .collect()
+    });
+
+    Ok(Points { positions, colours })
+}
+
+fn le_u16(data: &[u8], offset: usize) -> Result<u16> {
+    let bytes = data.get(offset..offset + 2).ok_or("truncated LAS file")?;
+    Ok(u16::from_le_bytes(bytes.try_into()?))
+}
+
+fn le_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data.get(offset..offset + 4).ok_or("truncated LAS file")?;
+    Ok(u32::from_le_bytes(bytes.try_into()?))
+}
+
+fn le_u64(data: &[u8], offset: usize) -> Result<u64> {
+    let bytes = data.get(offset..offset + 8).ok_or("truncated LAS file")?;
+    Ok(u64::from_le_bytes(bytes.try_into()?))
+}
+
+fn le_f64(data: &[u8], offset: usize) -> Result<f64> {
+    Ok(f64::from_bits(le_u64(data, offset)?))
+}
+
+// Where the red, green and blue of each point record are, for the point
+// formats that have them
+fn las_colour_offset(format: u8) -> Option<usize> {
+    match format {
+        2 => Some(20),
+        3 | 5 => Some(28),
+        7 | 8 | 10 => Some(30),
+        _ => None,
+    }
+}
+
+// Reads the points and any colours in an uncompressed LAS file. LAS is z up,
+// so z is swapped into y to match the rest of the scene.
+pub fn parse_las(data: &[u8]) -> Result<Points> {
+    if !data.starts_with(b"LASF") {
+        return Err("not a LAS file".into());
+    }
+
+    let offset = le_u32(data, 96)? as usize;
+    let format = *data.get(104).ok_or("truncated LAS file")? & 0x3f;
+    let record_length = le_u16(data, 105)? as usize;
+
+    // LAS 1.4 files can leave the legacy count empty in favour of a 64-bit one
+    let mut count = le_u32(data, 107)? as u64;
+    if count == 0 && le_u16(data, 94)? >= 375 {
+        count = le_u64(data, 247)?;
+    }
+    let count = count as usize;
+
+    let scale = [le_f64(data, 131)?, le_f64(data, 139)?, le_f64(data, 147)?];
+    let origin = [le_f64(data, 155)?, le_f64(data, 163)?, le_f64(data, 171)?];
+    if !scale.iter().chain(&origin).all(|v| v.is_finite()) {
+        return Err("LAS scale and offset must be finite".into());
+    }
+
+    let colour_offset = las_colour_offset(format);
+    if record_length < colour_offset.map_or(12, |offset| offset + 6) {
+        return Err(format!("LAS point records of {} bytes are too short", record_length).into());
+    }
+
+    let end = count.checked_mul(record_length).and_then(|size| size.checked_add(offset));
+    let records = match end {
+        Some(end) if end <= data.len() => &data[offset..end],
+        _ => return Err("LAS file has fewer points than its header says".into()),
+    };
+
+    let mut positions = Vec::with_capacity(count);
+    let mut colours = colour_offset.map(|_| Vec::with_capacity(count));
+
+    for record in records.chunks_exact(record_length) {
+        let coordinate = |k: usize| {
+            let raw = i32::from_le_bytes(record[4 * k..4 * k + 4].try_into().unwrap());
+            (raw as f64 * scale[k] + origin[k]) as f32
+        };
+        positions.push(Vec3::new(coordinate(0), coordinate(2), -coordinate(1)));
+
+        if let (Some(offset), Some(colours)) = (colour_offset, colours.as_mut()) {
+            let channel = |k: usize| {
+                u16::from_le_bytes([record[offset + 2 * k], record[offset + 2 * k + 1]]) as f32 / 65535.0
+            };
+            colours.push(Vec3::new(channel(0), channel(1), channel(2)));
+        }
+    }
+    // Finite scales and offsets can still scale a point past single precision
+    if !positions.iter().all(|p| p.x.is_finite() && p.y.is_finite() && p.z.is_finite()) {
+        return Err("LAS point positions must be finite".into());
+    }
+
+    Ok(Points { positions, colours })
+}
+
+impl PointCloud {
+    pub fn new(points: Points, radius: f32, material: Material) -> Self {
+        let extent = Vec3::new(radius, radius, radius);
+        let bounds: Vec<Aabb> = points
+            .positions
+            .iter()
+            .map(|&point| Aabb { min: point - extent, max: point + extent })
+            .collect();
+
+        PointCloud {
+            path: String::new(),
+            radius,
+            material,
+            position: Vec3::zero(),
+            data: Arc::new(PointData {
+                bvh: Bvh::build(&bounds),
+                points: points.positions,
+                colours: points.colours,
+            }),
+        }
+    }
+
+    // Loads a `.las` file as LAS and anything else as PLY
+    pub fn load(path: &str, radius: f32, material: Material) -> Result<Self> {
+        let data = fs::read(path)?;
+
+        let is_las = Path::new(path)
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("las"));
+        let points = if is_las { parse_las(&data)? } else { parse_ply(&data)? };
+
+        let mut cloud = PointCloud::new(points, radius, material);
+        cloud.path = path.to_string();
+        Ok(cloud)
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.points.len()
+    }
+
+    // Positions relative to the cloud's own position
+    pub fn points(&self) -> &[Vec3<f32>] {
+        &self.data.points
+    }
+
+    pub fn colours(&self) -> Option<&[Vec3<f32>]> {
+        self.data.colours.as_deref()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.points.is_empty()
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let bounds = self.data.bvh.bounds();
+        Aabb {
+            min: bounds.min + self.position,
+            max: bounds.max + self.position,
+        }
+    }
+}
+
+impl Intersect for PointCloud {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        let local = Ray {
+            origin: ray.origin - self.position,
+            direction: ray.direction,
+        };
+
+        let mut nearest_point = 0;
+        let distance = self.data.bvh.intersect(&local, f32::MAX, |point, max_distance| {
+            let sphere = Sphere::new(self.data.points[point], self.radius, self.material);
+            let distance = sphere.ray_distance(&local)?;
+            if distance < max_distance {
+                nearest_point = point;
+                Some(distance)
+            } else {
+                None
+            }
+        })?;
+
+        let centre = self.data.points[nearest_point];
+        let normal = (local.origin + local.direction * distance - centre).normalise();
+
+        let mut material = self.material;
+        if let Some(colours) = &self.data.colours {
+            material.diffuse_colour = colours[nearest_point];
+        }
+
+        Some(Hit::new(ray, distance, normal, material))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLY: &[u8] = b"ply
+format ascii 1.0
+comment two coloured points
+element vertex 2
+property float x
+property float y
+property float z
+property uchar red
+property uchar green
+property uchar blue
+element face 0
+property list uchar int vertex_indices
+end_header
+0 0 -5 255 0 0
+1 0 -5 0 255 0
+";
+
+    #[test]
+    fn parse_ascii_ply() {
+        let points = parse_ply(PLY).unwrap();
+        assert_eq!(points.positions, vec![Vec3::new(0.0, 0.0, -5.0), Vec3::new(1.0, 0.0, -5.0)]);
+        assert_eq!(points.colours.unwrap()[1], Vec3::new(0.0, 1.0, 0.0));
+    }
+
+    #[test]
+    fn parse_binary_ply() {
+        let mut data = b"ply\nformat binary_little_endian 1.0\nelement vertex 1\nproperty double x\nproperty float y\nproperty float z\nend_header\n".to_vec();
+        data.extend_from_slice(&2.0f64.to_le_bytes());
+        data.extend_from_slice(&3.0f32.to_le_bytes());
+        data.extend_from_slice(&(-4.0f32).to_le_bytes());
+
+        let points = parse_ply(&data).unwrap();
+        assert_eq!(points.positions, vec![Vec3::new(2.0, 3.0, -4.0)]);
+        assert!(points.colours.is_none());
+
+        assert!(parse_ply(&data[..data.len() - 1]).is_err());
+
+        // Doubles too large for single precision
+        let x = data.len() - 16;
+        data[x..x + 8].copy_from_slice(&1.0e300f64.to_le_bytes());
+        assert!(parse_ply(&data).is_err());
+    }
+
+    // A LAS 1.2 file with one point of format 2, each coordinate scaled by
+    // `scale`
+    fn las(scale: f64) -> Vec<u8> {
+        let mut data = vec![0u8; 227];
+        data[..4].copy_from_slice(b"LASF");
+        data[94..96].copy_from_slice(&227u16.to_le_bytes());
+        data[96..100].copy_from_slice(&227u32.to_le_bytes());
+        data[104] = 2;
+        data[105..107].copy_from_slice(&26u16.to_le_bytes());
+        data[107..111].copy_from_slice(&1u32.to_le_bytes());
+        for k in 0..3 {
+            data[131 + 8 * k..139 + 8 * k].copy_from_slice(&scale.to_le_bytes());
+        }
+        data[155..163].copy_from_slice(&10.0f64.to_le_bytes());
+
+        let mut record = vec![0u8; 26];
+        record[0..4].copy_from_slice(&1000i32.to_le_bytes());
+        record[4..8].copy_from_slice(&2000i32.to_le_bytes());
+        record[8..12].copy_from_slice(&3000i32.to_le_bytes());
+        record[20..22].copy_from_slice(&65535u16.to_le_bytes());
+        data.extend_from_slice(&record);
+        data
+    }
+
+    #[test]
+    fn parse_las_with_colour() {
+        // Scaled to millimetres
+        let data = las(0.001);
+        let points = parse_las(&data).unwrap();
+        assert_eq!(points.positions, vec![Vec3::new(11.0, 3.0, -2.0)]);
+        assert_eq!(points.colours.unwrap(), vec![Vec3::new(1.0, 0.0, 0.0)]);
+
+        assert!(parse_las(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn parse_las_refuses_positions_too_large() {
+        // A fuzzing regression: a finite scale that takes the points past f32
+        assert!(parse_las(&las(1.0e300)).is_err());
+        assert!(parse_las(&las(1.0e-300)).is_ok());
+    }
+
+    #[test]
+    fn hit_takes_point_colour() {
+        let cloud = PointCloud::new(parse_ply(PLY).unwrap(), 0.1, Material::default());
+        let ray = Ray {
+            origin: Vec3::new(1.0, 0.0, 0.0),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+
+        let hit = cloud.ray_intersect(&ray).unwrap();
+        assert!((hit.distance - 4.9).abs() < 1.0e-5);
+        assert_eq!(hit.material.diffuse_colour, Vec3::new(0.0, 1.0, 0.0));
+    }
+}
@@ -0,0 +1,129 @@
+use crate::geometry::{Ray, Vec3};
+use crate::panorama::{cube_direction, cube_face};
+use crate::render::{RenderMode, TraceSettings, Tracer};
+use crate::scene::Scene;
+
+// Width and height of each cube face in texels
+const FACE_SIZE: usize = 64;
+
+// A cubemap of the light arriving at a point from every direction, baked by
+// tracing the scene, for shiny surfaces to look up instead of tracing their
+// own reflections. A probe can belong to a shape, which is left out of its
+// bake so that it sees past itself.
+pub struct Probe {
+    pub position: Vec3<f32>,
+    pub shape: Option<usize>,
+    faces: Vec<Vec<Vec3<f32>>>,
+}
+
+impl Probe {
+    pub fn bake(scene: &Scene, position: Vec3<f32>, shape: Option<usize>, settings: TraceSettings) -> Self {
+        let hidden_scene;
+        let scene = match shape {
+            Some(shape) => {
+                let mut copy = scene.clone();
+                copy.hidden.insert(shape);
+                hidden_scene = copy;
+                &hidden_scene
+            }
+            None => scene,
+        };
+
+        let tracer = Tracer::new(scene, RenderMode::Shaded, settings);
+
+        let faces = (0..6)
+            .map(|face| {
+                (0..FACE_SIZE * FACE_SIZE)
+                    .map(|texel| {
+                        let (i, j) = (texel % FACE_SIZE, texel / FACE_SIZE);
+                        let u = 2.0 * (i as f32 + 0.5) / FACE_SIZE as f32 - 1.0;
+                        let v = 2.0 * (j as f32 + 0.5) / FACE_SIZE as f32 - 1.0;
+
+                        let ray = Ray {
+                            origin: position,
+                            direction: cube_direction(face, u, v).normalise(),
+                        };
+                        tracer.cast_ray(&ray, 0, (face * FACE_SIZE * FACE_SIZE + texel) as u64)
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Probe { position, shape, faces }
+    }
+
+    // Bilinearly filtered light arriving from the given direction
+    pub fn sample(&self, direction: Vec3<f32>) -> Vec3<f32> {
+        let (face, u, v) = cube_face(direction);
+        let texels = &self.faces[face];
+
+        let max = (FACE_SIZE - 1) as f32;
+        let x = ((u + 1.0) * 0.5 * FACE_SIZE as f32 - 0.5).clamp(0.0, max);
+        let y = ((v + 1.0) * 0.5 * FACE_SIZE as f32 - 0.5).clamp(0.0, max);
+
+        let (x0, y0) = (x as usize, y as usize);
+        let (x1, y1) = ((x0 + 1).min(FACE_SIZE - 1), (y0 + 1).min(FACE_SIZE - 1));
+        let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+        let texel = |i: usize, j: usize| texels[j * FACE_SIZE + i];
+        let top = texel(x0, y0) * (1.0 - fx) + texel(x1, y0) * fx;
+        let bottom = texel(x0, y1) * (1.0 - fx) + texel(x1, y1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+// Probes at the positions listed in the scene or, when it lists none, one in
+// the middle of each reflective shape
+pub struct Probes {
+    probes: Vec<Probe>,
+}
+
+impl Probes {
+    pub fn bake(scene: &Scene, settings: TraceSettings) -> Self {
+        let probes = if scene.probes.is_empty() {
+            scene
+                .shapes
+                .iter()
+                .enumerate()
+                .filter(|&(i, shape)| scene.is_visible(i) && shape.material().reflectivity > 0.0)
+                .map(|(i, shape)| Probe::bake(scene, shape.centre(), Some(i), settings))
+                .collect()
+        } else {
+            scene.probes.iter().map(|&position| Probe::bake(scene, position, None, settings)).collect()
+        };
+
+        Probes { probes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.probes.is_empty()
+    }
+
+    // The probe belonging to the shape, if it has one, or else the nearest to
+    // the point
+    pub fn lookup(&self, shape: usize, point: Vec3<f32>) -> Option<&Probe> {
+        self.probes.iter().find(|probe| probe.shape == Some(shape)).or_else(|| {
+            self.probes.iter().min_by(|a, b| {
+                let (da, db) = ((a.position - point).length(), (b.position - point).length());
+                da.total_cmp(&db)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_sees_background_in_empty_scene() {
+        let mut scene = Scene::default_scene();
+        scene.shapes.clear();
+        scene.probes.push(Vec3::zero());
+
+        let probes = Probes::bake(&scene, TraceSettings::default());
+        let probe = probes.lookup(0, Vec3::new(5.0, 0.0, 0.0)).unwrap();
+        let colour = probe.sample(Vec3::new(0.3, 0.4, -1.0).normalise());
+        assert!((colour - Vec3::new(0.2, 0.7, 0.8)).length() < 1.0e-5);
+    }
+}
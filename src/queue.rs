@@ -0,0 +1,258 @@
+use crate::camera::Camera;
+use crate::framebuffer::Framebuffer;
+use crate::image;
+use crate::render::Renderer;
+use crate::scene::Scene;
+
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn next(self) -> Self {
+        match self {
+            Priority::Low => Priority::Normal,
+            Priority::Normal => Priority::High,
+            Priority::High => Priority::Low,
+        }
+    }
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// An offline render of a snapshot of the scene, saved to `path` when done
+pub struct Job {
+    pub scene: Scene,
+    pub camera: Camera,
+    pub renderer: Renderer,
+    pub width: usize,
+    pub height: usize,
+    pub path: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Status {
+    Waiting,
+    Rendering,
+    Saved,
+    Failed(String),
+}
+
+struct Entry {
+    id: usize,
+    priority: Priority,
+    status: Status,
+    // Pixels finished so far, counted by the render thread
+    done: Arc<AtomicUsize>,
+    pixels: usize,
+    path: String,
+    job: Option<Job>,
+}
+
+// Renders jobs one at a time on a background thread, so the interactive
+// window keeps running meanwhile. Waiting jobs start highest priority first,
+// and in the order they were queued within a priority. Errors are kept as
+// strings since boxed errors can't be sent between threads.
+#[derive(Default)]
+pub struct RenderQueue {
+    entries: Vec<Entry>,
+    running: Option<(usize, JoinHandle<Result<(), String>>)>,
+    next_id: usize,
+}
+
+impl RenderQueue {
+    pub fn new() -> Self {
+        RenderQueue::default()
+    }
+
+    // The id the next job queued will be given, counting from one
+    pub fn next_id(&self) -> usize {
+        self.next_id + 1
+    }
+
+    pub fn push(&mut self, job: Job, priority: Priority) -> usize {
+        self.next_id += 1;
+        self.entries.push(Entry {
+            id: self.next_id,
+            priority,
+            status: Status::Waiting,
+            done: Arc::new(AtomicUsize::new(0)),
+            pixels: job.width * job.height,
+            path: job.path.clone(),
+            job: Some(job),
+        });
+        self.next_id
+    }
+
+    fn entry_mut(&mut self, id: usize) -> Option<&mut Entry> {
+        self.entries.iter_mut().find(|entry| entry.id == id)
+    }
+
+    // The most recently queued job that hasn't started
+    pub fn last_waiting(&self) -> Option<usize> {
+        self.entries.iter().rev().find(|entry| entry.status == Status::Waiting).map(|entry| entry.id)
+    }
+
+    // Changes a waiting job's priority, returning the new one
+    pub fn set_priority(&mut self, id: usize, priority: Priority) -> Option<Priority> {
+        let entry = self.entry_mut(id).filter(|entry| entry.status == Status::Waiting)?;
+        entry.priority = priority;
+        Some(priority)
+    }
+
+    pub fn priority(&self, id: usize) -> Option<Priority> {
+        self.entries.iter().find(|entry| entry.id == id).map(|entry| entry.priority)
+    }
+
+    // Takes a job that hasn't started off the queue. Jobs already rendering
+    // run to the end.
+    pub fn cancel(&mut self, id: usize) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|entry| entry.id != id || entry.status != Status::Waiting);
+        self.entries.len() < before
+    }
+
+    pub fn status(&self, id: usize) -> Option<&Status> {
+        self.entries.iter().find(|entry| entry.id == id).map(|entry| &entry.status)
+    }
+
+    // How much of a job is done, from 0 to 1
+    pub fn progress(&self, id: usize) -> Option<f32> {
+        self.entries.iter().find(|entry| entry.id == id).map(|entry| match entry.status {
+            Status::Waiting => 0.0,
+            Status::Rendering => entry.done.load(Ordering::Relaxed) as f32 / entry.pixels.max(1) as f32,
+            Status::Saved | Status::Failed(_) => 1.0,
+        })
+    }
+
+    pub fn running(&self) -> Option<usize> {
+        self.running.as_ref().map(|(id, _)| *id)
+    }
+
+    pub fn waiting(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.status == Status::Waiting).count()
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.running.is_some() || self.last_waiting().is_some()
+    }
+
+    // Collects the job that's finished, if any, and starts the next waiting
+    // one if none is rendering. Returns the ids of jobs that finished, to be
+    // looked up with `status`.
+    pub fn poll(&mut self) -> Vec<usize> {
+        let mut finished = Vec::new();
+
+        if self.running.as_ref().is_some_and(|(_, handle)| handle.is_finished()) {
+            let (id, handle) = self.running.take().expect("a job is running");
+            let status = match handle.join() {
+                Ok(Ok(())) => Status::Saved,
+                Ok(Err(e)) => Status::Failed(e),
+                Err(_) => Status::Failed("render panicked".to_string()),
+            };
+            if let Some(entry) = self.entry_mut(id) {
+                entry.status = status;
+            }
+            finished.push(id);
+        }
+
+        if self.running.is_none() {
+            let next = self
+                .entries
+                .iter_mut()
+                .filter(|entry| entry.status == Status::Waiting)
+                .min_by_key(|entry| (std::cmp::Reverse(entry.priority), entry.id));
+            if let Some(entry) = next {
+                let job = entry.job.take().expect("waiting jobs keep their job");
+                let done = Arc::clone(&entry.done);
+                entry.status = Status::Rendering;
+                self.running = Some((entry.id, thread::spawn(move || render(job, &done))));
+            }
+        }
+
+        finished
+    }
+
+    // A line for each job, in the order they were queued
+    pub fn describe(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .map(|entry| {
+                let status = match &entry.status {
+                    Status::Waiting => "waiting".to_string(),
+                    Status::Rendering => format!("{:.0}%", self.progress(entry.id).unwrap_or(0.0) * 100.0),
+                    Status::Saved => "saved".to_string(),
+                    Status::Failed(e) => format!("failed: {}", e),
+                };
+                format!("#{} [{}] {}: {}", entry.id, entry.priority, entry.path, status)
+            })
+            .collect()
+    }
+}
+
+fn render(job: Job, done: &AtomicUsize) -> Result<(), String> {
+    let mut framebuffer = Framebuffer::new(job.width, job.height);
+    job.renderer.render_counting(&mut framebuffer, &job.camera, &job.scene, done);
+    image::save(&framebuffer, &job.path).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn jobs_run_by_priority_then_order() {
+        let directory = std::env::temp_dir();
+        let job = |name: &str| Job {
+            scene: Scene::default_scene(),
+            camera: Camera::default(),
+            renderer: Renderer::default(),
+            width: 8,
+            height: 6,
+            path: directory.join(format!("tinyraytracer-queue-{}.ppm", name)).display().to_string(),
+        };
+
+        let mut queue = RenderQueue::new();
+        let low = queue.push(job("low"), Priority::Low);
+        let first = queue.push(job("first"), Priority::Normal);
+        let cancelled = queue.push(job("cancelled"), Priority::High);
+        let second = queue.push(job("second"), Priority::Low);
+        assert_eq!(queue.set_priority(second, Priority::Normal), Some(Priority::Normal));
+        assert!(queue.cancel(cancelled) && !queue.cancel(cancelled));
+        assert_eq!(queue.last_waiting(), Some(second));
+
+        let mut order = Vec::new();
+        while queue.is_busy() {
+            order.extend(queue.poll());
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(order, [first, second, low]);
+
+        for id in order {
+            assert_eq!(queue.status(id), Some(&Status::Saved));
+            assert_eq!(queue.progress(id), Some(1.0));
+            let _ = std::fs::remove_file(&queue.entries.iter().find(|entry| entry.id == id).unwrap().path);
+        }
+        assert_eq!(queue.describe().len(), 3);
+        assert!(queue.set_priority(low, Priority::High).is_none());
+    }
+}
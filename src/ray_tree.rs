@@ -0,0 +1,391 @@
+use crate::Result;
+use crate::camera::Camera;
+use crate::framebuffer::Framebuffer;
+use crate::geometry::{Vec3, dot};
+use crate::materials::Material;
+use crate::scene::Scene;
+
+use serde::Serialize;
+
+use std::ffi::OsStr;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+// Length of the line drawn for rays that leave the scene
+const MISS_LENGTH: f32 = 10.0;
+
+// Segments closer to the camera than this are cut short before drawing
+const NEAR: f32 = 1.0e-2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub enum BounceKind {
+    Camera,
+    Reflection,
+}
+
+// A shadow ray from a shading point towards a light, which either reaches the
+// light or stops at whatever is in the way. `weight` scales the light for
+// sampling and blockers, and `diffuse` and `specular` are what it added to the
+// shading point's intensities.
+#[derive(Clone, Debug, Serialize)]
+pub struct ShadowRay {
+    pub light: usize,
+    pub origin: Vec3<f32>,
+    pub end: Vec3<f32>,
+    pub occluded: bool,
+    pub weight: f32,
+    pub diffuse: f32,
+    pub specular: f32,
+}
+
+// One ray along a path, with what it hit (if anything), the shadow rays traced
+// from there and the colour it brought back. For hits, the colour is made up
+// as `diffuse_intensity * albedo.x * diffuse_colour + specular_intensity *
+// albedo.y + reflected * reflectivity`, where `reflected` has already been
+// divided by the chance `survival` of following the reflection.
+#[derive(Clone, Debug, Serialize)]
+pub struct Bounce {
+    pub kind: BounceKind,
+    pub depth: u32,
+    pub origin: Vec3<f32>,
+    pub direction: Vec3<f32>,
+    pub shape: Option<usize>,
+    pub point: Option<Vec3<f32>>,
+    pub normal: Option<Vec3<f32>>,
+    pub material: Option<Material>,
+    pub shadows: Vec<ShadowRay>,
+    pub diffuse_intensity: f32,
+    pub specular_intensity: f32,
+    pub survival: f32,
+    pub reflected: Vec3<f32>,
+    pub colour: Vec3<f32>,
+}
+
+impl Bounce {
+    pub fn new(kind: BounceKind, depth: u32, origin: Vec3<f32>, direction: Vec3<f32>) -> Self {
+        Bounce {
+            kind,
+            depth,
+            origin,
+            direction,
+            shape: None,
+            point: None,
+            normal: None,
+            material: None,
+            shadows: Vec::new(),
+            diffuse_intensity: 0.0,
+            specular_intensity: 0.0,
+            survival: 1.0,
+            reflected: Vec3::zero(),
+            colour: Vec3::zero(),
+        }
+    }
+}
+
+// Every ray traced for one pixel, as one path of bounces per sample
+#[derive(Clone, Debug, Serialize)]
+pub struct RayTree {
+    pub pixel: (usize, usize),
+    pub colour: Vec3<f32>,
+    pub paths: Vec<Vec<Bounce>>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SegmentKind {
+    Camera,
+    Reflection,
+    Miss,
+    Shadow,
+    Occluded,
+}
+
+impl SegmentKind {
+    fn name(self) -> &'static str {
+        match self {
+            SegmentKind::Camera => "camera",
+            SegmentKind::Reflection => "reflection",
+            SegmentKind::Miss => "miss",
+            SegmentKind::Shadow => "shadow",
+            SegmentKind::Occluded => "occluded",
+        }
+    }
+
+    fn colour(self) -> [u8; 3] {
+        match self {
+            SegmentKind::Camera => [255, 255, 255],
+            SegmentKind::Reflection => [0, 160, 255],
+            SegmentKind::Miss => [128, 128, 128],
+            SegmentKind::Shadow => [255, 220, 0],
+            SegmentKind::Occluded => [255, 0, 0],
+        }
+    }
+}
+
+fn format_vec(v: Vec3<f32>) -> String {
+    format!("({:.3}, {:.3}, {:.3})", v.x, v.y, v.z)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub kind: SegmentKind,
+    pub start: Vec3<f32>,
+    pub end: Vec3<f32>,
+}
+
+impl RayTree {
+    // The tree flattened into straight line segments. Rays that leave the
+    // scene are drawn a fixed length along their direction.
+    pub fn segments(&self) -> Vec<Segment> {
+        let mut segments = Vec::new();
+
+        for bounce in self.paths.iter().flatten() {
+            let kind = match (bounce.point, bounce.kind) {
+                (None, _) => SegmentKind::Miss,
+                (Some(_), BounceKind::Camera) => SegmentKind::Camera,
+                (Some(_), BounceKind::Reflection) => SegmentKind::Reflection,
+            };
+            let end = bounce.point.unwrap_or(bounce.origin + bounce.direction * MISS_LENGTH);
+            segments.push(Segment { kind, start: bounce.origin, end });
+
+            for shadow in &bounce.shadows {
+                let kind = if shadow.occluded { SegmentKind::Occluded } else { SegmentKind::Shadow };
+                segments.push(Segment { kind, start: shadow.origin, end: shadow.end });
+            }
+        }
+
+        segments
+    }
+
+    // A step by step account of how the pixel's colour was worked out, bounce
+    // by bounce for each sample
+    pub fn explain(&self, scene: &Scene) -> String {
+        let mut text = String::new();
+        let _ = self.write_explanation(&mut text, scene);
+        text
+    }
+
+    fn write_explanation(&self, text: &mut String, scene: &Scene) -> std::fmt::Result {
+        writeln!(
+            text,
+            "pixel {},{}: {} averaged over {} samples",
+            self.pixel.0,
+            self.pixel.1,
+            format_vec(self.colour),
+            self.paths.len(),
+        )?;
+
+        for (k, path) in self.paths.iter().enumerate() {
+            writeln!(text, "sample {}", k)?;
+
+            for bounce in path {
+                let indent = "  ".repeat(bounce.depth as usize + 1);
+                let kind = match bounce.kind {
+                    BounceKind::Camera => "camera",
+                    BounceKind::Reflection => "reflection",
+                };
+                writeln!(
+                    text,
+                    "{}{} ray from {} along {}",
+                    indent,
+                    kind,
+                    format_vec(bounce.origin),
+                    format_vec(bounce.direction),
+                )?;
+
+                let (shape, point, normal, material) =
+                    match (bounce.shape, bounce.point, bounce.normal, bounce.material) {
+                        (Some(shape), Some(point), Some(normal), Some(material)) => (shape, point, normal, material),
+                        _ => {
+                            writeln!(text, "{}  missed, background {}", indent, format_vec(bounce.colour))?;
+                            continue;
+                        }
+                    };
+
+                let name = scene.shapes.get(shape).map_or("shape", |shape| shape.name());
+                writeln!(
+                    text,
+                    "{}  hit {} {} at {}, normal {}",
+                    indent,
+                    name,
+                    shape,
+                    format_vec(point),
+                    format_vec(normal),
+                )?;
+                writeln!(
+                    text,
+                    "{}  albedo {:.2}/{:.2}, colour {}, specular exponent {}, reflectivity {:.2}",
+                    indent,
+                    material.albedo.x,
+                    material.albedo.y,
+                    format_vec(material.diffuse_colour),
+                    material.specular_exponent,
+                    material.reflectivity,
+                )?;
+
+                for shadow in &bounce.shadows {
+                    if shadow.occluded {
+                        writeln!(text, "{}  light {}: blocked at {}", indent, shadow.light, format_vec(shadow.end))?;
+                    } else {
+                        writeln!(
+                            text,
+                            "{}  light {}: weight {:.3}, diffuse {:.3}, specular {:.3}",
+                            indent, shadow.light, shadow.weight, shadow.diffuse, shadow.specular,
+                        )?;
+                    }
+                }
+
+                writeln!(
+                    text,
+                    "{}  {:.3} x {:.2} x {} + {:.3} x {:.2} + {} x {:.2} (survival {:.2})",
+                    indent,
+                    bounce.diffuse_intensity,
+                    material.albedo.x,
+                    format_vec(material.diffuse_colour),
+                    bounce.specular_intensity,
+                    material.albedo.y,
+                    format_vec(bounce.reflected),
+                    material.reflectivity,
+                    bounce.survival,
+                )?;
+                writeln!(text, "{}  = {}", indent, format_vec(bounce.colour))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Draws the tree over the displayed image as seen from the camera, with
+    // segments coloured by kind as in PLY files
+    pub fn draw(&self, framebuffer: &mut Framebuffer, camera: &Camera) {
+        let (width, height) = (framebuffer.width, framebuffer.height);
+        let forward = camera.forward();
+
+        for segment in self.segments() {
+            let (mut start, mut end) = (segment.start, segment.end);
+            let (near_start, near_end) = (
+                dot(start - camera.position, forward) - NEAR,
+                dot(end - camera.position, forward) - NEAR,
+            );
+
+            // Cut the segment off where it passes behind the camera
+            if near_start < 0.0 && near_end < 0.0 {
+                continue;
+            } else if near_start < 0.0 {
+                start = start + (end - start) * (near_start / (near_start - near_end));
+            } else if near_end < 0.0 {
+                end = end + (start - end) * (near_end / (near_end - near_start));
+            }
+
+            if let (Some(a), Some(b)) = (camera.project(start, width, height), camera.project(end, width, height)) {
+                framebuffer.line(a, b, segment.kind.colour());
+            }
+        }
+    }
+}
+
+// Writes the tree as OBJ or PLY polylines, or as JSON with every detail,
+// depending on the extension
+pub fn save<P: AsRef<Path>>(tree: &RayTree, path: P) -> Result<()> {
+    let path = path.as_ref();
+
+    match path.extension().and_then(OsStr::to_str) {
+        Some("json") => {
+            let mut writer = BufWriter::new(File::create(path)?);
+            writer.write_all(serde_json::to_string_pretty(tree)?.as_bytes())?;
+        }
+        Some("obj") => write_obj(&mut BufWriter::new(File::create(path)?), &tree.segments())?,
+        Some("ply") => write_ply(&mut BufWriter::new(File::create(path)?), &tree.segments())?,
+        _ => return Err(format!("unsupported ray tree format for `{}` (use .obj, .ply or .json)", path.display()).into()),
+    }
+
+    Ok(())
+}
+
+// Each segment is a line between its own two vertices, grouped by kind so
+// they can be styled separately
+fn write_obj<W: Write>(writer: &mut W, segments: &[Segment]) -> Result<()> {
+    let mut group = None;
+
+    for (i, segment) in segments.iter().enumerate() {
+        if group != Some(segment.kind) {
+            writeln!(writer, "g {}", segment.kind.name())?;
+            group = Some(segment.kind);
+        }
+
+        let Segment { start, end, .. } = segment;
+        writeln!(writer, "v {} {} {}", start.x, start.y, start.z)?;
+        writeln!(writer, "v {} {} {}", end.x, end.y, end.z)?;
+        writeln!(writer, "l {} {}", 2 * i + 1, 2 * i + 2)?;
+    }
+
+    Ok(())
+}
+
+// ASCII PLY with coloured edges, one colour per kind of segment
+fn write_ply<W: Write>(writer: &mut W, segments: &[Segment]) -> Result<()> {
+    writeln!(writer, "ply")?;
+    writeln!(writer, "format ascii 1.0")?;
+    writeln!(writer, "element vertex {}", 2 * segments.len())?;
+    writeln!(writer, "property float x")?;
+    writeln!(writer, "property float y")?;
+    writeln!(writer, "property float z")?;
+    writeln!(writer, "element edge {}", segments.len())?;
+    writeln!(writer, "property int vertex1")?;
+    writeln!(writer, "property int vertex2")?;
+    writeln!(writer, "property uchar red")?;
+    writeln!(writer, "property uchar green")?;
+    writeln!(writer, "property uchar blue")?;
+    writeln!(writer, "end_header")?;
+
+    for Segment { start, end, .. } in segments {
+        writeln!(writer, "{} {} {}", start.x, start.y, start.z)?;
+        writeln!(writer, "{} {} {}", end.x, end.y, end.z)?;
+    }
+
+    for (i, segment) in segments.iter().enumerate() {
+        let [r, g, b] = segment.kind.colour();
+        writeln!(writer, "{} {} {} {} {}", 2 * i, 2 * i + 1, r, g, b)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn obj_lines_per_segment() {
+        let mut camera = Bounce::new(BounceKind::Camera, 0, Vec3::zero(), Vec3::new(0.0, 0.0, -1.0));
+        camera.point = Some(Vec3::new(0.0, 0.0, -4.0));
+        camera.shadows.push(ShadowRay {
+            light: 0,
+            origin: Vec3::new(0.0, 0.0, -4.0),
+            end: Vec3::new(0.0, 0.0, 10.0),
+            occluded: false,
+            weight: 1.0,
+            diffuse: 1.0,
+            specular: 0.0,
+        });
+        let miss = Bounce::new(BounceKind::Reflection, 1, Vec3::new(0.0, 0.0, -4.0), Vec3::new(0.0, 0.0, 1.0));
+
+        let tree = RayTree {
+            pixel: (0, 0),
+            colour: Vec3::zero(),
+            paths: vec![vec![camera, miss]],
+        };
+
+        let segments = tree.segments();
+        let kinds: Vec<SegmentKind> = segments.iter().map(|s| s.kind).collect();
+        assert_eq!(kinds, vec![SegmentKind::Camera, SegmentKind::Shadow, SegmentKind::Miss]);
+        assert_eq!(segments[2].end, Vec3::new(0.0, 0.0, 6.0));
+
+        let mut obj = Vec::new();
+        write_obj(&mut obj, &segments).unwrap();
+        let obj = String::from_utf8(obj).unwrap();
+        assert_eq!(obj.lines().filter(|line| line.starts_with("l ")).count(), 3);
+        assert!(obj.contains("l 5 6"));
+    }
+}
@@ -0,0 +1,1058 @@
+use crate::aov::Expression;
+use crate::cache::ShadingCache;
+use crate::camera::Camera;
+use crate::diagnostics::luminance;
+use crate::environment::Environment;
+use crate::framebuffer::Framebuffer;
+use crate::geometry::{Hit, Intersect, Ray, Vec3, dot, reflect};
+use crate::lights::LightSampler;
+use crate::materials::Material;
+use crate::probes::Probes;
+use crate::ray_tree::{Bounce, BounceKind, RayTree, ShadowRay};
+use crate::sampling::{self, Sampling};
+use crate::scene::Scene;
+
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Distance over which the depth view fades to black
+const DEPTH_FALLOFF: f32 = 20.0;
+
+// Mixed into the seeds of environment samples, so they're independent of
+// those used to pick lights
+const ENVIRONMENT_SEED: u64 = 0x656e_7669_726f_6e6d;
+const SUN_SEED: u64 = 0x7375_6e64_6973_6b00;
+
+const BACKGROUND_COLOUR: Vec3<f32> = Vec3 {
+    x: 0.2,
+    y: 0.7,
+    z: 0.8,
+};
+
+/// Controls how far reflections are followed, trading noise against speed.
+///
+/// Paths end after `max_depth` bounces. From `rr_start_depth` bounces on,
+/// they're also ended at random by Russian roulette, surviving with a
+/// probability equal to the path's throughput, the fraction of light that
+/// makes it back to the camera after every reflection so far, but no less
+/// than `rr_min_probability`. Paths that can add little to the image are
+/// mostly ended early, and surviving paths are weighted up to compensate, so
+/// the image converges to the same result, just with more noise. The depth
+/// limit is only a backstop.
+///
+/// In scenes with more than `light_samples` lights, each shading point only
+/// traces shadow rays to that many lights, chosen in proportion to their power
+/// with stratified random numbers. Zero always uses every light.
+///
+/// Scenes with an environment map are also lit by it when
+/// `environment_samples` is above zero, with each shading point tracing that
+/// many rays towards the environment's brightest parts and as many spread
+/// like its material's reflection, weighted against each other with multiple
+/// importance sampling. Zero leaves the environment to be seen only directly
+/// and in mirrors.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TraceSettings {
+    pub max_depth: u32,
+    pub rr_start_depth: u32,
+    pub rr_min_probability: f32,
+    pub light_samples: usize,
+    pub environment_samples: usize,
+}
+
+impl Default for TraceSettings {
+    fn default() -> Self {
+        TraceSettings {
+            max_depth: 8,
+            rr_start_depth: 2,
+            rr_min_probability: 0.25,
+            light_samples: 8,
+            environment_samples: 0,
+        }
+    }
+}
+
+impl std::fmt::Display for TraceSettings {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "max depth {}, roulette from depth {} with floor {:.2}, {} light samples, {} environment samples",
+            self.max_depth, self.rr_start_depth, self.rr_min_probability, self.light_samples, self.environment_samples,
+        )
+    }
+}
+
+/// What the renderer shows for each ray.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RenderMode {
+    Shaded,
+    Normals,
+    Depth,
+    // Shaded with every material replaced by the scene's override material,
+    // or neutral grey clay if it has none
+    Clay,
+}
+
+impl RenderMode {
+    pub fn next(self) -> Self {
+        match self {
+            RenderMode::Shaded => RenderMode::Normals,
+            RenderMode::Normals => RenderMode::Depth,
+            RenderMode::Depth => RenderMode::Clay,
+            RenderMode::Clay => RenderMode::Shaded,
+        }
+    }
+}
+
+/// Finds the nearest visible shape hit by the ray, along with its index.
+pub fn nearest_shape(ray: &Ray, scene: &Scene) -> Option<(usize, Hit)> {
+    const MAX_DISTANCE: f32 = 1000.0;
+
+    let mut nearest: Option<(usize, Hit)> = None;
+
+    for (i, shape) in scene.shapes.iter().enumerate() {
+        if !scene.is_visible(i) {
+            continue;
+        }
+
+        if let Some(hit) = shape.ray_intersect(ray) {
+            if hit.distance < nearest.map_or(MAX_DISTANCE, |(_, nearest)| nearest.distance) {
+                nearest = Some((i, hit));
+            }
+        }
+    }
+
+    nearest
+}
+
+/// Resolves the hit material's procedural pattern, if any, into its diffuse
+/// colour at the hit. Done once, for visible hits only.
+pub fn resolve_pattern(mut hit: Hit) -> Hit {
+    hit.material.diffuse_colour = hit.material.colour_at(hit.point);
+    hit
+}
+
+/// The nearest visible hit along the ray, with any procedural pattern
+/// resolved into the hit material's diffuse colour.
+pub fn scene_intersect(ray: &Ray, scene: &Scene) -> Option<Hit> {
+    nearest_shape(ray, scene).map(|(_, hit)| resolve_pattern(hit))
+}
+
+// Nudges a point off the surface to the same side as `direction`, so rays
+// leaving it don't hit the surface itself
+fn offset_origin(point: Vec3<f32>, normal: Vec3<f32>, direction: Vec3<f32>) -> Vec3<f32> {
+    if dot(direction, normal) < 0.0 {
+        point - normal*1.0e-3
+    } else {
+        point + normal*1.0e-3
+    }
+}
+
+// The Kajiya-Kay model of a thin fibre lit from one direction and seen from
+// another. Light is scattered around a cone about the fibre rather than about
+// a normal, so it's diffusely brightest lit side on and shines where the
+// halfway vector is perpendicular to it.
+fn fibre_shading(tangent: Vec3<f32>, light: Vec3<f32>, view: Vec3<f32>, exponent: f32) -> (f32, f32) {
+    let sine = |cosine: f32| (1.0 - cosine * cosine).max(0.0).sqrt();
+
+    let halfway = (light + view).normalise();
+    (sine(dot(tangent, light)), sine(dot(tangent, halfway)).powf(exponent))
+}
+
+/// The mirror reflection of a ray at a hit, starting just off the surface.
+pub fn reflection_ray(ray: &Ray, hit: &Hit) -> Ray {
+    let direction = reflect(ray.direction, hit.normal).normalise();
+    Ray {
+        origin: offset_origin(hit.point, hit.normal, direction),
+        direction,
+    }
+}
+
+/// A ray from just off the surface at a hit towards a light at `position`,
+/// and the distance to the light.
+pub fn shadow_ray(hit: &Hit, position: Vec3<f32>) -> (Ray, f32) {
+    let direction = (position - hit.point).normalise();
+    let ray = Ray {
+        origin: offset_origin(hit.point, hit.normal, direction),
+        direction,
+    };
+    (ray, (position - hit.point).length())
+}
+
+// How much of the light arriving from `direction` a hit seen along `ray`
+// reflects diffusely and in its highlight, before the material's colour and
+// albedos
+fn response(ray: &Ray, hit: &Hit, material: &Material, direction: Vec3<f32>) -> (f32, f32) {
+    match hit.tangent {
+        Some(tangent) => fibre_shading(tangent, direction, -ray.direction, material.specular_exponent),
+        None => {
+            let reflection = reflect(-direction, hit.normal);
+            (
+                0.0f32.max(dot(direction, hit.normal)),
+                0.0f32.max(dot(-reflection, ray.direction)).powf(material.specular_exponent),
+            )
+        }
+    }
+}
+
+// Where a shadow ray is blocked before reaching a light `distance` away
+fn occluder(shadow_ray: &Ray, distance: f32, scene: &Scene) -> Option<Vec3<f32>> {
+    scene_intersect(shadow_ray, scene)
+        .map(|shadow_hit| shadow_hit.point)
+        .filter(|&end| (end - shadow_ray.origin).length() < distance)
+}
+
+/// The light leaving a surface of the given material towards the camera,
+/// from the summed intensities of the lights on it and the colour seen in
+/// its reflection. Negative lights can darken a surface but never past
+/// black.
+pub fn direct_components(material: &Material, diffuse: f32, specular: f32, reflected: Vec3<f32>) -> Components {
+    Components {
+        diffuse: material.diffuse_colour * diffuse.max(0.0) * material.albedo.x,
+        specular: Vec3::new(1.0, 1.0, 1.0) * specular.max(0.0) * material.albedo.y,
+        reflection: reflected * material.reflectivity,
+        emission: Vec3::zero(),
+    }
+}
+
+/// The light arriving back along a camera ray, split by how it got there.
+///
+/// `diffuse` and `specular` are the diffuse and highlight terms of the lights
+/// shining directly on the first surface hit, and `reflection` is what's seen
+/// in its mirror reflection. `emission` is the background seen directly,
+/// which is the only thing a ray can hit that gives off light of its own.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Components {
+    pub diffuse: Vec3<f32>,
+    pub specular: Vec3<f32>,
+    pub reflection: Vec3<f32>,
+    pub emission: Vec3<f32>,
+}
+
+impl Components {
+    pub fn total(&self) -> Vec3<f32> {
+        self.diffuse + self.specular + self.reflection + self.emission
+    }
+}
+
+/// Traces rays through a scene for one render mode within the limits of the
+/// given settings, sharing whatever can be worked out up front between rays.
+pub struct Tracer<'a> {
+    scene: &'a Scene,
+    mode: RenderMode,
+    settings: TraceSettings,
+    material_override: Option<Material>,
+    // A single sampler shared by every shape, or with light linking one per
+    // shape over just the lights that illuminate it
+    light_samplers: Vec<LightSampler>,
+    // Baked reflections looked up in place of tracing reflection rays
+    probes: Option<&'a Probes>,
+    // Light visibility remembered from earlier shadow rays
+    cache: Option<&'a ShadingCache>,
+    // The procedural sky's dome as an environment map, when it's lit by one
+    sky_dome: Option<Environment>,
+}
+
+impl<'a> Tracer<'a> {
+    pub fn new(scene: &'a Scene, mode: RenderMode, settings: TraceSettings) -> Self {
+        let material_override = match mode {
+            RenderMode::Clay => Some(scene.clay_material()),
+            _ => None,
+        };
+
+        Tracer {
+            scene,
+            mode,
+            settings,
+            material_override,
+            light_samplers: Self::light_samplers(scene),
+            probes: None,
+            cache: None,
+            sky_dome: match (&scene.environment, &scene.sky) {
+                (None, Some(sky)) if settings.environment_samples > 0 => Some(sky.dome_environment()),
+                _ => None,
+            },
+        }
+    }
+
+    pub fn scene(&self) -> &'a Scene {
+        self.scene
+    }
+
+    /// Looks reflections up in baked probes instead of tracing them, which is
+    /// much faster but only approximate away from the probes' positions.
+    pub fn with_probes(mut self, probes: &'a Probes) -> Self {
+        self.probes = Some(probes).filter(|probes| !probes.is_empty());
+        self
+    }
+
+    /// Reuses shadows from a cache shared between rays and frames, blurring
+    /// them a little in exchange for tracing far fewer shadow rays. Recorded
+    /// paths always trace their own.
+    pub fn with_cache(mut self, cache: &'a ShadingCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    fn light_samplers(scene: &Scene) -> Vec<LightSampler> {
+        if scene.has_light_links() {
+            (0..scene.shapes.len())
+                .map(|shape| LightSampler::with_filter(&scene.lights, |light| scene.illuminates(light, shape)))
+                .collect()
+        } else {
+            vec![LightSampler::new(&scene.lights)]
+        }
+    }
+
+    /// Traces a ray into the scene and returns the light arriving back along
+    /// it, following mirror reflections recursively from `depth`. `seed`
+    /// drives the random choices made along the path.
+    pub fn cast_ray(&self, ray: &Ray, depth: u32, seed: u64) -> Vec3<f32> {
+        self.trace(ray, depth, 1.0, seed, None).total()
+    }
+
+    /// Like [`cast_ray`](Self::cast_ray) from a camera ray, but keeping the
+    /// light split into its components.
+    pub fn components(&self, ray: &Ray, seed: u64) -> Components {
+        self.trace(ray, 0, 1.0, seed, None)
+    }
+
+    /// Like [`cast_ray`](Self::cast_ray) from a camera ray, but also returns
+    /// every ray traced along the path, in order of depth.
+    pub fn record(&self, ray: &Ray, seed: u64) -> (Vec3<f32>, Vec<Bounce>) {
+        let mut path = Vec::new();
+        let colour = self.trace(ray, 0, 1.0, seed, Some(&mut path)).total();
+        (colour, path)
+    }
+
+    // `throughput` is the fraction of the light arriving along the ray that
+    // reaches the camera. Records this bounce ahead of those made deeper down
+    // the path, once its colour is known.
+    fn trace(&self, ray: &Ray, depth: u32, throughput: f32, seed: u64, mut record: Option<&mut Vec<Bounce>>) -> Components {
+        let scene = self.scene;
+
+        let kind = if depth == 0 { BounceKind::Camera } else { BounceKind::Reflection };
+        let mut bounce = Bounce::new(kind, depth, ray.origin, ray.direction);
+        let start = record.as_ref().map_or(0, |path| path.len());
+
+        let (shape, hit) = match nearest_shape(ray, scene) {
+            Some((shape, hit)) if depth <= self.settings.max_depth => (shape, resolve_pattern(hit)),
+            _ => {
+                let colour = self.background(ray.direction);
+                if let Some(path) = record {
+                    bounce.colour = colour;
+                    path.insert(start, bounce);
+                }
+                return Components { emission: colour, ..Components::default() };
+            }
+        };
+
+        let Hit { point, normal, .. } = hit;
+        let material = self.material(&hit);
+
+        bounce.shape = Some(shape);
+        bounce.point = Some(point);
+        bounce.normal = Some(normal);
+        bounce.material = Some(material);
+
+        let throughput = throughput * material.reflectivity;
+        let survival = self.survival(depth, throughput);
+
+        let seed = sampling::hash(seed);
+
+        let reflect_colour = if material.reflectivity <= 0.0 {
+            Vec3::zero()
+        } else if let Some(colour) = self.probe_reflection(shape, ray, &hit) {
+            colour
+        } else if sampling::random(seed) < survival {
+            let reflect_ray = reflection_ray(ray, &hit);
+            self.trace(&reflect_ray, depth + 1, throughput, seed, record.as_deref_mut()).total() * (1.0 / survival)
+        } else {
+            Vec3::zero()
+        };
+
+        let mut diffuse_intensity = 0.0;
+        let mut specular_intensity = 0.0;
+        let shadows = &mut bounce.shadows;
+        let recording = record.is_some();
+
+        self.for_each_light(shape, point, seed, |i, weight| {
+            let (shadow_ray, light_distance) = shadow_ray(&hit, scene.lights[i].position);
+
+            let (visibility, occluder) = if recording {
+                let occluder = occluder(&shadow_ray, light_distance, scene);
+                (if occluder.is_some() { 0.0 } else { 1.0 }, occluder)
+            } else {
+                (self.visibility(shape, i, point, &shadow_ray, light_distance), None)
+            };
+            let (diffuse, specular) = self.light_response(ray, &hit, &material, i, weight * visibility);
+
+            diffuse_intensity += diffuse;
+            specular_intensity += specular;
+
+            if recording {
+                shadows.push(ShadowRay {
+                    light: i,
+                    origin: shadow_ray.origin,
+                    end: occluder.unwrap_or(scene.lights[i].position),
+                    occluded: occluder.is_some(),
+                    weight,
+                    diffuse,
+                    specular,
+                });
+            }
+        });
+
+        let (sun_diffuse, sun_specular) = self.sun_light(ray, &hit, &material, seed);
+        diffuse_intensity += sun_diffuse;
+        specular_intensity += sun_specular;
+
+        let mut components = direct_components(&material, diffuse_intensity, specular_intensity, reflect_colour);
+        let environment = self.environment_light(ray, &hit, &material, seed);
+        components.diffuse = components.diffuse + environment.diffuse;
+        components.specular = components.specular + environment.specular;
+
+        if let Some(path) = record {
+            // Negative lights can darken a surface but never past black
+            bounce.diffuse_intensity = diffuse_intensity.max(0.0);
+            bounce.specular_intensity = specular_intensity.max(0.0);
+            bounce.survival = survival;
+            bounce.reflected = reflect_colour;
+            bounce.colour = components.total();
+            path.insert(start, bounce);
+        }
+
+        components
+    }
+
+    /// The light reaching the camera from rays that escape the scene.
+    pub fn background(&self, direction: Vec3<f32>) -> Vec3<f32> {
+        self.scene.background(direction, BACKGROUND_COLOUR)
+    }
+
+    /// The material shading uses at a hit, which in clay mode is the same
+    /// for every shape.
+    pub fn material(&self, hit: &Hit) -> Material {
+        self.material_override.unwrap_or(hit.material)
+    }
+
+    /// The chance of following a reflection made at `depth` by a path with
+    /// the given throughput after it, which is certain until roulette starts.
+    pub fn survival(&self, depth: u32, throughput: f32) -> f32 {
+        if depth + 1 >= self.settings.rr_start_depth {
+            throughput.clamp(self.settings.rr_min_probability, 1.0)
+        } else {
+            1.0
+        }
+    }
+
+    /// The reflection at a hit on `shape` looked up in the baked probes, if
+    /// there are any near enough to use.
+    pub fn probe_reflection(&self, shape: usize, ray: &Ray, hit: &Hit) -> Option<Vec3<f32>> {
+        let probe = self.probes.and_then(|probes| probes.lookup(shape, hit.point))?;
+        Some(probe.sample(reflect(ray.direction, hit.normal).normalise()))
+    }
+
+    /// Calls `f` with each light to trace a shadow ray to from `point` on
+    /// `shape`, along with its weight, which takes in any light blockers.
+    /// With more lights than the settings' light samples, those are chosen
+    /// at random from `seed`.
+    pub fn for_each_light<F>(&self, shape: usize, point: Vec3<f32>, seed: u64, mut f: F)
+    where
+        F: FnMut(usize, f32),
+    {
+        let scene = self.scene;
+        let mut add_light = |i: usize, weight: f32| {
+            let weight = weight * scene.transmission(i, point);
+            if weight > 0.0 {
+                f(i, weight);
+            }
+        };
+
+        let samples = self.settings.light_samples;
+
+        if samples == 0 || scene.lights.len() <= samples {
+            for i in 0..scene.lights.len() {
+                if scene.illuminates(i, shape) {
+                    add_light(i, 1.0);
+                }
+            }
+        } else {
+            let sampler = &self.light_samplers[shape.min(self.light_samplers.len() - 1)];
+
+            // One light from each of `samples` equal slices of the CDF, each
+            // weighted by the inverse of its chance of being picked
+            for k in 0..samples {
+                let u = (k as f32 + sampling::random(seed.wrapping_add(k as u64 + 1))) / samples as f32;
+                if let Some(i) = sampler.sample(u) {
+                    add_light(i, 1.0 / (samples as f32 * sampler.pdf(i)));
+                }
+            }
+        }
+    }
+
+    /// How much of light `light` reaches `point` on `shape` along the
+    /// shadow ray, which is `distance` long, using the shading cache if
+    /// there is one.
+    pub fn visibility(&self, shape: usize, light: usize, point: Vec3<f32>, shadow_ray: &Ray, distance: f32) -> f32 {
+        let trace = || if occluder(shadow_ray, distance, self.scene).is_some() { 0.0 } else { 1.0 };
+
+        match self.cache {
+            Some(cache) => cache.visibility(shape, light, point, trace),
+            None => trace(),
+        }
+    }
+
+    /// The diffuse and specular intensities light `light` gives a hit seen
+    /// along `ray`, given the weight of the light that reaches it.
+    pub fn light_response(&self, ray: &Ray, hit: &Hit, material: &Material, light: usize, lit: f32) -> (f32, f32) {
+        let light = &self.scene.lights[light];
+        if lit <= 0.0 {
+            return (0.0, 0.0);
+        }
+
+        let (diffuse, specular) = response(ray, hit, material, (light.position - hit.point).normalise());
+        (light.intensity * lit * diffuse, light.intensity * lit * specular)
+    }
+
+    /// The diffuse and specular intensities the procedural sky's sun gives a
+    /// hit seen along `ray`, from one shadow ray to a random point on its
+    /// disk. Nothing if there's no sky, or an environment map replaces it.
+    pub fn sun_light(&self, ray: &Ray, hit: &Hit, material: &Material, seed: u64) -> (f32, f32) {
+        let sky = match (&self.scene.environment, &self.scene.sky) {
+            (None, Some(sky)) if sky.sun_intensity != 0.0 => sky,
+            _ => return (0.0, 0.0),
+        };
+
+        let seed = sampling::hash(seed ^ SUN_SEED);
+        let direction = sky.sample_sun(sampling::random(seed), sampling::random(seed.wrapping_add(1)));
+        let (diffuse, specular) = response(ray, hit, material, direction);
+        if diffuse + specular <= 0.0 {
+            return (0.0, 0.0);
+        }
+
+        let shadow_ray = Ray {
+            origin: offset_origin(hit.point, hit.normal, direction),
+            direction,
+        };
+        if scene_intersect(&shadow_ray, self.scene).is_some() {
+            return (0.0, 0.0);
+        }
+
+        // The sun's radiance over the density of picking the direction, which
+        // is one over the disk's solid angle, comes to its intensity times pi
+        (sky.sun_intensity * diffuse, sky.sun_intensity * specular)
+    }
+
+    /// The light from the scene's environment map on a hit seen along `ray`,
+    /// as diffuse and specular components, or nothing if there's no map or
+    /// no environment samples. A uniform environment lights a surface facing
+    /// it as much as a light of the same intensity shining straight on.
+    pub fn environment_light(&self, ray: &Ray, hit: &Hit, material: &Material, seed: u64) -> Components {
+        let samples = self.settings.environment_samples;
+        let environment = match self.scene.environment.as_ref().or(self.sky_dome.as_ref()) {
+            Some(environment) if samples > 0 => environment,
+            _ => return Components::default(),
+        };
+
+        // Reflection rays are picked from the diffuse or specular lobe in
+        // proportion to how much light each reflects, while fibres, which
+        // scatter all round, are sampled over the whole sphere
+        let diffuse_weight = material.albedo.x.max(0.0) * luminance(material.diffuse_colour).max(0.0);
+        let specular_weight = material.albedo.y.max(0.0) * 2.0 / (material.specular_exponent.max(0.0) + 2.0);
+        let diffuse_chance = match diffuse_weight + specular_weight {
+            total if total > 0.0 => diffuse_weight / total,
+            _ => return Components::default(),
+        };
+
+        let exponent = material.specular_exponent.max(0.0);
+        let mirror = reflect(ray.direction, hit.normal).normalise();
+        let reflection_pdf = |direction: Vec3<f32>| match hit.tangent {
+            Some(_) => 1.0 / (4.0 * PI),
+            None => {
+                let cosine = dot(direction, hit.normal);
+                let diffuse = if cosine > 0.0 { cosine / PI } else { 0.0 };
+                diffuse_chance * diffuse + (1.0 - diffuse_chance) * sampling::phong_pdf(dot(direction, mirror), exponent)
+            }
+        };
+
+        let (mut diffuse, mut specular) = (Vec3::zero(), Vec3::zero());
+        let mut add = |direction: Vec3<f32>, pdf: f32, other_pdf: f32| {
+            let (d, s) = response(ray, hit, material, direction);
+            if pdf <= 0.0 || d + s <= 0.0 {
+                return;
+            }
+
+            let shadow_ray = Ray {
+                origin: offset_origin(hit.point, hit.normal, direction),
+                direction,
+            };
+            if scene_intersect(&shadow_ray, self.scene).is_some() {
+                return;
+            }
+
+            // The power heuristic, which mostly trusts whichever strategy
+            // was more likely to pick this direction
+            let weight = pdf * pdf / (pdf * pdf + other_pdf * other_pdf);
+            let light = environment.sample(direction) * (weight / (PI * pdf * samples as f32));
+            diffuse = diffuse + light * d;
+            specular = specular + light * s;
+        };
+
+        let seed = sampling::hash(seed ^ ENVIRONMENT_SEED);
+        for k in 0..samples as u64 {
+            let random = |n: u64| sampling::random(seed.wrapping_add(4 * k + n));
+
+            if let Some((direction, pdf)) = environment.sample_direction(random(0), random(1)) {
+                add(direction, pdf, reflection_pdf(direction));
+            }
+
+            let direction = match hit.tangent {
+                Some(_) => sampling::sphere_direction(random(2), random(3)),
+                None if random(2) < diffuse_chance => {
+                    sampling::cosine_direction(hit.normal, random(2) / diffuse_chance, random(3))
+                }
+                None => {
+                    let u = (random(2) - diffuse_chance) / (1.0 - diffuse_chance);
+                    sampling::phong_direction(mirror, exponent, u, random(3))
+                }
+            };
+            add(direction, reflection_pdf(direction), environment.pdf(direction));
+        }
+
+        let colour = material.diffuse_colour * material.albedo.x;
+        Components {
+            diffuse: Vec3::new(colour.x * diffuse.x, colour.y * diffuse.y, colour.z * diffuse.z),
+            specular: specular * material.albedo.y,
+            ..Components::default()
+        }
+    }
+
+    /// The colour of a single primary ray.
+    pub fn shade(&self, ray: &Ray, seed: u64) -> Vec3<f32> {
+        match self.mode {
+            RenderMode::Shaded | RenderMode::Clay => self.cast_ray(ray, 0, seed),
+            RenderMode::Normals => match scene_intersect(ray, self.scene) {
+                Some(hit) => (hit.normal + Vec3::new(1.0, 1.0, 1.0)) * 0.5,
+                None => Vec3::zero(),
+            },
+            RenderMode::Depth => match scene_intersect(ray, self.scene) {
+                Some(hit) => {
+                    let v = (-hit.distance / DEPTH_FALLOFF).exp();
+                    Vec3::new(v, v, v)
+                }
+                None => Vec3::zero(),
+            },
+        }
+    }
+}
+
+/// Renders whole images of a scene.
+///
+/// Each pixel is the average of `samples` rays through positions in the
+/// pixel chosen by `sampling`, shaded according to `mode` and traced within
+/// the limits of `settings`. The random numbers for each sample come from the
+/// pixel, the sample's index and `seed`, and nothing else, so an image is the
+/// same however many threads render it.
+#[derive(Copy, Clone, Debug)]
+pub struct Renderer {
+    pub samples: usize,
+    pub sampling: Sampling,
+    pub mode: RenderMode,
+    pub settings: TraceSettings,
+    pub seed: u64,
+}
+
+impl Default for Renderer {
+    fn default() -> Self {
+        Renderer {
+            samples: 1,
+            sampling: Sampling::R2,
+            mode: RenderMode::Shaded,
+            settings: TraceSettings::default(),
+            seed: 0,
+        }
+    }
+}
+
+impl Renderer {
+    /// The seed of the `k`th sample of the pixel at index `pixel`, counting
+    /// row by row from the top left.
+    pub fn sample_seed(&self, pixel: usize, k: usize) -> u64 {
+        sampling::seed(pixel, k) ^ self.seed
+    }
+
+    /// Renders into a framebuffer, filling both its linear colours and its
+    /// displayable pixels.
+    pub fn render(&self, framebuffer: &mut Framebuffer, camera: &Camera, scene: &Scene) {
+        self.render_counting(framebuffer, camera, scene, &AtomicUsize::new(0));
+    }
+
+    /// Renders as [`render`](Self::render) does, adding one to `done` as each
+    /// pixel is finished so another thread can follow its progress.
+    pub fn render_counting(&self, framebuffer: &mut Framebuffer, camera: &Camera, scene: &Scene, done: &AtomicUsize) {
+        let (width, height) = (framebuffer.width, framebuffer.height);
+        let tracer = Tracer::new(scene, self.mode, self.settings);
+
+        framebuffer.render(|i, j| {
+            let colour = self.sample_pixel(&tracer, camera, width, height, i, j);
+            done.fetch_add(1, Ordering::Relaxed);
+            colour
+        });
+    }
+
+    /// The colour of pixel `(x, y)` of a `width` x `height` image, exactly as
+    /// [`render`](Self::render) would give it but without a framebuffer.
+    /// Every sample's position and random numbers come from the pixel and
+    /// sample index, so the same pixel always gives the same colour.
+    pub fn render_pixel(&self, scene: &Scene, camera: &Camera, width: usize, height: usize, x: usize, y: usize) -> Vec3<f32> {
+        let tracer = Tracer::new(scene, self.mode, self.settings);
+        self.sample_pixel(&tracer, camera, width, height, x, y)
+    }
+
+    fn sample_pixel(&self, tracer: &Tracer, camera: &Camera, width: usize, height: usize, x: usize, y: usize) -> Vec3<f32> {
+        let samples = self.sampling.sample_count(self.samples);
+        let mut colour = Vec3::zero();
+
+        for k in 0..samples {
+            let (dx, dy) = self.sampling.offset((x, y), width, k, samples);
+            let ray = camera.ray_through(x as f32 + dx, y as f32 + dy, width, height);
+            colour = colour + tracer.shade(&ray, self.sample_seed(y * width + x, k));
+        }
+
+        colour * (1.0 / samples as f32)
+    }
+
+    /// Renders only the light kept by the expression, taking the same samples
+    /// as [`render`](Self::render) so that outputs covering all the light
+    /// between them add up to the full render. The mode is ignored, as only
+    /// shaded renders have light to split up.
+    pub fn render_output(&self, framebuffer: &mut Framebuffer, camera: &Camera, scene: &Scene, expression: &Expression) {
+        let (width, height) = (framebuffer.width, framebuffer.height);
+        let samples = self.sampling.sample_count(self.samples);
+        let sampling = self.sampling;
+        let tracer = Tracer::new(scene, self.mode, self.settings);
+
+        framebuffer.render(|i, j| {
+            let mut colour = Vec3::zero();
+
+            for k in 0..samples {
+                let (dx, dy) = sampling.offset((i, j), width, k, samples);
+                let ray = camera.ray_through(i as f32 + dx, j as f32 + dy, width, height);
+                let seed = self.sample_seed(j * width + i, k);
+                colour = colour + expression.select(&tracer.components(&ray, seed));
+            }
+
+            colour * (1.0 / samples as f32)
+        });
+    }
+
+    /// Records every ray traced for pixel `(x, y)` of a `width` x `height`
+    /// image, taking the same samples as [`render`](Self::render).
+    pub fn record_pixel(&self, scene: &Scene, camera: &Camera, width: usize, height: usize, x: usize, y: usize) -> RayTree {
+        let samples = self.sampling.sample_count(self.samples);
+        let tracer = Tracer::new(scene, self.mode, self.settings);
+
+        let mut colour = Vec3::zero();
+        let mut paths = Vec::new();
+
+        for k in 0..samples {
+            let (dx, dy) = self.sampling.offset((x, y), width, k, samples);
+            let ray = camera.ray_through(x as f32 + dx, y as f32 + dy, width, height);
+            let (sample, path) = tracer.record(&ray, self.sample_seed(y * width + x, k));
+
+            colour = colour + sample;
+            paths.push(path);
+        }
+
+        RayTree {
+            pixel: (x, y),
+            colour: colour * (1.0 / samples as f32),
+            paths,
+        }
+    }
+
+    /// Renders a `width` x `height` image, returning the linear colour of
+    /// every pixel row by row from the top left.
+    pub fn render_to_buffer(&self, scene: &Scene, camera: &Camera, width: usize, height: usize) -> Vec<Vec3<f32>> {
+        let mut framebuffer = Framebuffer::new(width, height);
+        self.render(&mut framebuffer, camera, scene);
+        framebuffer.colours
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Plane, Sphere, Vec2};
+    use crate::image::Image;
+    use crate::sky::Sky;
+    use crate::lights::{Blocker, Region};
+    use crate::scene::{Light, Links};
+
+    #[test]
+    fn intersect_skips_hidden_shapes() {
+        let mut scene = Scene::default_scene();
+        scene.shapes = vec![
+            Sphere::new(Vec3::new(0.0, 0.0, -5.0), 1.0, Material::default()).into(),
+            Sphere::new(Vec3::new(0.0, 0.0, -10.0), 1.0, Material::default()).into(),
+        ];
+
+        let ray = Ray {
+            origin: Vec3::zero(),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+
+        assert_eq!(nearest_shape(&ray, &scene).map(|(i, _)| i), Some(0));
+
+        scene.hidden.insert(0);
+        assert_eq!(nearest_shape(&ray, &scene).map(|(i, _)| i), Some(1));
+    }
+
+    #[test]
+    fn outputs_add_up_to_the_render() {
+        let scene = Scene::default_scene();
+        let camera = Camera::default();
+        let renderer = Renderer { samples: 2, ..Renderer::default() };
+        let full = renderer.render_to_buffer(&scene, &camera, 32, 24);
+
+        let mut total = vec![Vec3::zero(); 32 * 24];
+        for expression in ["diffuse.direct", "specular.direct + diffuse.indirect", "specular.indirect", "emission"] {
+            let mut framebuffer = Framebuffer::new(32, 24);
+            renderer.render_output(&mut framebuffer, &camera, &scene, &expression.parse().unwrap());
+            for (sum, colour) in total.iter_mut().zip(&framebuffer.colours) {
+                *sum = *sum + *colour;
+            }
+        }
+
+        for (sum, colour) in total.iter().zip(&full) {
+            assert!((*sum - *colour).length() < 1.0e-4);
+        }
+    }
+
+    #[test]
+    fn roulette_is_unbiased() {
+        // A mirror facing a camera ray, reflecting the plain background
+        let mirror = Material::new(Vec2::new(0.0, 0.0), Vec3::zero(), 1.0).with_reflectivity(0.5);
+        let mut scene = Scene::default_scene();
+        scene.shapes = vec![Sphere::new(Vec3::new(0.0, 0.0, -5.0), 1.0, mirror).into()];
+        scene.lights.clear();
+
+        let ray = Ray {
+            origin: Vec3::zero(),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+
+        let settings = TraceSettings {
+            rr_start_depth: 0,
+            ..TraceSettings::default()
+        };
+
+        let tracer = Tracer::new(&scene, RenderMode::Shaded, settings);
+
+        let count = 4000;
+        let total = (0..count).fold(Vec3::zero(), |total, seed| total + tracer.cast_ray(&ray, 0, seed));
+        let mean = total * (1.0 / count as f32);
+
+        let expected = BACKGROUND_COLOUR * 0.5;
+        assert!((mean.z - expected.z).abs() < 0.05);
+    }
+
+    #[test]
+    fn roulette_ends_dim_paths_early_without_bias() {
+        // Two lit mirrors facing each other, between which rays bounce until
+        // cut, each bounce seeing less of the light
+        let mirror = Material::new(Vec2::new(0.5, 0.0), Vec3::new(1.0, 1.0, 1.0), 1.0).with_reflectivity(0.7);
+        let mut scene = Scene::default_scene();
+        scene.shapes = vec![
+            Plane::new(Vec3::new(0.0, 0.0, -5.0), Vec3::new(0.0, 0.0, 1.0), mirror).into(),
+            Plane::new(Vec3::new(0.0, 0.0, 5.0), Vec3::new(0.0, 0.0, -1.0), mirror).into(),
+        ];
+        scene.lights = vec![Light::new(Vec3::new(0.0, 0.0, 0.0), 1.0)];
+
+        let ray = Ray {
+            origin: Vec3::zero(),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+
+        let exact = TraceSettings {
+            rr_start_depth: u32::MAX,
+            ..TraceSettings::default()
+        };
+        let expected = Tracer::new(&scene, RenderMode::Shaded, exact).cast_ray(&ray, 0, 0);
+
+        let tracer = Tracer::new(&scene, RenderMode::Shaded, TraceSettings::default());
+        let count = 20000;
+        let (mut total, mut bounces) = (Vec3::zero(), 0);
+        for seed in 0..count {
+            let (colour, path) = tracer.record(&ray, seed);
+            total = total + colour;
+            bounces += path.len();
+        }
+        let mean = total * (1.0 / count as f32);
+
+        assert!((mean.x - expected.x).abs() < 0.03 * expected.x);
+        assert!((bounces as f32 / count as f32) < TraceSettings::default().max_depth as f32 / 2.0);
+    }
+
+    #[test]
+    fn environment_lighting_converges_to_the_irradiance() {
+        // A plain diffuse floor under a uniform white sky with a small sun
+        // ten thousand times brighter, and no lights
+        let (width, height) = (64, 32);
+        let mut texels = vec![Vec3::new(1.0, 1.0, 1.0); width * height];
+        texels[4 * width + 32] = Vec3::new(1.0e4, 1.0e4, 1.0e4);
+        let image = Image { width, height, texels };
+
+        let material = Material::new(Vec2::new(0.5, 0.0), Vec3::new(1.0, 1.0, 1.0), 1.0);
+        let mut scene = Scene::default_scene();
+        scene.shapes = vec![Plane::new(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), material).into()];
+        scene.lights.clear();
+        scene.environment = Some(Environment::new(image));
+
+        let ray = Ray {
+            origin: Vec3::zero(),
+            direction: Vec3::new(0.0, -1.0, 0.0),
+        };
+        let settings = TraceSettings {
+            environment_samples: 1,
+            ..TraceSettings::default()
+        };
+        let tracer = Tracer::new(&scene, RenderMode::Shaded, settings);
+
+        // The sky alone gives exactly the albedo, and the sun adds its
+        // irradiance over pi
+        let polar = PI * 4.5 / height as f32;
+        let texel_solid_angle = 2.0 * PI * PI * polar.sin() / (width * height) as f32;
+        let expected = 0.5 * (1.0 + (1.0e4 - 1.0) * texel_solid_angle * polar.cos() / PI);
+
+        let count = 10000;
+        let total = (0..count).fold(Vec3::zero(), |total, seed| total + tracer.cast_ray(&ray, 0, seed));
+        let mean = total * (1.0 / count as f32);
+
+        assert!((mean.x - expected).abs() < 0.03 * expected, "{} != {}", mean.x, expected);
+    }
+
+    #[test]
+    fn sun_lights_like_a_light_in_its_direction() {
+        let mut scene = Scene::default_scene();
+        scene.shapes = vec![Plane::new(Vec3::new(0.0, -1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), Material::default()).into()];
+        scene.lights.clear();
+        let sky = Sky { sun_elevation: 50.0, sky_intensity: 0.0, ..Sky::default() };
+        scene.sky = Some(sky);
+
+        let ray = Ray {
+            origin: Vec3::zero(),
+            direction: Vec3::new(0.0, -1.0, 0.0),
+        };
+        let tracer = Tracer::new(&scene, RenderMode::Shaded, TraceSettings::default());
+        let lit = tracer.cast_ray(&ray, 0, 0);
+
+        // The same as a point light far off in the sun's direction
+        scene.sky = None;
+        scene.lights = vec![Light::new(sky.sun_direction() * 1.0e4, sky.sun_intensity)];
+        let expected = Tracer::new(&scene, RenderMode::Shaded, TraceSettings::default()).cast_ray(&ray, 0, 0);
+        assert!((lit - expected).length() < 0.01 * expected.length());
+
+        // A blocker well away from the plane shades the whole of the sun, but
+        // one far smaller than the disk looks from there leaves a penumbra
+        scene.lights.clear();
+        scene.sky = Some(sky);
+        let towards_sun = Vec3::new(0.0, -1.0, 0.0) + sky.sun_direction() * 50.0;
+        let blocker = |radius: f32| Sphere::new(towards_sun, radius, Material::default()).into();
+        scene.shapes.push(blocker(2.0));
+        let shadowed = Tracer::new(&scene, RenderMode::Shaded, TraceSettings::default()).cast_ray(&ray, 0, 0);
+        assert!(shadowed.length() < 1.0e-6);
+
+        scene.shapes[1] = blocker(0.15);
+        let tracer = Tracer::new(&scene, RenderMode::Shaded, TraceSettings::default());
+        let count = 2000;
+        let total = (0..count).fold(Vec3::zero(), |total, seed| total + tracer.cast_ray(&ray, 0, seed));
+        let penumbra = (total * (1.0 / count as f32)).length() / expected.length();
+        assert!(0.1 < penumbra && penumbra < 0.9, "{}", penumbra);
+    }
+
+    #[test]
+    fn light_selection_is_unbiased() {
+        // Many lights of varying power around a plain diffuse sphere
+        let mut scene = Scene::default_scene();
+        scene.shapes = vec![Sphere::new(Vec3::new(0.0, 0.0, -5.0), 1.0, Material::default()).into()];
+        scene.lights = (0..40)
+            .map(|i| Light::new(Vec3::new(i as f32 - 20.0, 10.0, 10.0), 0.05 + (i % 7) as f32 * 0.02))
+            .collect();
+
+        let ray = Ray {
+            origin: Vec3::zero(),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+
+        let every_light = TraceSettings {
+            light_samples: 0,
+            ..TraceSettings::default()
+        };
+        let exact = Tracer::new(&scene, RenderMode::Shaded, every_light).cast_ray(&ray, 0, 0);
+
+        let sampled = Tracer::new(&scene, RenderMode::Shaded, TraceSettings::default());
+        let count = 2000;
+        let total = (0..count).fold(Vec3::zero(), |total, seed| total + sampled.cast_ray(&ray, 0, seed));
+        let mean = total * (1.0 / count as f32);
+
+        assert!((mean.x - exact.x).abs() < 0.02 * exact.x);
+    }
+
+    #[test]
+    fn light_linking_excludes_lights() {
+        let mut scene = Scene::default_scene();
+        scene.shapes = vec![Sphere::new(Vec3::new(0.0, 0.0, -5.0), 1.0, Material::default()).into()];
+        scene.lights = vec![
+            Light::new(Vec3::new(0.0, 0.0, 10.0), 1.0),
+            Light::new(Vec3::new(0.0, 0.0, 10.0), 1.0),
+        ];
+
+        let ray = Ray {
+            origin: Vec3::zero(),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+
+        let both = Tracer::new(&scene, RenderMode::Shaded, TraceSettings::default()).cast_ray(&ray, 0, 0);
+
+        scene.lights[1].shapes.except.insert(0);
+
+        // Looping over every light and sampling a single one should both
+        // leave the excluded light out
+        for light_samples in 0..2 {
+            let settings = TraceSettings {
+                light_samples,
+                ..TraceSettings::default()
+            };
+            let one = Tracer::new(&scene, RenderMode::Shaded, settings).cast_ray(&ray, 0, 0);
+            assert!((one.x - both.x * 0.5).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn negative_lights_and_blockers_darken() {
+        let mut scene = Scene::default_scene();
+        scene.shapes = vec![Sphere::new(Vec3::new(0.0, 0.0, -5.0), 1.0, Material::default()).into()];
+        scene.lights = vec![Light::new(Vec3::new(0.0, 0.0, 10.0), 1.0)];
+
+        let ray = Ray {
+            origin: Vec3::zero(),
+            direction: Vec3::new(0.0, 0.0, -1.0),
+        };
+        let colour = |scene: &Scene| Tracer::new(scene, RenderMode::Shaded, TraceSettings::default()).cast_ray(&ray, 0, 0);
+
+        let lit = colour(&scene);
+
+        scene.blockers.push(Blocker {
+            region: Region::Sphere { centre: Vec3::new(0.0, 0.0, -4.0), radius: 0.5 },
+            strength: 0.75,
+            falloff: 0.0,
+            lights: Links::default(),
+        });
+        assert!((colour(&scene).x - lit.x * 0.25).abs() < 1e-5);
+
+        scene.blockers.clear();
+        scene.lights.push(Light::new(Vec3::new(0.0, 0.0, 10.0), -3.0));
+        assert_eq!(colour(&scene), Vec3::zero());
+    }
+}
@@ -0,0 +1,307 @@
+use crate::geometry::{Vec3, basis};
+
+use std::f32::consts::PI;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+// How sub-pixel sample positions are chosen when supersampling
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Sampling {
+    // The R2 low-discrepancy sequence, which spreads any number of samples
+    // evenly over the pixel and suits progressive rendering
+    R2,
+    // One random position in each cell of an N x N grid
+    Jittered,
+    // Independent uniformly random positions
+    Random,
+    // R2 shifted in each pixel by a tiled blue-noise texture, so that at a
+    // few samples per pixel the error is spread as fine, even grain rather
+    // than the same pattern repeating in every pixel
+    BlueNoise,
+}
+
+impl FromStr for Sampling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "r2" => Ok(Sampling::R2),
+            "jittered" => Ok(Sampling::Jittered),
+            "random" => Ok(Sampling::Random),
+            "blue-noise" => Ok(Sampling::BlueNoise),
+            _ => Err(format!("unknown sampling `{}` (expected r2, jittered, random or blue-noise)", s)),
+        }
+    }
+}
+
+// Mixes the bits of `x` (the SplitMix64 finaliser), giving a cheap stateless
+// random number generator that renders the same image every time
+pub fn hash(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+// Identifies the kth sample of a pixel, as a starting point for random numbers
+pub fn seed(pixel: usize, k: usize) -> u64 {
+    hash((pixel as u64) << 32 ^ k as u64)
+}
+
+// The seed of one frame of an animation rendered with the given seed, for
+// mixing into its samples' seeds so that every frame's noise differs
+pub fn frame_seed(seed: u64, frame: usize) -> u64 {
+    hash(hash(seed) ^ frame as u64)
+}
+
+fn unit(bits: u64) -> f32 {
+    (bits & 0xff_ffff) as f32 / (1 << 24) as f32
+}
+
+// A uniform random number in [0, 1) derived from the seed
+pub fn random(seed: u64) -> f32 {
+    unit(hash(seed))
+}
+
+// A pair of uniform random numbers in [0, 1) for the given sample of a pixel
+fn random_pair(pixel: usize, k: usize) -> (f32, f32) {
+    let bits = seed(pixel, k);
+    (unit(bits), unit(bits >> 32))
+}
+
+// Sub-pixel offset of the kth sample, following the R2 low-discrepancy
+// sequence so that any number of samples is spread evenly over the pixel
+pub fn r2(k: usize) -> (f32, f32) {
+    const A1: f64 = 0.754_877_666_246_693;
+    const A2: f64 = 0.569_840_290_998_053;
+
+    let k = k as f64;
+    ((0.5 + A1 * k).fract() as f32, (0.5 + A2 * k).fract() as f32)
+}
+
+// A direction in the hemisphere around the normal, more likely the closer it
+// is to the normal in proportion to the cosine of the angle between them
+pub fn cosine_direction(normal: Vec3<f32>, u1: f32, u2: f32) -> Vec3<f32> {
+    let (tangent, bitangent) = basis(normal);
+    let (r, phi) = (u1.sqrt(), 2.0 * PI * u2);
+    (tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + normal * (1.0 - u1).sqrt()).normalise()
+}
+
+// A direction around the (unit) axis in proportion to the cosine of the
+// angle from it raised to `exponent`, as in a Phong highlight, whose density
+// per unit solid angle is `phong_pdf`
+pub fn phong_direction(axis: Vec3<f32>, exponent: f32, u1: f32, u2: f32) -> Vec3<f32> {
+    let (tangent, bitangent) = basis(axis);
+    let cosine = u1.powf(1.0 / (exponent + 1.0));
+    let (sine, phi) = ((1.0 - cosine * cosine).max(0.0).sqrt(), 2.0 * PI * u2);
+    (tangent * (sine * phi.cos()) + bitangent * (sine * phi.sin()) + axis * cosine).normalise()
+}
+
+pub fn phong_pdf(cosine: f32, exponent: f32) -> f32 {
+    if cosine <= 0.0 {
+        0.0
+    } else {
+        (exponent + 1.0) / (2.0 * PI) * cosine.powf(exponent)
+    }
+}
+
+// A direction anywhere on the sphere, all equally likely
+pub fn sphere_direction(u1: f32, u2: f32) -> Vec3<f32> {
+    let y = 1.0 - 2.0 * u1;
+    let (r, phi) = ((1.0 - y * y).max(0.0).sqrt(), 2.0 * PI * u2);
+    Vec3::new(r * phi.cos(), y, r * phi.sin())
+}
+
+// Width and height of the blue-noise tile, and the spread of the filter its
+// points are spaced out with
+const BLUE_NOISE_SIZE: usize = 64;
+const BLUE_NOISE_SIGMA: f32 = 1.5;
+
+// A tile of blue noise, giving every texel a different rank so that each
+// threshold picks out evenly spaced texels, generated by Ulichney's
+// void-and-cluster method. Texels are ranked by repeatedly filling the
+// emptiest spot, judged by a Gaussian filter that wraps around the edges so
+// the tile repeats seamlessly.
+fn blue_noise_ranks() -> &'static [u16] {
+    static RANKS: OnceLock<Vec<u16>> = OnceLock::new();
+
+    RANKS.get_or_init(|| {
+        const N: usize = BLUE_NOISE_SIZE;
+
+        // The filter's weight at each offset, wrapped around the tile
+        let mut filter = vec![0.0; N * N];
+        for (index, weight) in filter.iter_mut().enumerate() {
+            let wrap = |d: usize| d.min(N - d) as f32;
+            let (dx, dy) = (wrap(index % N), wrap(index / N));
+            *weight = (-(dx * dx + dy * dy) / (2.0 * BLUE_NOISE_SIGMA * BLUE_NOISE_SIGMA)).exp();
+        }
+
+        let mut on = vec![false; N * N];
+        let mut energy = vec![0.0f32; N * N];
+        let toggle = |on: &mut [bool], energy: &mut [f32], texel: usize| {
+            on[texel] = !on[texel];
+            let sign = if on[texel] { 1.0 } else { -1.0 };
+            let (x, y) = (texel % N, texel / N);
+
+            for (index, e) in energy.iter_mut().enumerate() {
+                let (dx, dy) = ((index % N + N - x) % N, (index / N + N - y) % N);
+                *e += sign * filter[dy * N + dx];
+            }
+        };
+
+        // The tightest cluster is the set texel with the most energy, and
+        // the largest void the clear one with the least
+        let extreme = |on: &[bool], energy: &[f32], set: bool| {
+            let candidates = (0..N * N).filter(|&texel| on[texel] == set);
+            if set {
+                candidates.max_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            } else {
+                candidates.min_by(|&a, &b| energy[a].total_cmp(&energy[b]))
+            }
+            .expect("the tile is never all set or all clear here")
+        };
+
+        // A random tenth of the texels, spread out by moving texels from
+        // clusters into voids until that changes nothing
+        let initial = N * N / 10;
+        let mut seed = 0;
+        while on.iter().filter(|&&set| set).count() < initial {
+            let texel = (hash(seed) % (N * N) as u64) as usize;
+            if !on[texel] {
+                toggle(&mut on, &mut energy, texel);
+            }
+            seed += 1;
+        }
+        loop {
+            let cluster = extreme(&on, &energy, true);
+            toggle(&mut on, &mut energy, cluster);
+            let void = extreme(&on, &energy, false);
+            toggle(&mut on, &mut energy, void);
+            if void == cluster {
+                break;
+            }
+        }
+
+        let mut ranks = vec![0; N * N];
+
+        // The initial texels rank below the rest, tightest clusters last
+        let (mut removing, mut removing_energy) = (on.clone(), energy.clone());
+        for rank in (0..initial).rev() {
+            let cluster = extreme(&removing, &removing_energy, true);
+            toggle(&mut removing, &mut removing_energy, cluster);
+            ranks[cluster] = rank as u16;
+        }
+
+        // Then the remaining texels in the order that fills the voids
+        for rank in initial..N * N {
+            let void = extreme(&on, &energy, false);
+            toggle(&mut on, &mut energy, void);
+            ranks[void] = rank as u16;
+        }
+
+        ranks
+    })
+}
+
+// The blue-noise value in [0, 1) at pixel `(x, y)`, tiling the image
+pub fn blue_noise(x: usize, y: usize) -> f32 {
+    let n = BLUE_NOISE_SIZE;
+    (blue_noise_ranks()[(y % n) * n + x % n] as f32 + 0.5) / (n * n) as f32
+}
+
+// The kth R2 offset shifted by the blue noise at pixel `(x, y)`, wrapping
+// around within the pixel. The shifts for x and y are read from texels far
+// apart in the tile, so they're independent of each other.
+pub fn dithered_r2(x: usize, y: usize, k: usize) -> (f32, f32) {
+    let half = BLUE_NOISE_SIZE / 2;
+    let (dx, dy) = r2(k);
+    ((dx + blue_noise(x, y)).fract(), (dy + blue_noise(x + half, y + half / 2)).fract())
+}
+
+impl Sampling {
+    // Jittered sampling needs a whole grid, so the requested count is rounded
+    // to the nearest square
+    pub fn sample_count(self, samples: usize) -> usize {
+        match self {
+            Sampling::Jittered => {
+                let n = (samples as f32).sqrt().round().max(1.0) as usize;
+                n * n
+            }
+            _ => samples.max(1),
+        }
+    }
+
+    // Offset within pixel `(x, y)` of an image `width` pixels wide of sample
+    // `k` out of `samples` (as returned by `sample_count`)
+    pub fn offset(self, (x, y): (usize, usize), width: usize, k: usize, samples: usize) -> (f32, f32) {
+        let pixel = y * width + x;
+        match self {
+            Sampling::R2 => r2(k),
+            Sampling::Jittered => {
+                let n = (samples as f32).sqrt() as usize;
+                let (dx, dy) = random_pair(pixel, k);
+                (((k % n) as f32 + dx) / n as f32, ((k / n) as f32 + dy) / n as f32)
+            }
+            Sampling::Random => random_pair(pixel, k),
+            Sampling::BlueNoise => dithered_r2(x, y, k),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jittered_covers_every_cell() {
+        let samples = Sampling::Jittered.sample_count(10);
+        assert_eq!(samples, 9);
+
+        let mut cells = vec![0; samples];
+        for k in 0..samples {
+            let (x, y) = Sampling::Jittered.offset((7, 0), 10, k, samples);
+            assert!((0.0..1.0).contains(&x) && (0.0..1.0).contains(&y));
+            cells[(y * 3.0) as usize * 3 + (x * 3.0) as usize] += 1;
+        }
+
+        assert_eq!(cells, vec![1; 9]);
+    }
+
+    #[test]
+    fn random_is_repeatable() {
+        assert_eq!(Sampling::Random.offset((3, 0), 10, 5, 8), Sampling::Random.offset((3, 0), 10, 5, 8));
+        assert_ne!(Sampling::Random.offset((3, 0), 10, 5, 8), Sampling::Random.offset((4, 0), 10, 5, 8));
+    }
+
+    #[test]
+    fn blue_noise_ranks_every_texel_and_has_little_low_frequency() {
+        let n = BLUE_NOISE_SIZE;
+        let mut ranks = blue_noise_ranks().to_vec();
+        ranks.sort_unstable();
+        assert!(ranks.iter().enumerate().all(|(i, &rank)| rank as usize == i));
+
+        // Averaged over 3 x 3 blocks, white noise varies by about a ninth as
+        // much as single values do, but blue noise, lacking coarse detail,
+        // evens out far more
+        let mut variance = 0.0;
+        for y in 0..n {
+            for x in 0..n {
+                let mut sum = 0.0;
+                for (dx, dy) in (0..9).map(|k| (k % 3, k / 3)) {
+                    sum += blue_noise(x + dx, y + dy);
+                }
+                variance += (sum / 9.0 - 0.5).powi(2);
+            }
+        }
+        variance /= (n * n) as f32;
+        assert!(variance < 0.25 / (12.0 * 9.0), "block variance {}", variance);
+    }
+
+    #[test]
+    fn dithered_offsets_stay_in_the_pixel() {
+        for (x, y, k) in [(0, 0, 0), (63, 63, 1), (100, 7, 3), (5, 200, 1000)] {
+            let (dx, dy) = Sampling::BlueNoise.offset((x, y), 256, k, 4);
+            assert!((0.0..1.0).contains(&dx) && (0.0..1.0).contains(&dy));
+        }
+    }
+}
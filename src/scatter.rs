@@ -0,0 +1,445 @@
+use crate::Result;
+use crate::bvh::Bvh;
+use crate::diagnostics::luminance;
+use crate::geometry::{Aabb, Hit, Intersect, Ray, Shape, Vec2, Vec3, cross};
+use crate::image::{self, Image};
+use crate::materials::{Material, Pattern};
+use crate::palette;
+use crate::sampling;
+
+use serde::{Deserialize, Serialize};
+
+use std::sync::Arc;
+
+// Candidates tried per instance at most, so a mostly black density map gives
+// fewer instances rather than never finishing
+const MAX_ATTEMPTS: usize = 64;
+
+fn default_jitter() -> f32 {
+    1.0
+}
+
+fn default_scale() -> Vec2<f32> {
+    Vec2::new(1.0, 1.0)
+}
+
+// A copy of the prototype, moved so its origin is at `offset` and scaled
+// about it. The seed is the instance's own, for varying it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Instance {
+    pub offset: Vec3<f32>,
+    pub scale: f32,
+    pub seed: u64,
+}
+
+// How much each instance's material differs from the prototype's, picked at
+// random from the instance's seed: its colour multiplied by one of `tints`,
+// its hue turned by up to `hue` of a turn either way, its brightness changed
+// by up to `brightness` of itself either way, and its specular exponent
+// multiplied or divided by up to 2 to the power of `roughness`
+//
+//     variation: (tints: [(x: 1.0, y: 0.9, z: 0.6), (x: 0.8, y: 1.0, z: 0.8)], hue: 0.03, roughness: 1.0)
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Variation {
+    #[serde(default)]
+    pub tints: Vec<Vec3<f32>>,
+    #[serde(default)]
+    pub hue: f32,
+    #[serde(default)]
+    pub brightness: f32,
+    #[serde(default)]
+    pub roughness: f32,
+}
+
+impl Variation {
+    pub fn is_none(&self) -> bool {
+        self.tints.is_empty() && self.hue == 0.0 && self.brightness == 0.0 && self.roughness == 0.0
+    }
+
+    // The material as varied for an instance. Colours stay between 0 and 1.
+    pub fn apply(&self, mut material: Material, seed: u64) -> Material {
+        let random = |n: u64| sampling::random(seed.wrapping_add(n));
+        let tint = match self.tints.len() {
+            0 => Vec3::new(1.0, 1.0, 1.0),
+            n => self.tints[((random(0) * n as f32) as usize).min(n - 1)],
+        };
+        let hue = self.hue * (2.0 * random(1) - 1.0);
+        let brightness = 1.0 + self.brightness * (2.0 * random(2) - 1.0);
+
+        let vary = |colour: Vec3<f32>| {
+            let (h, s, v) = palette::to_hsv(colour);
+            let c = palette::hsv(h + hue, s, v * brightness);
+            let c = Vec3::new(c.x * tint.x, c.y * tint.y, c.z * tint.z);
+            Vec3::new(c.x.clamp(0.0, 1.0), c.y.clamp(0.0, 1.0), c.z.clamp(0.0, 1.0))
+        };
+
+        material.diffuse_colour = vary(material.diffuse_colour);
+        if let Pattern::Checkerboard { other_colour, size } = material.pattern {
+            material.pattern = Pattern::Checkerboard { other_colour: vary(other_colour), size };
+        }
+        material.specular_exponent *= (self.roughness * (2.0 * random(3) - 1.0)).exp2();
+        material
+    }
+}
+
+#[derive(Debug)]
+struct ScatterData {
+    instances: Vec<Instance>,
+    bvh: Bvh,
+}
+
+impl ScatterData {
+    fn new(instances: Vec<Instance>, prototype: Aabb) -> Self {
+        let bounds: Vec<Aabb> = instances
+            .iter()
+            .map(|instance| Aabb {
+                min: prototype.min * instance.scale + instance.offset,
+                max: prototype.max * instance.scale + instance.offset,
+            })
+            .collect();
+
+        ScatterData { bvh: Bvh::build(&bounds), instances }
+    }
+}
+
+impl Default for ScatterData {
+    fn default() -> Self {
+        ScatterData::new(Vec::new(), Aabb::empty())
+    }
+}
+
+// `count` copies of a prototype shape spread over the surface of another
+// shape in the scene, given by its index: a bounded plane, a triangle, a
+// sphere or a mesh (such as a heightfield). The prototype is modelled around
+// the origin, which is put on the surface, so a sphere meant to sit on the
+// ground has its centre at its radius above the origin.
+//
+//     Scatter((prototype: Sphere((centre: (x: 0.0, y: 0.2, z: 0.0), radius: 0.2, material: (...))),
+//              surface: 4, count: 5000, seed: 1, scale: (x: 0.5, y: 1.5), density: Some("meadow.ppm")))
+//
+// Instances are spread evenly, each moved at random within its share of the
+// surface by up to `jitter` (0 for a regular pattern, 1 for fully random),
+// and scaled at random within `scale`. A density map, stretched over the
+// surface's texture coordinates, thins them out where it's darker. Each
+// instance's material can vary from the prototype's, so that large scatters
+// don't look artificially uniform.
+//
+// Every instance shares the prototype, so even large scatters take little
+// memory. The instances are placed when the scene is loaded, or when
+// `Scene::build_scatters` is called after changing one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Scatter {
+    pub prototype: Box<Shape>,
+    pub surface: usize,
+    pub count: usize,
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default = "default_jitter")]
+    pub jitter: f32,
+    #[serde(default = "default_scale")]
+    pub scale: Vec2<f32>,
+    #[serde(default)]
+    pub density: Option<String>,
+    #[serde(default)]
+    pub variation: Variation,
+    #[serde(default)]
+    pub position: Vec3<f32>,
+    #[serde(skip)]
+    data: Arc<ScatterData>,
+}
+
+impl Scatter {
+    pub fn new(prototype: Shape, surface: usize, count: usize) -> Self {
+        Scatter {
+            prototype: Box::new(prototype),
+            surface,
+            count,
+            seed: 0,
+            jitter: default_jitter(),
+            scale: default_scale(),
+            density: None,
+            variation: Variation::default(),
+            position: Vec3::zero(),
+            data: Arc::default(),
+        }
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_scale(mut self, min: f32, max: f32) -> Self {
+        self.scale = Vec2::new(min, max);
+        self
+    }
+
+    pub fn with_variation(mut self, variation: Variation) -> Self {
+        self.variation = variation;
+        self
+    }
+
+    pub fn instances(&self) -> &[Instance] {
+        &self.data.instances
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let bounds = self.data.bvh.bounds();
+        Aabb {
+            min: bounds.min + self.position,
+            max: bounds.max + self.position,
+        }
+    }
+
+    // Places the instances over the surface, replacing any there were
+    pub fn build(&mut self, surface: &Shape) -> Result<()> {
+        let prototype = prototype_bounds(&self.prototype)
+            .ok_or_else(|| format!("a {} can't be scattered", self.prototype.name()))?;
+        let sampler = Sampler::new(surface)?;
+        let density = self.density.as_deref().map(image::load).transpose()?;
+        if density.is_some() && !sampler.textured() {
+            return Err(format!("the {} has no texture coordinates for a density map", surface.name()).into());
+        }
+
+        // The spacing of `count` points spread evenly over the unit square
+        let spacing = 1.0 / (self.count.max(1) as f32).sqrt();
+        let jitter = self.jitter.clamp(0.0, 1.0);
+
+        let mut instances = Vec::with_capacity(self.count);
+        for attempt in 0..self.count * MAX_ATTEMPTS {
+            if instances.len() == self.count {
+                break;
+            }
+
+            let random = |n: u64| sampling::random(sampling::seed(attempt, self.seed as usize).wrapping_add(n));
+            let (u, v) = sampling::r2(attempt);
+            let u = (u + jitter * spacing * (random(0) - 0.5)).rem_euclid(1.0);
+            let v = (v + jitter * spacing * (random(1) - 0.5)).rem_euclid(1.0);
+
+            let (point, texcoord) = sampler.point(u, v);
+            if let (Some(map), Some(texcoord)) = (&density, texcoord) {
+                if random(2) >= density_at(map, texcoord) {
+                    continue;
+                }
+            }
+
+            instances.push(Instance {
+                offset: point,
+                scale: self.scale.x + (self.scale.y - self.scale.x) * random(3),
+                seed: sampling::seed(instances.len(), self.seed as usize),
+            });
+        }
+
+        self.data = Arc::new(ScatterData::new(instances, prototype));
+        Ok(())
+    }
+}
+
+impl Intersect for Scatter {
+    fn ray_intersect(&self, ray: &Ray) -> Option<Hit> {
+        let origin = ray.origin - self.position;
+        let local = Ray { origin, direction: ray.direction };
+
+        let mut nearest = None;
+        self.data.bvh.intersect(&local, f32::MAX, |index, max_distance| {
+            let instance = self.data.instances[index];
+            let instance_ray = Ray {
+                origin: (origin - instance.offset) * (1.0 / instance.scale),
+                direction: ray.direction,
+            };
+
+            let hit = self.prototype.ray_intersect(&instance_ray)?;
+            let distance = hit.distance * instance.scale;
+            if distance < max_distance {
+                let point = ray.origin + ray.direction * distance;
+                let material = if self.variation.is_none() {
+                    hit.material
+                } else {
+                    self.variation.apply(hit.material, instance.seed)
+                };
+                nearest = Some(Hit { distance, point, material, ..hit });
+                Some(distance)
+            } else {
+                None
+            }
+        })?;
+
+        nearest
+    }
+}
+
+// Anything bounded can be scattered, apart from other scatters
+fn prototype_bounds(shape: &Shape) -> Option<Aabb> {
+    match shape {
+        Shape::Sphere(sphere) => {
+            let radius = Vec3::new(sphere.radius, sphere.radius, sphere.radius);
+            Some(Aabb { min: sphere.centre - radius, max: sphere.centre + radius })
+        }
+        Shape::Triangle(triangle) => Some(Aabb::from_points(&triangle.vertices)),
+        Shape::Mesh(mesh) => Some(mesh.bounds()),
+        Shape::Points(cloud) => Some(cloud.bounds()),
+        Shape::Curves(curves) => Some(curves.bounds()),
+        Shape::Plane(_) | Shape::Scatter(_) => None,
+    }
+}
+
+// The brightness of the map at the texture coordinates, from 0 to 1
+fn density_at(map: &Image, texcoord: Vec2<f32>) -> f32 {
+    let x = ((texcoord.x.rem_euclid(1.0) * map.width as f32) as usize).min(map.width - 1);
+    let y = (((1.0 - texcoord.y).rem_euclid(1.0) * map.height as f32) as usize).min(map.height - 1);
+    luminance(map.texels[y * map.width + x]).clamp(0.0, 1.0)
+}
+
+// A triangle's corners, and their texture coordinates if it has them
+type Corners = ([Vec3<f32>; 3], Option<[Vec2<f32>; 3]>);
+
+// Maps the unit square evenly onto a shape's surface
+enum Sampler {
+    Plane { centre: Vec3<f32>, tangent: Vec3<f32>, bitangent: Vec3<f32> },
+    Sphere { centre: Vec3<f32>, radius: f32 },
+    Triangles { triangles: Vec<Corners>, areas: Vec<f32> },
+}
+
+impl Sampler {
+    fn new(surface: &Shape) -> Result<Self> {
+        let triangles = match surface {
+            Shape::Plane(plane) => {
+                let extent = plane.extent.ok_or("an unbounded plane can't be scattered over")?;
+                let (tangent, bitangent) = plane.tangents();
+                return Ok(Sampler::Plane {
+                    centre: plane.point,
+                    tangent: tangent * extent.x,
+                    bitangent: bitangent * extent.y,
+                });
+            }
+            Shape::Sphere(sphere) => {
+                return Ok(Sampler::Sphere { centre: sphere.centre, radius: sphere.radius });
+            }
+            Shape::Triangle(triangle) => vec![(triangle.vertices, None)],
+            Shape::Mesh(mesh) => (0..mesh.face_count())
+                .map(|face| (mesh.triangle(face).map(|v| v + mesh.position), mesh.triangle_texcoords(face)))
+                .collect(),
+            _ => return Err(format!("a {} can't be scattered over", surface.name()).into()),
+        };
+
+        // Running totals of the triangles' areas
+        let mut total = 0.0;
+        let areas: Vec<f32> = triangles
+            .iter()
+            .map(|([a, b, c], _)| {
+                total += 0.5 * cross(*b - *a, *c - *a).length();
+                total
+            })
+            .collect();
+        if total <= 0.0 {
+            return Err(format!("the {} has no area to scatter over", surface.name()).into());
+        }
+
+        Ok(Sampler::Triangles { triangles, areas })
+    }
+
+    fn textured(&self) -> bool {
+        match self {
+            Sampler::Triangles { triangles, .. } => triangles.iter().any(|(_, texcoords)| texcoords.is_some()),
+            _ => true,
+        }
+    }
+
+    // The point on the surface for a point in the unit square, and its
+    // texture coordinates if it has any
+    fn point(&self, u: f32, v: f32) -> (Vec3<f32>, Option<Vec2<f32>>) {
+        match self {
+            Sampler::Plane { centre, tangent, bitangent } => {
+                (*centre + *tangent * (2.0 * u - 1.0) + *bitangent * (2.0 * v - 1.0), Some(Vec2::new(u, v)))
+            }
+            Sampler::Sphere { centre, radius } => {
+                (*centre + sampling::sphere_direction(v, u) * *radius, Some(Vec2::new(u, 1.0 - v)))
+            }
+            Sampler::Triangles { triangles, areas } => {
+                // Picks a triangle in proportion to its area with u, reusing
+                // what's left of u within it so nearby points stay nearby
+                let total = areas[areas.len() - 1];
+                let target = u * total;
+                let index = areas.partition_point(|&area| area <= target).min(areas.len() - 1);
+                let start = if index == 0 { 0.0 } else { areas[index - 1] };
+                let s = ((target - start) / (areas[index] - start)).clamp(0.0, 1.0);
+
+                // Uniform barycentric coordinates from the square
+                let r = s.sqrt();
+                let weights = [1.0 - r, r * (1.0 - v), r * v];
+
+                let ([a, b, c], texcoords) = &triangles[index];
+                let point = *a * weights[0] + *b * weights[1] + *c * weights[2];
+                let texcoord = texcoords.map(|corners| {
+                    let blend = |f: fn(Vec2<f32>) -> f32| corners.iter().zip(&weights).map(|(&t, w)| f(t) * w).sum();
+                    Vec2::new(blend(|t| t.x), blend(|t| t.y))
+                });
+                (point, texcoord)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Plane, Sphere};
+    use crate::materials::Material;
+
+    fn meadow() -> (Shape, Scatter) {
+        let ground = Plane::new(Vec3::zero(), Vec3::new(0.0, 1.0, 0.0), Material::default())
+            .with_extent(Vec2::new(10.0, 5.0));
+        let pebble = Sphere::new(Vec3::new(0.0, 0.1, 0.0), 0.1, Material::default());
+        (ground.into(), Scatter::new(pebble.into(), 0, 500).with_seed(3).with_scale(0.5, 2.0))
+    }
+
+    #[test]
+    fn instances_cover_the_surface() {
+        let (ground, mut scatter) = meadow();
+        scatter.build(&ground).unwrap();
+
+        let instances = scatter.instances();
+        assert_eq!(instances.len(), 500);
+        assert!(instances.iter().all(|i| i.offset.x.abs() <= 10.0 && i.offset.z.abs() <= 5.0 && i.offset.y == 0.0));
+        assert!(instances.iter().all(|i| (0.5..=2.0).contains(&i.scale)));
+
+        // Each half of the plane gets about half
+        let left = instances.iter().filter(|i| i.offset.x < 0.0).count();
+        assert!((200..=300).contains(&left), "{} on the left", left);
+    }
+
+    #[test]
+    fn rays_hit_scaled_instances() {
+        let (ground, mut scatter) = meadow();
+        scatter.position = Vec3::new(0.0, 1.0, 0.0);
+        scatter.build(&ground).unwrap();
+
+        let instance = scatter.instances()[0];
+        let top = instance.offset + scatter.position + Vec3::new(0.0, 0.2 * instance.scale, 0.0);
+        let ray = Ray { origin: top + Vec3::new(0.0, 5.0, 0.0), direction: Vec3::new(0.0, -1.0, 0.0) };
+
+        let hit = scatter.ray_intersect(&ray).unwrap();
+        assert!((hit.point - top).length() < 1.0e-3, "{:?} != {:?}", hit.point, top);
+        assert!((hit.distance - 5.0).abs() < 1.0e-3);
+    }
+
+    #[test]
+    fn instances_vary_their_materials() {
+        let (ground, scatter) = meadow();
+        let variation = Variation { tints: vec![Vec3::new(1.0, 0.5, 0.5)], hue: 0.1, brightness: 0.3, roughness: 1.0 };
+        let mut scatter = scatter.with_variation(variation.clone());
+        scatter.build(&ground).unwrap();
+
+        let prototype = *scatter.prototype.material();
+        let materials: Vec<Material> = scatter.instances().iter().map(|i| variation.apply(prototype, i.seed)).collect();
+        let colours = |m: &Material| [m.diffuse_colour.x, m.diffuse_colour.y, m.diffuse_colour.z];
+        assert!(materials.iter().all(|m| colours(m).iter().all(|c| (0.0..=1.0).contains(c))));
+        assert!(materials.iter().all(|m| (0.5..=2.0).contains(&(m.specular_exponent / prototype.specular_exponent))));
+        assert!(materials.windows(2).any(|pair| pair[0].diffuse_colour != pair[1].diffuse_colour));
+
+        // The same instance always looks the same
+        let seed = scatter.instances()[7].seed;
+        assert_eq!(variation.apply(prototype, seed).diffuse_colour, variation.apply(prototype, seed).diffuse_colour);
+        assert!(Variation::default().is_none());
+    }
+}
@@ -0,0 +1,502 @@
+use crate::Result;
+use crate::animation::{Animation, Curve, Interpolation, Track};
+use crate::aov::Output;
+use crate::archive;
+use crate::collision::Collider;
+use crate::curves::Curves;
+use crate::environment::Environment;
+use crate::geometry::{Plane, Shape, Sphere, Vec2, Vec3};
+use crate::lights::Blocker;
+use crate::materials::{Material, Pattern};
+use crate::scatter::{Scatter, Variation};
+use crate::sky::Sky;
+use crate::triggers::{Action, Trigger};
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+
+// Restricts which lights and shapes are linked, by index: with `only` set
+// just those are linked, and anything in `except` never is
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Links {
+    #[serde(default)]
+    pub only: Option<BTreeSet<usize>>,
+    #[serde(default)]
+    pub except: BTreeSet<usize>,
+}
+
+impl Links {
+    pub fn allows(&self, index: usize) -> bool {
+        self.only.as_ref().is_none_or(|only| only.contains(&index)) && !self.except.contains(&index)
+    }
+
+    pub fn is_unrestricted(&self) -> bool {
+        self.only.is_none() && self.except.is_empty()
+    }
+}
+
+// Lights with negative intensity take light away instead of adding it, though
+// never so much that a surface ends up darker than black
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Light {
+    pub position: Vec3<f32>,
+    pub intensity: f32,
+    // The shapes this light illuminates
+    #[serde(default)]
+    pub shapes: Links,
+}
+
+impl Light {
+    pub fn new(position: Vec3<f32>, intensity: f32) -> Self {
+        Light {
+            position,
+            intensity,
+            shapes: Links::default(),
+        }
+    }
+}
+
+/// Everything that's rendered: shapes, lights and the environment around
+/// them, along with per-shape visibility. Scenes are loaded from and saved to
+/// RON or JSON files with [`load`] and [`save`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Scene {
+    pub shapes: Vec<Shape>,
+    pub lights: Vec<Light>,
+    #[serde(default)]
+    pub environment: Option<Environment>,
+    // A procedural sky and sun, seen where there's no environment map
+    #[serde(default)]
+    pub sky: Option<Sky>,
+    // Used in place of every material in the clay render mode
+    #[serde(default)]
+    pub override_material: Option<Material>,
+    // Indices of shapes left out of rendering entirely, shadows included
+    #[serde(default)]
+    pub hidden: BTreeSet<usize>,
+    // The lights illuminating particular shapes, keyed by shape index. Shapes
+    // are lit by a light only if both its links and theirs allow it.
+    #[serde(default)]
+    pub light_links: BTreeMap<usize, Links>,
+    // Volumes that take away direct light without casting shadows of their own
+    #[serde(default)]
+    pub blockers: Vec<Blocker>,
+    // Where reflection probes are baked, or in each reflective shape if empty
+    #[serde(default)]
+    pub probes: Vec<Vec3<f32>>,
+    // Images of parts of the light rendered alongside the main one
+    #[serde(default)]
+    pub outputs: Vec<Output>,
+    // Curves driving numbers in the scene and camera over time
+    #[serde(default)]
+    pub animation: Animation,
+    // Simpler shapes standing in for shapes in the simulation, keyed by shape
+    // index. Shapes without one pass through everything.
+    #[serde(default)]
+    pub colliders: BTreeMap<usize, Collider>,
+    // Regions that change the scene when shapes or the camera enter them
+    #[serde(default)]
+    pub triggers: Vec<Trigger>,
+}
+
+impl Scene {
+    pub fn default_scene() -> Self {
+        let ivory = Material::new(Vec2::new(0.6, 0.3), Vec3::new(0.4, 0.4, 0.3), 50.0);
+        let red_rubber = Material::new(Vec2::new(0.9, 0.1), Vec3::new(0.3, 0.1, 0.1), 10.0);
+        let mirror = Material::new(Vec2::new(0.0, 10.0), Vec3::new(1.0, 1.0, 1.0), 1425.0)
+            .with_reflectivity(0.8);
+
+        // The checkerboard floor from the reference tinyraytracer
+        let checkerboard = Material::new(Vec2::new(1.0, 0.0), Vec3::new(0.3, 0.3, 0.3), 1.0)
+            .with_pattern(Pattern::Checkerboard {
+                other_colour: Vec3::new(0.3, 0.2, 0.1),
+                size: 2.0,
+            });
+
+        Scene {
+            shapes: vec![
+                Sphere::new(Vec3::new(-3.0, 0.0, -16.0), 2.0, ivory)
+                    .with_velocity(Vec3::new(0.05, 0.0, 0.0))
+                    .into(),
+                Sphere::new(Vec3::new(-1.0, -1.5, -12.0), 2.0, red_rubber)
+                    .with_velocity(Vec3::new(0.0, 0.05, 0.0))
+                    .into(),
+                Sphere::new(Vec3::new(1.5, -0.5, -18.0), 3.0, red_rubber)
+                    .with_velocity(Vec3::new(0.0, 0.0, 0.05))
+                    .into(),
+                Sphere::new(Vec3::new(7.0, 5.0, -18.0), 4.0, mirror)
+                    .with_velocity(Vec3::new(0.0, 0.0, -0.05))
+                    .into(),
+                Plane::new(Vec3::new(0.0, -4.0, -20.0), Vec3::new(0.0, 1.0, 0.0), checkerboard)
+                    .with_extent(Vec2::new(10.0, 10.0))
+                    .into(),
+            ],
+            lights: vec![
+                Light::new(Vec3::new(-20.0, 20.0,  20.0), 1.5),
+                Light::new(Vec3::new( 30.0, 50.0, -25.0), 1.8),
+                Light::new(Vec3::new( 30.0, 20.0,  30.0), 1.7),
+            ],
+            environment: None,
+            sky: None,
+            override_material: None,
+            hidden: BTreeSet::new(),
+            light_links: BTreeMap::new(),
+            blockers: Vec::new(),
+            probes: Vec::new(),
+            outputs: Vec::new(),
+            animation: Animation::default(),
+            colliders: BTreeMap::new(),
+            triggers: Vec::new(),
+        }
+    }
+
+    // A furry ball sitting on the floor, for looking at hair
+    pub fn fur_ball_scene() -> Self {
+        let fur = Material::new(Vec2::new(0.5, 0.2), Vec3::new(0.35, 0.2, 0.1), 60.0);
+        let floor = Material::new(Vec2::new(1.0, 0.0), Vec3::new(0.3, 0.3, 0.3), 1.0);
+
+        Scene {
+            shapes: vec![
+                Curves::fur_ball(Vec3::new(0.0, -1.0, -12.0), 1.5, 20_000, 1.0, 0.03, fur).into(),
+                Plane::new(Vec3::new(0.0, -4.0, -12.0), Vec3::new(0.0, 1.0, 0.0), floor).into(),
+            ],
+            ..Scene::default_scene()
+        }
+    }
+
+    // A patch of grass seen from just above it
+    pub fn grass_scene() -> Self {
+        let grass = Material::new(Vec2::new(0.6, 0.1), Vec3::new(0.15, 0.35, 0.08), 30.0);
+        let soil = Material::new(Vec2::new(1.0, 0.0), Vec3::new(0.25, 0.17, 0.1), 1.0);
+
+        Scene {
+            shapes: vec![
+                Curves::grass(Vec3::new(0.0, -4.0, -14.0), 12.0, 40_000, 1.5, 0.06, grass).into(),
+                Plane::new(Vec3::new(0.0, -4.0, -14.0), Vec3::new(0.0, 1.0, 0.0), soil)
+                    .with_extent(Vec2::new(6.0, 6.0))
+                    .into(),
+            ],
+            ..Scene::default_scene()
+        }
+    }
+
+    // A field of bushes scattered over a wide plain, for stress tests
+    pub fn field_scene() -> Self {
+        let foliage = Material::new(Vec2::new(0.8, 0.05), Vec3::new(0.12, 0.3, 0.08), 20.0);
+        let soil = Material::new(Vec2::new(1.0, 0.0), Vec3::new(0.25, 0.17, 0.1), 1.0);
+
+        let ground: Shape = Plane::new(Vec3::new(0.0, -4.0, -40.0), Vec3::new(0.0, 1.0, 0.0), soil)
+            .with_extent(Vec2::new(40.0, 40.0))
+            .into();
+        let bush = Sphere::new(Vec3::new(0.0, 0.3, 0.0), 0.4, foliage);
+        let variation = Variation { hue: 0.04, brightness: 0.3, roughness: 1.0, ..Variation::default() };
+        let mut bushes = Scatter::new(bush.into(), 0, 20_000)
+            .with_seed(1)
+            .with_scale(0.5, 2.0)
+            .with_variation(variation);
+        bushes.build(&ground).expect("the plain can be scattered over");
+
+        Scene {
+            shapes: vec![ground, bushes.into()],
+            ..Scene::default_scene()
+        }
+    }
+
+    // The default scene with a switch: the ivory sphere rolling into it
+    // turns the floor red and fades the lights down and up again
+    pub fn switch_scene() -> Self {
+        let mut scene = Scene::default_scene();
+        let lit = Material::new(Vec2::new(0.9, 0.1), Vec3::new(0.6, 0.1, 0.1), 10.0);
+
+        scene.colliders.insert(0, Collider::Sphere { radius: 2.0 });
+        scene.triggers.push(
+            Trigger::new("switch", Vec3::new(4.0, 0.0, -16.0), Collider::Box { size: Vec3::new(2.0, 8.0, 8.0) }, Some(0))
+                .with_action(Action::Material { shape: 4, material: lit })
+                .firing_once(),
+        );
+
+        let dim = Curve::new(&[(0.0, 1.5), (1.0, 0.3), (3.0, 1.5)], Interpolation::Smooth);
+        scene.animation.curves.insert("dim".to_string(), dim);
+        scene.animation.tracks.push(Track {
+            path: "lights[0].intensity".to_string(),
+            curve: "dim".to_string(),
+            on: Some("switch".to_string()),
+        });
+
+        scene
+    }
+
+    // Fraction of light `light` reaching `point` past all the blockers
+    pub fn transmission(&self, light: usize, point: Vec3<f32>) -> f32 {
+        self.blockers
+            .iter()
+            .map(|blocker| blocker.transmission(light, point))
+            .product()
+    }
+
+    pub fn illuminates(&self, light: usize, shape: usize) -> bool {
+        self.lights[light].shapes.allows(shape)
+            && self.light_links.get(&shape).is_none_or(|links| links.allows(light))
+    }
+
+    pub fn has_light_links(&self) -> bool {
+        !self.light_links.values().all(Links::is_unrestricted)
+            || !self.lights.iter().all(|light| light.shapes.is_unrestricted())
+    }
+
+    pub fn is_visible(&self, shape: usize) -> bool {
+        !self.hidden.contains(&shape)
+    }
+
+    // Hides every shape but the given one, or shows them all again if it's
+    // already the only one visible
+    pub fn isolate(&mut self, shape: usize) {
+        let others: BTreeSet<usize> = (0..self.shapes.len()).filter(|&i| i != shape).collect();
+
+        if self.hidden == others {
+            self.hidden.clear();
+        } else {
+            self.hidden = others;
+        }
+    }
+
+    pub fn toggle_hidden(&mut self, shape: usize) {
+        if !self.hidden.remove(&shape) {
+            self.hidden.insert(shape);
+        }
+    }
+
+    // Hides shapes that can't be rendered and turns off lights that aren't
+    // finite, returning a warning for each. Shapes are hidden rather than
+    // removed so that indices into the list stay the same.
+    pub fn sanitise(&mut self) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        for (index, shape) in self.shapes.iter().enumerate() {
+            if let Some(problem) = shape.problem() {
+                if self.hidden.insert(index) {
+                    warnings.push(format!("hiding {} {}, which has {}", shape.name(), index, problem));
+                }
+            }
+        }
+
+        for (index, light) in self.lights.iter_mut().enumerate() {
+            if !(light.position.is_finite() && light.intensity.is_finite()) && light.intensity != 0.0 {
+                // Moved too, as with no power anywhere lights are still chosen
+                light.intensity = 0.0;
+                light.position = Vec3::zero();
+                warnings.push(format!("turning off light {}, which isn't finite", index));
+            }
+        }
+
+        warnings
+    }
+
+    /// Finds materials that are physically impossible, such as those that
+    /// reflect more light than they receive and make everything glow,
+    /// returning a warning for each. With `normalise`, they're also replaced
+    /// by the nearest possible material.
+    pub fn check_materials(&mut self, normalise: bool) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let mut check = |material: &mut Material, name: String| {
+            let problems = material.problems();
+            if problems.is_empty() {
+                return;
+            }
+
+            if normalise {
+                *material = material.normalised();
+                warnings.push(format!("normalised the material of {}, which had {}", name, problems.join(", ")));
+            } else {
+                warnings.push(format!("the material of {} has {}", name, problems.join(", ")));
+            }
+        };
+
+        for (index, shape) in self.shapes.iter_mut().enumerate() {
+            let name = format!("{} {}", shape.name(), index);
+            check(shape.material_mut(), name);
+        }
+        if let Some(material) = &mut self.override_material {
+            check(material, "override".to_string());
+        }
+
+        warnings
+    }
+
+    /// Places the instances of every scatter over its surface, which must be
+    /// another shape that isn't a scatter.
+    pub fn build_scatters(&mut self) -> Result<()> {
+        for index in 0..self.shapes.len() {
+            let surface = match &self.shapes[index] {
+                Shape::Scatter(scatter) => scatter.surface,
+                _ => continue,
+            };
+
+            let surface = match self.shapes.get(surface) {
+                Some(Shape::Scatter(_)) => return Err(format!("scatter {} is over another scatter", index).into()),
+                Some(shape) => shape.clone(),
+                None => return Err(format!("scatter {} is over shape {}, which doesn't exist", index, surface).into()),
+            };
+            if let Shape::Scatter(scatter) = &mut self.shapes[index] {
+                scatter.build(&surface).map_err(|e| format!("scatter {}: {}", index, e))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Switches every mesh to its level of detail for a camera at `eye`,
+    /// returning whether any changed
+    pub fn select_lods(&mut self, eye: Vec3<f32>) -> bool {
+        let mut changed = false;
+        for shape in &mut self.shapes {
+            if let Shape::Mesh(mesh) = shape {
+                changed |= mesh.select_lod(eye);
+            }
+        }
+        changed
+    }
+
+    pub fn clay_material(&self) -> Material {
+        self.override_material.unwrap_or_else(Material::clay)
+    }
+
+    // The colour seen by rays that leave the scene in the given direction
+    pub fn background(&self, direction: Vec3<f32>, default: Vec3<f32>) -> Vec3<f32> {
+        match (&self.environment, &self.sky) {
+            (Some(environment), _) => environment.sample(direction),
+            (None, Some(sky)) => sky.radiance(direction),
+            (None, None) => default,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    Ron,
+    Json,
+}
+
+// Scene files are RON unless they have a `.json` extension
+pub fn format_of(path: &Path) -> Format {
+    match path.extension().and_then(OsStr::to_str) {
+        Some("json") => Format::Json,
+        _ => Format::Ron,
+    }
+}
+
+// Scene archives (.trscene) are unpacked, and anything else read as a scene
+// file
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Scene> {
+    let path = path.as_ref();
+    if path.extension().and_then(OsStr::to_str) == Some(archive::EXTENSION) {
+        return archive::load(path);
+    }
+    parse(&fs::read_to_string(path)?, format_of(path))
+}
+
+// Parses a scene from its source. Any meshes, point clouds or environments it
+// refers to are loaded from their paths, and scatters are spread over their
+// surfaces.
+pub fn parse(source: &str, format: Format) -> Result<Scene> {
+    let mut scene: Scene = match format {
+        Format::Ron => ron::from_str(source)?,
+        Format::Json => serde_json::from_str(source)?,
+    };
+
+    scene.build_scatters()?;
+    Ok(scene)
+}
+
+// Saving as a .trscene archive bundles the files the scene refers to with it
+pub fn save<P: AsRef<Path>>(scene: &Scene, path: P) -> Result<()> {
+    let path = path.as_ref();
+    if path.extension().and_then(OsStr::to_str) == Some(archive::EXTENSION) {
+        return archive::save(scene, path);
+    }
+
+    let source = match format_of(path) {
+        Format::Ron => ron::ser::to_string_pretty(scene, ron::ser::PrettyConfig::default())?,
+        Format::Json => serde_json::to_string_pretty(scene)?,
+    };
+
+    fs::write(path, source)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isolate_and_restore() {
+        let mut scene = Scene::default_scene();
+        let count = scene.shapes.len();
+
+        scene.isolate(1);
+        assert!(scene.is_visible(1));
+        assert_eq!(scene.hidden.len(), count - 1);
+
+        scene.isolate(1);
+        assert!(scene.hidden.is_empty());
+
+        scene.toggle_hidden(2);
+        assert!(!scene.is_visible(2));
+        scene.toggle_hidden(2);
+        assert!(scene.is_visible(2));
+    }
+
+    #[test]
+    fn sanitise_hides_degenerate_shapes() {
+        let mut scene = Scene::default_scene();
+        let material = Material::default();
+        scene.shapes.push(Sphere::new(Vec3::zero(), 0.0, material).into());
+        scene.shapes.push(Sphere::new(Vec3::new(f32::NAN, 0.0, 0.0), 1.0, material).into());
+        scene.shapes.push(Plane::new(Vec3::zero(), Vec3::zero(), material).into());
+        scene.lights.push(Light::new(Vec3::new(0.0, f32::INFINITY, 0.0), 1.0));
+
+        let count = scene.shapes.len();
+        let warnings = scene.sanitise();
+        assert_eq!(warnings.len(), 4);
+        assert_eq!(scene.hidden, (count - 3..count).collect());
+        assert_eq!(scene.lights.last().unwrap().intensity, 0.0);
+
+        // Only once
+        assert!(scene.sanitise().is_empty());
+    }
+
+    #[test]
+    fn impossible_materials_are_found_and_normalised() {
+        let mut scene = Scene::default_scene();
+        assert!(scene.check_materials(false).is_empty());
+
+        let glowing = Material::new(Vec2::new(1.0, 1.0), Vec3::new(1.0, 1.0, 1.0), 1.0);
+        scene.shapes.push(Sphere::new(Vec3::zero(), 1.0, glowing).into());
+        assert_eq!(scene.check_materials(false).len(), 1);
+        assert_eq!(scene.check_materials(true).len(), 1);
+        assert!(scene.check_materials(false).is_empty());
+    }
+
+    #[test]
+    fn light_links_from_both_sides() {
+        let mut scene = Scene::default_scene();
+        assert!(!scene.has_light_links());
+
+        scene.lights[0].shapes.except.insert(1);
+        scene.light_links.insert(2, Links {
+            only: Some([1].iter().copied().collect()),
+            except: BTreeSet::new(),
+        });
+        assert!(scene.has_light_links());
+
+        assert!(!scene.illuminates(0, 1));
+        assert!(scene.illuminates(1, 1));
+        assert!(!scene.illuminates(0, 2));
+        assert!(scene.illuminates(1, 2));
+        assert!(!scene.illuminates(2, 2));
+    }
+}
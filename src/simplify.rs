@@ -0,0 +1,295 @@
+use crate::geometry::{Vec3, cross, dot};
+use crate::mesh::Obj;
+
+use serde::{Deserialize, Serialize};
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// How much worse than moving off a surface it is to move off the edge of an
+// open mesh, which keeps holes and the borders of scans from shrinking
+const BOUNDARY_WEIGHT: f64 = 1000.0;
+
+// How far to simplify a mesh: down to a number of triangles, until the next
+// edge to collapse would move the surface further than `error` (in scene
+// units), or whichever comes first if both are given
+//
+//     (triangles: Some(20000), error: Some(0.01))
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Target {
+    #[serde(default)]
+    pub triangles: Option<usize>,
+    #[serde(default)]
+    pub error: Option<f32>,
+}
+
+// The sum of the squared distances to a set of planes, as a symmetric 4x4
+// matrix stored as its upper triangle
+#[derive(Copy, Clone, Debug, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    fn plane(normal: Vec3<f32>, point: Vec3<f32>, weight: f64) -> Self {
+        let (a, b, c) = (normal.x as f64, normal.y as f64, normal.z as f64);
+        let d = -(a * point.x as f64 + b * point.y as f64 + c * point.z as f64);
+        let q = [a * a, a * b, a * c, a * d, b * b, b * c, b * d, c * c, c * d, d * d];
+        Quadric(q.map(|v| v * weight))
+    }
+
+    fn add(self, other: Quadric) -> Self {
+        let mut q = self.0;
+        for (a, b) in q.iter_mut().zip(&other.0) {
+            *a += b;
+        }
+        Quadric(q)
+    }
+
+    fn error(&self, v: Vec3<f32>) -> f64 {
+        let q = &self.0;
+        let (x, y, z) = (v.x as f64, v.y as f64, v.z as f64);
+        let error = q[0] * x * x + 2.0 * q[1] * x * y + 2.0 * q[2] * x * z + 2.0 * q[3] * x
+            + q[4] * y * y + 2.0 * q[5] * y * z + 2.0 * q[6] * y
+            + q[7] * z * z + 2.0 * q[8] * z
+            + q[9];
+        error.max(0.0)
+    }
+
+    // The point with the least error, if there's just one
+    fn minimum(&self) -> Option<Vec3<f32>> {
+        let q = &self.0;
+        let det3 = |m: [[f64; 3]; 3]| {
+            m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1]) - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+                + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+        };
+
+        let a = [[q[0], q[1], q[2]], [q[1], q[4], q[5]], [q[2], q[5], q[7]]];
+        let b = [-q[3], -q[6], -q[8]];
+        let det = det3(a);
+        let scale = q[0].abs().max(q[4].abs()).max(q[7].abs());
+        if det.abs() <= 1.0e-9 * scale * scale * scale || scale == 0.0 {
+            return None;
+        }
+
+        // Cramer's rule
+        let solve = |column: usize| {
+            let mut m = a;
+            for (row, &value) in b.iter().enumerate() {
+                m[row][column] = value;
+            }
+            (det3(m) / det) as f32
+        };
+        let point = Vec3::new(solve(0), solve(1), solve(2));
+        if point.is_finite() { Some(point) } else { None }
+    }
+}
+
+// Simplifies a mesh by collapsing its edges one at a time, cheapest first,
+// where the cost of moving a vertex is measured by quadric error: the sum of
+// its squared distances from the planes of the triangles that met there
+// originally. Collapses that would flip a triangle over are skipped. Corners
+// keep their texture coordinates, which stretch a little as vertices move.
+pub fn decimate(obj: &Obj, target: Target) -> Obj {
+    let min_faces = target.triangles.unwrap_or(0);
+    let max_error = target.error.map(|e| (e as f64) * (e as f64)).unwrap_or(f64::INFINITY);
+    if obj.faces.len() <= min_faces || (target.triangles.is_none() && target.error.is_none()) {
+        return obj.clone();
+    }
+
+    let mut positions = obj.vertices.clone();
+    let mut faces = obj.faces.clone();
+    let mut live = vec![true; faces.len()];
+    let mut live_count = faces.len();
+    let mut removed = vec![false; positions.len()];
+    let mut stamps = vec![0u32; positions.len()];
+
+    let mut quadrics = vec![Quadric::default(); positions.len()];
+    let mut vertex_faces = vec![Vec::new(); positions.len()];
+    let mut edges = Vec::with_capacity(faces.len() * 3);
+
+    for (index, &face) in faces.iter().enumerate() {
+        let [a, b, c] = face.map(|v| positions[v]);
+        let normal = cross(b - a, c - a);
+        if normal.length() > 0.0 {
+            let quadric = Quadric::plane(normal.normalise(), a, 1.0);
+            for &v in &face {
+                quadrics[v] = quadrics[v].add(quadric);
+            }
+        }
+
+        for corner in 0..3 {
+            let (u, v) = (face[corner], face[(corner + 1) % 3]);
+            edges.push((u.min(v), u.max(v), index));
+            vertex_faces[face[corner]].push(index);
+        }
+    }
+
+    // Edges with only one triangle are on a boundary, held in place by a
+    // plane through them at right angles to their triangle
+    edges.sort_unstable();
+    for (k, &(u, v, face)) in edges.iter().enumerate() {
+        let shared = |other: Option<&(usize, usize, usize)>| other.is_some_and(|&(a, b, _)| (a, b) == (u, v));
+        if shared(edges.get(k + 1)) || shared(k.checked_sub(1).and_then(|k| edges.get(k))) {
+            continue;
+        }
+
+        let [a, b, c] = faces[face].map(|v| positions[v]);
+        let normal = cross(positions[v] - positions[u], cross(b - a, c - a));
+        if normal.length() > 0.0 {
+            let quadric = Quadric::plane(normal.normalise(), positions[u], BOUNDARY_WEIGHT);
+            quadrics[u] = quadrics[u].add(quadric);
+            quadrics[v] = quadrics[v].add(quadric);
+        }
+    }
+    edges.dedup_by_key(|&mut (u, v, _)| (u, v));
+
+    // Where an edge's vertices would go, and the error of putting them there
+    let collapse = |quadrics: &[Quadric], positions: &[Vec3<f32>], u: usize, v: usize| {
+        let quadric = quadrics[u].add(quadrics[v]);
+        let candidates = [positions[u], positions[v], (positions[u] + positions[v]) * 0.5];
+        quadric
+            .minimum()
+            .into_iter()
+            .chain(candidates)
+            .map(|p| (quadric.error(p), p))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .expect("there are always candidates")
+    };
+
+    // Queued collapses go stale when either vertex moves, which is caught by
+    // stamping each with how many times its vertices have moved
+    let mut queue = BinaryHeap::new();
+    for &(u, v, _) in &edges {
+        let (error, _) = collapse(&quadrics, &positions, u, v);
+        queue.push(Reverse((error.to_bits(), u, v, 0u32, 0u32)));
+    }
+
+    while live_count > min_faces {
+        let Some(Reverse((bits, u, v, stamp_u, stamp_v))) = queue.pop() else { break };
+        if removed[u] || removed[v] || stamps[u] != stamp_u || stamps[v] != stamp_v {
+            continue;
+        }
+        if f64::from_bits(bits) > max_error {
+            break;
+        }
+
+        let (_, point) = collapse(&quadrics, &positions, u, v);
+        let flips = [u, v].iter().flat_map(|&w| &vertex_faces[w]).any(|&f| {
+            let face = faces[f];
+            if !live[f] || (face.contains(&u) && face.contains(&v)) {
+                return false;
+            }
+            let [a, b, c] = face.map(|w| positions[w]);
+            let [d, e, g] = face.map(|w| if w == u || w == v { point } else { positions[w] });
+            dot(cross(b - a, c - a), cross(e - d, g - d)) <= 0.0
+        });
+        if flips {
+            continue;
+        }
+
+        // Move u to the new point and hand it v's triangles, dropping those
+        // that had both
+        for f in std::mem::take(&mut vertex_faces[v]) {
+            if !live[f] {
+                continue;
+            }
+            if faces[f].contains(&u) {
+                live[f] = false;
+                live_count -= 1;
+            } else {
+                for w in &mut faces[f] {
+                    if *w == v {
+                        *w = u;
+                    }
+                }
+                vertex_faces[u].push(f);
+            }
+        }
+        vertex_faces[u].retain(|&f| live[f]);
+
+        positions[u] = point;
+        quadrics[u] = quadrics[u].add(quadrics[v]);
+        removed[v] = true;
+        stamps[u] += 1;
+
+        let mut neighbours: Vec<usize> = vertex_faces[u].iter().flat_map(|&f| faces[f]).filter(|&w| w != u).collect();
+        neighbours.sort_unstable();
+        neighbours.dedup();
+        for w in neighbours {
+            let (error, _) = collapse(&quadrics, &positions, u, w);
+            queue.push(Reverse((error.to_bits(), u.min(w), u.max(w), stamps[u.min(w)], stamps[u.max(w)])));
+        }
+    }
+
+    // Keep only the vertices still in use, in their original order
+    let mut remap = vec![usize::MAX; positions.len()];
+    let mut simplified = Obj { texcoords: obj.texcoords.clone(), ..Obj::default() };
+    for (f, face) in faces.iter().enumerate().filter(|&(f, _)| live[f]) {
+        for &w in face {
+            if remap[w] == usize::MAX {
+                remap[w] = simplified.vertices.len();
+                simplified.vertices.push(positions[w]);
+            }
+        }
+        simplified.faces.push(face.map(|w| remap[w]));
+        simplified.texcoord_faces.push(obj.texcoord_faces[f]);
+    }
+
+    simplified
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A flat grid of n by n squares, each split into two triangles
+    fn grid(n: usize) -> Obj {
+        let mut obj = Obj::default();
+        for j in 0..=n {
+            for i in 0..=n {
+                obj.vertices.push(Vec3::new(i as f32, j as f32, 0.0));
+            }
+        }
+        for j in 0..n {
+            for i in 0..n {
+                let corner = j * (n + 1) + i;
+                obj.faces.push([corner, corner + 1, corner + n + 2]);
+                obj.faces.push([corner, corner + n + 2, corner + n + 1]);
+            }
+        }
+        obj.texcoord_faces = vec![None; obj.faces.len()];
+        obj
+    }
+
+    #[test]
+    fn flat_grid_simplifies_without_losing_its_shape() {
+        let obj = grid(10);
+        let simplified = decimate(&obj, Target { triangles: Some(20), error: None });
+        assert!(simplified.faces.len() <= 20 && !simplified.faces.is_empty());
+
+        // Still flat and still covering the square, with every triangle the
+        // same way up
+        assert!(simplified.vertices.iter().all(|v| v.z.abs() < 1.0e-4));
+        let area: f32 = simplified
+            .faces
+            .iter()
+            .map(|face| {
+                let [a, b, c] = face.map(|v| simplified.vertices[v]);
+                let normal = cross(b - a, c - a);
+                assert!(normal.z > 0.0);
+                normal.length() * 0.5
+            })
+            .sum();
+        assert!((area - 100.0).abs() < 1.0e-2, "area {}", area);
+    }
+
+    #[test]
+    fn error_bound_stops_collapses_that_change_the_shape() {
+        let mut obj = grid(6);
+        // A bump in the middle, which can't be flattened within the bound
+        obj.vertices[3 * 7 + 3].z = 1.0;
+
+        let simplified = decimate(&obj, Target { triangles: None, error: Some(0.01) });
+        assert!(simplified.faces.len() < obj.faces.len());
+        assert!(simplified.vertices.iter().any(|v| (v.z - 1.0).abs() < 1.0e-4));
+    }
+}
@@ -0,0 +1,168 @@
+use crate::collision;
+use crate::geometry::{Vec3, dot};
+use crate::scene::Scene;
+
+use std::fmt;
+
+// The update rate shapes' velocities are given at: a velocity is how far the
+// shape moves in one update at this rate, whatever the actual rate
+pub const REFERENCE_UPDATES_PER_SECOND: u32 = 60;
+
+pub const MIN_UPDATES_PER_SECOND: u32 = 10;
+pub const MAX_UPDATES_PER_SECOND: u32 = 960;
+pub const MAX_SUBSTEPS: u32 = 64;
+
+// How often the scene is updated while running, and how many smaller steps
+// each update is split into. Updates are what the views see, so they set how
+// smooth motion looks, while substeps only make the motion more accurate:
+// stiff motion can be given more substeps without updating (and restarting
+// the views' accumulation) more often.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Simulation {
+    pub updates_per_second: u32,
+    pub substeps: u32,
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Simulation {
+            updates_per_second: REFERENCE_UPDATES_PER_SECOND,
+            substeps: 1,
+        }
+    }
+}
+
+impl fmt::Display for Simulation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let plural = if self.substeps == 1 { "" } else { "s" };
+        write!(f, "{} updates per second, {} substep{}", self.updates_per_second, self.substeps, plural)
+    }
+}
+
+impl Simulation {
+    // Keeps the rate and substeps within their limits
+    pub fn clamped(self) -> Self {
+        Simulation {
+            updates_per_second: self.updates_per_second.clamp(MIN_UPDATES_PER_SECOND, MAX_UPDATES_PER_SECOND),
+            substeps: self.substeps.clamp(1, MAX_SUBSTEPS),
+        }
+    }
+
+    pub fn seconds_per_update(&self) -> f64 {
+        1.0 / self.updates_per_second.max(1) as f64
+    }
+
+    // How many updates at the reference rate each update is worth, which is
+    // how far shapes move in it in units of their velocity
+    pub fn ticks_per_update(&self) -> f32 {
+        REFERENCE_UPDATES_PER_SECOND as f32 / self.updates_per_second.max(1) as f32
+    }
+
+    // Advances the scene by one update, returning whether anything moved. A
+    // shape whose step would take its collider into another's is held back
+    // and bounced off it instead, which is caught sooner with more substeps.
+    // Shapes already overlapping are left to move apart.
+    pub fn update(&self, scene: &mut Scene) -> bool {
+        let substeps = self.substeps.max(1);
+        let step = self.ticks_per_update() / substeps as f32;
+        let mut moved = false;
+
+        for _ in 0..substeps {
+            for index in 0..scene.shapes.len() {
+                let velocity = scene.shapes[index].velocity();
+                if velocity == Vec3::zero() {
+                    continue;
+                }
+
+                scene.shapes[index].translate(velocity * step);
+                moved = true;
+
+                let normal = collision::collision(scene, index).filter(|&normal| dot(velocity, normal) < 0.0);
+                if let Some(normal) = normal {
+                    let shape = &mut scene.shapes[index];
+                    shape.translate(velocity * -step);
+                    shape.set_velocity(collision::bounce(velocity, normal));
+                }
+            }
+        }
+
+        moved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collision::Collider;
+
+    #[test]
+    fn motion_is_the_same_at_any_rate() {
+        let mut reference = Scene::default_scene();
+        for _ in 0..REFERENCE_UPDATES_PER_SECOND {
+            Simulation::default().update(&mut reference);
+        }
+
+        let mut scene = Scene::default_scene();
+        let fast = Simulation { updates_per_second: 240, substeps: 4 };
+        for _ in 0..240 {
+            assert!(fast.update(&mut scene));
+        }
+
+        for (a, b) in reference.shapes.iter().zip(&scene.shapes) {
+            assert!((a.centre() - b.centre()).length() < 1.0e-3);
+        }
+        assert_eq!(Simulation { updates_per_second: 1, substeps: 0 }.clamped().substeps, 1);
+    }
+
+    #[test]
+    fn spheres_bounce_off_colliders() {
+        // A sphere falling onto a mesh-sized box standing in for the floor
+        let mut scene = Scene::default_scene();
+        scene.shapes.truncate(2);
+        scene.shapes[1].set_velocity(Vec3::zero());
+        scene.shapes[0].set_velocity(Vec3::new(0.0, -0.5, 0.0));
+        let start = scene.shapes[0].centre();
+        let floor = scene.shapes[1].centre();
+
+        scene.colliders.insert(0, Collider::Sphere { radius: 2.0 });
+        scene.colliders.insert(1, Collider::Box { size: Vec3::new(100.0, 2.0, 100.0) });
+        scene.shapes[1].translate(Vec3::new(start.x, start.y - 10.0, start.z) - floor);
+
+        let simulation = Simulation { updates_per_second: 60, substeps: 4 };
+        let mut lowest = start.y;
+        for _ in 0..60 {
+            simulation.update(&mut scene);
+            lowest = lowest.min(scene.shapes[0].centre().y);
+        }
+
+        // It never passes into the box, whose top is 9 below where it started
+        assert!(lowest >= start.y - 7.0 - 1.0e-3 && lowest < start.y - 6.0, "lowest {}", lowest);
+        assert!(scene.shapes[0].velocity().y > 0.0);
+        assert!(scene.shapes[0].centre().y > lowest);
+    }
+
+    #[test]
+    fn overlapping_shapes_can_separate() {
+        let mut scene = Scene::default_scene();
+        scene.shapes.truncate(2);
+        scene.shapes[1].set_velocity(Vec3::zero());
+        scene.shapes[0].set_velocity(Vec3::new(0.5, 0.0, 0.0));
+        let other = scene.shapes[1].centre();
+        let start = scene.shapes[0].centre();
+        scene.shapes[0].translate(other + Vec3::new(1.0, 0.0, 0.0) - start);
+
+        scene.colliders.insert(0, Collider::Sphere { radius: 2.0 });
+        scene.colliders.insert(1, Collider::Sphere { radius: 2.0 });
+        assert!(collision::collision(&scene, 0).is_some());
+
+        let simulation = Simulation::default();
+        for _ in 0..10 {
+            simulation.update(&mut scene);
+        }
+
+        // It carries on moving away until it's clear, rather than being held
+        assert_eq!(scene.shapes[0].velocity(), Vec3::new(0.5, 0.0, 0.0));
+        assert!((scene.shapes[0].centre().x - (other.x + 6.0)).abs() < 1.0e-3);
+        assert!(collision::collision(&scene, 0).is_none());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::environment::Environment;
+use crate::geometry::{Vec3, basis, dot};
+use crate::image::Image;
+
+use serde::{Deserialize, Serialize};
+
+use std::f32::consts::PI;
+
+// Size of the latlong image the sky dome is baked into for lighting
+const DOME_HEIGHT: usize = 64;
+
+// The sky's colour straight up and at the horizon, each with a luminance of
+// one, and the ground's seen from above, blended by elevation
+const ZENITH_COLOUR: Vec3<f32> = Vec3 { x: 0.45, y: 0.95, z: 2.6 };
+const HORIZON_COLOUR: Vec3<f32> = Vec3 { x: 0.9, y: 1.0, z: 1.15 };
+const GROUND_COLOUR: Vec3<f32> = Vec3 { x: 0.35, y: 0.3, z: 0.25 };
+
+fn default_sun_elevation() -> f32 {
+    45.0
+}
+
+fn default_sun_intensity() -> f32 {
+    2.0
+}
+
+fn default_sun_angular_diameter() -> f32 {
+    0.53
+}
+
+fn default_sky_intensity() -> f32 {
+    0.4
+}
+
+// A procedural daylight sky in place of an environment map: a dome fading
+// from blue overhead to pale at the horizon, over a dark ground, and a sun
+// with the angular size of the real one. Angles are in degrees, with the
+// azimuth measured from -z towards +x:
+//
+//     sky: Some((sun_elevation: 30.0, sun_azimuth: 120.0))
+//
+// The sun lights surfaces facing it as much as a light of `sun_intensity`,
+// and the dome lights surfaces facing up as much as a light of
+// `sky_intensity`. The sun is sampled as a disk, so its shadows have the
+// same soft edges as a real sun's.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Sky {
+    #[serde(default = "default_sun_elevation")]
+    pub sun_elevation: f32,
+    #[serde(default)]
+    pub sun_azimuth: f32,
+    #[serde(default = "default_sun_intensity")]
+    pub sun_intensity: f32,
+    #[serde(default = "default_sun_angular_diameter")]
+    pub sun_angular_diameter: f32,
+    #[serde(default = "default_sky_intensity")]
+    pub sky_intensity: f32,
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        Sky {
+            sun_elevation: default_sun_elevation(),
+            sun_azimuth: 0.0,
+            sun_intensity: default_sun_intensity(),
+            sun_angular_diameter: default_sun_angular_diameter(),
+            sky_intensity: default_sky_intensity(),
+        }
+    }
+}
+
+impl Sky {
+    // The unit direction towards the centre of the sun
+    pub fn sun_direction(&self) -> Vec3<f32> {
+        let (elevation, azimuth) = (self.sun_elevation.to_radians(), self.sun_azimuth.to_radians());
+        Vec3::new(elevation.cos() * azimuth.sin(), elevation.sin(), -elevation.cos() * azimuth.cos())
+    }
+
+    // Cosine of the angle from the sun's centre to its edge
+    fn sun_cos_radius(&self) -> f32 {
+        (self.sun_angular_diameter.max(0.0) * 0.5).to_radians().cos()
+    }
+
+    // The solid angle the sun covers
+    pub fn sun_solid_angle(&self) -> f32 {
+        2.0 * PI * (1.0 - self.sun_cos_radius())
+    }
+
+    // The sun's radiance, such that over its whole disk it gives a surface
+    // facing it `sun_intensity` times pi of irradiance, which is what a
+    // light of that intensity gives
+    pub fn sun_radiance(&self) -> f32 {
+        match self.sun_solid_angle() {
+            solid_angle if solid_angle > 0.0 => self.sun_intensity * PI / solid_angle,
+            _ => 0.0,
+        }
+    }
+
+    // A direction towards a point on the sun's disk, all equally likely, so
+    // the density per unit solid angle is one over `sun_solid_angle`
+    pub fn sample_sun(&self, u1: f32, u2: f32) -> Vec3<f32> {
+        let axis = self.sun_direction();
+        let (tangent, bitangent) = basis(axis);
+
+        let cosine = 1.0 - u1 * (1.0 - self.sun_cos_radius());
+        let (sine, phi) = ((1.0 - cosine * cosine).max(0.0).sqrt(), 2.0 * PI * u2);
+        (tangent * (sine * phi.cos()) + bitangent * (sine * phi.sin()) + axis * cosine).normalise()
+    }
+
+    // The light arriving from the dome in the given (unit) direction, without
+    // the sun. A uniform dome of this brightness would give a surface facing
+    // up `sky_intensity` times pi of irradiance, like the sun.
+    pub fn dome(&self, direction: Vec3<f32>) -> Vec3<f32> {
+        if direction.y < 0.0 {
+            return GROUND_COLOUR * self.sky_intensity;
+        }
+
+        // Mostly zenith coloured until low down
+        let t = (1.0 - direction.y).powi(4);
+        (ZENITH_COLOUR * (1.0 - t) + HORIZON_COLOUR * t) * self.sky_intensity
+    }
+
+    // Everything seen in the given (unit) direction, the sun included
+    pub fn radiance(&self, direction: Vec3<f32>) -> Vec3<f32> {
+        let dome = self.dome(direction);
+        if dot(direction, self.sun_direction()) >= self.sun_cos_radius() {
+            let sun = self.sun_radiance();
+            dome + Vec3::new(sun, sun, sun)
+        } else {
+            dome
+        }
+    }
+
+    // The dome baked into an environment map, so surfaces can be lit by it
+    // with importance sampling. The sun is too small to be caught by the
+    // map's texels, so it's left out and sampled separately.
+    pub fn dome_environment(&self) -> Environment {
+        let (width, height) = (2 * DOME_HEIGHT, DOME_HEIGHT);
+        let mut texels = Vec::with_capacity(width * height);
+
+        for y in 0..height {
+            let polar = PI * (y as f32 + 0.5) / height as f32;
+            for x in 0..width {
+                let azimuth = 2.0 * PI * ((x as f32 + 0.5) / width as f32 - 0.5);
+                let direction = Vec3::new(polar.sin() * azimuth.sin(), polar.cos(), -polar.sin() * azimuth.cos());
+                texels.push(self.dome(direction));
+            }
+        }
+
+        Environment::new(Image { width, height, texels })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sun_disk_gives_its_intensity_head_on() {
+        let sky = Sky { sun_elevation: 60.0, sun_azimuth: 30.0, ..Sky::default() };
+        let axis = sky.sun_direction();
+
+        // Irradiance over pi, integrated by sampling the disk
+        let count = 1000;
+        let mut irradiance = 0.0;
+        for k in 0..count {
+            let direction = sky.sample_sun((k as f32 + 0.5) / count as f32, (k * 37 % count) as f32 / count as f32);
+            assert!(dot(direction, axis) >= sky.sun_cos_radius() - 1.0e-6);
+            irradiance += sky.sun_radiance() * dot(direction, axis) * sky.sun_solid_angle() / count as f32;
+        }
+
+        assert!((irradiance / PI - sky.sun_intensity).abs() < 1.0e-3);
+        assert!(sky.radiance(axis).x > sky.sun_radiance());
+    }
+}
@@ -0,0 +1,192 @@
+use crate::curves::Curves;
+use crate::geometry::{Plane, Shape, Sphere, Vec2, Vec3, dot, triangle_normal};
+use crate::mesh::Mesh;
+
+use std::f32::consts::PI;
+
+// Divisions around and from pole to pole of a tessellated sphere
+const SPHERE_SEGMENTS: usize = 48;
+const SPHERE_RINGS: usize = 24;
+
+// Straight segments each curve is cut into
+const CURVE_SEGMENTS: usize = 8;
+
+// Half the size of the square an unbounded plane is cut down to
+const PLANE_HALF_SIZE: f32 = 100.0;
+
+// A shape's surface as triangles in world space, for exporting to formats
+// that can't describe spheres or infinite planes. Corners are listed
+// anticlockwise seen from the side the normals face, and `texcoords` is
+// either empty or has one entry per position.
+#[derive(Debug, Default)]
+pub struct Surface {
+    pub positions: Vec<Vec3<f32>>,
+    pub normals: Vec<Vec3<f32>>,
+    pub texcoords: Vec<Vec2<f32>>,
+    pub triangles: Vec<[u32; 3]>,
+}
+
+// The surface of a sphere, plane, triangle or mesh, or of every instance of a
+// scatter of them. Point clouds and curves have none.
+pub fn surface(shape: &Shape) -> Option<Surface> {
+    match shape {
+        Shape::Sphere(sphere) => Some(sphere_surface(sphere)),
+        Shape::Plane(plane) => Some(plane_surface(plane)),
+        Shape::Triangle(triangle) => {
+            let normal = triangle.normal();
+            Some(Surface {
+                positions: triangle.vertices.to_vec(),
+                normals: vec![normal; 3],
+                texcoords: Vec::new(),
+                triangles: vec![[0, 1, 2]],
+            })
+        }
+        Shape::Mesh(mesh) => Some(mesh_surface(mesh)),
+        Shape::Points(_) | Shape::Curves(_) => None,
+        Shape::Scatter(scatter) => {
+            let prototype = surface(&scatter.prototype)?;
+            let mut surface = Surface::default();
+
+            for instance in scatter.instances() {
+                let first = surface.positions.len() as u32;
+                let offset = instance.offset + scatter.position;
+                surface.positions.extend(prototype.positions.iter().map(|&p| p * instance.scale + offset));
+                surface.normals.extend_from_slice(&prototype.normals);
+                surface.texcoords.extend_from_slice(&prototype.texcoords);
+                surface.triangles.extend(prototype.triangles.iter().map(|t| t.map(|corner| corner + first)));
+            }
+            Some(surface)
+        }
+    }
+}
+
+// Each curve as a polyline of points in world space, for formats that can
+// only draw strands as lines
+pub fn polylines(curves: &Curves) -> Vec<Vec<Vec3<f32>>> {
+    curves
+        .curves()
+        .iter()
+        .map(|curve| {
+            (0..=CURVE_SEGMENTS)
+                .map(|k| curve.point(k as f32 / CURVE_SEGMENTS as f32) + curves.position)
+                .collect()
+        })
+        .collect()
+}
+
+// A latitude and longitude grid, with texture coordinates running once
+// around the equator and up from the bottom pole
+fn sphere_surface(sphere: &Sphere) -> Surface {
+    let mut surface = Surface::default();
+
+    for ring in 0..=SPHERE_RINGS {
+        let polar = PI * ring as f32 / SPHERE_RINGS as f32;
+
+        for segment in 0..=SPHERE_SEGMENTS {
+            let azimuth = 2.0 * PI * segment as f32 / SPHERE_SEGMENTS as f32;
+            let normal = Vec3::new(polar.sin() * azimuth.sin(), polar.cos(), polar.sin() * azimuth.cos());
+
+            surface.positions.push(sphere.centre + normal * sphere.radius);
+            surface.normals.push(normal);
+            surface.texcoords.push(Vec2::new(
+                segment as f32 / SPHERE_SEGMENTS as f32,
+                1.0 - ring as f32 / SPHERE_RINGS as f32,
+            ));
+        }
+    }
+
+    let row = (SPHERE_SEGMENTS + 1) as u32;
+    for ring in 0..SPHERE_RINGS as u32 {
+        for segment in 0..SPHERE_SEGMENTS as u32 {
+            let (a, b) = (ring * row + segment, (ring + 1) * row + segment);
+
+            // The triangles touching the poles would have no area
+            if ring > 0 {
+                surface.triangles.push([a, b, a + 1]);
+            }
+            if ring + 1 < SPHERE_RINGS as u32 {
+                surface.triangles.push([a + 1, b, b + 1]);
+            }
+        }
+    }
+
+    surface
+}
+
+fn plane_surface(plane: &Plane) -> Surface {
+    let (tangent, bitangent) = plane.tangents();
+    let normal = plane.normal.normalise();
+    let extent = plane.extent.unwrap_or(Vec2::new(PLANE_HALF_SIZE, PLANE_HALF_SIZE));
+
+    let corner = |u: f32, v: f32| plane.point + tangent * (u * extent.x) + bitangent * (v * extent.y);
+    let positions = vec![corner(-1.0, -1.0), corner(1.0, -1.0), corner(1.0, 1.0), corner(-1.0, 1.0)];
+
+    // The tangents may make either winding face the normal
+    let triangles = if dot(triangle_normal([positions[0], positions[1], positions[2]]), normal) > 0.0 {
+        vec![[0, 1, 2], [0, 2, 3]]
+    } else {
+        vec![[0, 2, 1], [0, 3, 2]]
+    };
+
+    Surface {
+        positions,
+        normals: vec![normal; 4],
+        texcoords: vec![Vec2::new(0.0, 0.0), Vec2::new(1.0, 0.0), Vec2::new(1.0, 1.0), Vec2::new(0.0, 1.0)],
+        triangles,
+    }
+}
+
+// Every face gets its own corners, so each can keep its own normal and
+// texture coordinates
+fn mesh_surface(mesh: &Mesh) -> Surface {
+    let mut surface = Surface::default();
+    let textured = (0..mesh.face_count()).any(|face| mesh.triangle_texcoords(face).is_some());
+
+    for face in 0..mesh.face_count() {
+        let corners = mesh.triangle(face).map(|corner| corner + mesh.position);
+        let first = surface.positions.len() as u32;
+
+        surface.positions.extend_from_slice(&corners);
+        surface.normals.extend_from_slice(&[triangle_normal(corners); 3]);
+        if textured {
+            let texcoords = mesh.triangle_texcoords(face).unwrap_or([Vec2::zero(); 3]);
+            surface.texcoords.extend_from_slice(&texcoords);
+        }
+        surface.triangles.push([first, first + 1, first + 2]);
+    }
+
+    surface
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::materials::Material;
+
+    fn faces_outwards(surface: &Surface) -> bool {
+        surface.triangles.iter().all(|&[a, b, c]| {
+            let corners = [a, b, c].map(|i| surface.positions[i as usize]);
+            dot(triangle_normal(corners), surface.normals[a as usize]) > 0.0
+        })
+    }
+
+    #[test]
+    fn sphere_lies_on_its_surface() {
+        let sphere = Sphere::new(Vec3::new(1.0, 2.0, -3.0), 1.5, Material::default());
+        let surface = sphere_surface(&sphere);
+
+        assert!(surface.positions.iter().all(|&p| ((p - sphere.centre).length() - 1.5).abs() < 1.0e-5));
+        assert_eq!(surface.triangles.len(), 2 * SPHERE_SEGMENTS * (SPHERE_RINGS - 1));
+        assert!(faces_outwards(&surface));
+    }
+
+    #[test]
+    fn planes_face_their_normal() {
+        for normal in [Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0), Vec3::new(1.0, 0.0, 1.0)] {
+            let plane = Plane::new(Vec3::zero(), normal, Material::default()).with_extent(Vec2::new(2.0, 3.0));
+            let surface = plane_surface(&plane);
+            assert!(faces_outwards(&surface));
+            assert!(surface.positions.iter().all(|&p| dot(p, plane.normal).abs() < 1.0e-5));
+        }
+    }
+}
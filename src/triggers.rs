@@ -0,0 +1,201 @@
+use crate::animation;
+use crate::camera::Camera;
+use crate::collision::{self, Collider};
+use crate::geometry::Vec3;
+use crate::materials::Material;
+use crate::scene::Scene;
+
+use serde::{Deserialize, Serialize};
+
+// Something done to the scene when a trigger fires
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Action {
+    // Sets a number by its animation path, such as `lights[0].intensity`
+    Set { path: String, value: f32 },
+    // Gives a shape a new material
+    Material { shape: usize, material: Material },
+}
+
+// A region that fires when the shape it tracks, or the camera if it tracks
+// none, enters it: its actions are carried out, and animation tracks started
+// `on` its name begin playing from that moment. Shapes are tracked by their
+// collider if they have one and their centre otherwise.
+//
+//     triggers: [(
+//         name: "door",
+//         centre: (0.0, 0.0, -10.0),
+//         region: Box(size: (4.0, 4.0, 4.0)),
+//         shape: Some(2),
+//         actions: [Set(path: "lights[0].intensity", value: 3.0)],
+//     )]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Trigger {
+    pub name: String,
+    pub centre: Vec3<f32>,
+    pub region: Collider,
+    #[serde(default)]
+    pub shape: Option<usize>,
+    #[serde(default)]
+    pub actions: Vec<Action>,
+    // Fire the first time only, rather than every time it's entered
+    #[serde(default)]
+    pub once: bool,
+    #[serde(skip)]
+    inside: bool,
+    #[serde(skip)]
+    fired: bool,
+}
+
+impl Trigger {
+    pub fn new(name: &str, centre: Vec3<f32>, region: Collider, shape: Option<usize>) -> Self {
+        Trigger {
+            name: name.to_string(),
+            centre,
+            region,
+            shape,
+            actions: Vec::new(),
+            once: false,
+            inside: false,
+            fired: false,
+        }
+    }
+
+    pub fn with_action(mut self, action: Action) -> Self {
+        self.actions.push(action);
+        self
+    }
+
+    pub fn firing_once(mut self) -> Self {
+        self.once = true;
+        self
+    }
+
+    fn contains(&self, scene: &Scene, camera: &Camera) -> bool {
+        let point = Collider::Sphere { radius: 0.0 };
+        let (tracked, centre) = match self.shape {
+            None => (point, camera.position),
+            Some(shape) => match scene.shapes.get(shape) {
+                Some(tracked) => (scene.colliders.get(&shape).copied().unwrap_or(point), tracked.centre()),
+                None => return false,
+            },
+        };
+        collision::contact(tracked, centre, self.region, self.centre).is_some()
+    }
+}
+
+fn apply(scene: &mut Scene, camera: &mut Camera, action: &Action) -> crate::Result<bool> {
+    match action {
+        Action::Set { path, value } => animation::set(scene, camera, path, *value),
+        Action::Material { shape, material } => {
+            let target = scene.shapes.get_mut(*shape).ok_or_else(|| format!("there's no shape {}", shape))?;
+            let changed = target.material() != material;
+            *target.material_mut() = *material;
+            Ok(changed)
+        }
+    }
+}
+
+// Fires the triggers whose tracked shapes have entered them since last
+// checked, at `time` seconds into the animation, returning whether the scene
+// changed. Actions that can't be carried out are skipped, having been
+// reported by `problems` on loading.
+pub fn fire(scene: &mut Scene, camera: &mut Camera, time: f32) -> bool {
+    if scene.triggers.is_empty() {
+        return false;
+    }
+
+    let mut triggers = std::mem::take(&mut scene.triggers);
+    let mut changed = false;
+    for trigger in &mut triggers {
+        let inside = trigger.contains(scene, camera);
+        let entered = inside && !trigger.inside;
+        trigger.inside = inside;
+        if !entered || (trigger.once && trigger.fired) {
+            continue;
+        }
+
+        trigger.fired = true;
+        for action in &trigger.actions {
+            changed |= apply(scene, camera, action).unwrap_or(false);
+        }
+        changed |= scene.animation.start(&trigger.name, time);
+    }
+    scene.triggers = triggers;
+
+    changed
+}
+
+// Why each trigger or action that can't work won't, found by trying the
+// actions on a copy of the scene
+pub fn problems(scene: &Scene) -> Vec<String> {
+    let mut copy = scene.clone();
+    let mut camera = Camera::default();
+    let mut problems = Vec::new();
+
+    for trigger in &scene.triggers {
+        if let Some(shape) = trigger.shape.filter(|&shape| shape >= scene.shapes.len()) {
+            problems.push(format!("trigger `{}` tracks shape {}, which doesn't exist", trigger.name, shape));
+        }
+        for action in &trigger.actions {
+            if let Err(e) = apply(&mut copy, &mut camera, action) {
+                problems.push(format!("trigger `{}`: {}", trigger.name, e));
+            }
+        }
+    }
+
+    for track in &scene.animation.tracks {
+        if let Some(name) = track.on.as_ref().filter(|&name| scene.triggers.iter().all(|t| &t.name != name)) {
+            problems.push(format!("track `{}` starts on `{}`, which isn't a trigger", track.path, name));
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::{Curve, Interpolation, Track};
+
+    #[test]
+    fn entering_fires_actions_and_starts_tracks() {
+        let mut scene = Scene::default_scene();
+        let mut camera = Camera::default();
+        let region = Collider::Box { size: Vec3::new(2.0, 2.0, 2.0) };
+        let red = Material::default().with_reflectivity(0.5);
+        scene.triggers.push(
+            Trigger::new("door", Vec3::new(0.0, 0.0, -5.0), region, None)
+                .with_action(Action::Set { path: "lights[0].intensity".to_string(), value: 3.0 })
+                .with_action(Action::Material { shape: 4, material: red }),
+        );
+        scene.animation.curves.insert("fade".to_string(), Curve::new(&[(0.0, 0.0), (1.0, 1.0)], Interpolation::Linear));
+        let path = "lights[1].intensity".to_string();
+        scene.animation.tracks.push(Track { path, curve: "fade".to_string(), on: Some("door".to_string()) });
+        assert!(problems(&scene).is_empty());
+
+        // Nothing happens outside, and the track waits for the trigger
+        let before = scene.lights[1].intensity;
+        assert!(!fire(&mut scene, &mut camera, 1.0));
+        animation::animate(&mut scene, &mut camera, 1.0);
+        assert_eq!(scene.lights[1].intensity, before);
+
+        camera.position = Vec3::new(0.5, 0.0, -5.0);
+        assert!(fire(&mut scene, &mut camera, 2.0));
+        assert_eq!(scene.lights[0].intensity, 3.0);
+        assert_eq!(*scene.shapes[4].material(), red);
+        animation::animate(&mut scene, &mut camera, 2.5);
+        assert_eq!(scene.lights[1].intensity, 0.5);
+
+        // Staying inside doesn't fire again, but leaving and coming back does
+        scene.lights[0].intensity = 1.0;
+        assert!(!fire(&mut scene, &mut camera, 3.0));
+        camera.position = Vec3::zero();
+        fire(&mut scene, &mut camera, 4.0);
+        camera.position = Vec3::new(0.0, 0.0, -5.0);
+        assert!(fire(&mut scene, &mut camera, 5.0));
+        assert_eq!(scene.lights[0].intensity, 3.0);
+
+        scene.triggers[0].shape = Some(10);
+        assert_eq!(problems(&scene).len(), 1);
+    }
+}
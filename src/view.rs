@@ -0,0 +1,363 @@
+use tinyraytracer::Result;
+use tinyraytracer::camera::{Camera, MAX_FOV, MIN_FOV};
+use tinyraytracer::denoise::Guides;
+use tinyraytracer::diagnostics::Preview;
+use tinyraytracer::film::Film;
+use tinyraytracer::framebuffer::{Framebuffer, Tile};
+use tinyraytracer::geometry::Vec3;
+use tinyraytracer::render::{RenderMode, TraceSettings};
+
+use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{Window, WindowContext};
+
+use std::str::FromStr;
+
+// Fraction of the remaining distance to the target field of view covered each
+// update tick, and how close counts as there
+const FOV_EASING: f32 = 0.2;
+const FOV_SNAP: f32 = 1.0e-3;
+
+// Limits of the render scale, as a fraction of the window's drawable size
+pub const MIN_RENDER_SCALE: f32 = 0.5;
+pub const MAX_RENDER_SCALE: f32 = 2.0;
+
+// The resolution rendered at, independent of the size of the window the image
+// is stretched to fill
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Resolution {
+    // A fraction of the window's drawable size
+    Scale(f32),
+    Fixed(usize, usize),
+}
+
+impl Resolution {
+    // The common presets in turn, ending back at the window's own size
+    pub fn next_preset(self) -> Self {
+        match self {
+            Resolution::Fixed(1280, 720) => Resolution::Fixed(1920, 1080),
+            Resolution::Fixed(1920, 1080) => Resolution::Fixed(3840, 2160),
+            Resolution::Fixed(3840, 2160) => Resolution::Scale(1.0),
+            _ => Resolution::Fixed(1280, 720),
+        }
+    }
+}
+
+impl FromStr for Resolution {
+    type Err = String;
+
+    // A preset name or a percentage of the window size, such as `75%`
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "720p" => Ok(Resolution::Fixed(1280, 720)),
+            "1080p" => Ok(Resolution::Fixed(1920, 1080)),
+            "4k" => Ok(Resolution::Fixed(3840, 2160)),
+            _ => {
+                let scale = s
+                    .strip_suffix('%')
+                    .and_then(|percent| percent.parse::<f32>().ok())
+                    .map(|percent| percent / 100.0)
+                    .filter(|scale| (MIN_RENDER_SCALE..=MAX_RENDER_SCALE).contains(scale));
+
+                scale.map(Resolution::Scale).ok_or_else(|| {
+                    format!("unknown resolution `{}` (expected 720p, 1080p, 4k or 50% to 200%)", s)
+                })
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Resolution {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Resolution::Scale(scale) => write!(f, "{:.0}% of the window", scale * 100.0),
+            Resolution::Fixed(width, height) => write!(f, "{}x{}", width, height),
+        }
+    }
+}
+
+// A window together with the camera and render mode used to fill it. The
+// texture borrows from a creator owned by the caller, since the creator has to
+// outlive every texture made from it.
+//
+// Frames are accumulated progressively: each one adds a sample per pixel to
+// the framebuffer until something changes what's being rendered. When a
+// region is set, only the pixels inside it go on being refined once the
+// whole image has a preview sample.
+//
+// The image is rendered at its own resolution and stretched over the window,
+// so it can be traced at less than the window's size for speed or more for
+// quality.
+//
+// With denoising on, the displayed image is a filtered copy of the
+// accumulated one, guided by normal and depth images rendered whenever the
+// accumulation starts over. A preview transform, when set, is applied to
+// whichever is displayed.
+//
+// The first sample after starting over is previewed coarse to fine, over as
+// many frames as it takes, so that slow frames still show something current.
+//
+// Zooming sets a target field of view that the camera eases towards over the
+// following updates, rather than jumping straight to it.
+pub struct View<'a> {
+    pub canvas: Canvas<Window>,
+    pub framebuffer: Framebuffer,
+    pub camera: Camera,
+    pub mode: RenderMode,
+    pub settings: TraceSettings,
+    pub accumulated: usize,
+    // The stride of the coarsest preview grid of the first sample rendered so
+    // far, or zero if it hasn't been started
+    pub preview_stride: usize,
+    resolution: Resolution,
+    region: Option<Tile>,
+    pub region_accumulated: usize,
+    pub denoise: bool,
+    pub guides: Option<Guides>,
+    // The filtered image, and the sample counts it was filtered at
+    pub denoised: Vec<Vec3<f32>>,
+    pub denoised_at: Option<(usize, usize)>,
+    pub preview: Preview,
+    accumulated_view: Option<(Camera, RenderMode, TraceSettings)>,
+    target_fov: f32,
+    // Field of view in whole degrees last shown in the window title
+    shown_fov: Option<i32>,
+    texture: Texture<'a>,
+}
+
+impl<'a> View<'a> {
+    pub fn new(
+        canvas: Canvas<Window>,
+        texture_creator: &'a TextureCreator<WindowContext>,
+        camera: Camera,
+        mode: RenderMode,
+        resolution: Resolution,
+    ) -> Result<Self> {
+        let (width, height) = render_size(&canvas, resolution)?;
+        let texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB24, width, height)?;
+
+        Ok(View {
+            canvas,
+            framebuffer: Framebuffer::new(width as usize, height as usize),
+            camera,
+            mode,
+            settings: TraceSettings::default(),
+            accumulated: 0,
+            preview_stride: 0,
+            resolution,
+            region: None,
+            region_accumulated: 0,
+            denoise: false,
+            guides: None,
+            denoised: Vec::new(),
+            denoised_at: None,
+            preview: Preview::Off,
+            accumulated_view: None,
+            target_fov: camera.fov,
+            shown_fov: None,
+            texture,
+        })
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.canvas.window().id()
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    pub fn set_resolution(
+        &mut self,
+        resolution: Resolution,
+        texture_creator: &'a TextureCreator<WindowContext>,
+    ) -> Result<()> {
+        self.resolution = resolution;
+        self.resize(texture_creator)
+    }
+
+    // Follows the drawable size of the window when rendering at a scale of it,
+    // which changes on resize or when moving to a display with a different
+    // pixel density
+    pub fn resize(&mut self, texture_creator: &'a TextureCreator<WindowContext>) -> Result<()> {
+        let (width, height) = render_size(&self.canvas, self.resolution)?;
+
+        if (width as usize, height as usize) != (self.framebuffer.width, self.framebuffer.height) {
+            self.texture = texture_creator.create_texture_streaming(PixelFormatEnum::RGB24, width, height)?;
+            self.framebuffer = Framebuffer::new(width as usize, height as usize);
+            self.restart();
+            self.region = None;
+        }
+
+        Ok(())
+    }
+
+    // Converts a position in window coordinates, as reported by mouse events,
+    // to framebuffer pixels, which differ on high-DPI displays
+    pub fn pixel_at(&self, x: i32, y: i32) -> (f32, f32) {
+        let (width, height) = self.canvas.window().size();
+        (
+            x as f32 * self.framebuffer.width as f32 / width.max(1) as f32,
+            y as f32 * self.framebuffer.height as f32 / height.max(1) as f32,
+        )
+    }
+
+    // Throws away the accumulated samples if the scene changed or the camera,
+    // mode or trace settings differ from those they were rendered with
+    pub fn restart_if_changed(&mut self, scene_changed: bool) {
+        let current = Some((self.camera, self.mode, self.settings));
+
+        if scene_changed || self.accumulated_view != current {
+            self.restart();
+            self.region_accumulated = 0;
+            self.accumulated_view = current;
+        }
+    }
+
+    // Starts accumulating again from nothing
+    pub fn restart(&mut self) {
+        self.accumulated = 0;
+        self.preview_stride = 0;
+    }
+
+    // Jumps to another camera, field of view included
+    pub fn set_camera(&mut self, camera: Camera) {
+        self.camera = camera;
+        self.target_fov = camera.fov;
+    }
+
+    // Narrows the field of view by `amount` radians, or widens it for
+    // negative amounts
+    pub fn zoom(&mut self, amount: f32) {
+        self.set_target_fov(self.target_fov - amount);
+    }
+
+    pub fn set_target_fov(&mut self, fov: f32) {
+        self.target_fov = fov.clamp(MIN_FOV, MAX_FOV);
+    }
+
+    // Moves the camera's field of view one update tick closer to the target
+    pub fn ease_fov(&mut self) {
+        let remaining = self.target_fov - self.camera.fov;
+
+        if remaining.abs() < FOV_SNAP {
+            self.camera.set_fov(self.target_fov);
+        } else {
+            self.camera.set_fov(self.camera.fov + remaining * FOV_EASING);
+        }
+    }
+
+    // Keeps the current field of view on show after the window's title
+    pub fn show_fov(&mut self, title: &str) -> Result<()> {
+        let degrees = self.camera.fov.to_degrees().round() as i32;
+
+        if self.shown_fov != Some(degrees) {
+            self.canvas.window_mut().set_title(&format!("{} - fov {}°", title, degrees))?;
+            self.shown_fov = Some(degrees);
+        }
+
+        Ok(())
+    }
+
+    // What's needed besides the framebuffer to carry on accumulating later
+    pub fn film(&self) -> Film {
+        Film {
+            camera: self.camera,
+            mode: self.mode,
+            settings: self.settings,
+            accumulated: self.accumulated,
+            region: self.region,
+            region_accumulated: self.region_accumulated,
+        }
+    }
+
+    // Carries on from a saved film, rendering at its resolution whatever the
+    // size of the window
+    pub fn resume(
+        &mut self,
+        film: Film,
+        framebuffer: Framebuffer,
+        texture_creator: &'a TextureCreator<WindowContext>,
+    ) -> Result<()> {
+        self.set_resolution(Resolution::Fixed(framebuffer.width, framebuffer.height), texture_creator)?;
+
+        self.set_camera(film.camera);
+        self.mode = film.mode;
+        self.settings = film.settings;
+        self.framebuffer = framebuffer;
+        self.accumulated = film.accumulated;
+        self.region = None;
+        if film.region.is_some() {
+            self.set_region(film.region);
+        }
+        self.region_accumulated = film.region_accumulated;
+        self.accumulated_view = Some((self.camera, self.mode, self.settings));
+
+        Ok(())
+    }
+
+    pub fn region(&self) -> Option<Tile> {
+        self.region
+    }
+
+    // Restricts refinement to a rectangle of the image, clipped to its
+    // bounds, or lifts the restriction. Pixels inside a region end up with
+    // more samples than the rest, so lifting it starts over.
+    pub fn set_region(&mut self, region: Option<Tile>) {
+        self.region = region.and_then(|region| {
+            let x = region.x.min(self.framebuffer.width);
+            let y = region.y.min(self.framebuffer.height);
+            let width = region.width.min(self.framebuffer.width - x);
+            let height = region.height.min(self.framebuffer.height - y);
+
+            if width > 0 && height > 0 {
+                Some(Tile { x, y, width, height })
+            } else {
+                None
+            }
+        });
+
+        if self.region.is_some() {
+            self.region_accumulated = self.accumulated;
+        } else {
+            self.restart();
+        }
+    }
+
+    pub fn present(&mut self) -> Result<()> {
+        self.texture.update(None, &self.framebuffer.pixels, self.framebuffer.pitch())?;
+        self.canvas.copy(&self.texture, None, None)?;
+        self.canvas.present();
+        Ok(())
+    }
+}
+
+fn render_size(canvas: &Canvas<Window>, resolution: Resolution) -> Result<(u32, u32)> {
+    match resolution {
+        Resolution::Scale(scale) => {
+            let (width, height) = canvas.output_size()?;
+            let scaled = |size: u32| ((size as f32 * scale).round() as u32).max(1);
+            Ok((scaled(width), scaled(height)))
+        }
+        Resolution::Fixed(width, height) => Ok((width as u32, height as u32)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_resolutions() {
+        assert_eq!("1080p".parse(), Ok(Resolution::Fixed(1920, 1080)));
+        assert_eq!("75%".parse(), Ok(Resolution::Scale(0.75)));
+        assert!("300%".parse::<Resolution>().is_err());
+        assert!("big".parse::<Resolution>().is_err());
+
+        let mut resolution = Resolution::Scale(0.5);
+        for _ in 0..4 {
+            resolution = resolution.next_preset();
+        }
+        assert_eq!(resolution, Resolution::Scale(1.0));
+    }
+}
@@ -0,0 +1,257 @@
+use crate::camera::Camera;
+use crate::framebuffer::{Framebuffer, Tile};
+use crate::geometry::{Hit, Ray, Vec3};
+use crate::materials::Material;
+use crate::render::{
+    Components, RenderMode, Renderer, Tracer, direct_components, nearest_shape, reflection_ray, resolve_pattern, shadow_ray,
+};
+use crate::sampling;
+use crate::scene::Scene;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+// A path waiting for its next ray to be intersected. `weight` is how much of
+// the light arriving along the ray reaches the pixel, including the boost
+// for having survived roulette, while `throughput` leaves that out.
+#[derive(Copy, Clone, Debug)]
+struct Path {
+    pixel: usize,
+    ray: Ray,
+    depth: u32,
+    throughput: f32,
+    weight: f32,
+    seed: u64,
+}
+
+// A path's ray that hit something, gathering light from the shadow rays
+// traced from it
+#[derive(Copy, Clone, Debug)]
+struct Shading {
+    path: Path,
+    shape: usize,
+    hit: Hit,
+    material: Material,
+    reflected: Vec3<f32>,
+    diffuse: f32,
+    specular: f32,
+    sun: (f32, f32),
+    environment: Components,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Shadow {
+    shading: usize,
+    light: usize,
+    weight: f32,
+    ray: Ray,
+    distance: f32,
+}
+
+// Renders the same image as `Renderer::render` with the path tracer split
+// into stages, each run over a whole queue of rays before the next: camera
+// rays are generated for every sample of a tile, intersected, shaded into
+// shadow rays and reflection rays, and the shadow rays traced, repeating
+// with the reflection rays until no paths are left. Each stage runs the same
+// code over plain arrays, which keeps the scene and the queue in cache and
+// maps directly onto GPU kernels. Normals and depth have no paths to follow,
+// so they're rendered as usual.
+pub fn render(renderer: &Renderer, framebuffer: &mut Framebuffer, camera: &Camera, scene: &Scene) {
+    if !matches!(renderer.mode, RenderMode::Shaded | RenderMode::Clay) {
+        return renderer.render(framebuffer, camera, scene);
+    }
+
+    let tracer = Tracer::new(scene, renderer.mode, renderer.settings);
+    let tiles = framebuffer.tiles_in(framebuffer.bounds());
+    let next_tile = AtomicUsize::new(0);
+    let threads = framebuffer.worker_count(tiles.len());
+
+    let rendered: Vec<(Tile, Vec<Vec3<f32>>)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    while let Some(&tile) = tiles.get(next_tile.fetch_add(1, Ordering::Relaxed)) {
+                        done.push((tile, render_tile(renderer, &tracer, camera, framebuffer, tile)));
+                    }
+                    done
+                })
+            })
+            .collect();
+
+        workers.into_iter().flat_map(|worker| worker.join().expect("render worker panicked")).collect()
+    });
+
+    for (tile, colours) in rendered {
+        for (index, &colour) in colours.iter().enumerate() {
+            framebuffer.set(tile.x + index % tile.width, tile.y + index / tile.width, colour);
+        }
+    }
+}
+
+fn render_tile(
+    renderer: &Renderer,
+    tracer: &Tracer,
+    camera: &Camera,
+    framebuffer: &Framebuffer,
+    tile: Tile,
+) -> Vec<Vec3<f32>> {
+    let (width, height) = (framebuffer.width, framebuffer.height);
+    let samples = renderer.sampling.sample_count(renderer.samples);
+    let mut colours = vec![Vec3::zero(); tile.width * tile.height];
+
+    let mut paths = generate(renderer, camera, width, height, tile, samples);
+    let mut shadings = Vec::new();
+    let mut shadows = Vec::new();
+
+    while !paths.is_empty() {
+        intersect(tracer, &paths, &mut shadings, &mut colours, renderer.settings.max_depth);
+        paths = shade(tracer, &mut shadings, &mut shadows);
+        trace_shadows(tracer, &mut shadings, &shadows);
+
+        for shading in &shadings {
+            let (diffuse, specular) = (shading.diffuse + shading.sun.0, shading.specular + shading.sun.1);
+            let components = direct_components(&shading.material, diffuse, specular, shading.reflected);
+            let pixel = shading.path.pixel;
+            colours[pixel] = colours[pixel] + (components.total() + shading.environment.total()) * shading.path.weight;
+        }
+    }
+
+    colours.iter().map(|&colour| colour * (1.0 / samples as f32)).collect()
+}
+
+// A camera ray for every sample of every pixel in the tile
+fn generate(renderer: &Renderer, camera: &Camera, width: usize, height: usize, tile: Tile, samples: usize) -> Vec<Path> {
+    let mut paths = Vec::with_capacity(tile.width * tile.height * samples);
+
+    for j in tile.y..tile.y + tile.height {
+        for i in tile.x..tile.x + tile.width {
+            for k in 0..samples {
+                let (dx, dy) = renderer.sampling.offset((i, j), width, k, samples);
+                paths.push(Path {
+                    pixel: (j - tile.y) * tile.width + i - tile.x,
+                    ray: camera.ray_through(i as f32 + dx, j as f32 + dy, width, height),
+                    depth: 0,
+                    throughput: 1.0,
+                    weight: 1.0,
+                    seed: renderer.sample_seed(j * width + i, k),
+                });
+            }
+        }
+    }
+
+    paths
+}
+
+// Finds what each path's ray hits, replacing the previous shadings. Rays
+// that escape, or that go deeper than allowed, see the background.
+fn intersect(tracer: &Tracer, paths: &[Path], shadings: &mut Vec<Shading>, colours: &mut [Vec3<f32>], max_depth: u32) {
+    shadings.clear();
+
+    for path in paths {
+        match nearest_shape(&path.ray, tracer.scene()) {
+            Some((shape, hit)) if path.depth <= max_depth => {
+                let hit = resolve_pattern(hit);
+                shadings.push(Shading {
+                    path: *path,
+                    shape,
+                    hit,
+                    material: tracer.material(&hit),
+                    reflected: Vec3::zero(),
+                    diffuse: 0.0,
+                    specular: 0.0,
+                    sun: (0.0, 0.0),
+                    environment: Components::default(),
+                });
+            }
+            _ => colours[path.pixel] = colours[path.pixel] + tracer.background(path.ray.direction) * path.weight,
+        }
+    }
+}
+
+// Queues the shadow rays from every hit, replacing the previous ones, and
+// returns the reflection rays that survive roulette as the next paths. The
+// random numbers are drawn just as the recursive tracer draws them.
+fn shade(tracer: &Tracer, shadings: &mut [Shading], shadows: &mut Vec<Shadow>) -> Vec<Path> {
+    shadows.clear();
+    let mut next = Vec::new();
+
+    for (index, shading) in shadings.iter_mut().enumerate() {
+        let Shading { path, material, .. } = *shading;
+        let throughput = path.throughput * material.reflectivity;
+        let survival = tracer.survival(path.depth, throughput);
+        let seed = sampling::hash(path.seed);
+
+        if material.reflectivity > 0.0 {
+            if let Some(colour) = tracer.probe_reflection(shading.shape, &path.ray, &shading.hit) {
+                shading.reflected = colour;
+            } else if sampling::random(seed) < survival {
+                next.push(Path {
+                    pixel: path.pixel,
+                    ray: reflection_ray(&path.ray, &shading.hit),
+                    depth: path.depth + 1,
+                    throughput,
+                    weight: path.weight * material.reflectivity / survival,
+                    seed,
+                });
+            }
+        }
+
+        shading.sun = tracer.sun_light(&path.ray, &shading.hit, &material, seed);
+        shading.environment = tracer.environment_light(&path.ray, &shading.hit, &material, seed);
+
+        tracer.for_each_light(shading.shape, shading.hit.point, seed, |light, weight| {
+            let (ray, distance) = shadow_ray(&shading.hit, tracer.scene().lights[light].position);
+            shadows.push(Shadow { shading: index, light, weight, ray, distance });
+        });
+    }
+
+    next
+}
+
+// Traces the queued shadow rays, adding the light each lets through to the
+// hit it came from, in the same order as the recursive tracer adds them
+fn trace_shadows(tracer: &Tracer, shadings: &mut [Shading], shadows: &[Shadow]) {
+    for shadow in shadows {
+        let shading = &mut shadings[shadow.shading];
+        let visibility = tracer.visibility(shading.shape, shadow.light, shading.hit.point, &shadow.ray, shadow.distance);
+        let lit = shadow.weight * visibility;
+        let (diffuse, specular) = tracer.light_response(&shading.path.ray, &shading.hit, &shading.material, shadow.light, lit);
+
+        shading.diffuse += diffuse;
+        shading.specular += specular;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::{Shape, Sphere};
+    use crate::sampling::Sampling;
+    use crate::scene::Light;
+
+    #[test]
+    fn matches_the_recursive_tracer() {
+        let mut scene = Scene::default_scene();
+
+        // More lights than light samples, so lights are picked at random,
+        // and a mirror for paths to bounce between
+        for k in 0..12 {
+            scene.lights.push(Light::new(Vec3::new(k as f32 - 6.0, 15.0, 5.0), 0.2));
+        }
+        let mirror = Material::default().with_reflectivity(0.9);
+        scene.shapes.push(Shape::Sphere(Sphere::new(Vec3::new(0.0, 0.0, -12.0), 2.0, mirror)));
+
+        let renderer = Renderer { samples: 3, sampling: Sampling::BlueNoise, ..Renderer::default() };
+        let camera = Camera::default();
+
+        let mut expected = Framebuffer::new(48, 36);
+        renderer.render(&mut expected, &camera, &scene);
+        let mut wavefront = Framebuffer::new(48, 36);
+        render(&renderer, &mut wavefront, &camera, &scene);
+
+        for (a, b) in expected.colours.iter().zip(&wavefront.colours) {
+            assert!((*a - *b).length() < 1.0e-4, "{:?} != {:?}", a, b);
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use tinyraytracer::geometry::{Aabb, Intersect, Plane, Ray, Sphere, Triangle, Vec3, cross, dot, reflect};
+use tinyraytracer::materials::Material;
+
+use proptest::prelude::*;
+
+// Invariants of the geometry, checked over randomly generated rays and shapes.
+// Tolerances are relative to the size of the scene the values come from, as
+// everything is single precision.
+
+const TOLERANCE: f32 = 1.0e-3;
+
+fn coordinate() -> impl Strategy<Value = f32> {
+    -100.0f32..100.0
+}
+
+fn point() -> impl Strategy<Value = Vec3<f32>> {
+    (coordinate(), coordinate(), coordinate()).prop_map(|(x, y, z)| Vec3::new(x, y, z))
+}
+
+fn direction() -> impl Strategy<Value = Vec3<f32>> {
+    (-1.0f32..1.0, -1.0f32..1.0, -1.0f32..1.0)
+        .prop_map(|(x, y, z)| Vec3::new(x, y, z))
+        .prop_filter("too short to normalise", |v| v.length() > 0.1)
+        .prop_map(Vec3::normalise)
+}
+
+fn ray() -> impl Strategy<Value = Ray> {
+    (point(), direction()).prop_map(|(origin, direction)| Ray { origin, direction })
+}
+
+fn sphere() -> impl Strategy<Value = Sphere> {
+    (point(), 0.1f32..20.0).prop_map(|(centre, radius)| Sphere::new(centre, radius, Material::default()))
+}
+
+fn triangle() -> impl Strategy<Value = Triangle> {
+    (point(), point(), point()).prop_map(|(a, b, c)| Triangle {
+        vertices: [a, b, c],
+        material: Material::default(),
+    })
+}
+
+fn aabb() -> impl Strategy<Value = Aabb> {
+    (point(), point()).prop_map(|(a, b)| Aabb::from_points(&[a, b]))
+}
+
+fn is_unit(v: Vec3<f32>) -> bool {
+    (v.length() - 1.0).abs() < TOLERANCE
+}
+
+proptest! {
+    #[test]
+    fn sphere_hits_lie_on_the_surface(sphere in sphere(), ray in ray()) {
+        if let Some(hit) = sphere.ray_intersect(&ray) {
+            prop_assert!(hit.distance >= 0.0);
+            prop_assert!(((hit.point - sphere.centre).length() - sphere.radius).abs() < TOLERANCE * 100.0);
+            prop_assert!(is_unit(hit.normal));
+        }
+    }
+
+    #[test]
+    fn rays_towards_a_sphere_hit_it(sphere in sphere(), origin in point()) {
+        let offset = sphere.centre - origin;
+        prop_assume!(offset.length() > sphere.radius * 1.01);
+
+        let ray = Ray { origin, direction: offset.normalise() };
+        let hit = sphere.ray_intersect(&ray);
+        prop_assert!(hit.is_some());
+
+        // From outside, the near side is hit and faces back along the ray
+        let hit = hit.unwrap();
+        prop_assert!(hit.distance <= offset.length());
+        prop_assert!(dot(hit.normal, ray.direction) < 0.0);
+    }
+
+    #[test]
+    fn triangles_are_hit_where_aimed(triangle in triangle(), origin in point(), u in 0.01f32..0.99, v in 0.01f32..0.99) {
+        let [a, b, c] = triangle.vertices;
+        prop_assume!(cross(b - a, c - a).length() > 1.0);
+
+        // Folded back into the triangle if past its far edge
+        let (u, v) = if u + v > 1.0 { (1.0 - u, 1.0 - v) } else { (u, v) };
+
+        let target = a + (b - a) * u + (c - a) * v;
+        let offset = target - origin;
+        prop_assume!(offset.length() > 1.0 && dot(offset.normalise(), triangle.normal()).abs() > 0.1);
+
+        let ray = Ray { origin, direction: offset.normalise() };
+        let hit = triangle.ray_intersect(&ray);
+        prop_assert!(hit.is_some());
+
+        let hit = hit.unwrap();
+        prop_assert!((hit.point - target).length() < TOLERANCE * 100.0);
+        prop_assert!(is_unit(hit.normal));
+        prop_assert!(dot(hit.normal, ray.direction) <= 0.0);
+    }
+
+    #[test]
+    fn plane_hits_lie_on_the_plane(point in point(), normal in direction(), ray in ray()) {
+        let plane = Plane::new(point, normal, Material::default());
+
+        if let Some(hit) = plane.ray_intersect(&ray) {
+            prop_assert!(hit.distance >= 0.0);
+            prop_assert!(dot(hit.point - point, plane.normal).abs() < TOLERANCE * 100.0);
+            prop_assert!(is_unit(hit.normal));
+            prop_assert!(dot(hit.normal, ray.direction) <= 0.0);
+        }
+    }
+
+    #[test]
+    fn boxes_contain_their_points(a in point(), b in point(), c in point()) {
+        let aabb = Aabb::from_points(&[a, b, c]);
+
+        for p in [a, b, c] {
+            prop_assert!(aabb.min.x <= p.x && aabb.min.y <= p.y && aabb.min.z <= p.z);
+            prop_assert!(p.x <= aabb.max.x && p.y <= aabb.max.y && p.z <= aabb.max.z);
+        }
+    }
+
+    #[test]
+    fn box_hits_enter_and_leave_the_box(aabb in aabb(), ray in ray()) {
+        let inside = |p: Vec3<f32>, slack: f32| {
+            aabb.min.x - slack <= p.x && p.x <= aabb.max.x + slack
+                && aabb.min.y - slack <= p.y && p.y <= aabb.max.y + slack
+                && aabb.min.z - slack <= p.z && p.z <= aabb.max.z + slack
+        };
+        let slack = TOLERANCE * 100.0;
+
+        match aabb.ray_intersect(&ray) {
+            Some((near, far)) => {
+                prop_assert!(0.0 <= near && near <= far);
+                prop_assert!(inside(ray.origin + ray.direction * near, slack));
+                prop_assert!(far.is_infinite() || inside(ray.origin + ray.direction * far, slack));
+            }
+            // Misses can't start inside the box
+            None => prop_assert!(!inside(ray.origin, 0.0)),
+        }
+    }
+
+    #[test]
+    fn reflection_keeps_length_and_mirrors_the_normal(incident in direction(), normal in direction()) {
+        let reflected = reflect(incident, normal);
+
+        prop_assert!(is_unit(reflected));
+        prop_assert!((dot(reflected, normal) + dot(incident, normal)).abs() < TOLERANCE);
+
+        // Reflecting back again gives the original direction
+        prop_assert!((reflect(reflected, normal) - incident).length() < TOLERANCE);
+    }
+}
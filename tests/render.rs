@@ -0,0 +1,119 @@
+use tinyraytracer::camera::Camera;
+use tinyraytracer::framebuffer::Framebuffer;
+use tinyraytracer::geometry::{Ray, Sphere, Vec2, Vec3};
+use tinyraytracer::materials::Material;
+use tinyraytracer::render::{self, RenderMode, Renderer, TraceSettings, Tracer};
+use tinyraytracer::sampling;
+use tinyraytracer::scene::{Light, Scene};
+
+// A single red sphere straight ahead of the default camera, lit from behind
+// the camera
+fn one_sphere() -> Scene {
+    let red = Material::new(Vec2::new(1.0, 0.0), Vec3::new(1.0, 0.0, 0.0), 1.0);
+
+    let mut scene = Scene::default_scene();
+    scene.shapes = vec![Sphere::new(Vec3::new(0.0, 0.0, -5.0), 1.0, red).into()];
+    scene.lights = vec![Light::new(Vec3::new(0.0, 0.0, 10.0), 1.0)];
+    scene
+}
+
+#[test]
+fn render_to_buffer_sees_sphere_and_background() {
+    let (width, height) = (64, 48);
+    let colours = Renderer::default().render_to_buffer(&one_sphere(), &Camera::default(), width, height);
+
+    assert_eq!(colours.len(), width * height);
+
+    let centre = colours[height / 2 * width + width / 2];
+    assert!(centre.x > 0.9 && centre.y == 0.0 && centre.z == 0.0);
+
+    // The corners see past the sphere to the background
+    let corner = colours[0];
+    assert!(corner.z > corner.x);
+}
+
+#[test]
+fn cast_ray_lights_facing_surface() {
+    let scene = one_sphere();
+    let ray = Ray {
+        origin: Vec3::zero(),
+        direction: Vec3::new(0.0, 0.0, -1.0),
+    };
+
+    let hit = render::scene_intersect(&ray, &scene).unwrap();
+    assert_eq!(hit.distance, 4.0);
+
+    let tracer = Tracer::new(&scene, RenderMode::Shaded, TraceSettings::default());
+    let colour = tracer.cast_ray(&ray, 0, 0);
+    assert_eq!(colour, Vec3::new(1.0, 0.0, 0.0));
+}
+
+#[test]
+fn depth_mode_fades_with_distance() {
+    let scene = one_sphere();
+    let renderer = Renderer {
+        mode: RenderMode::Depth,
+        ..Renderer::default()
+    };
+
+    let colours = renderer.render_to_buffer(&scene, &Camera::default(), 9, 9);
+    let centre = colours[4 * 9 + 4];
+
+    assert!(centre.x > 0.0 && centre.x < 1.0);
+    assert_eq!(colours[0], Vec3::zero());
+}
+
+#[test]
+fn recorded_pixel_matches_render() {
+    let scene = one_sphere();
+    let renderer = Renderer {
+        samples: 4,
+        ..Renderer::default()
+    };
+
+    let colours = renderer.render_to_buffer(&scene, &Camera::default(), 16, 12);
+    let tree = renderer.record_pixel(&scene, &Camera::default(), 16, 12, 8, 6);
+
+    assert_eq!(tree.colour, colours[6 * 16 + 8]);
+    assert_eq!(tree.paths.len(), 4);
+    assert!(tree.paths.iter().all(|path| path[0].shape == Some(0) && path[0].shadows.len() == 1));
+}
+
+#[test]
+fn single_pixels_match_render() {
+    let scene = Scene::default_scene();
+    let renderer = Renderer {
+        samples: 4,
+        ..Renderer::default()
+    };
+
+    let colours = renderer.render_to_buffer(&scene, &Camera::default(), 16, 12);
+    for (x, y) in [(0, 0), (8, 6), (15, 11), (3, 9)] {
+        let colour = renderer.render_pixel(&scene, &Camera::default(), 16, 12, x, y);
+        assert_eq!(colour, colours[y * 16 + x]);
+    }
+}
+
+#[test]
+fn threads_dont_change_the_image() {
+    // Sampling one light at a time and roulette from the first bounce leave
+    // plenty of noise for the seed to change
+    let scene = Scene::default_scene();
+    let renderer = Renderer {
+        samples: 2,
+        settings: TraceSettings { rr_start_depth: 0, light_samples: 1, ..TraceSettings::default() },
+        seed: sampling::frame_seed(7, 3),
+        ..Renderer::default()
+    };
+
+    let render = |renderer: &Renderer, threads| {
+        let mut framebuffer = Framebuffer::new(80, 60);
+        framebuffer.threads = Some(threads);
+        renderer.render(&mut framebuffer, &Camera::default(), &scene);
+        framebuffer.colours
+    };
+
+    let single = render(&renderer, 1);
+    assert_eq!(render(&renderer, 7), single);
+    assert_ne!(render(&Renderer { seed: sampling::frame_seed(7, 4), ..renderer }, 7), single);
+}